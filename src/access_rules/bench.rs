@@ -0,0 +1,72 @@
+//! Throughput benchmarks of the parse, diff and apply path against the in-memory
+//! firewall, so they run without BPF. They are ignored by default; run them with
+//! `cargo test --release access_rules::bench -- --ignored --nocapture`.
+
+use std::time::Instant;
+
+use super::*;
+use crate::firewall::MemoryFirewall;
+
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+/// Share of the entries replaced between the two cycles of a size
+const CHURN_PERCENT: usize = 10;
+
+/// Distinct block entries: mostly IPv4 hosts, every fourth an IPv4 /24 and every
+/// tenth an IPv6 /48, starting at entry `offset`
+fn synthetic_entries(count: usize, offset: usize) -> Vec<String> {
+    (offset..offset + count)
+        .map(|i| {
+            let n = i as u32;
+            if i % 10 == 0 {
+                format!("2001:db8:{:x}::/48", n / 10)
+            } else if i % 4 == 0 {
+                format!("{}/24", Ipv4Addr::from(0x0b00_0000 | ((n / 4) << 8)))
+            } else {
+                Ipv4Addr::from(0x0a00_0000 | n).to_string()
+            }
+        })
+        .collect()
+}
+
+/// Parse `body` and apply it, returning the seconds spent parsing and applying
+fn timed_cycle(targets: &[Mutex<MemoryFirewall>], previous: &PreviousRuleSets, body: &str) -> (f64, f64) {
+    let config = AccessRulesConfig::default();
+    let start = Instant::now();
+    let response: ConfigApiResponse = serde_json::from_str(body).unwrap();
+    let parsed = start.elapsed().as_secs_f64();
+    let start = Instant::now();
+    apply_rules(targets, Some(&response.config.access_rules), previous, &config, None).unwrap();
+    let applied = start.elapsed().as_secs_f64();
+    targets[0].lock().unwrap().writes.clear();
+    (parsed, applied)
+}
+
+fn report(label: &str, entries: usize, seconds: f64) {
+    println!(
+        "{:<28} {:>7} entries {:>10.2} ms {:>12.0} entries/s",
+        label, entries, seconds * 1000.0, entries as f64 / seconds
+    );
+}
+
+#[test]
+#[ignore]
+fn bench_parse_diff_apply() {
+    for size in SIZES {
+        let initial = rules_response(&synthetic_entries(size, 0), serde_json::json!({}));
+        let churn = size * CHURN_PERCENT / 100;
+        let rotated = rules_response(&synthetic_entries(size, churn), serde_json::json!({}));
+        let targets = [Mutex::new(MemoryFirewall::default())];
+        let previous = PreviousRuleSets::default();
+
+        let (parsed, applied) = timed_cycle(&targets, &previous, &initial);
+        report("parse", size, parsed);
+        report("apply, cold start", size, applied);
+        let (_, applied) = timed_cycle(&targets, &previous, &rotated);
+        report(&format!("diff + apply, {}% churn", CHURN_PERCENT), size, applied);
+        let (_, applied) = timed_cycle(&targets, &previous, &rotated);
+        report("diff, unchanged", size, applied);
+
+        let installed = targets[0].lock().unwrap().banned.len();
+        assert_eq!(installed, previous.rules.lock().unwrap().len() + previous.rules_v6.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,229 @@
+//! Periodic resolution of the domain entries of access rule sets
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use hickory_resolver::TokioResolver;
+use tokio::sync::Semaphore;
+
+/// Addresses of every configured domain as of the last resolution, keyed by normalized name
+pub(crate) type ResolvedDomains = BTreeMap<String, Vec<IpAddr>>;
+
+/// Lower-case a domain entry and strip surrounding whitespace and the root dot
+pub(crate) fn normalize_domain(entry: &str) -> String {
+    entry.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether a normalized entry is a plausible DNS hostname: dot-separated labels of
+/// letters, digits and inner hyphens, at most 253 characters in total. A numeric last
+/// label is rejected so IPv4 literals never end up here.
+pub(crate) fn is_valid_domain(name: &str) -> bool {
+    if name.is_empty() || name.len() > 253 || !name.contains('.') {
+        return false;
+    }
+    if name.rsplit('.').next().is_some_and(|tld| tld.bytes().all(|b| b.is_ascii_digit())) {
+        return false;
+    }
+    name.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    })
+}
+
+struct CachedLookup {
+    ips: Vec<IpAddr>,
+    /// Re-resolve once this passes; taken from the record TTLs
+    valid_until: Instant,
+}
+
+/// Outcome of one resolution pass, reported in the cycle summary
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ResolveStats {
+    /// Lookups that answered
+    pub resolved: usize,
+    /// Domains whose cached records were still valid
+    pub cached: usize,
+    pub failed: usize,
+    /// Lookups still pending when the pass ran out of time
+    pub timed_out: usize,
+    pub took: Duration,
+}
+
+/// Limits of one resolution pass
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ResolveLimits {
+    /// Lookups in flight at once
+    pub concurrency: usize,
+    /// Deadline for the whole pass; zero waits for every lookup
+    pub timeout: Duration,
+}
+
+/// TTL-aware cache in front of the system resolver. A failed lookup keeps the
+/// addresses of the last successful one and is retried on the next cycle.
+#[derive(Default)]
+pub(crate) struct DomainCache {
+    resolver: Option<TokioResolver>,
+    entries: HashMap<String, CachedLookup>,
+}
+
+impl DomainCache {
+    /// Resolve every domain whose cached records expired and drop the domains that are
+    /// no longer configured. Returns the addresses of all configured domains.
+    pub(crate) async fn resolve(&mut self, domains: &[String], limits: ResolveLimits) -> (ResolvedDomains, ResolveStats) {
+        self.entries.retain(|name, _| domains.contains(name));
+        if domains.is_empty() {
+            return (ResolvedDomains::new(), ResolveStats::default());
+        }
+
+        if self.resolver.is_none() {
+            match TokioResolver::builder_tokio() {
+                Ok(builder) => self.resolver = Some(builder.build()),
+                Err(e) => log::error!("failed to create DNS resolver, keeping last resolved domain addresses: {}", e),
+            }
+        }
+
+        let mut stats = ResolveStats::default();
+        if let Some(resolver) = self.resolver.clone() {
+            stats = self
+                .refresh(domains, limits, |name| {
+                    let resolver = resolver.clone();
+                    async move {
+                        let lookup = resolver.lookup_ip(name.as_str()).await.map_err(|e| e.to_string())?;
+                        Ok::<_, String>((lookup.iter().collect::<Vec<IpAddr>>(), lookup.valid_until()))
+                    }
+                })
+                .await;
+        }
+
+        let resolved = self.entries.iter().map(|(name, cached)| (name.clone(), cached.ips.clone())).collect();
+        (resolved, stats)
+    }
+
+    /// Look up the domains whose cached records expired through `lookup`, at most
+    /// `limits.concurrency` at a time. A failed or unfinished lookup keeps the last
+    /// resolved addresses and does not hold up the others.
+    async fn refresh<F, Fut>(&mut self, domains: &[String], limits: ResolveLimits, lookup: F) -> ResolveStats
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<(Vec<IpAddr>, Instant), String>>,
+    {
+        let start = Instant::now();
+        let mut stats = ResolveStats::default();
+        let semaphore = Semaphore::new(limits.concurrency.max(1));
+        let mut pending: FuturesUnordered<_> = domains
+            .iter()
+            .filter(|name| {
+                let fresh = self.entries.get(*name).is_some_and(|cached| cached.valid_until > start);
+                stats.cached += usize::from(fresh);
+                !fresh
+            })
+            .map(|name| {
+                let (semaphore, lookup) = (&semaphore, &lookup);
+                async move {
+                    let _permit = semaphore.acquire().await;
+                    (name, lookup(name.clone()).await)
+                }
+            })
+            .collect();
+
+        let deadline = (!limits.timeout.is_zero()).then(|| tokio::time::Instant::now() + limits.timeout);
+        loop {
+            let next = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, pending.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        stats.timed_out = pending.len();
+                        log::warn!(
+                            "DNS resolution ran out of its {:?} budget, keeping last resolved addresses of {} domains",
+                            limits.timeout, stats.timed_out
+                        );
+                        break;
+                    }
+                },
+                None => pending.next().await,
+            };
+            let Some((name, result)) = next else { break };
+            match result {
+                Ok((mut ips, valid_until)) => {
+                    ips.sort();
+                    ips.dedup();
+                    log::debug!(domain = name.as_str(); "resolved {} to {} addresses", name, ips.len());
+                    self.entries.insert(name.clone(), CachedLookup { ips, valid_until });
+                    stats.resolved += 1;
+                }
+                Err(e) => {
+                    let kept = self.entries.get(name).map_or(0, |cached| cached.ips.len());
+                    log::warn!(domain = name.as_str(); "failed to resolve {}, keeping {} last resolved addresses: {}", name, kept, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+        stats.took = start.elapsed();
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_refresh_bounds_lookups_and_keeps_slow_domains() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let domains: Vec<String> = ["a.example", "b.example", "c.example", "slow.example", "bad.example"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let old: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut cache = DomainCache::default();
+        cache.entries.insert("slow.example".to_string(), CachedLookup { ips: vec![old], valid_until: Instant::now() });
+        let (in_flight, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let limits = ResolveLimits { concurrency: 2, timeout: Duration::from_millis(200) };
+
+        let stats = cache
+            .refresh(&domains, limits, |name| {
+                let (in_flight, peak) = (&in_flight, &peak);
+                async move {
+                    peak.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    let delay = if name == "slow.example" { 60_000 } else { 10 };
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    match name.as_str() {
+                        "bad.example" => Err("NXDOMAIN".to_string()),
+                        _ => Ok((vec!["198.51.100.1".parse().unwrap()], Instant::now() + Duration::from_secs(60))),
+                    }
+                }
+            })
+            .await;
+
+        assert_eq!((stats.resolved, stats.failed, stats.timed_out, stats.cached), (3, 1, 1, 0));
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+        // The slow domain keeps what it resolved to before
+        assert_eq!(cache.entries["slow.example"].ips, vec![old]);
+
+        // Fresh records are not looked up again
+        let stats = cache.refresh(&domains[..1], limits, |_| async { Err("unexpected lookup".to_string()) }).await;
+        assert_eq!((stats.resolved, stats.failed, stats.cached), (0, 0, 1));
+    }
+
+    #[test]
+    fn test_domain_validation() {
+        assert_eq!(normalize_domain(" Bad.Example.COM. "), "bad.example.com");
+        assert!(is_valid_domain("bad.example.com"));
+        assert!(is_valid_domain("x-1.example"));
+        assert!(!is_valid_domain("localhost"));
+        assert!(!is_valid_domain("-x.example"));
+        assert!(!is_valid_domain("a..example"));
+        assert!(!is_valid_domain("10.0.0.0/8"));
+        assert!(!is_valid_domain("192.0.2.1"));
+        assert!(!is_valid_domain(&format!("{}.example", "a".repeat(64))));
+    }
+}
@@ -0,0 +1,191 @@
+//! JSON line audit trail of the ban and unban actions applied to the BPF maps
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError, TrySendError, sync_channel};
+
+use serde::Serialize;
+
+use crate::cli::EventSinkConfig;
+
+/// Events buffered between the apply loop and the writer thread; more are dropped
+const EVENT_QUEUE_CAPACITY: usize = 4096;
+
+/// One rule change, serialized as a single JSON line
+#[derive(Debug, Serialize)]
+pub(crate) struct RuleEvent<'a> {
+    pub timestamp: String,
+    pub action: &'a str,
+    pub ip: IpAddr,
+    pub prefix: u32,
+    pub family: &'a str,
+    pub source: &'a str,
+}
+
+impl<'a> RuleEvent<'a> {
+    pub(crate) fn now(action: &'a str, ip: IpAddr, prefix: u32, source: &'a str) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            action,
+            ip,
+            prefix,
+            family: if ip.is_ipv4() { "ipv4" } else { "ipv6" },
+            source,
+        }
+    }
+}
+
+enum SinkTarget {
+    File(BufWriter<std::fs::File>),
+    Datagram(UnixDatagram),
+}
+
+impl SinkTarget {
+    fn open(config: &EventSinkConfig) -> std::io::Result<Self> {
+        match config.kind.trim().to_ascii_lowercase().as_str() {
+            "file" => {
+                let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+                Ok(Self::File(BufWriter::new(file)))
+            }
+            "unix_datagram" => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&config.path)?;
+                Ok(Self::Datagram(socket))
+            }
+            other => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown event sink type '{}', expected file or unix_datagram", other),
+            )),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            Self::File(writer) => writeln!(writer, "{}", line),
+            Self::Datagram(socket) => socket.send(line.as_bytes()).map(|_| ()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::File(writer) => writer.flush(),
+            Self::Datagram(_) => Ok(()),
+        }
+    }
+}
+
+/// Non-blocking handle to the event writer thread. Events are queued and written in
+/// the background, so a slow consumer drops events instead of stalling the apply loop.
+#[derive(Debug)]
+pub struct EventSink {
+    tx: SyncSender<String>,
+    dropped: AtomicU64,
+}
+
+impl EventSink {
+    /// Open the configured target and start its writer thread
+    pub fn open(config: &EventSinkConfig) -> std::io::Result<Self> {
+        let target = SinkTarget::open(config)?;
+        let (tx, rx) = sync_channel(EVENT_QUEUE_CAPACITY);
+        std::thread::Builder::new()
+            .name("moat-events".to_string())
+            .spawn(move || run_writer(target, rx))?;
+        Ok(Self { tx, dropped: AtomicU64::new(0) })
+    }
+
+    pub(crate) fn emit(&self, event: &RuleEvent<'_>) {
+        let line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => {
+                log::warn!("failed to serialize rule event: {}", e);
+                return;
+            }
+        };
+        match self.tx.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                // Warn on the first drop and then once per full queue's worth
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed);
+                if dropped % EVENT_QUEUE_CAPACITY as u64 == 0 {
+                    log::warn!("rule event queue full, {} events dropped so far", dropped + 1);
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+/// Write queued lines and flush whenever the queue runs dry; exits once every
+/// sender is gone
+fn run_writer(mut target: SinkTarget, rx: Receiver<String>) {
+    while let Ok(line) = rx.recv() {
+        let mut next = Some(line);
+        while let Some(line) = next.take() {
+            if let Err(e) = target.write_line(&line) {
+                log::warn!("failed to write rule event: {}", e);
+            }
+            match rx.try_recv() {
+                Ok(line) => next = Some(line),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => {}
+            }
+        }
+        if let Err(e) = target.flush() {
+            log::warn!("failed to flush rule events: {}", e);
+        }
+    }
+    let _ = target.flush();
+}
+
+/// Lines of the file sink at `path` once `count` were written, then remove the file.
+/// The writer thread drains in the background after the last sender is dropped, so
+/// the file is polled for up to a second.
+#[cfg(test)]
+pub(crate) fn written_lines(path: &std::path::Path, count: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for _ in 0..100 {
+        lines = std::fs::read_to_string(path).unwrap_or_default().lines().map(str::to_string).collect();
+        if lines.len() == count {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    let _ = std::fs::remove_file(path);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_sink_writes_json_lines() {
+        let path = std::env::temp_dir().join(format!("moat-events-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = EventSinkConfig { kind: "file".to_string(), path: path.display().to_string() };
+        let sink = EventSink::open(&config).unwrap();
+        sink.emit(&RuleEvent::now("ban", "192.0.2.0".parse().unwrap(), 24, "sync"));
+        sink.emit(&RuleEvent::now("unban", "2001:db8::".parse().unwrap(), 32, "sync"));
+        // Dropping the last sender lets the writer drain, flush and exit
+        drop(sink);
+
+        let lines = written_lines(&path, 2);
+        assert_eq!(lines.len(), 2);
+        let first: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(first["action"], "ban");
+        assert_eq!(first["ip"], "192.0.2.0");
+        assert_eq!(first["prefix"], 24);
+        assert_eq!(first["family"], "ipv4");
+        assert_eq!(first["source"], "sync");
+        let second: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(second["family"], "ipv6");
+    }
+
+    #[test]
+    fn test_unknown_sink_type_is_rejected() {
+        let config = EventSinkConfig { kind: "kafka".to_string(), path: "/dev/null".to_string() };
+        assert!(EventSink::open(&config).is_err());
+    }
+}
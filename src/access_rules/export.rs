@@ -0,0 +1,110 @@
+//! On-demand export of the applied rule set as JSON or CSV
+
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::{RuleSnapshot, write_atomically};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// One exported rule; `source` is the list it was applied to, `block` or `allow`
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub(crate) struct ExportRow {
+    pub source: &'static str,
+    pub ip: IpAddr,
+    pub prefix: u32,
+    pub family: &'static str,
+}
+
+impl ExportRow {
+    fn new(source: &'static str, ip: IpAddr, prefix: u32) -> Self {
+        Self { source, ip, prefix, family: if ip.is_ipv4() { "ipv4" } else { "ipv6" } }
+    }
+}
+
+/// Every block and allow entry of both families, sorted by list, network and prefix
+pub(crate) fn export_rows(snapshot: &RuleSnapshot) -> Vec<ExportRow> {
+    let v4 = |source, rules: &HashSet<(Ipv4Addr, u32)>| {
+        rules.iter().map(move |(net, prefix)| ExportRow::new(source, IpAddr::V4(*net), *prefix)).collect::<Vec<_>>()
+    };
+    let v6 = |source, rules: &HashSet<(Ipv6Addr, u32)>| {
+        rules.iter().map(move |(net, prefix)| ExportRow::new(source, IpAddr::V6(*net), *prefix)).collect::<Vec<_>>()
+    };
+    let mut rows: Vec<ExportRow> = v4("block", &snapshot.rules)
+        .into_iter()
+        .chain(v6("block", &snapshot.rules_v6))
+        .chain(v4("allow", &snapshot.allow_rules))
+        .chain(v6("allow", &snapshot.allow_rules_v6))
+        .collect();
+    rows.sort();
+    rows
+}
+
+/// Render the rows as a JSON array or as CSV with an `ip,prefix,family,source` header
+pub(crate) fn render(rows: &[ExportRow], format: ExportFormat) -> Result<String, serde_json::Error> {
+    match format {
+        ExportFormat::Json => serde_json::to_string_pretty(rows),
+        ExportFormat::Csv => {
+            // None of the fields can contain a comma or quote, so nothing needs escaping
+            let mut out = String::from("ip,prefix,family,source\n");
+            for row in rows {
+                let _ = writeln!(out, "{},{},{},{}", row.ip, row.prefix, row.family, row.source);
+            }
+            Ok(out)
+        }
+    }
+}
+
+/// Write the applied rule set to `path`, replacing it atomically. Returns the number
+/// of exported rules.
+pub fn export_rules(snapshot: &RuleSnapshot, format: ExportFormat, path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let rows = export_rows(snapshot);
+    write_atomically(path, render(&rows, format)?.as_bytes())?;
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> RuleSnapshot {
+        RuleSnapshot {
+            rules: [("192.0.2.0".parse().unwrap(), 24)].into_iter().collect(),
+            rules_v6: [("2001:db8::".parse().unwrap(), 32)].into_iter().collect(),
+            allow_rules: [("198.51.100.7".parse().unwrap(), 32)].into_iter().collect(),
+            ..RuleSnapshot::default()
+        }
+    }
+
+    #[test]
+    fn test_render_csv() {
+        let csv = render(&export_rows(&snapshot()), ExportFormat::Csv).unwrap();
+        assert_eq!(
+            csv,
+            "ip,prefix,family,source\n198.51.100.7,32,ipv4,allow\n192.0.2.0,24,ipv4,block\n2001:db8::,32,ipv6,block\n"
+        );
+    }
+
+    #[test]
+    fn test_export_json_file() {
+        let path = std::env::temp_dir().join(format!("moat-export-{}.json", std::process::id()));
+        let exported = export_rules(&snapshot(), ExportFormat::Json, &path).unwrap();
+        let rows: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(exported, 3);
+        assert_eq!(rows[1]["ip"], "192.0.2.0");
+        assert_eq!(rows[1]["prefix"], 24);
+        assert_eq!(rows[1]["family"], "ipv4");
+        assert_eq!(rows[1]["source"], "block");
+    }
+}
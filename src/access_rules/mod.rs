@@ -0,0 +1,5106 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::net::{Ipv4Addr, Ipv6Addr, IpAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, MissedTickBehavior, sleep, sleep_until, timeout};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use thiserror::Error;
+
+use crate::bpf;
+use crate::config;
+use crate::config::{ConfigApiResponse, fetch_config, global_config, set_global_config};
+use crate::wirefilter::update_http_filter_from_config_value;
+use crate::firewall::{AddressFamily, BanAction, BanSource, Firewall, MOATFirewall, Precedence, ReplaceSummary, TemporaryBans, is_missing_entry, skeleton_firewalls};
+use crate::metrics::{self, MapUtilization};
+
+#[cfg(test)]
+mod bench;
+pub(crate) mod dns;
+pub(crate) mod events;
+pub(crate) mod export;
+pub(crate) mod parse;
+use dns::{DomainCache, ResolveLimits, ResolveStats, ResolvedDomains, is_valid_domain, normalize_domain};
+use events::EventSink;
+use parse::{IpNetwork, RangeCidrs, glob_match, ipv6_non_global_scope, normalize_entry, parse_ip_or_cidr, parse_ip_range, parse_ipv4_wildcard};
+
+/// Errors surfaced by the access rules updater. Failed BPF map writes are not among
+/// them: apply cycles count those in `ApplyStats` and retry the entries next cycle.
+#[derive(Debug, Error)]
+pub enum AccessRulesError {
+    /// The config API request failed (network, HTTP status or response decoding)
+    #[error("access rules fetch failed: {0}")]
+    Fetch(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// The config API did not answer within the fetch deadline
+    #[error("access rules fetch timed out after {}s", .after.as_secs())]
+    Timeout { after: Duration },
+    /// A rules file could not be parsed
+    #[error("failed to parse {what}: {reason}")]
+    Parse { what: String, reason: String },
+    /// A rules or state file could not be read or written
+    #[error("I/O error on {}: {source}", .path.display())]
+    Io { path: PathBuf, #[source] source: std::io::Error },
+    /// No BPF skeleton is loaded while `require_skel` is set
+    #[error("no BPF skeleton loaded, access rules cannot be enforced")]
+    MissingSkeleton,
+    /// A per-family apply thread panicked, leaving that family's maps partially updated
+    #[error("{family} access rules apply thread panicked")]
+    ApplyPanicked { family: &'static str },
+    /// Another cycle was still writing the BPF maps; its changes are picked up next cycle
+    #[error("another access rules apply is still writing the BPF maps")]
+    ApplyInProgress,
+    /// The API key is missing or cannot be sent as a bearer token
+    #[error("invalid API key: {0}")]
+    InvalidApiKey(&'static str),
+    /// A rule entry was rejected while `strict_parsing` is set
+    #[error("invalid access rules entry {entry:?} in {rule_source}: {reason}")]
+    InvalidEntry { rule_source: String, entry: String, reason: String },
+}
+
+impl AccessRulesError {
+    /// Whether the failure is likely transient, so a later cycle may succeed without intervention
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Fetch(_) | Self::Timeout { .. } | Self::ApplyInProgress)
+    }
+}
+
+// Store previous rules state for comparison
+type PreviousRules = Arc<Mutex<HashSet<(Ipv4Addr, u32)>>>;
+type PreviousRulesV6 = Arc<Mutex<HashSet<(Ipv6Addr, u32)>>>;
+
+/// Block and allow rule sets last applied to the BPF maps
+#[derive(Clone, Default)]
+struct PreviousRuleSets {
+    rules: PreviousRules,
+    rules_v6: PreviousRulesV6,
+    allow_rules: PreviousRules,
+    allow_rules_v6: PreviousRulesV6,
+    /// Entries of the block sets installed as log-only
+    log_only: PreviousRules,
+    log_only_v6: PreviousRulesV6,
+    /// Rate-limit rules last written to the rate limit maps, with their packets per second.
+    /// Tracked apart from the block sets so the ban diff never sees them.
+    rate_limits: Arc<Mutex<HashMap<(IpAddr, u32), u32>>>,
+    /// TTL cache of the configured domains, locked across lookups only
+    domain_cache: Arc<tokio::sync::Mutex<DomainCache>>,
+    /// Domain addresses the next apply cycle adds to the rule sets
+    resolved_domains: Arc<Mutex<ResolvedDomains>>,
+    /// When the full rule sets were last written, for `force_reapply_every`
+    last_full_apply: Arc<Mutex<Option<Instant>>>,
+    /// Block entries missing from the desired rules but still installed, within `unban_grace_cycles`
+    pending_unbans: Arc<Mutex<HashMap<(Ipv4Addr, u32), AbsentEntry>>>,
+    pending_unbans_v6: Arc<Mutex<HashMap<(Ipv6Addr, u32), AbsentEntry>>>,
+    /// Negative cache of the block entries unbanned within `oscillation_window`
+    recently_unbanned: Arc<Mutex<HashMap<(Ipv4Addr, u32), RecentUnban>>>,
+    recently_unbanned_v6: Arc<Mutex<HashMap<(Ipv6Addr, u32), RecentUnban>>>,
+    /// Running hit totals per skeleton index and rule, for `monotonic_rule_hits`
+    rule_hit_totals: Arc<Mutex<HashMap<(usize, IpAddr, u32), HitTotal>>>,
+    /// Block sets a canary apply held back, applied in full once a cycle wants them again
+    canary_hold: Arc<Mutex<Option<CanaryHold>>>,
+    /// Wall clock rule expiries are checked against, kept from running backward
+    expiry_clock: Arc<Mutex<ExpiryClock>>,
+    /// Earliest rule expiry still ahead as of the last apply
+    next_expiry: Arc<Mutex<Option<chrono::DateTime<chrono::Utc>>>>,
+    /// Interface and map pairs at or above `map_utilization_warn_percent`, so each
+    /// crossing warns once
+    maps_over_threshold: Arc<Mutex<HashSet<(String, &'static str)>>>,
+    /// Stops map writes after `apply_breaker_threshold` cycles in a row failed to write
+    apply_breaker: Arc<Mutex<ApplyBreaker>>,
+    /// Set while a cycle writes the BPF maps, so an overlapping cycle defers instead of
+    /// interleaving its writes. The snapshot locks are only held to diff and to store.
+    applying: Arc<AtomicBool>,
+    /// `Config::rule_version` of the API config last applied without errors, together
+    /// with the `ManualRules` generation applied alongside it
+    applied_version: Arc<Mutex<Option<(String, u64)>>>,
+}
+
+/// Circuit breaker over the BPF map writes of the update cycles. Once enough cycles in a
+/// row hit map errors (a full map, a lost capability, ...) it opens and the cycles stop
+/// writing for a cooldown, logging one error instead of the same failures every cycle.
+/// The first cycle after the cooldown probes the maps: success closes the breaker, a
+/// failure opens it for another cooldown.
+#[derive(Debug, Default)]
+struct ApplyBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed,
+    Open,
+    /// The cooldown passed and the next cycle probes the maps
+    HalfOpen,
+}
+
+impl ApplyBreaker {
+    fn state(&self, now: Instant) -> BreakerState {
+        match self.open_until {
+            None => BreakerState::Closed,
+            Some(until) if now < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Record whether a cycle's map writes `failed`; returns the state afterwards
+    fn record(&mut self, failed: bool, now: Instant, threshold: u32, cooldown: Duration) -> BreakerState {
+        let probing = self.open_until.is_some();
+        if !failed {
+            if probing {
+                log::info!("BPF map writes succeeded again, access rules circuit breaker closed");
+            }
+            *self = Self::default();
+            return BreakerState::Closed;
+        }
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if probing {
+            log::warn!("BPF map writes still failing, circuit breaker stays open for another {:?}", cooldown);
+            self.open_until = Some(now + cooldown);
+        } else if self.consecutive_failures >= threshold {
+            log::error!(
+                "BPF map writes failed in {} consecutive update cycles, circuit breaker open: \
+                 no map writes for {:?}, then a single probe cycle",
+                self.consecutive_failures, cooldown
+            );
+            self.open_until = Some(now + cooldown);
+        }
+        self.state(now)
+    }
+}
+
+/// Desired block sets of a change gated by `canary_change_percent`
+#[derive(Debug, PartialEq)]
+struct CanaryHold {
+    rules: HashSet<(Ipv4Addr, u32)>,
+    rules_v6: HashSet<(Ipv6Addr, u32)>,
+}
+
+/// Wall clock for the API's expiry timestamps that never runs backward. Between two
+/// readings it advances at least as far as the monotonic clock did, so a backward step
+/// (NTP step, VM clock reset) neither revives expired entries nor stalls pending
+/// expiries. Forward steps are taken as-is, since the monotonic clock does not count
+/// time spent suspended. After a backward step the clock stays ahead of the system
+/// clock until restart.
+#[derive(Debug, Default)]
+struct ExpiryClock {
+    /// Last reading and the monotonic instant it was taken at
+    last: Option<(chrono::DateTime<chrono::Utc>, Instant)>,
+    /// How far the last reading was ahead of the system clock
+    ahead: chrono::Duration,
+}
+
+/// Lag behind the monotonic projection the system clock may gain between two readings
+/// before it is reported as a backward step
+const CLOCK_STEP_TOLERANCE: Duration = Duration::from_secs(1);
+
+impl ExpiryClock {
+    fn now(&mut self) -> chrono::DateTime<chrono::Utc> {
+        self.observe(chrono::Utc::now(), Instant::now())
+    }
+
+    fn observe(&mut self, wall: chrono::DateTime<chrono::Utc>, at: Instant) -> chrono::DateTime<chrono::Utc> {
+        let now = match self.last {
+            Some((last, last_at)) => {
+                let elapsed = chrono::Duration::from_std(at.saturating_duration_since(last_at)).unwrap_or(chrono::Duration::MAX);
+                let projected = last.checked_add_signed(elapsed).unwrap_or(last);
+                wall.max(projected)
+            }
+            None => wall,
+        };
+        let ahead = now - wall;
+        if (ahead - self.ahead).to_std().is_ok_and(|step| step > CLOCK_STEP_TOLERANCE) {
+            log::warn!(
+                "system clock stepped back by {}s, checking rule expiries against monotonic time ({}s ahead of the system clock)",
+                (ahead - self.ahead).num_seconds(), ahead.num_seconds()
+            );
+        }
+        self.last = Some((now, at));
+        self.ahead = ahead;
+        now
+    }
+}
+
+/// How long a block entry has been missing from the desired rules
+#[derive(Debug, Clone, Copy)]
+struct AbsentEntry {
+    since: Instant,
+    cycles: u32,
+}
+
+/// What the negative cache remembers of a recently unbanned block entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecentUnban {
+    /// Unbanned at this instant
+    Unbanned(Instant),
+    /// Listed again within the window of its unban, at this instant. Its removals are
+    /// held until the window has passed since then.
+    Oscillating(Instant),
+}
+
+/// Claim on the BPF map writes of a `PreviousRuleSets`, released on drop
+struct ApplyClaim<'a>(&'a AtomicBool);
+
+impl Drop for ApplyClaim<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl PreviousRuleSets {
+    /// Claim the map writes, or `None` while another cycle holds them
+    fn claim_apply(&self) -> Option<ApplyClaim<'_>> {
+        self.applying
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| ApplyClaim(&self.applying))
+    }
+
+    /// Whether removals are held back, so a cycle must run even if the config is unchanged
+    fn has_pending_unbans(&self) -> bool {
+        let oscillating = |state: &RecentUnban| matches!(state, RecentUnban::Oscillating(_));
+        !self.pending_unbans.lock().unwrap().is_empty()
+            || !self.pending_unbans_v6.lock().unwrap().is_empty()
+            || self.recently_unbanned.lock().unwrap().values().any(oscillating)
+            || self.recently_unbanned_v6.lock().unwrap().values().any(oscillating)
+    }
+
+    /// Whether a canary apply waits for the next cycle to confirm the rest of its change
+    fn canary_pending(&self) -> bool {
+        self.canary_hold.lock().unwrap().is_some()
+    }
+
+    /// Whether a rule expiry passed since the last apply, so a cycle must run to remove it
+    fn expiry_due(&self) -> bool {
+        let Some(at) = *self.next_expiry.lock().unwrap() else { return false };
+        at <= self.expiry_clock.lock().unwrap().now()
+    }
+
+    /// Whether `force_reapply_every` has passed since the full rule sets were last written
+    fn full_reapply_due(&self, config: &AccessRulesConfig) -> bool {
+        let Some(every) = config.force_reapply_every else { return false };
+        self.last_full_apply.lock().unwrap().is_some_and(|at| at.elapsed() >= every)
+    }
+}
+
+/// Rule sets published to subscribers after an apply cycle that changed something
+#[derive(Debug, Clone, Default)]
+pub struct RuleSnapshot {
+    pub stats: ApplyStats,
+    pub rules: HashSet<(Ipv4Addr, u32)>,
+    pub rules_v6: HashSet<(Ipv6Addr, u32)>,
+    pub allow_rules: HashSet<(Ipv4Addr, u32)>,
+    pub allow_rules_v6: HashSet<(Ipv6Addr, u32)>,
+}
+
+/// Summary of the BPF map operations performed by one `apply_rules` call,
+/// summed over all skeletons. Errors include failed allow-list operations.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ApplyStats {
+    pub v4_added: usize,
+    pub v4_removed: usize,
+    pub v6_added: usize,
+    pub v6_removed: usize,
+    pub v4_errors: usize,
+    pub v6_errors: usize,
+    /// The family is disabled, so its counts are not reported rather than zero
+    pub v4_skipped: bool,
+    pub v6_skipped: bool,
+    /// API country and ASN groups left out by `country_filter` or the group key filters
+    pub groups_skipped: usize,
+    /// Rule application is paused, so the changes were queued instead of written
+    pub paused: bool,
+    /// Resolution of the cycle's domain entries, None when no domains are configured
+    pub(crate) domains: Option<ResolveStats>,
+}
+
+impl ApplyStats {
+    fn for_families(families: AddressFamilies) -> Self {
+        Self { v4_skipped: !families.ipv4, v6_skipped: !families.ipv6, ..Self::default() }
+    }
+
+    /// True when no map operation was attempted
+    pub fn is_empty(&self) -> bool {
+        self.v4_added == 0
+            && self.v4_removed == 0
+            && self.v6_added == 0
+            && self.v6_removed == 0
+            && self.v4_errors == 0
+            && self.v6_errors == 0
+    }
+
+    /// Log the one-line end-of-cycle summary for people tailing the logs, with the
+    /// block entry totals left in `previous`, how long the cycle `took` and where its
+    /// rules came from. Cycles without map operations are only logged when `always`.
+    fn log_summary(&self, previous: &PreviousRuleSets, took: Duration, source: &str, always: bool) {
+        let changed = !self.is_empty();
+        if !changed && !always {
+            return;
+        }
+        if self.groups_skipped > 0 {
+            log::info!(groups_skipped = self.groups_skipped; "{} API country/ASN groups skipped by group filters", self.groups_skipped);
+        }
+        if let Some(dns) = &self.domains {
+            log::info!(
+                domains_resolved = dns.resolved,
+                domains_cached = dns.cached,
+                domains_failed = dns.failed,
+                domains_timed_out = dns.timed_out,
+                dns_took_ms = dns.took.as_millis() as u64;
+                "domains: {} resolved, {} cached, {} failed, {} timed out, took {}ms",
+                dns.resolved, dns.cached, dns.failed, dns.timed_out, dns.took.as_millis()
+            );
+        }
+        let family = |skipped: bool, added: usize, removed: usize, errors: usize| {
+            if skipped {
+                "skipped".to_string()
+            } else {
+                format!("+{}/-{} (errors {})", added, removed, errors)
+            }
+        };
+        let (total_v4, total_v6) = (previous.rules.lock().unwrap().len(), previous.rules_v6.lock().unwrap().len());
+        log::info!(
+            v4_added = self.v4_added,
+            v4_removed = self.v4_removed,
+            v4_errors = self.v4_errors,
+            v6_added = self.v6_added,
+            v6_removed = self.v6_removed,
+            v6_errors = self.v6_errors,
+            total_v4 = total_v4,
+            total_v6 = total_v6,
+            took_ms = took.as_millis() as u64,
+            source = source,
+            changed = changed;
+            "cycle complete: v4 {}, v6 {}, total v4={} v6={}, took {}ms, source={}, changed={}",
+            family(self.v4_skipped, self.v4_added, self.v4_removed, self.v4_errors),
+            family(self.v6_skipped, self.v6_added, self.v6_removed, self.v6_errors),
+            total_v4, total_v6, took.as_millis(), source, changed
+        );
+    }
+}
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(300);
+const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_FETCH_RETRIES: u32 = 2;
+const DEFAULT_FETCH_RETRY_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_STARTUP_RETRIES: u32 = 3;
+const DEFAULT_STARTUP_RETRY_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_DNS_CONCURRENCY: usize = 8;
+const DEFAULT_APPLY_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_OSCILLATION_WINDOW: Duration = Duration::from_secs(600);
+const DEFAULT_APPLY_BREAKER_COOLDOWN: Duration = Duration::from_secs(300);
+const DEFAULT_DNS_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_POLL_JITTER_PERCENT: u32 = 50;
+
+/// Address families the updater maintains BPF map entries for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AddressFamilies {
+    ipv4: bool,
+    ipv6: bool,
+}
+
+impl Default for AddressFamilies {
+    fn default() -> Self {
+        Self { ipv4: true, ipv6: true }
+    }
+}
+
+/// Access rules updater configuration
+#[derive(Debug, Clone)]
+pub struct AccessRulesConfig {
+    pub poll_interval: Duration,
+    /// Upper bound for the exponential backoff applied after consecutive fetch failures
+    pub max_backoff: Duration,
+    /// Drop block entries already covered by a broader block entry of the same family
+    pub collapse_cidrs: bool,
+    /// Merge sibling block entries that together form their parent network, repeatedly
+    pub merge_adjacent_cidrs: bool,
+    /// Compute and log rule changes without writing to the BPF maps
+    pub dry_run: bool,
+    /// File the applied rule sets are persisted to and restored from across restarts
+    pub state_file: Option<PathBuf>,
+    /// Remove every installed ban from the BPF maps when the updater shuts down
+    pub unban_on_shutdown: bool,
+    /// Upper bound on the `unban_on_shutdown` drain; bans still installed when it
+    /// elapses are left in place so shutdown is never held up
+    pub shutdown_timeout: Option<Duration>,
+    /// File the last successful config response is cached to, applied on a cold start
+    /// when the initial fetch fails
+    pub cache_path: Option<PathBuf>,
+    /// Baseline rules loaded from `local_rules_file`, always applied in addition to the API rules
+    pub local_rules: Option<Arc<LocalRules>>,
+    /// Lookups of domain entries in flight at once
+    pub dns_concurrency: usize,
+    /// Deadline for resolving every domain entry of a cycle; zero waits for every lookup
+    pub dns_timeout: Duration,
+    /// Rules added at runtime through the control API, applied like local rules
+    pub manual_rules: Option<Arc<ManualRules>>,
+    /// Maintenance switch that holds back map writes while the updater keeps fetching
+    pub apply_pause: Option<Arc<ApplyPause>>,
+    /// Temporary bans the sync must not remove while they are active
+    pub temporary_bans: Option<Arc<TemporaryBans>>,
+    /// Fail startup when no BPF skeleton could be loaded instead of running without one
+    pub require_skel: bool,
+    /// Interface each loaded skeleton is attached to, in skeleton order, so logs name
+    /// the interface a skeleton failed on
+    pub skeleton_ifaces: Vec<String>,
+    /// Maintain IPv4 rules; when false IPv4 entries are ignored and the IPv4 maps are never touched
+    pub enable_ipv4: bool,
+    /// Maintain IPv6 rules; when false IPv6 entries are ignored and the IPv6 maps are never touched
+    pub enable_ipv6: bool,
+    /// Deadline for a single config fetch; an expired deadline counts as a fetch failure
+    pub fetch_timeout: Duration,
+    /// Extra attempts made within one cycle after a transient network error
+    pub fetch_retries: u32,
+    /// Delay between those attempts
+    pub fetch_retry_delay: Duration,
+    /// Log the N broadest block CIDRs per family on every cycle
+    pub log_broad_rules: Option<usize>,
+    /// Block entries with a prefix shorter than this are always logged when the IPv4 set changes
+    pub broad_rule_warn_prefix_v4: u32,
+    /// Block entries with a prefix shorter than this are always logged when the IPv6 set changes
+    pub broad_rule_warn_prefix_v6: u32,
+    /// Install `0.0.0.0/0` and `::/0` block entries instead of rejecting them
+    pub allow_default_route_block: bool,
+    /// Compare the banned maps against the applied snapshots every N cycles; 0 disables
+    pub reconcile_every_cycles: u32,
+    /// On drift, rebuild the banned maps from the snapshots instead of only re-adding
+    pub reconcile_replace: bool,
+    /// Re-write the full block and allow sets this often even when nothing changed
+    pub force_reapply_every: Option<Duration>,
+    /// How long `start_access_rules_updater` waits for the first successful apply before returning
+    pub initial_apply_timeout: Option<Duration>,
+    /// Audit trail every applied ban and unban is reported to
+    pub event_sink: Option<Arc<EventSink>>,
+    /// Cycles a block entry stays installed after the cycle it went missing from the
+    /// desired rules in; 0 unbans on the first cycle it is missing
+    pub unban_grace_cycles: u32,
+    /// How long unbanned block entries are remembered, so one the feed lists again
+    /// within it is reported as oscillating and kept installed for the window; zero
+    /// disables the tracking
+    pub oscillation_window: Duration,
+    /// Fail the cycle on the first rejected entry instead of logging and skipping it
+    pub strict_parsing: bool,
+    /// Gate block changes larger than this percentage of the installed block entries:
+    /// only `canary_sample` of them are applied until the next cycle asks for the same sets
+    pub canary_change_percent: Option<u32>,
+    /// Changes per family applied by a gated cycle
+    pub canary_sample: usize,
+    /// Apply a family's block changes through an atomically swapped map once a cycle
+    /// has at least this many of them, instead of entry by entry
+    pub atomic_swap_min_changes: Option<usize>,
+    /// Warn when a BPF rule map is at least this full, in percent; 0 disables
+    pub map_utilization_warn_percent: u32,
+    /// Consecutive cycles with BPF map errors that open the circuit breaker; 0 disables it
+    pub apply_breaker_threshold: u32,
+    /// How long an open breaker holds back map writes before probing again
+    pub apply_breaker_cooldown: Duration,
+    /// Sort every change list by network and prefix before logging and applying it
+    pub deterministic_order: bool,
+    /// Log the end-of-cycle summary for cycles that changed nothing as well
+    pub log_unchanged_cycles: bool,
+    /// Ask the API for the changes since the held config version instead of a full snapshot
+    pub delta_sync: bool,
+    /// Carry rule hit counts forward across BPF counter resets so the exported totals never drop
+    pub monotonic_rule_hits: bool,
+    /// Most recently rejected entries, kept for on-demand inspection
+    pub recent_rejects: Option<Arc<RecentRejects>>,
+    /// Invalid entries warned about individually per cycle before the rest are summarized
+    pub invalid_entry_log_limit: usize,
+    /// Upper-case country codes whose API block groups may be applied; None applies all
+    pub country_filter: Option<HashSet<String>>,
+    /// Include/exclude patterns on API country group keys
+    pub country_keys: KeyFilter,
+    /// Include/exclude patterns on API ASN group keys
+    pub asn_keys: KeyFilter,
+    /// Maximum block entries per family; the broadest prefixes are kept when exceeded
+    pub max_rules: Option<usize>,
+    /// Order in which API block sources survive `max_rules` trimming, highest priority first.
+    /// Local rules always rank above every API source.
+    pub block_source_priority: Vec<RuleSource>,
+    /// How the datapath settles a source matching both an allow and an enforced block entry
+    pub precedence: Precedence,
+    /// Spread each poll interval randomly by up to this percentage in either direction
+    pub poll_jitter_percent: u32,
+    /// Delay the initial fetch by a random duration up to this bound
+    pub initial_jitter_max: Duration,
+    /// Extra attempts at the initial fetch before falling back to the poll loop
+    pub startup_retries: u32,
+    /// Delay before the first startup retry, doubling (with jitter) for each one after
+    pub startup_retry_delay: Duration,
+    /// Seed for the jitter RNG; None seeds from the OS
+    pub jitter_seed: Option<u64>,
+    /// How the next tick is scheduled when a cycle overran its slot
+    pub missed_tick_behavior: MissedTickBehavior,
+}
+
+/// Static block and allow rules from a local YAML/JSON file, in the same shape as the
+/// API's `access_rules` section
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LocalRules {
+    #[serde(default)]
+    pub block: config::RuleSet,
+    #[serde(default)]
+    pub allow: config::RuleSet,
+}
+
+/// Block rules pushed at runtime through the control API. They are merged into every
+/// apply cycle next to the local rules, so the periodic API sync never removes them.
+#[derive(Debug, Default)]
+pub struct ManualRules {
+    rules: Mutex<HashSet<(Ipv4Addr, u32)>>,
+    rules_v6: Mutex<HashSet<(Ipv6Addr, u32)>>,
+    /// Bumped on every change, so an apply cycle is not skipped as unchanged
+    generation: AtomicU64,
+}
+
+impl ManualRules {
+    /// Parse an IP, CIDR or range entry into per-family networks
+    pub(crate) fn parse(entry: &str) -> Result<(HashSet<(Ipv4Addr, u32)>, HashSet<(Ipv6Addr, u32)>), AccessRulesError> {
+        let mut rules = HashSet::new();
+        let mut rules_v6 = HashSet::new();
+        collect_entry(entry, AddressFamilies::default(), &mut rules, &mut rules_v6);
+        if rules.is_empty() && rules_v6.is_empty() {
+            return Err(AccessRulesError::Parse {
+                what: format!("manual rule {:?}", entry),
+                reason: "expected an IP address, CIDR or range".to_string(),
+            });
+        }
+        Ok((rules, rules_v6))
+    }
+
+    /// Add the networks of `entry`, returning how many were not already present
+    pub fn ban(&self, entry: &str) -> Result<usize, AccessRulesError> {
+        let (v4, v6) = Self::parse(entry)?;
+        let mut added = 0;
+        {
+            let mut rules = self.rules.lock().unwrap();
+            added += v4.into_iter().filter(|net| rules.insert(*net)).count();
+        }
+        {
+            let mut rules_v6 = self.rules_v6.lock().unwrap();
+            added += v6.into_iter().filter(|net| rules_v6.insert(*net)).count();
+        }
+        if added > 0 {
+            self.generation.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(added)
+    }
+
+    /// Remove the networks of `entry`, returning how many were present
+    pub fn unban(&self, entry: &str) -> Result<usize, AccessRulesError> {
+        let (v4, v6) = Self::parse(entry)?;
+        let mut removed = 0;
+        {
+            let mut rules = self.rules.lock().unwrap();
+            removed += v4.iter().filter(|net| rules.remove(*net)).count();
+        }
+        {
+            let mut rules_v6 = self.rules_v6.lock().unwrap();
+            removed += v6.iter().filter(|net| rules_v6.remove(*net)).count();
+        }
+        if removed > 0 {
+            self.generation.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(removed)
+    }
+
+    /// Copy of the current manual rules
+    pub fn snapshot(&self) -> (HashSet<(Ipv4Addr, u32)>, HashSet<(Ipv6Addr, u32)>) {
+        (self.rules.lock().unwrap().clone(), self.rules_v6.lock().unwrap().clone())
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+}
+
+/// Pause switch for rule application. While paused, update cycles still fetch and diff
+/// but write nothing and keep the applied snapshots, so the accumulated changes are
+/// applied by the first cycle after resuming.
+#[derive(Debug, Default)]
+pub struct ApplyPause {
+    paused: AtomicBool,
+}
+
+impl ApplyPause {
+    /// Stop writing rule changes; returns false when already paused
+    pub fn pause(&self) -> bool {
+        let changed = !self.paused.swap(true, Ordering::SeqCst);
+        if changed {
+            log::warn!("access rules application paused, changes are fetched and queued until resumed");
+        }
+        changed
+    }
+
+    /// Resume writing rule changes; returns false when not paused
+    pub fn resume(&self) -> bool {
+        let changed = self.paused.swap(false, Ordering::SeqCst);
+        if changed {
+            log::info!("access rules application resumed, applying queued changes");
+        }
+        changed
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+}
+
+impl LocalRules {
+    /// Load local rules from a YAML or JSON file
+    pub fn from_file(path: &Path) -> Result<Self, AccessRulesError> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|source| AccessRulesError::Io { path: path.to_path_buf(), source })?;
+        serde_yaml::from_str(&data).map_err(|e| AccessRulesError::Parse {
+            what: format!("local rules file {}", path.display()),
+            reason: e.to_string(),
+        })
+    }
+}
+
+impl Default for AccessRulesConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            collapse_cidrs: false,
+            merge_adjacent_cidrs: false,
+            dry_run: false,
+            state_file: None,
+            unban_on_shutdown: false,
+            shutdown_timeout: None,
+            cache_path: None,
+            local_rules: None,
+            dns_concurrency: DEFAULT_DNS_CONCURRENCY,
+            dns_timeout: DEFAULT_DNS_TIMEOUT,
+            manual_rules: None,
+            apply_pause: None,
+            temporary_bans: None,
+            require_skel: false,
+            skeleton_ifaces: Vec::new(),
+            enable_ipv4: true,
+            enable_ipv6: true,
+            fetch_timeout: DEFAULT_FETCH_TIMEOUT,
+            fetch_retries: DEFAULT_FETCH_RETRIES,
+            fetch_retry_delay: DEFAULT_FETCH_RETRY_DELAY,
+            log_broad_rules: None,
+            broad_rule_warn_prefix_v4: 8,
+            broad_rule_warn_prefix_v6: 16,
+            allow_default_route_block: false,
+            reconcile_every_cycles: 0,
+            reconcile_replace: false,
+            force_reapply_every: None,
+            initial_apply_timeout: None,
+            event_sink: None,
+            unban_grace_cycles: 0,
+            oscillation_window: DEFAULT_OSCILLATION_WINDOW,
+            strict_parsing: false,
+            canary_change_percent: None,
+            atomic_swap_min_changes: None,
+            canary_sample: 10,
+            map_utilization_warn_percent: 90,
+            apply_breaker_threshold: DEFAULT_APPLY_BREAKER_THRESHOLD,
+            apply_breaker_cooldown: DEFAULT_APPLY_BREAKER_COOLDOWN,
+            deterministic_order: false,
+            log_unchanged_cycles: false,
+            delta_sync: false,
+            monotonic_rule_hits: false,
+            recent_rejects: None,
+            invalid_entry_log_limit: 20,
+            country_filter: None,
+            country_keys: KeyFilter::default(),
+            asn_keys: KeyFilter::default(),
+            max_rules: None,
+            block_source_priority: RuleSource::ALL.to_vec(),
+            precedence: Precedence::default(),
+            poll_jitter_percent: 0,
+            initial_jitter_max: Duration::ZERO,
+            startup_retries: DEFAULT_STARTUP_RETRIES,
+            startup_retry_delay: DEFAULT_STARTUP_RETRY_DELAY,
+            jitter_seed: None,
+            missed_tick_behavior: MissedTickBehavior::Delay,
+        }
+    }
+}
+
+impl AccessRulesConfig {
+    /// Whether rules other than the API's are configured, so a cycle has something to
+    /// apply even before the first successful fetch
+    fn has_own_rules(&self) -> bool {
+        self.local_rules.is_some() || self.manual_rules.is_some()
+    }
+
+    /// Name of the skeleton at `idx` in logs: its interface, or its index when the
+    /// interfaces were not recorded
+    fn skeleton_name(&self, idx: usize) -> String {
+        skeleton_name(&self.skeleton_ifaces, idx)
+    }
+
+    /// Convert from CLI configuration, loading the local rules file if one is configured.
+    /// An unreadable local rules file is logged and skipped.
+    pub fn from_cli_config(cli_config: &crate::cli::AccessRulesConfig) -> Self {
+        let local_rules = cli_config.local_rules_file.as_ref().and_then(|path| {
+            match LocalRules::from_file(Path::new(path)) {
+                Ok(rules) => {
+                    log::info!("loaded local access rules from {}", path);
+                    Some(Arc::new(rules))
+                }
+                Err(e) => {
+                    log::warn!("failed to load local access rules from {}: {}", path, e);
+                    None
+                }
+            }
+        });
+
+        Self {
+            poll_interval: Duration::from_secs(cli_config.poll_interval_secs),
+            max_backoff: Duration::from_secs(cli_config.max_backoff_secs),
+            collapse_cidrs: cli_config.collapse_cidrs,
+            merge_adjacent_cidrs: cli_config.merge_adjacent_cidrs,
+            dry_run: cli_config.dry_run,
+            state_file: cli_config.state_file.as_ref().map(PathBuf::from),
+            unban_on_shutdown: cli_config.unban_on_shutdown,
+            shutdown_timeout: cli_config.shutdown_timeout_secs.filter(|secs| *secs > 0).map(Duration::from_secs),
+            cache_path: cli_config.cache_path.as_ref().map(PathBuf::from),
+            local_rules,
+            dns_concurrency: cli_config.dns_concurrency.max(1),
+            dns_timeout: Duration::from_secs(cli_config.dns_timeout_secs),
+            manual_rules: None,
+            apply_pause: None,
+            temporary_bans: None,
+            require_skel: cli_config.require_skel,
+            enable_ipv4: cli_config.enable_ipv4,
+            enable_ipv6: cli_config.enable_ipv6,
+            fetch_timeout: Duration::from_secs(cli_config.fetch_timeout_secs),
+            fetch_retries: cli_config.fetch_retries,
+            fetch_retry_delay: Duration::from_millis(cli_config.fetch_retry_delay_ms),
+            log_broad_rules: cli_config.log_broad_rules,
+            broad_rule_warn_prefix_v4: cli_config.broad_rule_warn_prefix_v4,
+            broad_rule_warn_prefix_v6: cli_config.broad_rule_warn_prefix_v6,
+            allow_default_route_block: cli_config.allow_default_route_block,
+            reconcile_every_cycles: cli_config.reconcile_every_cycles,
+            reconcile_replace: cli_config.reconcile_replace,
+            force_reapply_every: cli_config.force_reapply_every_secs.filter(|secs| *secs > 0).map(Duration::from_secs),
+            initial_apply_timeout: cli_config.initial_apply_timeout_secs.filter(|secs| *secs > 0).map(Duration::from_secs),
+            // Opened by main, so building a config never starts the writer thread
+            event_sink: None,
+            unban_grace_cycles: cli_config.unban_grace_cycles,
+            oscillation_window: Duration::from_secs(cli_config.oscillation_window_secs),
+            strict_parsing: cli_config.strict_parsing,
+            canary_change_percent: cli_config.canary_change_percent.filter(|percent| *percent > 0),
+            atomic_swap_min_changes: cli_config.atomic_swap_min_changes.filter(|min| *min > 0),
+            canary_sample: cli_config.canary_sample,
+            map_utilization_warn_percent: cli_config.map_utilization_warn_percent,
+            apply_breaker_threshold: cli_config.apply_breaker_threshold,
+            apply_breaker_cooldown: Duration::from_secs(cli_config.apply_breaker_cooldown_secs),
+            deterministic_order: cli_config.deterministic_order,
+            log_unchanged_cycles: cli_config.log_unchanged_cycles,
+            delta_sync: cli_config.delta_sync,
+            monotonic_rule_hits: cli_config.monotonic_rule_hits,
+            recent_rejects: (cli_config.recent_rejects > 0)
+                .then(|| Arc::new(RecentRejects::new(cli_config.recent_rejects))),
+            invalid_entry_log_limit: cli_config.invalid_entry_log_limit,
+            country_filter: cli_config.country_filter.as_ref().map(|codes| {
+                codes.iter().map(|cc| cc.trim().to_ascii_uppercase()).collect()
+            }),
+            country_keys: KeyFilter::new(cli_config.country_include.as_deref(), &cli_config.country_exclude),
+            asn_keys: KeyFilter::new(cli_config.asn_include.as_deref(), &cli_config.asn_exclude),
+            max_rules: cli_config.max_rules,
+            block_source_priority: parse_source_priority(&cli_config.block_source_priority),
+            precedence: cli_config.allow_block_precedence.parse().unwrap_or_else(|e| {
+                log::warn!("{}, using {}", e, Precedence::default());
+                Precedence::default()
+            }),
+            poll_jitter_percent: cli_config.poll_jitter_percent.min(MAX_POLL_JITTER_PERCENT),
+            initial_jitter_max: Duration::from_millis(cli_config.initial_jitter_max_ms),
+            startup_retries: cli_config.startup_retries,
+            startup_retry_delay: Duration::from_millis(cli_config.startup_retry_delay_ms),
+            jitter_seed: cli_config.jitter_seed,
+            missed_tick_behavior: parse_missed_tick_behavior(&cli_config.missed_tick_behavior).unwrap_or_else(|| {
+                log::warn!("unknown missed_tick_behavior '{}', using delay", cli_config.missed_tick_behavior);
+                MissedTickBehavior::Delay
+            }),
+        }
+    }
+
+    fn families(&self) -> AddressFamilies {
+        AddressFamilies { ipv4: self.enable_ipv4, ipv6: self.enable_ipv6 }
+    }
+}
+
+/// Start a background task that fetches access rules every `poll_interval` and
+/// applies them to the `banned_ips` and `allowed_ips` BPF maps in the provided skeletons.
+///
+/// Contract:
+/// - Inputs: `base_urls` are the config API endpoints, in failover order
+///   `skels` are the loaded BPF skeletons, one per attached interface; every rule change is
+///   written to the LPM_TRIE maps of each (value = a flag byte of `BanSource` bits)
+///   `api_key` is the ArxIgnis API key
+///   `config` holds the updater settings; `config.poll_interval` is the delay between
+///   fetches, and zero falls back to 10s
+///   `snapshot_tx` optionally receives the applied rule sets, `refresh_rx` optionally
+///   triggers manual refreshes (both described below)
+///   `shutdown` is a watch receiver that signals graceful shutdown when set to true
+/// - Behavior: Runs immediately, then every `poll_interval`; on fetch error, logs and backs off
+///   exponentially (doubling per consecutive failure, capped at `config.max_backoff`) until the
+///   next success restores the base interval. With `config.unban_on_shutdown` every installed
+///   ban is removed before the task returns, within `config.shutdown_timeout` when set
+/// - Notifications: if `snapshot_tx` is given, a `RuleSnapshot` is sent after every cycle whose
+///   rule sets changed. It is sent only once that cycle's BPF map writes have completed on all
+///   skeletons (in dry-run mode, once the planned changes were logged), so a receiver never
+///   observes a snapshot ahead of the maps. Cycles without changes send nothing.
+/// - Jitter: with `config.poll_jitter_percent` every wait is spread randomly around the
+///   current interval, and `config.initial_jitter_max` delays the first fetch, so a fleet
+///   started together drifts apart instead of polling in lockstep
+/// - Missed ticks: when a cycle overruns its slot, `config.missed_tick_behavior` picks the
+///   next one: `Delay` runs right away and restarts the cadence, `Skip` waits for the next
+///   slot on the original cadence, and `Burst` runs the missed ticks back to back
+/// - Manual refresh: each message on `refresh_rx` runs a cycle right away and restarts the
+///   ticker from that point. Cycles run one at a time inside the task, so a manual refresh
+///   never overlaps a scheduled tick; signals queued while a cycle runs coalesce into one.
+/// - Failover: `base_urls` are tried in order each cycle until one answers, starting with
+///   the endpoint that last succeeded. A cycle only fails (and backs off) when all do.
+/// - Startup retries: a failed initial fetch is retried up to `config.startup_retries` times,
+///   after `config.startup_retry_delay` doubling per attempt and never longer than the poll
+///   interval, so a flaky network at boot doesn't leave the maps empty for a whole interval
+/// - Startup: with `config.initial_apply_timeout` set, the call only returns once a fetch has
+///   been applied to the BPF maps or the timeout elapsed, so the service is not reported ready
+///   while nothing is blocked. A timeout is logged; the periodic loop keeps retrying either way.
+/// - Returns: JoinHandle for the spawned task, or `InvalidApiKey` without spawning when the
+///   key could never authenticate
+pub async fn start_access_rules_updater(
+    base_urls: Vec<String>,
+    skels: Vec<Arc<bpf::FilterSkel<'static>>>,
+    api_key: String,
+    config: AccessRulesConfig,
+    snapshot_tx: Option<watch::Sender<RuleSnapshot>>,
+    mut refresh_rx: Option<mpsc::Receiver<()>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<JoinHandle<()>, AccessRulesError> {
+    validate_api_key_format(&api_key, &config)?;
+    let poll_interval = if config.poll_interval.is_zero() {
+        log::warn!(
+            "access rules poll interval must be non-zero, falling back to {}s",
+            DEFAULT_POLL_INTERVAL.as_secs()
+        );
+        DEFAULT_POLL_INTERVAL
+    } else {
+        config.poll_interval
+    };
+
+    let max_backoff = config.max_backoff.max(poll_interval);
+    if config.dry_run {
+        log::warn!("access rules dry-run enabled: changes are logged but not written to BPF maps");
+    }
+
+    // Initialize previous rules state, seeding it from the state file when configured
+    let previous = PreviousRuleSets::default();
+    if let Some(path) = &config.state_file {
+        restore_rule_state(&skels[..], &previous, path, &config);
+    }
+    let mut endpoints = ApiEndpoints::new(base_urls);
+    let initial_apply_timeout = config.initial_apply_timeout;
+    let (applied_tx, mut applied_rx) = watch::channel(false);
+    let handle = tokio::spawn(async move {
+        let mut rng = match config.jitter_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        let mut current_interval = poll_interval;
+        let mut consecutive_failures: u32 = 0;
+        let mut cycles: u64 = 0;
+
+        let initial_delay = initial_jitter(config.initial_jitter_max, &mut rng);
+        if !initial_delay.is_zero() {
+            log::debug!("delaying initial access rules fetch by {}ms", initial_delay.as_millis());
+            select! {
+                _ = sleep(initial_delay) => {}
+                _ = wait_for_shutdown(&mut shutdown) => {
+                    if config.unban_on_shutdown && !config.dry_run {
+                        unban_all(&skels[..], &previous, config.shutdown_timeout);
+                    }
+                    return;
+                }
+            }
+        }
+
+        let mut initial = fetch_and_apply(&mut endpoints, &api_key, &skels, &previous, &config, snapshot_tx.as_ref()).await;
+        let mut attempt = 0;
+        while attempt < config.startup_retries {
+            let Err(e) = &initial else { break };
+            attempt += 1;
+            let delay = startup_retry_delay(config.startup_retry_delay, attempt, poll_interval, &mut rng);
+            log::warn!(
+                phase = "startup_retry";
+                "initial access rules fetch failed, retry {attempt}/{} in {}ms: {e}",
+                config.startup_retries, delay.as_millis()
+            );
+            select! {
+                _ = sleep(delay) => {}
+                _ = wait_for_shutdown(&mut shutdown) => {
+                    if config.unban_on_shutdown && !config.dry_run {
+                        unban_all(&skels[..], &previous, config.shutdown_timeout);
+                    }
+                    return;
+                }
+            }
+            initial = fetch_and_apply(&mut endpoints, &api_key, &skels, &previous, &config, snapshot_tx.as_ref()).await;
+        }
+        if attempt > 0 && initial.is_ok() {
+            log::info!(phase = "startup_retry"; "initial access rules fetch succeeded on retry {attempt}");
+        }
+        metrics::access_rules().record_cycle(initial.is_ok());
+        if initial.is_ok() {
+            applied_tx.send_replace(true);
+        }
+        if let Err(e) = initial {
+            log::error!(phase = "initial"; "initial access rules update failed after {} attempts: {e}", attempt + 1);
+            metrics::access_rules().update_failures_total.fetch_add(1, Ordering::Relaxed);
+            consecutive_failures += 1;
+            if let Some(path) = &config.cache_path {
+                apply_cached_config(path, &skels, &previous, &config, snapshot_tx.as_ref()).await;
+            }
+        }
+        let mut next_tick = Instant::now() + jittered_interval(poll_interval, config.poll_jitter_percent, &mut rng);
+
+        loop {
+            let manual = select! {
+                _ = shutdown.changed() => {
+                    if *shutdown.borrow() { break; }
+                    continue;
+                }
+                _ = sleep_until(next_tick) => false,
+                Some(()) = next_refresh(&mut refresh_rx) => true,
+            };
+
+            let phase = if manual {
+                // Coalesce refresh requests that queued up behind this one
+                if let Some(rx) = refresh_rx.as_mut() {
+                    while rx.try_recv().is_ok() {}
+                }
+                log::info!("manual access rules refresh requested");
+                "manual"
+            } else {
+                "periodic"
+            };
+
+            let cycle_start = Instant::now();
+            let result = fetch_and_apply(&mut endpoints, &api_key, &skels, &previous, &config, snapshot_tx.as_ref()).await;
+            let elapsed = cycle_start.elapsed();
+            if elapsed > current_interval {
+                log::warn!(
+                    phase = phase;
+                    "access rules update took {:.1}s, longer than the {}s poll interval; updates are backing up",
+                    elapsed.as_secs_f64(), current_interval.as_secs()
+                );
+            }
+            metrics::access_rules().record_cycle(result.is_ok());
+            match result {
+                Ok(()) => {
+                    consecutive_failures = 0;
+                    applied_tx.send_replace(true);
+                }
+                Err(e) => {
+                    log::error!(phase = phase; "{} access rules update failed: {e}", phase);
+                    metrics::access_rules().update_failures_total.fetch_add(1, Ordering::Relaxed);
+                    consecutive_failures = consecutive_failures.saturating_add(1);
+                }
+            }
+
+            cycles += 1;
+            if config.reconcile_every_cycles > 0
+                && !config.dry_run
+                && cycles % u64::from(config.reconcile_every_cycles) == 0
+            {
+                reconcile_rules(&skels, &previous, &config);
+            }
+
+            let next_interval = backoff_interval(poll_interval, max_backoff, consecutive_failures);
+            let interval_changed = next_interval != current_interval;
+            if interval_changed {
+                if consecutive_failures == 0 {
+                    log::info!(
+                        "access rules update recovered, restoring poll interval to {}s",
+                        next_interval.as_secs()
+                    );
+                } else {
+                    log::warn!(
+                        "access rules update failed {} times in a row, backing off to {}s",
+                        consecutive_failures,
+                        next_interval.as_secs()
+                    );
+                }
+                current_interval = next_interval;
+            }
+            // Scheduled ticks keep their cadence; a manual refresh or an interval change
+            // restarts the wait from now. An overdue tick follows missed_tick_behavior.
+            let now = Instant::now();
+            let start = if manual || interval_changed { now } else { next_tick };
+            let period = jittered_interval(current_interval, config.poll_jitter_percent, &mut rng);
+            next_tick = next_tick_after(config.missed_tick_behavior, start, period, now);
+        }
+
+        if config.unban_on_shutdown && !config.dry_run {
+            unban_all(&skels[..], &previous, config.shutdown_timeout);
+        }
+    });
+
+    if let Some(wait) = initial_apply_timeout {
+        log::info!("waiting up to {}s for the initial access rules apply", wait.as_secs());
+        match timeout(wait, applied_rx.wait_for(|applied| *applied)).await {
+            Ok(Ok(_)) => log::info!("initial access rules applied"),
+            // The task exited, e.g. on shutdown, before anything was applied
+            Ok(Err(_)) => {}
+            Err(_) => log::error!(
+                "no access rules applied within {}s, continuing startup; the sync keeps retrying",
+                wait.as_secs()
+            ),
+        }
+    }
+    Ok(handle)
+}
+
+/// Reject API keys that can never authenticate: empty keys (unless local or manual rules
+/// give the updater something to apply on its own) and keys with whitespace or
+/// non-printable characters, which cannot be sent in a bearer token
+fn validate_api_key_format(api_key: &str, config: &AccessRulesConfig) -> Result<(), AccessRulesError> {
+    if api_key.is_empty() {
+        if config.has_own_rules() {
+            log::warn!("no API key configured: only local and manual access rules will be applied");
+            return Ok(());
+        }
+        return Err(AccessRulesError::InvalidApiKey("no API key configured"));
+    }
+    if !api_key.chars().all(|c| c.is_ascii_graphic()) {
+        return Err(AccessRulesError::InvalidApiKey("API key contains whitespace or non-printable characters"));
+    }
+    Ok(())
+}
+
+/// Compare the banned maps of every skeleton with the applied snapshots, re-installing
+/// entries that went missing and logging entries that moat did not install
+fn reconcile_rules(skels: &[Arc<bpf::FilterSkel<'_>>], previous: &PreviousRuleSets, config: &AccessRulesConfig) {
+    let Some(_claim) = previous.claim_apply() else {
+        log::debug!("access rules apply in flight, skipping reconciliation");
+        return;
+    };
+    let rules = previous.rules.lock().unwrap();
+    let rules_v6 = previous.rules_v6.lock().unwrap();
+    let families = config.families();
+
+    for (idx, s) in skels.iter().enumerate() {
+        let mut fw = MOATFirewall::new(s);
+        let name = config.skeleton_name(idx);
+        if config.reconcile_replace {
+            replace_drifted(&mut fw, &name, &rules, &rules_v6, config);
+            continue;
+        }
+
+        if families.ipv4 {
+            reconcile_family(&mut fw, &name, &rules);
+        }
+        if families.ipv6 {
+            reconcile_family(&mut fw, &name, &rules_v6);
+        }
+    }
+}
+
+/// Log one family's drift of a skeleton's banned map from the applied snapshot and
+/// re-install the entries that went missing
+fn reconcile_family<T: AddressFamily>(fw: &mut impl Firewall, name: &str, rules: &HashSet<(T, u32)>) {
+    let family = T::NAME;
+    match T::list_rules(&*fw) {
+        Ok(actual) => {
+            let actual: HashSet<(T, u32)> = actual.into_iter().collect();
+            let (unexpected, missing) = diff_rules(&actual, rules);
+            for (net, prefix) in &unexpected {
+                log::warn!(ip:% = net, prefix = *prefix; "{} drift on {}: {}/{} present in map but not applied by moat", family, name, net, prefix);
+            }
+            for (net, prefix) in &missing {
+                log::warn!(ip:% = net, prefix = *prefix; "{} drift on {}: {}/{} missing from map, re-applying", family, name, net, prefix);
+                if let Err(e) = T::ban(fw, *net, *prefix) {
+                    log::error!(ip:% = net, prefix = *prefix; "{} re-apply: BPF map update failed for {}/{}: {}", family, net, prefix, e);
+                }
+            }
+        }
+        Err(e) => log::error!("failed to list {} bans for reconciliation: {}", family, e),
+    }
+}
+
+/// Rebuild one skeleton's sync entries of the banned maps from the applied snapshots
+/// when they drifted. Entries of other sources, temporary bans included, are left to
+/// their owners, and a family moat does not manage is left as found.
+fn replace_drifted(
+    fw: &mut impl Firewall,
+    name: &str,
+    rules: &HashSet<(Ipv4Addr, u32)>,
+    rules_v6: &HashSet<(Ipv6Addr, u32)>,
+    config: &AccessRulesConfig,
+) {
+    let families = config.families();
+    let (mut wanted, mut wanted_v6) = (Vec::new(), Vec::new());
+    if !families.ipv4 || !families.ipv6 {
+        let sources = match fw.list_rule_sources() {
+            Ok(sources) => sources,
+            Err(e) => {
+                log::error!("failed to list bans for reconciliation: {}", e);
+                return;
+            }
+        };
+        for (ip, prefix, flags) in sources {
+            match ip {
+                _ if flags & BanSource::Sync as u8 == 0 => {}
+                IpAddr::V4(ip) if !families.ipv4 => wanted.push((ip, prefix)),
+                IpAddr::V6(ip) if !families.ipv6 => wanted_v6.push((ip, prefix)),
+                _ => {}
+            }
+        }
+    }
+    if families.ipv4 {
+        wanted.extend(rules.iter().copied());
+    }
+    if families.ipv6 {
+        wanted_v6.extend(rules_v6.iter().copied());
+    }
+
+    match fw.replace_all(&wanted, &wanted_v6) {
+        Ok(summary) if summary == ReplaceSummary::default() => {}
+        Ok(summary) => log::warn!(
+            "drift on {}: replaced banned maps from the applied snapshots ({} added, {} released)",
+            name, summary.added, summary.removed
+        ),
+        Err(e) => log::error!("failed to replace drifted banned maps on {}: {}", name, e),
+    }
+}
+
+/// Outcome of the shutdown drain, counted per entry and skeleton
+#[derive(Debug, Default, PartialEq, Eq)]
+struct DrainSummary {
+    /// Entries deleted from the maps
+    removed: usize,
+    /// Entries that lost the sync's reference but stay banned for another source
+    kept: usize,
+    /// Entries the map refused to release
+    failed: usize,
+    /// Entries not reached before the timeout, left installed
+    remaining: usize,
+}
+
+/// Remove every ban recorded in the previous snapshots from the BPF maps. Entries that
+/// another source also bans only lose the sync's reference and stay installed. Once
+/// `timeout` elapses the drain stops and the remaining bans and the snapshots are left
+/// in place. Returns None when an apply cycle still holds the maps.
+fn unban_all(
+    targets: &(impl FirewallTargets + ?Sized),
+    previous: &PreviousRuleSets,
+    timeout: Option<Duration>,
+) -> Option<DrainSummary> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let Some(_claim) = previous.claim_apply() else {
+        log::warn!("access rules apply still in flight, leaving the synced bans installed");
+        return None;
+    };
+    let mut rules = previous.rules.lock().unwrap();
+    let mut rules_v6 = previous.rules_v6.lock().unwrap();
+
+    let mut summary = DrainSummary::default();
+    let mut count = |result: Result<bool, Box<dyn std::error::Error>>, family: &str, net: &dyn std::fmt::Display, prefix: u32| {
+        match result {
+            Ok(true) => summary.removed += 1,
+            Ok(false) => summary.kept += 1,
+            Err(e) => {
+                summary.failed += 1;
+                log::error!("{} unban on shutdown: BPF map delete failed for {}/{}: {}", family, net, prefix, e);
+            }
+        }
+    };
+    let (mut skeletons, mut attempted) = (0usize, 0usize);
+    let expired = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    targets.for_each(None, &mut |fw| {
+        skeletons += 1;
+        for (net, prefix) in rules.iter() {
+            if expired() {
+                return;
+            }
+            attempted += 1;
+            count(fw.unban_ip_as(*net, *prefix, BanSource::Sync), "IPv4", net, *prefix);
+        }
+        for (net, prefix) in rules_v6.iter() {
+            if expired() {
+                return;
+            }
+            attempted += 1;
+            count(fw.unban_ipv6_as(*net, *prefix, BanSource::Sync), "IPv6", net, *prefix);
+        }
+    });
+    summary.remaining = (rules.len() + rules_v6.len()) * skeletons - attempted;
+
+    if summary.remaining > 0 {
+        log::warn!(
+            removed = summary.removed, remaining = summary.remaining;
+            "shutdown timeout of {}s elapsed while removing access rule bans: {} removed, {} kept for other sources, {} failed, {} left installed",
+            timeout.unwrap_or_default().as_secs(), summary.removed, summary.kept, summary.failed, summary.remaining
+        );
+        return Some(summary);
+    }
+
+    log::info!(
+        "removed {} access rule bans on shutdown, {} kept for other sources, {} failed ({} IPv4, {} IPv6 rules across {} interfaces)",
+        summary.removed, summary.kept, summary.failed, rules.len(), rules_v6.len(), skeletons
+    );
+    rules.clear();
+    rules_v6.clear();
+    Some(summary)
+}
+
+/// Shutdown and refresh handles driven by the standard Unix signals
+pub struct SignalHandles {
+    /// Turns true on SIGTERM or SIGINT
+    pub shutdown: watch::Receiver<bool>,
+    /// Extra sender for the refresh channel, for triggers other than SIGHUP
+    pub refresh_tx: mpsc::Sender<()>,
+    /// Refresh requests to hand to `start_access_rules_updater`
+    pub refresh_rx: mpsc::Receiver<()>,
+}
+
+/// Wire SIGTERM and SIGINT to a shutdown watch and SIGHUP to an immediate rule refresh.
+/// Embedders that manage signals themselves keep passing their own channels to
+/// `start_access_rules_updater` instead.
+pub fn install_signal_handlers() -> std::io::Result<SignalHandles> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let (refresh_tx, refresh_rx) = mpsc::channel(1);
+    let hangup_tx = refresh_tx.clone();
+    tokio::spawn(async move {
+        loop {
+            select! {
+                Some(()) = sigterm.recv() => {
+                    log::info!("SIGTERM received, shutting down");
+                    break;
+                }
+                Some(()) = sigint.recv() => {
+                    log::info!("SIGINT received, shutting down");
+                    break;
+                }
+                Some(()) = sighup.recv() => {
+                    log::info!("SIGHUP received, refreshing access rules");
+                    // A refresh that is already queued covers this one too
+                    let _ = hangup_tx.try_send(());
+                }
+                else => break,
+            }
+        }
+        let _ = shutdown_tx.send(true);
+    });
+    Ok(SignalHandles { shutdown, refresh_tx, refresh_rx })
+}
+
+/// Wait for the next manual refresh request; never resolves without a receiver
+async fn next_refresh(refresh_rx: &mut Option<mpsc::Receiver<()>>) -> Option<()> {
+    match refresh_rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Compute the delay before the next fetch: the base interval doubled once per
+/// consecutive failure, capped at `max`
+fn backoff_interval(base: Duration, max: Duration, consecutive_failures: u32) -> Duration {
+    if consecutive_failures == 0 {
+        return base;
+    }
+    let factor = 1u32 << consecutive_failures.min(16);
+    base.checked_mul(factor).unwrap_or(max).min(max)
+}
+
+fn parse_missed_tick_behavior(name: &str) -> Option<MissedTickBehavior> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "delay" => Some(MissedTickBehavior::Delay),
+        "skip" => Some(MissedTickBehavior::Skip),
+        "burst" => Some(MissedTickBehavior::Burst),
+        _ => None,
+    }
+}
+
+/// Deadline of the tick after the one `scheduled` for, given that the cycle finished at `now`
+fn next_tick_after(behavior: MissedTickBehavior, scheduled: Instant, period: Duration, now: Instant) -> Instant {
+    let next = scheduled + period;
+    if next > now {
+        return next;
+    }
+    match behavior {
+        MissedTickBehavior::Burst => next,
+        MissedTickBehavior::Skip if !period.is_zero() => {
+            let behind = (now - scheduled).as_nanos() % period.as_nanos();
+            now + (period - Duration::from_nanos(behind as u64))
+        }
+        _ => now,
+    }
+}
+
+/// Spread `base` uniformly by up to `percent` percent in either direction
+fn jittered_interval(base: Duration, percent: u32, rng: &mut impl Rng) -> Duration {
+    if percent == 0 {
+        return base;
+    }
+    let spread = f64::from(percent.min(MAX_POLL_JITTER_PERCENT)) / 100.0;
+    base.mul_f64(1.0 + rng.random_range(-spread..=spread))
+}
+
+/// Random delay in `[0, max]` before the first fetch
+fn initial_jitter(max: Duration, rng: &mut impl Rng) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(rng.random_range(0..=max.as_millis().min(u128::from(u64::MAX)) as u64))
+}
+
+/// Delay before startup retry `attempt` (1-based): `base` doubled per earlier attempt and
+/// capped at `max`, then jittered down by up to half so instances booting together spread out
+fn startup_retry_delay(base: Duration, attempt: u32, max: Duration, rng: &mut impl Rng) -> Duration {
+    let factor = 1u32 << attempt.saturating_sub(1).min(16);
+    let delay = base.checked_mul(factor).unwrap_or(max).min(max);
+    delay.mul_f64(rng.random_range(0.5..=1.0))
+}
+
+/// Resolve once the shutdown flag is set
+async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Check at startup that a BPF skeleton is available. Without one the updater cannot
+/// touch any map, which is a hard error with `config.require_skel` and a warning otherwise.
+pub fn check_skeletons(
+    skels: &[Arc<bpf::FilterSkel<'static>>],
+    config: &AccessRulesConfig,
+) -> Result<(), AccessRulesError> {
+    if !skels.is_empty() {
+        return Ok(());
+    }
+    if config.require_skel {
+        return Err(AccessRulesError::MissingSkeleton);
+    }
+    log::warn!(
+        "no BPF skeleton loaded: access rules will be fetched but never written to any map; \
+         set access_rules.require_skel to fail startup instead"
+    );
+    Ok(())
+}
+
+/// Apply access rules once using the current global config snapshot and local rules
+pub fn init_access_rules_from_global(
+    skels: &Vec<Arc<bpf::FilterSkel<'static>>>,
+    config: &AccessRulesConfig,
+) -> Result<(), AccessRulesError> {
+    if skels.is_empty() {
+        return Ok(());
+    }
+    for skel in skels {
+        if let Err(e) = MOATFirewall::new(skel).set_precedence(config.precedence) {
+            log::error!("failed to set allow/block precedence to {}: {}", config.precedence, e);
+        }
+    }
+    if let Ok(guard) = global_config().read() {
+        let api_rules = guard.as_ref().map(|cfg| &cfg.access_rules);
+        if api_rules.is_some() || config.has_own_rules() {
+            let previous = PreviousRuleSets::default();
+            let start = Instant::now();
+            let stats = apply_rules(skels.as_slice(), api_rules, &previous, config, None)?;
+            stats.log_summary(&previous, start.elapsed(), "startup", config.log_unchanged_cycles);
+        }
+    }
+    Ok(())
+}
+
+/// Config API base URLs, tried in configured order but starting from the last one
+/// that answered so a recovered primary does not cause flapping
+#[derive(Debug)]
+struct ApiEndpoints {
+    urls: Vec<String>,
+    preferred: usize,
+}
+
+impl ApiEndpoints {
+    fn new(urls: Vec<String>) -> Self {
+        Self { urls, preferred: 0 }
+    }
+
+    /// Indices to try this cycle: the preferred endpoint, then the rest in order
+    fn order(&self) -> Vec<usize> {
+        std::iter::once(self.preferred)
+            .chain((0..self.urls.len()).filter(|idx| *idx != self.preferred))
+            .filter(|idx| *idx < self.urls.len())
+            .collect()
+    }
+}
+
+/// Fetch the config from the first endpoint that answers, remembering it for next time
+async fn fetch_config_with_failover(
+    endpoints: &mut ApiEndpoints,
+    api_key: &str,
+    config: &AccessRulesConfig,
+) -> Result<ConfigApiResponse, AccessRulesError> {
+    let order = endpoints.order();
+    for (attempt, idx) in order.iter().copied().enumerate() {
+        let url = &endpoints.urls[idx];
+        match fetch_config_with_retry(url, api_key, config).await {
+            Ok(response) => {
+                if idx != endpoints.preferred {
+                    log::warn!(endpoint = url.as_str(); "config API failed over to {}", url);
+                    endpoints.preferred = idx;
+                }
+                return Ok(response);
+            }
+            // Only the last endpoint's error is returned; errors are not held across
+            // awaits since they are not Send
+            Err(e) if attempt + 1 == order.len() => return Err(e),
+            Err(e) => log::warn!(endpoint = url.as_str(); "config API endpoint {} failed: {}", url, e),
+        }
+    }
+    Err(AccessRulesError::Fetch("no config API endpoints configured".into()))
+}
+
+/// Combine per-skeleton hit counts into one counter per rule, sorted by rule
+fn merge_rule_hits(per_skel: impl IntoIterator<Item = Vec<(IpAddr, u32, u64)>>) -> Vec<(String, u64)> {
+    let mut merged: HashMap<(IpAddr, u32), u64> = HashMap::new();
+    for hits in per_skel {
+        for (ip, prefix, count) in hits {
+            let total = merged.entry((ip, prefix)).or_insert(0);
+            *total = total.saturating_add(count);
+        }
+    }
+    let mut merged: Vec<_> = merged.into_iter().collect();
+    merged.sort_unstable_by_key(|(key, _)| *key);
+    merged.into_iter().map(|((ip, prefix), count)| (format!("{}/{}", ip, prefix), count)).collect()
+}
+
+/// Running hit total of one rule on one skeleton
+#[derive(Debug, Clone, Copy, Default)]
+struct HitTotal {
+    /// Hits counted before the BPF counter was last recreated
+    base: u64,
+    /// BPF counter value at the last read
+    last: u64,
+}
+
+/// Fold fresh per-skeleton counter readings into running totals that never go down. A
+/// reading below the previous one means the counter was recreated, so the previous
+/// reading moves into the base. Rules that are no longer installed are dropped.
+fn accumulate_rule_hits(
+    totals: &mut HashMap<(usize, IpAddr, u32), HitTotal>,
+    per_skel: Vec<Vec<(IpAddr, u32, u64)>>,
+) -> Vec<Vec<(IpAddr, u32, u64)>> {
+    let mut next = HashMap::new();
+    let per_skel = per_skel
+        .into_iter()
+        .enumerate()
+        .map(|(index, hits)| {
+            hits.into_iter()
+                .map(|(ip, prefix, count)| {
+                    let mut total = totals.get(&(index, ip, prefix)).copied().unwrap_or_default();
+                    if count < total.last {
+                        total.base = total.base.saturating_add(total.last);
+                    }
+                    total.last = count;
+                    next.insert((index, ip, prefix), total);
+                    (ip, prefix, total.base.saturating_add(count))
+                })
+                .collect()
+        })
+        .collect();
+    *totals = next;
+    per_skel
+}
+
+/// Running totals of every skeleton, named by interface, as written to the state file
+fn persisted_rule_hits(totals: &HashMap<(usize, IpAddr, u32), HitTotal>, ifaces: &[String]) -> Vec<(String, IpAddr, u32, u64)> {
+    let mut persisted: Vec<_> = totals
+        .iter()
+        .map(|(&(idx, ip, prefix), total)| (skeleton_name(ifaces, idx), ip, prefix, total.base.saturating_add(total.last)))
+        .collect();
+    persisted.sort_unstable();
+    persisted
+}
+
+/// Seed running totals from persisted ones. The counters of freshly loaded maps start
+/// at zero, so each total becomes the base of its skeleton; totals of interfaces that
+/// are no longer loaded are dropped.
+fn restored_rule_hits(persisted: Vec<(String, IpAddr, u32, u64)>, ifaces: &[String]) -> HashMap<(usize, IpAddr, u32), HitTotal> {
+    persisted
+        .into_iter()
+        .filter_map(|(name, ip, prefix, count)| {
+            let idx = skeleton_index(ifaces, &name)?;
+            Some(((idx, ip, prefix), HitTotal { base: count, last: 0 }))
+        })
+        .collect()
+}
+
+/// Refresh the per-rule hit counters exported by the metrics endpoint. Runs before the
+/// cycle's map writes, so counts are taken before any rewrite can reset them.
+fn record_rule_hits(skels: &[Arc<bpf::FilterSkel<'static>>], previous: &PreviousRuleSets, config: &AccessRulesConfig) {
+    let mut per_skel = Vec::with_capacity(skels.len());
+    for skel in skels {
+        match MOATFirewall::new(skel).rule_hits() {
+            Ok(hits) => per_skel.push(hits),
+            Err(e) => {
+                log::warn!("failed to read rule hit counters: {}", e);
+                return;
+            }
+        }
+    }
+    if config.monotonic_rule_hits {
+        per_skel = accumulate_rule_hits(&mut previous.rule_hit_totals.lock().unwrap(), per_skel);
+    }
+    if let Ok(mut hits) = metrics::access_rules().rule_hits.lock() {
+        *hits = merge_rule_hits(per_skel);
+    }
+}
+
+/// Refresh the map utilization gauges. Occupancy counts the keys installed in the
+/// maps, including manual, temporary and datapath bans written outside the sync.
+/// Every skeleton is measured, since manual and datapath bans can fill one interface's
+/// maps without touching the others.
+fn record_map_utilization(skels: &[Arc<bpf::FilterSkel<'static>>], previous: &PreviousRuleSets, config: &AccessRulesConfig) {
+    let maps: Vec<MapUtilization> = skels
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, skel)| {
+            let iface = config.skeleton_name(idx);
+            MOATFirewall::new(skel)
+                .map_usage()
+                .into_iter()
+                .map(move |(map, used, capacity)| MapUtilization {
+                    iface: iface.clone(),
+                    map,
+                    used: used as u64,
+                    capacity: capacity as u64,
+                })
+        })
+        .collect();
+
+    let mut over = previous.maps_over_threshold.lock().unwrap();
+    for (usage, crossed_up) in utilization_crossings(&maps, config.map_utilization_warn_percent, &mut over) {
+        let (iface, map) = (usage.iface.as_str(), usage.map);
+        if crossed_up {
+            log::warn!(iface = iface, map = map; "BPF map {} on {} is {}% full ({}/{} entries)", map, iface, usage.percent(), usage.used, usage.capacity);
+        } else {
+            log::info!(iface = iface, map = map; "BPF map {} on {} is back below {}% full ({}/{} entries)", map, iface, config.map_utilization_warn_percent, usage.used, usage.capacity);
+        }
+    }
+    drop(over);
+
+    if let Ok(mut current) = metrics::access_rules().map_utilization.lock() {
+        *current = maps;
+    }
+}
+
+/// Maps whose utilization moved across `threshold_percent` since the last call, with
+/// whether they crossed upwards. `over` tracks the interface and map pairs at or above
+/// the threshold.
+fn utilization_crossings<'a>(
+    maps: &'a [MapUtilization],
+    threshold_percent: u32,
+    over: &mut HashSet<(String, &'static str)>,
+) -> Vec<(&'a MapUtilization, bool)> {
+    if threshold_percent == 0 {
+        over.clear();
+        return Vec::new();
+    }
+    let mut crossings = Vec::new();
+    for usage in maps {
+        let key = (usage.iface.clone(), usage.map);
+        let full = usage.capacity > 0 && usage.percent() >= u64::from(threshold_percent);
+        if full && !over.contains(&key) {
+            over.insert(key);
+            crossings.push((usage, true));
+        } else if !full && over.remove(&key) {
+            crossings.push((usage, false));
+        }
+    }
+    crossings
+}
+
+async fn fetch_and_apply(
+    endpoints: &mut ApiEndpoints,
+    api_key: &str,
+    skels: &Vec<Arc<bpf::FilterSkel<'static>>>,
+    previous: &PreviousRuleSets,
+    config: &AccessRulesConfig,
+    snapshot_tx: Option<&watch::Sender<RuleSnapshot>>,
+) -> Result<(), AccessRulesError> {
+    metrics::access_rules().update_cycles_total.fetch_add(1, Ordering::Relaxed);
+    let cycle_start = Instant::now();
+    record_rule_hits(skels, previous, config);
+
+    // Refresh global config from API; on failure the last known snapshot is still applied
+    let fetch_start = Instant::now();
+    let fetch_result = fetch_config_with_failover(endpoints, api_key, config).await;
+    metrics::access_rules().update_fetch_duration.observe(fetch_start.elapsed());
+    // The cache holds no signature to re-check, so it is not written when responses must be signed
+    if let (Ok(response), Some(path), false) = (&fetch_result, &config.cache_path, config::signing_required()) {
+        if let Err(e) = save_config_cache(path, response) {
+            log::warn!("failed to write config cache {}: {}", path.display(), e);
+        }
+    }
+
+    // Domains are resolved outside the config lock. A changed address set must be
+    // applied even when the config revision did not move.
+    let (domains_changed, domain_stats) = resolve_domains(previous, config).await;
+
+    // Read from global config and apply if available; local rules are applied even
+    // when no API config has ever been fetched. The API rules are copied out so the
+    // read lock is not held while the maps are written.
+    let (api_rules, applied_key, source) = {
+        let global = global_config();
+        let Ok(guard) = global.read() else {
+            fetch_result?;
+            return Ok(());
+        };
+        let manual_generation = config.manual_rules.as_ref().map_or(0, |m| m.generation());
+        let version = guard.as_ref().and_then(|cfg| cfg.rule_version());
+        let applied_key = version.map(|v| (v.to_string(), manual_generation));
+        // Rules from the last known config are still applied when this cycle's fetch failed
+        let source = match (&fetch_result, guard.is_some()) {
+            (Ok(_), _) => "api",
+            (Err(_), true) => "last_known",
+            (Err(_), false) => "local",
+        };
+        if applied_key.is_some()
+            && !domains_changed
+            && !previous.full_reapply_due(config)
+            && !previous.has_pending_unbans()
+            && !previous.canary_pending()
+            && !previous.expiry_due()
+            && *previous.applied_version.lock().unwrap() == applied_key
+        {
+            // Same revision as the last clean apply: nothing to diff. A server that honours
+            // If-None-Match answered 304, so the body was not downloaded or parsed either.
+            log::debug!("access rules config version {} unchanged, skipping apply", version.unwrap_or_default());
+            ApplyStats { domains: domain_stats, ..ApplyStats::for_families(config.families()) }
+                .log_summary(previous, cycle_start.elapsed(), source, config.log_unchanged_cycles);
+            fetch_result?;
+            return Ok(());
+        }
+        if let Some(cfg) = guard.as_ref() {
+            // Update WAF wirefilter when config changes
+            if let Err(e) = update_http_filter_from_config_value(cfg) {
+                log::error!("failed to update HTTP filter from config: {e}");
+            }
+        }
+        (guard.as_ref().map(|cfg| cfg.access_rules.clone()), applied_key, source)
+    };
+    let breaker_open = config.apply_breaker_threshold > 0
+        && previous.apply_breaker.lock().unwrap().state(Instant::now()) == BreakerState::Open;
+    if breaker_open {
+        log::debug!("access rules circuit breaker open, skipping BPF map writes");
+    }
+    if !skels.is_empty() && !breaker_open && (api_rules.is_some() || config.has_own_rules()) {
+        let apply_start = Instant::now();
+        let applied = apply_rules_blocking(skels, api_rules, previous, config, snapshot_tx).await;
+        metrics::access_rules().update_apply_duration.observe(apply_start.elapsed());
+        let stats = ApplyStats { domains: domain_stats, ..applied? };
+        stats.log_summary(previous, cycle_start.elapsed(), source, config.log_unchanged_cycles);
+        record_map_utilization(skels, previous, config);
+        if config.apply_breaker_threshold > 0 {
+            let failed = stats.v4_errors + stats.v6_errors > 0;
+            let state = previous.apply_breaker.lock().unwrap().record(
+                failed, Instant::now(), config.apply_breaker_threshold, config.apply_breaker_cooldown,
+            );
+            metrics::access_rules().set_apply_breaker((state == BreakerState::Open).then_some(config.apply_breaker_cooldown));
+        }
+        // Only remember clean applies, so entries that failed are retried next cycle
+        // and changes queued while paused are applied once resumed
+        if stats.v4_errors == 0 && stats.v6_errors == 0 && !stats.paused {
+            *previous.applied_version.lock().unwrap() = applied_key;
+        }
+    }
+
+    fetch_result?;
+    Ok(())
+}
+
+/// Run `apply_rules` on the blocking pool, so the map writes and the per-family apply
+/// threads never hold up a runtime worker. A panic in the apply is resumed here.
+async fn apply_rules_blocking(
+    skels: &[Arc<bpf::FilterSkel<'static>>],
+    api_rules: Option<config::AccessRule>,
+    previous: &PreviousRuleSets,
+    config: &AccessRulesConfig,
+    snapshot_tx: Option<&watch::Sender<RuleSnapshot>>,
+) -> Result<ApplyStats, AccessRulesError> {
+    let (skels, previous, config, snapshot_tx) = (skels.to_vec(), previous.clone(), config.clone(), snapshot_tx.cloned());
+    tokio::task::spawn_blocking(move || apply_rules(skels.as_slice(), api_rules.as_ref(), &previous, &config, snapshot_tx.as_ref()))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+/// Normalized, deduplicated domains listed by the local rules and the current API rules.
+/// Malformed names are left out here and reported by `collect_domains`.
+fn configured_domains(api_rules: Option<&config::AccessRule>, config: &AccessRulesConfig) -> Vec<String> {
+    let mut rule_sets: Vec<&config::RuleSet> = Vec::new();
+    if let Some(local) = &config.local_rules {
+        rule_sets.extend([&local.block, &local.allow]);
+    }
+    if let Some(rule) = api_rules {
+        rule_sets.extend([&rule.block, &rule.allow]);
+    }
+    let mut domains: Vec<String> = rule_sets
+        .iter()
+        .flat_map(|set| set.domains.iter())
+        .map(|entry| normalize_domain(entry))
+        .filter(|name| is_valid_domain(name))
+        .collect();
+    domains.sort();
+    domains.dedup();
+    domains
+}
+
+/// Resolve the configured domains and publish their addresses for the next apply.
+/// Returns whether any domain's address set changed since the last call, and the
+/// resolution stats when any domain is configured.
+async fn resolve_domains(previous: &PreviousRuleSets, config: &AccessRulesConfig) -> (bool, Option<ResolveStats>) {
+    let domains = {
+        let global = global_config();
+        let guard = global.read().ok();
+        let api_rules = guard.as_ref().and_then(|g| g.as_ref()).map(|cfg| &cfg.access_rules);
+        configured_domains(api_rules, config)
+    };
+    let limits = ResolveLimits { concurrency: config.dns_concurrency, timeout: config.dns_timeout };
+    let (resolved, stats) = previous.domain_cache.lock().await.resolve(&domains, limits).await;
+    let stats = (!domains.is_empty()).then_some(stats);
+    let mut current = previous.resolved_domains.lock().unwrap();
+    if *current == resolved {
+        return (false, stats);
+    }
+    *current = resolved;
+    (true, stats)
+}
+
+/// Last successful config response and when it was fetched, as written to `cache_path`
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedConfig {
+    /// Seconds since the Unix epoch
+    fetched_at: u64,
+    response: ConfigApiResponse,
+}
+
+fn save_config_cache(path: &Path, response: &ConfigApiResponse) -> Result<(), Box<dyn std::error::Error>> {
+    let fetched_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let cached = CachedConfig { fetched_at, response: response.clone() };
+    write_atomically(path, &serde_json::to_vec(&cached)?)
+}
+
+fn load_config_cache(path: &Path) -> Result<CachedConfig, AccessRulesError> {
+    let data = std::fs::read(path).map_err(|source| AccessRulesError::Io { path: path.to_path_buf(), source })?;
+    serde_json::from_slice(&data).map_err(|e| AccessRulesError::Parse {
+        what: format!("config cache {}", path.display()),
+        reason: e.to_string(),
+    })
+}
+
+/// Cold-start fallback: when no config has been fetched yet, load the cached response
+/// into the global config and apply it so the last-known-good rules are enforced. A
+/// cache is never applied when responses must be signed, since its signature cannot
+/// be checked again.
+async fn apply_cached_config(
+    path: &Path,
+    skels: &Vec<Arc<bpf::FilterSkel<'static>>>,
+    previous: &PreviousRuleSets,
+    config: &AccessRulesConfig,
+    snapshot_tx: Option<&watch::Sender<RuleSnapshot>>,
+) {
+    if global_config().read().map(|guard| guard.is_some()).unwrap_or(true) {
+        return;
+    }
+    if config::signing_required() {
+        log::warn!("API unreachable, not applying config cache {}: config responses must be signed", path.display());
+        return;
+    }
+    let cached = match load_config_cache(path) {
+        Ok(cached) => cached,
+        Err(e) => {
+            log::warn!("no usable config cache to fall back to: {}", e);
+            return;
+        }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    log::warn!(
+        fetched_at = cached.fetched_at;
+        "API unreachable, applying cached config from {} fetched {}s ago",
+        path.display(), now.saturating_sub(cached.fetched_at)
+    );
+
+    let cfg = cached.response.config;
+    if let Err(e) = update_http_filter_from_config_value(&cfg) {
+        log::error!("failed to update HTTP filter from cached config: {e}");
+    }
+    if !skels.is_empty() {
+        let start = Instant::now();
+        match apply_rules_blocking(skels, Some(cfg.access_rules.clone()), previous, config, snapshot_tx).await {
+            Ok(stats) => stats.log_summary(previous, start.elapsed(), "cache", config.log_unchanged_cycles),
+            Err(e) => log::error!("failed to apply cached access rules: {e}"),
+        }
+    }
+    set_global_config(cfg);
+}
+
+/// Entry dropped while parsing a rule set, with the reason it was rejected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RejectedEntry {
+    /// Rule set section and source, e.g. `block.country`
+    pub rule_source: String,
+    pub entry: String,
+    pub reason: String,
+}
+
+/// Rejected entry together with when it was seen
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentReject {
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub rejected: RejectedEntry,
+}
+
+/// Bounded ring buffer of the most recently rejected entries. A cycle's rejections are
+/// pushed in one batch after parsing, so the lock is taken once per cycle and never
+/// inside the parse loop.
+#[derive(Debug)]
+pub struct RecentRejects {
+    capacity: usize,
+    entries: Mutex<VecDeque<RecentReject>>,
+}
+
+impl RecentRejects {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, entries: Mutex::new(VecDeque::with_capacity(capacity)) }
+    }
+
+    /// Append a cycle's rejected entries, evicting the oldest beyond the capacity
+    pub fn record(&self, rejected: &[RejectedEntry]) {
+        if rejected.is_empty() || self.capacity == 0 {
+            return;
+        }
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        // Only the newest entries of an oversized batch can survive
+        let newest = &rejected[rejected.len().saturating_sub(self.capacity)..];
+        let mut entries = self.entries.lock().unwrap();
+        let overflow = (entries.len() + newest.len()).saturating_sub(self.capacity);
+        entries.drain(..overflow);
+        entries.extend(newest.iter().map(|rejected| RecentReject { timestamp: timestamp.clone(), rejected: rejected.clone() }));
+    }
+
+    /// Buffered rejections, oldest first
+    pub fn recent(&self) -> Vec<RecentReject> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Parse a single rule entry into the IPv4 or IPv6 set, routing IPv4-mapped IPv6
+/// entries to the IPv4 set so the IPv4 datapath can match them. The family is decided
+/// by which parser accepts the entry. Dotted-wildcard IPv4 patterns such as `10.0.*.*`
+/// become their CIDR when the wildcards are whole trailing octets and are rejected
+/// otherwise. Valid entries of a disabled family are dropped
+/// without warning, as are entries that are empty once comments and whitespace are
+/// stripped. A rejected entry's reason is returned; the caller records it and the
+/// cycle logs a sample through [`log_rejected`].
+fn collect_entry(
+    ip_str: &str,
+    families: AddressFamilies,
+    rules: &mut HashSet<(Ipv4Addr, u32)>,
+    rules_v6: &mut HashSet<(Ipv6Addr, u32)>,
+) -> Option<String> {
+    // A comment-only entry is skipped, not rejected
+    let entry = normalize_entry(ip_str)?;
+    if entry.contains('-') {
+        // start-end range
+        match parse_ip_range(&entry) {
+            Ok(RangeCidrs::V4(cidrs)) => {
+                if families.ipv4 { rules.extend(cidrs); }
+                None
+            }
+            Ok(RangeCidrs::V6(cidrs)) => {
+                if families.ipv6 { rules_v6.extend(cidrs); }
+                None
+            }
+            Err(reason) => Some(format!("invalid ip range ({})", reason)),
+        }
+    } else if entry.contains('*') {
+        // dotted-wildcard IPv4 pattern such as 10.0.*.*
+        match parse_ipv4_wildcard(&entry) {
+            Ok(net) => {
+                if families.ipv4 { rules.insert(net); }
+                None
+            }
+            Err(reason) => Some(format!("invalid wildcard pattern ({})", reason)),
+        }
+    } else if let Some(scope) = ipv6_non_global_scope(&entry) {
+        families.ipv6.then(|| format!("{} IPv6 address, not valid in a global blocklist", scope.describe()))
+    } else {
+        match parse_ip_or_cidr(&entry) {
+            Ok(IpNetwork::V4(net, prefix)) => {
+                if families.ipv4 { rules.insert((net, prefix)); }
+                None
+            }
+            Ok(IpNetwork::V6(net, prefix)) => {
+                if families.ipv6 { rules_v6.insert((net, prefix)); }
+                None
+            }
+            Err(reason) => Some(reason.to_string()),
+        }
+    }
+}
+
+/// ASN map keys are `AS<number>` (any case) or a bare 32-bit number
+fn is_valid_asn_key(key: &str) -> bool {
+    let digits = match key.get(..2) {
+        Some(p) if p.eq_ignore_ascii_case("as") => &key[2..],
+        _ => key,
+    };
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) && digits.parse::<u32>().is_ok()
+}
+
+/// Country map keys are 2-letter ISO 3166-1 alpha-2 codes
+fn is_valid_country_key(key: &str) -> bool {
+    key.len() == 2 && key.bytes().all(|b| b.is_ascii_alphabetic())
+}
+
+/// Include and exclude glob patterns on group keys, compared case-insensitively. An
+/// unset include list admits every key; exclusion wins over inclusion.
+#[derive(Debug, Clone, Default)]
+pub struct KeyFilter {
+    include: Option<Vec<String>>,
+    exclude: Vec<String>,
+}
+
+impl KeyFilter {
+    pub fn new(include: Option<&[String]>, exclude: &[String]) -> Self {
+        let normalize = |patterns: &[String]| patterns.iter().map(|p| p.trim().to_ascii_lowercase()).collect();
+        Self { include: include.map(normalize), exclude: normalize(exclude) }
+    }
+
+    fn admits(&self, key: &str) -> bool {
+        let key = key.to_ascii_lowercase();
+        let included = self.include.as_ref().is_none_or(|patterns| patterns.iter().any(|p| glob_match(p, &key)));
+        included && !self.exclude.iter().any(|p| glob_match(p, &key))
+    }
+}
+
+/// Which API country and ASN groups a block section is collected from; groups left
+/// out are counted in `skipped`
+struct GroupSelection<'a> {
+    country_filter: Option<&'a HashSet<String>>,
+    country_keys: &'a KeyFilter,
+    asn_keys: &'a KeyFilter,
+    skipped: usize,
+}
+
+impl<'a> GroupSelection<'a> {
+    fn new(config: &'a AccessRulesConfig) -> Self {
+        Self {
+            country_filter: config.country_filter.as_ref(),
+            country_keys: &config.country_keys,
+            asn_keys: &config.asn_keys,
+            skipped: 0,
+        }
+    }
+
+    fn admits_country(&mut self, key: &str) -> bool {
+        let admitted = self.country_filter.is_none_or(|filter| filter.contains(&key.to_ascii_uppercase()))
+            && self.country_keys.admits(key);
+        self.skipped += usize::from(!admitted);
+        admitted
+    }
+
+    fn admits_asn(&mut self, key: &str) -> bool {
+        let admitted = self.asn_keys.admits(key);
+        self.skipped += usize::from(!admitted);
+        admitted
+    }
+}
+
+/// Section of a rule set an entry comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleSource {
+    Ips,
+    Country,
+    Asn,
+}
+
+impl RuleSource {
+    /// Default priority order, also the order rule sets are parsed in
+    pub const ALL: [RuleSource; 3] = [RuleSource::Ips, RuleSource::Country, RuleSource::Asn];
+
+    fn name(self) -> &'static str {
+        match self {
+            RuleSource::Ips => "ips",
+            RuleSource::Country => "country",
+            RuleSource::Asn => "asn",
+        }
+    }
+}
+
+impl FromStr for RuleSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ips" => Ok(RuleSource::Ips),
+            "country" => Ok(RuleSource::Country),
+            "asn" => Ok(RuleSource::Asn),
+            other => Err(format!("unknown rule source '{}', expected ips, country or asn", other)),
+        }
+    }
+}
+
+/// Parse a configured source priority list, dropping unknown or duplicate names and
+/// appending any source that was left out in the default order
+fn parse_source_priority(names: &[String]) -> Vec<RuleSource> {
+    let mut priority = Vec::with_capacity(RuleSource::ALL.len());
+    for name in names {
+        match RuleSource::from_str(name) {
+            Ok(source) if !priority.contains(&source) => priority.push(source),
+            Ok(_) => {}
+            Err(e) => log::warn!("ignoring block_source_priority entry: {}", e),
+        }
+    }
+    for source in RuleSource::ALL {
+        if !priority.contains(&source) {
+            priority.push(source);
+        }
+    }
+    priority
+}
+
+/// Parse the API rate-limit rules into networks and their packets-per-second limit. An
+/// entry listed by several rules keeps the strictest limit.
+fn collect_rate_limits(
+    rules: &[config::RateLimitRule],
+    families: AddressFamilies,
+    rejected: &mut Vec<RejectedEntry>,
+) -> HashMap<(IpAddr, u32), u32> {
+    let mut limits: HashMap<(IpAddr, u32), u32> = HashMap::new();
+    for rule in rules {
+        if rule.pps == 0 {
+            log::warn!("rate limit rule with pps 0 ignored, use a block rule to drop all traffic");
+            rejected.extend(rule.ips.iter().map(|entry| RejectedEntry {
+                rule_source: "rate_limit".to_string(),
+                entry: entry.clone(),
+                reason: "rate limit of 0 pps".to_string(),
+            }));
+            continue;
+        }
+        let mut nets = HashSet::new();
+        let mut nets_v6 = HashSet::new();
+        for entry in &rule.ips {
+            if let Some(reason) = collect_entry(entry, families, &mut nets, &mut nets_v6) {
+                rejected.push(RejectedEntry { rule_source: "rate_limit".to_string(), entry: entry.clone(), reason });
+            }
+        }
+        let nets = nets.into_iter().map(|(net, prefix)| (IpAddr::V4(net), prefix));
+        let nets_v6 = nets_v6.into_iter().map(|(net, prefix)| (IpAddr::V6(net), prefix));
+        for key in nets.chain(nets_v6) {
+            limits.entry(key).and_modify(|pps| *pps = (*pps).min(rule.pps)).or_insert(rule.pps);
+        }
+    }
+    limits
+}
+
+/// Write the rate-limit rules that changed since the last cycle to every skeleton and
+/// remove the ones that went away. An entry whose map write failed on any skeleton keeps
+/// its previous state in `previous`, so the next cycle retries it.
+fn apply_rate_limits(
+    targets: &(impl FirewallTargets + ?Sized),
+    current: HashMap<(IpAddr, u32), u32>,
+    previous: &Mutex<HashMap<(IpAddr, u32), u32>>,
+    config: &AccessRulesConfig,
+) {
+    let mut applied = previous.lock().unwrap();
+    if *applied == current {
+        return;
+    }
+    let removed: Vec<(IpAddr, u32)> = applied.keys().filter(|key| !current.contains_key(key)).copied().collect();
+    let changed: Vec<((IpAddr, u32), u32)> = current
+        .iter()
+        .filter(|(key, pps)| applied.get(key) != Some(pps))
+        .map(|(key, pps)| (*key, *pps))
+        .collect();
+
+    if config.dry_run {
+        for (net, prefix) in &removed { log::info!("would_remove_rate_limit {}/{}", net, prefix); }
+        for ((net, prefix), pps) in &changed { log::info!("would_rate_limit {}/{} {}pps", net, prefix, pps); }
+        *applied = current;
+        return;
+    }
+
+    let mut errors = 0usize;
+    for ((net, prefix), pps) in &changed {
+        let mut ok = true;
+        targets.for_each(None, &mut |fw| {
+            let result = match net {
+                IpAddr::V4(ip) => fw.set_rate_limit(*ip, *prefix, *pps),
+                IpAddr::V6(ip) => fw.set_rate_limit_v6(*ip, *prefix, *pps),
+            };
+            if let Err(e) = result {
+                log::error!(ip:% = net, prefix = *prefix; "rate limit: BPF map update failed for {}/{}: {}", net, prefix, e);
+                ok = false;
+            }
+        });
+        if ok {
+            applied.insert((*net, *prefix), *pps);
+        } else {
+            errors += 1;
+        }
+    }
+    for (net, prefix) in &removed {
+        let mut ok = true;
+        targets.for_each(None, &mut |fw| {
+            let result = match net {
+                IpAddr::V4(ip) => fw.remove_rate_limit(*ip, *prefix),
+                IpAddr::V6(ip) => fw.remove_rate_limit_v6(*ip, *prefix),
+            };
+            if let Err(e) = result {
+                log::error!(ip:% = net, prefix = *prefix; "rate limit: BPF map delete failed for {}/{}: {}", net, prefix, e);
+                ok = false;
+            }
+        });
+        if ok {
+            applied.remove(&(*net, *prefix));
+        } else {
+            errors += 1;
+        }
+    }
+    log::info!(
+        "rate limit rules updated: {} set, {} removed, {} failed",
+        changed.len(), removed.len(), errors
+    );
+}
+
+/// Parse the ips, country and asn sections of a rule set into per-family networks.
+/// `section` names the rule set ("block" or "allow") in log events. When `groups` is
+/// set, only the country and ASN groups it admits are collected. Returns the entries
+/// that were rejected.
+fn collect_rule_set(
+    section: &str,
+    rule_set: &config::RuleSet,
+    families: AddressFamilies,
+    mut groups: Option<&mut GroupSelection<'_>>,
+    now: chrono::DateTime<chrono::Utc>,
+    rules: &mut HashSet<(Ipv4Addr, u32)>,
+    rules_v6: &mut HashSet<(Ipv6Addr, u32)>,
+) -> Vec<RejectedEntry> {
+    let mut rejected = Vec::new();
+    let mut seen = HashSet::new();
+    for source in RuleSource::ALL {
+        rejected.extend(collect_rule_source(section, source, rule_set, families, groups.as_deref_mut(), now, &mut seen, rules, rules_v6));
+    }
+    rejected
+}
+
+/// Add the last resolved addresses of a rule set's domains as host entries. Loopback
+/// and unspecified answers, as returned by DNS sinkholes, are skipped.
+fn collect_domains(
+    section: &str,
+    rule_set: &config::RuleSet,
+    resolved: &ResolvedDomains,
+    families: AddressFamilies,
+    rules: &mut HashSet<(Ipv4Addr, u32)>,
+    rules_v6: &mut HashSet<(Ipv6Addr, u32)>,
+) -> Vec<RejectedEntry> {
+    let rule_source = format!("{}.domains", section);
+    let mut rejected = Vec::new();
+    for entry in &rule_set.domains {
+        let name = normalize_domain(entry);
+        if !is_valid_domain(&name) {
+            rejected.push(RejectedEntry { rule_source: rule_source.clone(), entry: entry.clone(), reason: "invalid domain".to_string() });
+            continue;
+        }
+        for ip in resolved.get(&name).into_iter().flatten() {
+            if ip.is_loopback() || ip.is_unspecified() {
+                continue;
+            }
+            // The domain entry itself is valid, so a resolved address that cannot be
+            // blocked is not counted as a rejected entry
+            let ip = ip.to_string();
+            if let Some(reason) = collect_entry(&ip, families, rules, rules_v6) {
+                log::debug!(domain = entry.as_str(), ip = ip.as_str(); "resolved address of {} ignored: {}", entry, reason);
+            }
+        }
+    }
+    rejected
+}
+
+/// Parse a single section of a rule set, see `collect_rule_set`. Entries whose expiry
+/// is at or before `now` are left out. `seen` holds the trimmed entries already parsed
+/// from this rule set; an entry listed again, in this or an earlier section, is skipped
+/// instead of parsed twice.
+#[allow(clippy::too_many_arguments)]
+fn collect_rule_source<'a>(
+    section: &str,
+    source: RuleSource,
+    rule_set: &'a config::RuleSet,
+    families: AddressFamilies,
+    mut groups: Option<&mut GroupSelection<'_>>,
+    now: chrono::DateTime<chrono::Utc>,
+    seen: &mut HashSet<&'a str>,
+    rules: &mut HashSet<(Ipv4Addr, u32)>,
+    rules_v6: &mut HashSet<(Ipv6Addr, u32)>,
+) -> Vec<RejectedEntry> {
+    let rule_source = format!("{}.{}", section, source.name());
+    let seen_before = seen.len();
+    let mut duplicates = 0usize;
+    let mut expired = 0usize;
+    let mut is_expired = |entry: &str| {
+        let due = entry_expiry(rule_set, entry).is_some_and(|at| at <= now);
+        expired += usize::from(due);
+        due
+    };
+    let mut rejected = Vec::new();
+    let mut reject = |entry: &str, reason: String| rejected.push(RejectedEntry {
+        rule_source: rule_source.clone(),
+        entry: entry.to_string(),
+        reason,
+    });
+    match source {
+        RuleSource::Ips => {
+            for ip_str in &rule_set.ips {
+                if !seen.insert(ip_str.trim()) {
+                    duplicates += 1;
+                    continue;
+                }
+                if is_expired(ip_str) {
+                    continue;
+                }
+                if let Some(reason) = collect_entry(ip_str, families, rules, rules_v6) {
+                    reject(ip_str, reason);
+                }
+            }
+        }
+        RuleSource::Country => {
+            for country_map in &rule_set.country {
+                for (cc, list) in country_map.iter() {
+                    if !is_valid_country_key(cc) {
+                        reject(cc, format!("malformed country key, {} entries skipped", list.len()));
+                        continue;
+                    }
+                    if groups.as_deref_mut().is_some_and(|groups| !groups.admits_country(cc)) {
+                        log::debug!(
+                            key = cc.as_str(), rule_source = rule_source.as_str();
+                            "country {} not selected by the group filters, skipping {} entries", cc, list.len()
+                        );
+                        continue;
+                    }
+                    for ip_str in list.entries() {
+                        if !seen.insert(ip_str.trim()) {
+                            duplicates += 1;
+                            continue;
+                        }
+                        if is_expired(ip_str) {
+                            continue;
+                        }
+                        if let Some(reason) = collect_entry(ip_str, families, rules, rules_v6) {
+                            reject(ip_str, reason);
+                        }
+                    }
+                }
+            }
+        }
+        RuleSource::Asn => {
+            for asn_map in &rule_set.asn {
+                for (asn, list) in asn_map.iter() {
+                    if !is_valid_asn_key(asn) {
+                        reject(asn, format!("malformed ASN key, {} entries skipped", list.len()));
+                        continue;
+                    }
+                    if groups.as_deref_mut().is_some_and(|groups| !groups.admits_asn(asn)) {
+                        log::debug!(
+                            key = asn.as_str(), rule_source = rule_source.as_str();
+                            "ASN {} not selected by the group filters, skipping {} entries", asn, list.len()
+                        );
+                        continue;
+                    }
+                    for ip_str in list.entries() {
+                        if !seen.insert(ip_str.trim()) {
+                            duplicates += 1;
+                            continue;
+                        }
+                        if is_expired(ip_str) {
+                            continue;
+                        }
+                        if let Some(reason) = collect_entry(ip_str, families, rules, rules_v6) {
+                            reject(ip_str, reason);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if duplicates > 0 {
+        log::debug!(
+            rule_source = rule_source.as_str();
+            "{} parsed {} unique entries, skipped {} duplicates", rule_source, seen.len() - seen_before, duplicates
+        );
+    }
+    if expired > 0 {
+        log::info!(rule_source = rule_source.as_str(); "{} dropped {} expired entries", rule_source, expired);
+    }
+    rejected
+}
+
+/// Networks of the entries `actions` marks `log`. An unknown action is logged and the
+/// entry enforced as a plain block.
+fn collect_log_only(
+    rule_set: &config::RuleSet,
+    families: AddressFamilies,
+) -> (HashSet<(Ipv4Addr, u32)>, HashSet<(Ipv6Addr, u32)>) {
+    let (mut log_only, mut log_only_v6) = (HashSet::new(), HashSet::new());
+    for (entry, action) in &rule_set.actions {
+        match action.trim().to_ascii_lowercase().as_str() {
+            "log" => {
+                let _ = collect_entry(entry.trim(), families, &mut log_only, &mut log_only_v6);
+            }
+            "drop" => {}
+            // Throttling needs a packet rate, which only the `rate_limit` rules carry
+            "rate-limit" | "rate_limit" => log::warn!(
+                ip = entry.as_str();
+                "action {:?} is not supported per entry, list {} under rate_limit with a pps instead; enforcing it as a block",
+                action, entry
+            ),
+            _ => log::warn!(ip = entry.as_str(); "unknown action {:?} for {}, enforcing it as a block", action, entry),
+        }
+    }
+    (log_only, log_only_v6)
+}
+
+/// Expiry of a rule set entry from the `expires` map. A malformed timestamp is logged
+/// and treated as no expiry, so the entry stays enforced.
+fn entry_expiry(rule_set: &config::RuleSet, entry: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let raw = rule_set.expires.get(entry.trim())?;
+    match chrono::DateTime::parse_from_rfc3339(raw.trim()) {
+        Ok(at) => Some(at.with_timezone(&chrono::Utc)),
+        Err(e) => {
+            log::warn!(ip = entry; "ignoring malformed expiry {:?} of {}: {}", raw, entry, e);
+            None
+        }
+    }
+}
+
+/// Earliest expiry after `now` across the given rule sets, when the next apply must run
+/// even if the config does not change
+fn next_rule_expiry<'a>(
+    rule_sets: impl IntoIterator<Item = &'a config::RuleSet>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    rule_sets
+        .into_iter()
+        .flat_map(|rule_set| rule_set.expires.values())
+        .filter_map(|raw| chrono::DateTime::parse_from_rfc3339(raw.trim()).ok())
+        .map(|at| at.with_timezone(&chrono::Utc))
+        .filter(|at| *at > now)
+        .min()
+}
+
+const RULE_STATE_VERSION: u32 = 3;
+
+/// On-disk snapshot of the applied rule sets. Files with a different `version`
+/// are ignored rather than parsed, so a schema change never fails startup.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct PersistedRuleState {
+    version: u32,
+    #[serde(default)]
+    block_v4: Vec<(Ipv4Addr, u32)>,
+    #[serde(default)]
+    block_v6: Vec<(Ipv6Addr, u32)>,
+    #[serde(default)]
+    allow_v4: Vec<(Ipv4Addr, u32)>,
+    #[serde(default)]
+    allow_v6: Vec<(Ipv6Addr, u32)>,
+    /// Block entries installed as log-only, restored without enforcing them
+    #[serde(default)]
+    log_only_v4: Vec<(Ipv4Addr, u32)>,
+    #[serde(default)]
+    log_only_v6: Vec<(Ipv6Addr, u32)>,
+    /// Rule hit totals for `monotonic_rule_hits` per skeleton, named by interface, carried
+    /// into the counters after a restart
+    #[serde(default)]
+    rule_hits: Vec<(String, IpAddr, u32, u64)>,
+}
+
+/// Load a persisted rule state, returning None (with a warning) if the file is
+/// missing, unreadable, corrupt or written by an unknown format version
+fn load_rule_state(path: &Path) -> Option<PersistedRuleState> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::warn!("access rules state file {} not found, starting empty", path.display());
+            return None;
+        }
+        Err(e) => {
+            log::warn!("failed to read access rules state file {}: {}, starting empty", path.display(), e);
+            return None;
+        }
+    };
+    let state: PersistedRuleState = match serde_json::from_slice(&data) {
+        Ok(state) => state,
+        Err(e) => {
+            log::warn!("corrupt access rules state file {}: {}, starting empty", path.display(), e);
+            return None;
+        }
+    };
+    if state.version != RULE_STATE_VERSION {
+        log::warn!(
+            "unsupported access rules state file version {} in {} (expected {}), starting empty",
+            state.version, path.display(), RULE_STATE_VERSION
+        );
+        return None;
+    }
+    Some(state)
+}
+
+fn save_rule_state(path: &Path, state: &PersistedRuleState) -> Result<(), Box<dyn std::error::Error>> {
+    write_atomically(path, &serde_json::to_vec(state)?)
+}
+
+/// Write via a temporary file and rename so a crash never leaves a partial file
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Seed the previous rule snapshots from the state file and install those rules in the
+/// freshly loaded BPF maps, so enforcement resumes before the first successful fetch and
+/// the first cycle only applies the difference. In dry-run mode the maps are left alone
+/// and only the snapshots are seeded.
+fn restore_rule_state(
+    targets: &(impl FirewallTargets + ?Sized),
+    previous: &PreviousRuleSets,
+    path: &Path,
+    config: &AccessRulesConfig,
+) {
+    let Some(mut state) = load_rule_state(path) else {
+        return;
+    };
+    let families = config.families();
+    if !families.ipv4 {
+        state.block_v4.clear();
+        state.allow_v4.clear();
+        state.log_only_v4.clear();
+    }
+    if !families.ipv6 {
+        state.block_v6.clear();
+        state.allow_v6.clear();
+        state.log_only_v6.clear();
+    }
+    let log_only: HashSet<(Ipv4Addr, u32)> = state.log_only_v4.iter().filter(|rule| state.block_v4.contains(rule)).copied().collect();
+    let log_only_v6: HashSet<(Ipv6Addr, u32)> = state.log_only_v6.iter().filter(|rule| state.block_v6.contains(rule)).copied().collect();
+
+    if !config.dry_run {
+        targets.for_each(None, &mut |fw| {
+            for (net, prefix) in &state.allow_v4 {
+                if let Err(e) = fw.allow_ip(*net, *prefix) {
+                    log::error!("IPv4 allow restore: BPF map update failed for {}/{}: {}", net, prefix, e);
+                }
+            }
+            for (net, prefix) in &state.allow_v6 {
+                if let Err(e) = fw.allow_ipv6(*net, *prefix) {
+                    log::error!("IPv6 allow restore: BPF map update failed for {}/{}: {}", net, prefix, e);
+                }
+            }
+            for (net, prefix) in &state.block_v4 {
+                let action = BanAction::of(log_only.contains(&(*net, *prefix)));
+                if let Err(e) = fw.ban_ip_with_action(*net, *prefix, action) {
+                    log::error!("IPv4 ban restore: BPF map update failed for {}/{}: {}", net, prefix, e);
+                }
+            }
+            for (net, prefix) in &state.block_v6 {
+                let action = BanAction::of(log_only_v6.contains(&(*net, *prefix)));
+                if let Err(e) = fw.ban_ipv6_with_action(*net, *prefix, action) {
+                    log::error!("IPv6 ban restore: BPF map update failed for {}/{}: {}", net, prefix, e);
+                }
+            }
+        });
+    }
+
+    log::info!(
+        "{}restored access rules from {}: {} IPv4, {} IPv6 block rules ({} log-only), {} IPv4, {} IPv6 allow rules",
+        if config.dry_run { "dry-run: " } else { "" }, path.display(), state.block_v4.len(), state.block_v6.len(),
+        log_only.len() + log_only_v6.len(), state.allow_v4.len(), state.allow_v6.len()
+    );
+    *previous.rules.lock().unwrap() = state.block_v4.into_iter().collect();
+    *previous.rules_v6.lock().unwrap() = state.block_v6.into_iter().collect();
+    *previous.log_only.lock().unwrap() = log_only;
+    *previous.log_only_v6.lock().unwrap() = log_only_v6;
+    *previous.allow_rules.lock().unwrap() = state.allow_v4.into_iter().collect();
+    *previous.allow_rules_v6.lock().unwrap() = state.allow_v6.into_iter().collect();
+    publish_applied_allow(&previous.allow_rules.lock().unwrap(), &previous.allow_rules_v6.lock().unwrap());
+    *previous.rule_hit_totals.lock().unwrap() = restored_rule_hits(state.rule_hits, &config.skeleton_ifaces);
+}
+
+/// Drop networks fully contained in a broader network of the same set
+fn collapse_covered<T: AddressFamily>(rules: HashSet<(T, u32)>) -> HashSet<(T, u32)> {
+    rules
+        .iter()
+        .filter(|(net, prefix)| !covered_by(&rules, *net, *prefix))
+        .copied()
+        .collect()
+}
+
+/// Whether a strictly broader network than `net`/`prefix` is in `rules`
+fn covered_by<T: AddressFamily>(rules: &HashSet<(T, u32)>, net: T, prefix: u32) -> bool {
+    (0..prefix).any(|p| rules.contains(&(net.masked(p), p)))
+}
+
+/// Replace every pair of sibling IPv4 networks (same prefix, differing only in the last
+/// prefix bit) with their parent, see `merge_siblings`
+fn merge_adjacent_v4(rules: HashSet<(Ipv4Addr, u32)>) -> HashSet<(Ipv4Addr, u32)> {
+    let bits = rules.into_iter().map(|(net, prefix)| (u128::from(u32::from(net)), prefix)).collect();
+    merge_siblings(bits, 32).into_iter().map(|(net, prefix)| (Ipv4Addr::from(net as u32), prefix)).collect()
+}
+
+/// Replace every pair of sibling IPv6 networks with their parent, see `merge_siblings`
+fn merge_adjacent_v6(rules: HashSet<(Ipv6Addr, u32)>) -> HashSet<(Ipv6Addr, u32)> {
+    let bits = rules.into_iter().map(|(net, prefix)| (u128::from(net), prefix)).collect();
+    merge_siblings(bits, 128).into_iter().map(|(net, prefix)| (Ipv6Addr::from(net), prefix)).collect()
+}
+
+/// Route aggregation over `width`-bit networks: two networks of the same prefix that
+/// together fill their parent are replaced by it, longest prefixes first, so merged
+/// parents merge again with their own siblings. Networks that only touch without
+/// sharing a parent (e.g. 10.0.1.0/24 and 10.0.2.0/24) are left alone, and nothing is
+/// ever merged into a prefix-0 catch-all.
+fn merge_siblings(mut rules: HashSet<(u128, u32)>, width: u32) -> HashSet<(u128, u32)> {
+    for prefix in (2..=width).rev() {
+        let bit = 1u128 << (width - prefix);
+        let mut lower: Vec<u128> = rules
+            .iter()
+            .filter(|(net, p)| *p == prefix && net & bit == 0 && rules.contains(&(net | bit, prefix)))
+            .map(|(net, _)| *net)
+            .collect();
+        lower.sort_unstable();
+        for net in lower {
+            rules.remove(&(net, prefix));
+            rules.remove(&(net | bit, prefix));
+            rules.insert((net, prefix - 1));
+        }
+    }
+    rules
+}
+
+/// Fetch the config with a per-attempt deadline, retrying transient network errors
+/// up to `config.fetch_retries` times. A timeout is returned as an error without retrying,
+/// so a stalled API falls through to the updater's backoff.
+async fn fetch_config_with_retry(
+    base_url: &str,
+    api_key: &str,
+    config: &AccessRulesConfig,
+) -> Result<ConfigApiResponse, AccessRulesError> {
+    let fetch_timeout = if config.fetch_timeout.is_zero() { DEFAULT_FETCH_TIMEOUT } else { config.fetch_timeout };
+    let attempts = config.fetch_retries.saturating_add(1);
+    // Without a held version there is nothing for a delta to build on
+    let since_version = if config.delta_sync {
+        let global = global_config();
+        let guard = global.read().ok();
+        guard.as_ref().and_then(|g| g.as_ref()).and_then(|cfg| cfg.rule_version()).map(str::to_string)
+    } else {
+        None
+    };
+
+    for attempt in 1..=attempts {
+        let fetch = fetch_config(base_url.to_string(), api_key.to_string(), since_version.clone());
+        match timeout(fetch_timeout, fetch).await {
+            Ok(Ok(response)) => {
+                if attempt > 1 {
+                    log::info!(attempt = attempt; "access rules fetch succeeded on attempt {}/{}", attempt, attempts);
+                } else {
+                    log::debug!(attempt = attempt; "access rules fetch succeeded on attempt {}/{}", attempt, attempts);
+                }
+                return Ok(response);
+            }
+            Ok(Err(e)) => {
+                if attempt == attempts || !is_transient_fetch_error(e.as_ref()) {
+                    return Err(AccessRulesError::Fetch(e.to_string().into()));
+                }
+                log::warn!(
+                    attempt = attempt;
+                    "access rules fetch attempt {}/{} failed: {}, retrying in {}ms",
+                    attempt, attempts, e, config.fetch_retry_delay.as_millis()
+                );
+            }
+            Err(_) => {
+                log::warn!(attempt = attempt; "access rules fetch attempt {}/{} timed out", attempt, attempts);
+                return Err(AccessRulesError::Timeout { after: fetch_timeout });
+            }
+        }
+        sleep(config.fetch_retry_delay).await;
+    }
+    unreachable!("fetch attempts is always at least one")
+}
+
+/// Connection, request and body transfer failures are worth retrying; API and parse errors are not
+fn is_transient_fetch_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect() || e.is_timeout() || e.is_request() || e.is_body())
+}
+
+/// Remove prefix-0 entries from a block set, logging an error for each
+fn drop_default_route_blocks<T: std::fmt::Display>(rules: &mut HashSet<(T, u32)>, family: &str) {
+    rules.retain(|(net, prefix)| {
+        if *prefix == 0 {
+            log::error!(
+                "refusing to install catch-all {} block {}/0, set allow_default_route_block to apply it",
+                family, net
+            );
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// The `n` block entries covering the most addresses (shortest prefix first)
+fn broadest_rules<T: Copy + Ord>(rules: &HashSet<(T, u32)>, n: usize) -> Vec<(T, u32)> {
+    let mut sorted: Vec<(T, u32)> = rules.iter().copied().collect();
+    sorted.sort_by_key(|(net, prefix)| (*prefix, *net));
+    sorted.truncate(n);
+    sorted
+}
+
+/// Record `rank` for every entry that does not already have a better one
+fn record_ranks<T: Copy + Eq + Hash>(rules: &HashSet<T>, rank: usize, ranks: &mut HashMap<T, usize>) {
+    for entry in rules {
+        ranks.entry(*entry).or_insert(rank);
+    }
+}
+
+/// Trim a block set to `max_rules` entries, keeping the best-ranked sources first and,
+/// within a rank, the prefixes that cover the most addresses. Unranked entries sort last.
+/// The selection is deterministic, so surviving entries do not churn between cycles.
+fn cap_rules<T: Copy + Ord + Hash + std::fmt::Display>(
+    rules: HashSet<(T, u32)>,
+    ranks: &HashMap<(T, u32), usize>,
+    max_rules: usize,
+    family: &str,
+) -> HashSet<(T, u32)> {
+    if rules.len() <= max_rules {
+        return rules;
+    }
+    let total = rules.len();
+    let mut sorted: Vec<(T, u32)> = rules.into_iter().collect();
+    sorted.sort_by_key(|entry| (ranks.get(entry).copied().unwrap_or(usize::MAX), entry.1, entry.0));
+    sorted.truncate(max_rules);
+    log::warn!(
+        "{} block rules exceed max_rules ({} > {}), dropped {} lower-priority entries",
+        family, total, max_rules, total - sorted.len()
+    );
+    sorted.into_iter().collect()
+}
+
+/// Split the difference between two rule snapshots into (removed, added)
+fn diff_rules<T: Copy + Eq + Hash>(previous: &HashSet<T>, current: &HashSet<T>) -> (Vec<T>, Vec<T>) {
+    let removed = previous.difference(current).copied().collect();
+    let added = current.difference(previous).copied().collect();
+    (removed, added)
+}
+
+/// Whether `changes` block entries against `installed` ones exceed `percent`. Nothing
+/// is gated before the first entries are installed, so a cold start applies in full.
+fn exceeds_canary_threshold(installed: usize, changes: usize, percent: u32) -> bool {
+    installed > 0 && changes * 100 > installed * percent as usize
+}
+
+/// Cut a gated change down to at most `sample` entries, additions first and the lowest
+/// networks within each list, so every host picks the same canary. Returns how many
+/// changes were held back.
+fn canary_sample<T: Copy + Ord>(added: &mut Vec<(T, u32)>, removed: &mut Vec<(T, u32)>, sample: usize) -> usize {
+    let total = added.len() + removed.len();
+    added.sort_unstable();
+    removed.sort_unstable();
+    added.truncate(sample);
+    removed.truncate(sample - added.len());
+    total - added.len() - removed.len()
+}
+
+/// `previous` with only the sampled changes applied
+fn sampled_rules<T: Copy + Eq + Hash>(previous: &HashSet<(T, u32)>, added: &[(T, u32)], removed: &[(T, u32)]) -> HashSet<(T, u32)> {
+    let mut rules = previous.clone();
+    for entry in removed {
+        rules.remove(entry);
+    }
+    rules.extend(added.iter().copied());
+    rules
+}
+
+/// Split `removed` into entries that stayed absent for `grace` cycles after the one
+/// they vanished in, which stay in `removed`, and entries still within the grace,
+/// which are returned. An entry no longer being removed has reappeared and its
+/// pending unban is cancelled.
+fn hold_removals<T: Copy + Eq + Hash + std::fmt::Display>(
+    removed: &mut Vec<(T, u32)>,
+    pending: &mut HashMap<(T, u32), AbsentEntry>,
+    grace: u32,
+    now: Instant,
+) -> Vec<(T, u32)> {
+    let removing: HashSet<(T, u32)> = removed.iter().copied().collect();
+    pending.retain(|entry, _| removing.contains(entry));
+
+    let mut held = Vec::new();
+    removed.retain(|entry| {
+        // The cycle an entry vanished in is cycle 0 of its grace
+        let absent = pending
+            .entry(*entry)
+            .and_modify(|absent| absent.cycles += 1)
+            .or_insert(AbsentEntry { since: now, cycles: 0 });
+        if absent.cycles < grace {
+            held.push(*entry);
+            return false;
+        }
+        let AbsentEntry { since, cycles } = *absent;
+        pending.remove(entry);
+        log::debug!(
+            ip:% = entry.0, prefix = entry.1;
+            "unbanning {}/{} after {} grace cycles ({:?})", entry.0, entry.1, cycles, now.duration_since(since)
+        );
+        true
+    });
+    held
+}
+
+/// Damp flapping block entries with the negative cache `recent`. An `added` entry
+/// unbanned less than `window` ago is counted and logged as oscillating, and its
+/// removals are taken out of `removed` and returned until `window` has passed since it
+/// reappeared, so it stays installed instead of being unbanned and banned again every
+/// cycle. Other removals are remembered as unbanned at `now`; entries older than the
+/// window are forgotten. Returns the number of oscillating entries and the held removals.
+fn damp_oscillations<T: Copy + Eq + Hash + std::fmt::Display>(
+    added: &[(T, u32)],
+    removed: &mut Vec<(T, u32)>,
+    recent: &mut HashMap<(T, u32), RecentUnban>,
+    window: Duration,
+    now: Instant,
+) -> (usize, Vec<(T, u32)>) {
+    recent.retain(|_, state| match state {
+        RecentUnban::Unbanned(at) | RecentUnban::Oscillating(at) => now.duration_since(*at) < window,
+    });
+    let mut flapping = 0;
+    for entry in added {
+        if let Some(RecentUnban::Unbanned(unbanned)) = recent.get(entry).copied() {
+            flapping += 1;
+            log::info!(
+                ip:% = entry.0, prefix = entry.1;
+                "{}/{} is listed again {:?} after it was unbanned, its feed source is oscillating",
+                entry.0, entry.1, now.duration_since(unbanned)
+            );
+            recent.insert(*entry, RecentUnban::Oscillating(now));
+        }
+    }
+    let mut held = Vec::new();
+    removed.retain(|entry| match recent.get(entry).copied() {
+        Some(RecentUnban::Oscillating(_)) => {
+            held.push(*entry);
+            false
+        }
+        _ => {
+            recent.insert(*entry, RecentUnban::Unbanned(now));
+            true
+        }
+    });
+    (flapping, held)
+}
+
+/// Block and allow sets an apply cycle would install, before diffing against the maps
+#[derive(Debug, Default)]
+struct DesiredRules {
+    rules: HashSet<(Ipv4Addr, u32)>,
+    rules_v6: HashSet<(Ipv6Addr, u32)>,
+    allow_rules: HashSet<(Ipv4Addr, u32)>,
+    allow_rules_v6: HashSet<(Ipv6Addr, u32)>,
+    /// Entries of `rules` and `rules_v6` the API marked log-only
+    log_only: HashSet<(Ipv4Addr, u32)>,
+    log_only_v6: HashSet<(Ipv6Addr, u32)>,
+    /// API block groups left out by the group filters
+    groups_skipped: usize,
+}
+
+/// Merge the API rules (if any) with the local and manual rules into the sets to
+/// install, after default-route filtering, collapsing and `max_rules` trimming.
+/// Entries expired as of `now` are left out, and entries dropped as invalid are
+/// appended to `rejected`.
+fn desired_rules(
+    api_rules: Option<&config::AccessRule>,
+    config: &AccessRulesConfig,
+    resolved: &ResolvedDomains,
+    now: chrono::DateTime<chrono::Utc>,
+    rejected: &mut Vec<RejectedEntry>,
+) -> DesiredRules {
+    let mut current_rules: HashSet<(Ipv4Addr, u32)> = HashSet::new();
+    let mut current_rules_v6: HashSet<(Ipv6Addr, u32)> = HashSet::new();
+    let mut current_allow_rules: HashSet<(Ipv4Addr, u32)> = HashSet::new();
+    let mut current_allow_rules_v6: HashSet<(Ipv6Addr, u32)> = HashSet::new();
+    let families = config.families();
+
+    // Priority rank of every block entry for `max_rules` trimming (lower wins): local
+    // rules rank 0, followed by the API sources in the configured order. An entry
+    // listed by several sources keeps its best rank.
+    let mut block_ranks: HashMap<(Ipv4Addr, u32), usize> = HashMap::new();
+    let mut block_ranks_v6: HashMap<(Ipv6Addr, u32), usize> = HashMap::new();
+
+    // Merge local rules into the same sets before diffing so they never churn
+    if let Some(local) = &config.local_rules {
+        rejected.extend(collect_rule_set("local.block", &local.block, families, None, now, &mut current_rules, &mut current_rules_v6));
+        rejected.extend(collect_rule_set("local.allow", &local.allow, families, None, now, &mut current_allow_rules, &mut current_allow_rules_v6));
+        rejected.extend(collect_domains("local.block", &local.block, resolved, families, &mut current_rules, &mut current_rules_v6));
+        rejected.extend(collect_domains("local.allow", &local.allow, resolved, families, &mut current_allow_rules, &mut current_allow_rules_v6));
+        record_ranks(&current_rules, 0, &mut block_ranks);
+        record_ranks(&current_rules_v6, 0, &mut block_ranks_v6);
+    }
+    if let Some(manual) = &config.manual_rules {
+        let (manual_rules, manual_rules_v6) = manual.snapshot();
+        if families.ipv4 {
+            record_ranks(&manual_rules, 0, &mut block_ranks);
+            current_rules.extend(manual_rules);
+        }
+        if families.ipv6 {
+            record_ranks(&manual_rules_v6, 0, &mut block_ranks_v6);
+            current_rules_v6.extend(manual_rules_v6);
+        }
+    }
+
+    let mut groups = GroupSelection::new(config);
+    if let Some(rule) = api_rules {
+        // Parse block.ips, block.country and block.asn in priority order. An entry already
+        // parsed from a higher priority source is skipped, it keeps that source's rank anyway.
+        let mut seen = HashSet::new();
+        for (idx, source) in config.block_source_priority.iter().enumerate() {
+            let mut source_rules = HashSet::new();
+            let mut source_rules_v6 = HashSet::new();
+            rejected.extend(collect_rule_source(
+                "block", *source, &rule.block, families, Some(&mut groups), now, &mut seen,
+                &mut source_rules, &mut source_rules_v6,
+            ));
+            // Domain addresses rank with the literal ips they stand in for
+            if *source == RuleSource::Ips {
+                rejected.extend(collect_domains("block", &rule.block, resolved, families, &mut source_rules, &mut source_rules_v6));
+            }
+            record_ranks(&source_rules, idx + 1, &mut block_ranks);
+            record_ranks(&source_rules_v6, idx + 1, &mut block_ranks_v6);
+            current_rules.extend(source_rules);
+            current_rules_v6.extend(source_rules_v6);
+        }
+
+        // Parse allow.ips, allow.country and allow.asn
+        rejected.extend(collect_rule_set("allow", &rule.allow, families, None, now, &mut current_allow_rules, &mut current_allow_rules_v6));
+        rejected.extend(collect_domains("allow", &rule.allow, resolved, families, &mut current_allow_rules, &mut current_allow_rules_v6));
+    }
+
+    // A prefix-0 block black-holes all traffic of the family, so it is rejected unless
+    // explicitly enabled. This runs before collapsing, which would otherwise fold
+    // every other entry into it.
+    if !config.allow_default_route_block {
+        for (net, _) in current_rules.iter().filter(|(_, prefix)| *prefix == 0) {
+            rejected.push(default_route_rejection(net));
+        }
+        for (net, _) in current_rules_v6.iter().filter(|(_, prefix)| *prefix == 0) {
+            rejected.push(default_route_rejection(net));
+        }
+        drop_default_route_blocks(&mut current_rules, "IPv4");
+        drop_default_route_blocks(&mut current_rules_v6, "IPv6");
+    }
+
+    // Log-only entries sit out merging and collapsing so a trial rule never absorbs an
+    // enforced one. Entries local or manual rules also block stay enforced.
+    let (mut log_only, mut log_only_v6) = api_rules
+        .map(|rule| collect_log_only(&rule.block, families))
+        .unwrap_or_default();
+    log_only.retain(|rule| current_rules.contains(rule) && block_ranks.get(rule) != Some(&0));
+    log_only_v6.retain(|rule| current_rules_v6.contains(rule) && block_ranks_v6.get(rule) != Some(&0));
+    current_rules.retain(|rule| !log_only.contains(rule));
+    current_rules_v6.retain(|rule| !log_only_v6.contains(rule));
+
+    // Optionally merge sibling block entries into their parent. This runs before
+    // collapsing, so entries the merged parents cover are dropped too.
+    if config.merge_adjacent_cidrs {
+        let (before_v4, before_v6) = (current_rules.len(), current_rules_v6.len());
+        current_rules = merge_adjacent_v4(current_rules);
+        current_rules_v6 = merge_adjacent_v6(current_rules_v6);
+        log::debug!(
+            "merged adjacent block CIDRs: IPv4 {} -> {}, IPv6 {} -> {}",
+            before_v4, current_rules.len(), before_v6, current_rules_v6.len()
+        );
+    }
+
+    // Optionally drop block entries covered by a broader block entry. Allow entries are
+    // never considered here, so an allow is never folded into an overlapping block.
+    if config.collapse_cidrs {
+        let (before_v4, before_v6) = (current_rules.len(), current_rules_v6.len());
+        current_rules = collapse_covered(current_rules);
+        current_rules_v6 = collapse_covered(current_rules_v6);
+        log::debug!(
+            "collapsed covered block CIDRs: IPv4 {} -> {}, IPv6 {} -> {}",
+            before_v4, current_rules.len(), before_v6, current_rules_v6.len()
+        );
+    }
+
+    // A log-only entry inside an enforced one of the feed is enforced after all. Broader
+    // bans of other sources, written outside this cycle, are covered by the datapath,
+    // which looks past a log-only match for an enforced entry.
+    let enforced = log_only.iter().filter(|(net, prefix)| covered_by(&current_rules, *net, *prefix)).copied().collect::<Vec<_>>();
+    let enforced_v6 = log_only_v6.iter().filter(|(net, prefix)| covered_by(&current_rules_v6, *net, *prefix)).copied().collect::<Vec<_>>();
+    current_rules.extend(log_only.iter().copied());
+    current_rules_v6.extend(log_only_v6.iter().copied());
+    log_only.retain(|rule| !enforced.contains(rule));
+    log_only_v6.retain(|rule| !enforced_v6.contains(rule));
+
+    // Keep the block sets within the BPF map capacity, dropping lower-priority sources
+    // first and the narrowest prefixes within a source
+    if let Some(max_rules) = config.max_rules {
+        current_rules = cap_rules(current_rules, &block_ranks, max_rules, "IPv4");
+        current_rules_v6 = cap_rules(current_rules_v6, &block_ranks_v6, max_rules, "IPv6");
+        log_only.retain(|rule| current_rules.contains(rule));
+        log_only_v6.retain(|rule| current_rules_v6.contains(rule));
+    }
+
+    DesiredRules {
+        rules: current_rules,
+        rules_v6: current_rules_v6,
+        allow_rules: current_allow_rules,
+        allow_rules_v6: current_allow_rules_v6,
+        log_only,
+        log_only_v6,
+        groups_skipped: groups.skipped,
+    }
+}
+
+/// Outcome of parsing a rule set offline, see `validate_rules`
+#[derive(Debug, Default)]
+pub struct RulesValidation {
+    pub rules: usize,
+    pub rules_v6: usize,
+    pub allow_rules: usize,
+    pub allow_rules_v6: usize,
+    pub rate_limits: usize,
+    pub rejected: Vec<RejectedEntry>,
+}
+
+/// Run `rules` through the same parsing and filtering as an apply cycle without
+/// touching any BPF map, counting what would be installed and listing every rejected entry
+pub fn validate_rules(rules: &config::AccessRule, config: &AccessRulesConfig) -> RulesValidation {
+    let mut rejected = Vec::new();
+    let desired = desired_rules(Some(rules), config, &ResolvedDomains::new(), chrono::Utc::now(), &mut rejected);
+    let rate_limits = collect_rate_limits(&rules.rate_limit, config.families(), &mut rejected);
+    RulesValidation {
+        rules: desired.rules.len(),
+        rules_v6: desired.rules_v6.len(),
+        allow_rules: desired.allow_rules.len(),
+        allow_rules_v6: desired.allow_rules_v6.len(),
+        rate_limits: rate_limits.len(),
+        rejected,
+    }
+}
+
+/// Warn about the first `limit` rejected entries of a cycle and summarize the rest in
+/// one line. Returns how many were summarized.
+fn log_rejected(rejected: &[RejectedEntry], limit: usize) -> usize {
+    for entry in rejected.iter().take(limit) {
+        log::warn!(
+            entry = entry.entry.as_str(), rule_source = entry.rule_source.as_str();
+            "{} ignored: {}", entry.reason, entry.entry
+        );
+    }
+    let more = rejected.len().saturating_sub(limit);
+    if more > 0 {
+        log::warn!("... and {} more invalid entries.", more);
+    }
+    more
+}
+
+/// `strict_parsing`: turn the first rejected entry into an error
+fn strict_check(rejected: Vec<RejectedEntry>) -> Result<(), AccessRulesError> {
+    match rejected.into_iter().next() {
+        Some(RejectedEntry { rule_source, entry, reason }) => {
+            Err(AccessRulesError::InvalidEntry { rule_source, entry, reason })
+        }
+        None => Ok(()),
+    }
+}
+
+fn default_route_rejection(net: &impl std::fmt::Display) -> RejectedEntry {
+    RejectedEntry {
+        rule_source: "block".to_string(),
+        entry: format!("{}/0", net),
+        reason: "catch-all block, set allow_default_route_block to apply it".to_string(),
+    }
+}
+
+/// Merge the API rules (if any) with the configured local rules and apply the
+/// difference against the previous snapshots. Local rules are always applied and
+/// API rules are additive; an allow from either source takes precedence in the datapath.
+fn apply_rules(
+    skels: &(impl FirewallTargets + ?Sized),
+    api_rules: Option<&config::AccessRule>,
+    previous: &PreviousRuleSets,
+    config: &AccessRulesConfig,
+    snapshot_tx: Option<&watch::Sender<RuleSnapshot>>,
+) -> Result<ApplyStats, AccessRulesError> {
+    let families = config.families();
+    let resolved = previous.resolved_domains.lock().unwrap().clone();
+    let now = previous.expiry_clock.lock().unwrap().now();
+    let mut rejected = Vec::new();
+    let DesiredRules {
+        rules: mut current_rules,
+        rules_v6: mut current_rules_v6,
+        allow_rules: current_allow_rules,
+        allow_rules_v6: current_allow_rules_v6,
+        log_only,
+        log_only_v6,
+        groups_skipped,
+    } = desired_rules(api_rules, config, &resolved, now, &mut rejected);
+    let rule_sets = api_rules
+        .into_iter()
+        .flat_map(|rule| [&rule.block, &rule.allow])
+        .chain(config.local_rules.iter().flat_map(|local| [&local.block, &local.allow]));
+    *previous.next_expiry.lock().unwrap() = next_rule_expiry(rule_sets, now);
+    let rate_limits = api_rules
+        .map(|rule| collect_rate_limits(&rule.rate_limit, families, &mut rejected))
+        .unwrap_or_default();
+    log_rejected(&rejected, config.invalid_entry_log_limit);
+    if let Some(recent) = &config.recent_rejects {
+        recent.record(&rejected);
+    }
+    if config.strict_parsing {
+        strict_check(rejected)?;
+    }
+
+    let m = metrics::access_rules();
+    m.rules_ipv4_total.store(current_rules.len() as u64, Ordering::Relaxed);
+    m.rules_ipv6_total.store(current_rules_v6.len() as u64, Ordering::Relaxed);
+
+    if let Some(n) = config.log_broad_rules {
+        for (net, prefix) in broadest_rules(&current_rules, n) {
+            log::info!(ip:% = net, prefix = prefix; "broad IPv4 block rule: {}/{}", net, prefix);
+        }
+        for (net, prefix) in broadest_rules(&current_rules_v6, n) {
+            log::info!(ip:% = net, prefix = prefix; "broad IPv6 block rule: {}/{}", net, prefix);
+        }
+    }
+
+    // A cycle overlapping one that is still writing defers rather than interleaving
+    // diff, apply and store with it; the snapshot locks themselves are only held briefly
+    let Some(_claim) = previous.claim_apply() else {
+        return Err(AccessRulesError::ApplyInProgress);
+    };
+    // The first cycle writes everything anyway and starts the forced re-apply clock
+    previous.last_full_apply.lock().unwrap().get_or_insert_with(Instant::now);
+
+    let paused = config.apply_pause.as_ref().is_some_and(|pause| pause.is_paused());
+    if !paused {
+        apply_rate_limits(skels, rate_limits, &previous.rate_limits, config);
+    }
+
+    // Compare with previous rules to detect changes and compute diffs, then release the locks
+    let (mut ipv4_changed, mut ipv6_changed, mut allow_ipv4_changed, mut allow_ipv6_changed, mut v4, mut v6) = {
+        let previous_rules_guard = previous.rules.lock().unwrap();
+        let previous_rules_v6_guard = previous.rules_v6.lock().unwrap();
+        let previous_allow_rules_guard = previous.allow_rules.lock().unwrap();
+        let previous_allow_rules_v6_guard = previous.allow_rules_v6.lock().unwrap();
+
+        // Compute diffs once against snapshots
+        let (mut removed_v4, mut added_v4) = diff_rules(&previous_rules_guard, &current_rules);
+        let (mut removed_v6, mut added_v6) = diff_rules(&previous_rules_v6_guard, &current_rules_v6);
+        let (removed_allow_v4, added_allow_v4) = diff_rules(&previous_allow_rules_guard, &current_allow_rules);
+        let (removed_allow_v6, added_allow_v6) = diff_rules(&previous_allow_rules_v6_guard, &current_allow_rules_v6);
+        // Entries kept across the cycle whose action flipped are written again
+        let retagged_v4 = retagged_rules(&previous_rules_guard, &current_rules, &previous.log_only.lock().unwrap(), &log_only);
+        let retagged_v6 = retagged_rules(&previous_rules_v6_guard, &current_rules_v6, &previous.log_only_v6.lock().unwrap(), &log_only_v6);
+
+        // Gate drastic block changes: apply a sample now, the rest once a second cycle agrees
+        if let Some(percent) = config.canary_change_percent.filter(|_| !paused) {
+            let wanted = CanaryHold { rules: current_rules.clone(), rules_v6: current_rules_v6.clone() };
+            let changes = removed_v4.len() + added_v4.len() + removed_v6.len() + added_v6.len();
+            let installed = previous_rules_guard.len() + previous_rules_v6_guard.len();
+            let mut hold = previous.canary_hold.lock().unwrap();
+            if !exceeds_canary_threshold(installed, changes, percent) {
+                *hold = None;
+            } else if hold.as_ref() == Some(&wanted) {
+                log::info!(changes = changes; "canary change confirmed by a second cycle, applying the remaining {} block changes", changes);
+                *hold = None;
+            } else {
+                // Install only the sample; the snapshots then hold exactly what was written
+                let held = canary_sample(&mut added_v4, &mut removed_v4, config.canary_sample)
+                    + canary_sample(&mut added_v6, &mut removed_v6, config.canary_sample);
+                current_rules = sampled_rules(&previous_rules_guard, &added_v4, &removed_v4);
+                current_rules_v6 = sampled_rules(&previous_rules_v6_guard, &added_v6, &removed_v6);
+                log::warn!(
+                    changes = changes, installed = installed, held = held;
+                    "canary gating: {} block changes against {} installed entries exceed {}%, applying {} and holding {} until the next cycle confirms",
+                    changes, installed, percent, changes - held, held
+                );
+                *hold = Some(wanted);
+            }
+        }
+
+        // Leave entries that are also temporarily banned in place; their expiry lifts them
+        if let Some(temp) = &config.temporary_bans {
+            removed_v4.retain(|(net, prefix)| !temp.is_active(IpAddr::V4(*net), *prefix));
+            removed_v6.retain(|(net, prefix)| !temp.is_active(IpAddr::V6(*net), *prefix));
+        }
+
+        // Removals within the grace period stay installed and in the applied set, so
+        // they show up as removed again next cycle until the grace runs out
+        if config.unban_grace_cycles > 0 {
+            let now = Instant::now();
+            let mut pending = previous.pending_unbans.lock().unwrap();
+            current_rules.extend(hold_removals(&mut removed_v4, &mut pending, config.unban_grace_cycles, now));
+            let mut pending_v6 = previous.pending_unbans_v6.lock().unwrap();
+            current_rules_v6.extend(hold_removals(&mut removed_v6, &mut pending_v6, config.unban_grace_cycles, now));
+        }
+
+        // Entries listed again shortly after they were unbanned point at a flapping
+        // source; their removals are held like grace removals while the window runs
+        if !config.oscillation_window.is_zero() && !paused {
+            let now = Instant::now();
+            let window = config.oscillation_window;
+            let mut recent = previous.recently_unbanned.lock().unwrap();
+            let (flapping_v4, held_v4) = damp_oscillations(&added_v4, &mut removed_v4, &mut recent, window, now);
+            let mut recent_v6 = previous.recently_unbanned_v6.lock().unwrap();
+            let (flapping_v6, held_v6) = damp_oscillations(&added_v6, &mut removed_v6, &mut recent_v6, window, now);
+            metrics::access_rules().oscillations_total.fetch_add((flapping_v4 + flapping_v6) as u64, Ordering::Relaxed);
+            if !held_v4.is_empty() || !held_v6.is_empty() {
+                log::debug!("keeping {} oscillating block entries installed until their window passes", held_v4.len() + held_v6.len());
+            }
+            current_rules.extend(held_v4);
+            current_rules_v6.extend(held_v6);
+        }
+
+        if !retagged_v4.is_empty() || !retagged_v6.is_empty() {
+            log::info!(
+                "{} IPv4 and {} IPv6 block entries switch between drop and log-only",
+                retagged_v4.len(), retagged_v6.len()
+            );
+        }
+
+        // Check if rules have changed
+        // A disabled family never reports changes, so its snapshots and maps stay untouched
+        let ipv4_changed = families.ipv4 && (*previous_rules_guard != current_rules || !retagged_v4.is_empty());
+        let ipv6_changed = families.ipv6 && (*previous_rules_v6_guard != current_rules_v6 || !retagged_v6.is_empty());
+        added_v4.extend(retagged_v4);
+        added_v6.extend(retagged_v6);
+        let allow_ipv4_changed = families.ipv4 && *previous_allow_rules_guard != current_allow_rules;
+        let allow_ipv6_changed = families.ipv6 && *previous_allow_rules_v6_guard != current_allow_rules_v6;
+
+        let v4 = FamilyDiff {
+            block_changed: ipv4_changed,
+            removed: removed_v4,
+            added: added_v4,
+            removed_allow: removed_allow_v4,
+            added_allow: added_allow_v4,
+            log_only: log_only.clone(),
+        };
+        let v6 = FamilyDiff {
+            block_changed: ipv6_changed,
+            removed: removed_v6,
+            added: added_v6,
+            removed_allow: removed_allow_v6,
+            added_allow: added_allow_v6,
+            log_only: log_only_v6.clone(),
+        };
+        (ipv4_changed, ipv6_changed, allow_ipv4_changed, allow_ipv6_changed, v4, v6)
+    };
+
+    // Flag suspiciously broad block entries whenever their family's block set changes
+    if ipv4_changed {
+        let mut broad: Vec<_> = current_rules.iter().filter(|(_, p)| *p < config.broad_rule_warn_prefix_v4).collect();
+        if config.deterministic_order {
+            broad.sort_unstable();
+        }
+        for (net, prefix) in broad {
+            log::warn!(ip:% = net, prefix = *prefix; "very broad IPv4 block rule {}/{} (shorter than /{})", net, prefix, config.broad_rule_warn_prefix_v4);
+        }
+    }
+    if ipv6_changed {
+        let mut broad: Vec<_> = current_rules_v6.iter().filter(|(_, p)| *p < config.broad_rule_warn_prefix_v6).collect();
+        if config.deterministic_order {
+            broad.sort_unstable();
+        }
+        for (net, prefix) in broad {
+            log::warn!(ip:% = net, prefix = *prefix; "very broad IPv6 block rule {}/{} (shorter than /{})", net, prefix, config.broad_rule_warn_prefix_v6);
+        }
+    }
+
+    // If nothing changed, skip quietly with a single log entry, unless a forced
+    // re-apply is due; then every current entry is written again as if it were new
+    let full_reapply = !ipv4_changed && !ipv6_changed && !allow_ipv4_changed && !allow_ipv6_changed;
+    if full_reapply {
+        // A paused cycle leaves the forced re-apply for the first cycle after resuming
+        if paused || !previous.full_reapply_due(config) {
+            log::debug!("No IPv4 or IPv6 access rule changes detected, skipping BPF map updates");
+            return Ok(ApplyStats { groups_skipped, ..ApplyStats::for_families(families) });
+        }
+        log::info!("No access rule changes detected, re-applying the full rule sets to the BPF maps");
+        (ipv4_changed, allow_ipv4_changed) = (families.ipv4, families.ipv4);
+        (ipv6_changed, allow_ipv6_changed) = (families.ipv6, families.ipv6);
+        v4 = FamilyDiff::full(&current_rules, &current_allow_rules, &log_only, families.ipv4);
+        v6 = FamilyDiff::full(&current_rules_v6, &current_allow_rules_v6, &log_only_v6, families.ipv6);
+    }
+    // Same logs and the same LPM trie insertion order for the same rules on every host
+    if config.deterministic_order {
+        v4.sort();
+        v6.sort();
+    }
+
+    // Nothing is written and the snapshots stay put, so the next cycle after resuming
+    // diffs against what is actually installed and applies everything queued meanwhile
+    if paused {
+        log::info!(
+            pending_ipv4 = v4.len(),
+            pending_ipv6 = v6.len();
+            "access rules application paused, {} IPv4 and {} IPv6 changes pending", v4.len(), v6.len()
+        );
+        return Ok(ApplyStats { groups_skipped, paused: true, ..ApplyStats::for_families(families) });
+    }
+
+    log::info!(
+        ipv4_rules = current_rules.len(),
+        ipv6_rules = current_rules_v6.len(),
+        ipv4_allow_rules = current_allow_rules.len(),
+        ipv6_allow_rules = current_allow_rules_v6.len();
+        "Access rules changed, applying updates to BPF maps"
+    );
+
+    let mut stats = ApplyStats { groups_skipped, ..ApplyStats::for_families(families) };
+    let (mut failed_v4, mut failed_v6) = (FailedChanges::default(), FailedChanges::default());
+    if config.dry_run {
+        // Log the planned operations only; the snapshots still advance below so the
+        // next cycle diffs against what would have been applied
+        v4.log_planned("IPv4");
+        v6.log_planned("IPv6");
+    } else {
+        // The families touch independent maps, so apply them on separate threads
+        let (v4_result, v6_result) = std::thread::scope(|scope| {
+            let events = config.event_sink.as_deref();
+            let swap_min = config.atomic_swap_min_changes;
+            let ifaces = &config.skeleton_ifaces;
+            let v4_apply = scope.spawn(|| apply_changes(skels, &v4, events, swap_min, ifaces));
+            let v6_apply = scope.spawn(|| apply_changes(skels, &v6, events, swap_min, ifaces));
+            (v4_apply.join(), v6_apply.join())
+        });
+        let (v4_counts, v4_failed) = v4_result.map_err(|_| AccessRulesError::ApplyPanicked { family: "IPv4" })?;
+        let (v6_counts, v6_failed) = v6_result.map_err(|_| AccessRulesError::ApplyPanicked { family: "IPv6" })?;
+        (failed_v4, failed_v6) = (v4_failed, v6_failed);
+        (stats.v4_added, stats.v4_removed, stats.v4_errors) = (v4_counts.added, v4_counts.removed, v4_counts.errors);
+        (stats.v6_added, stats.v6_removed, stats.v6_errors) = (v6_counts.added, v6_counts.removed, v6_counts.errors);
+    }
+    if full_reapply {
+        *previous.last_full_apply.lock().unwrap() = Some(Instant::now());
+    }
+
+    // Re-acquire the snapshot locks to store what was applied to all skels. Entries
+    // whose map operation failed are stored as they actually are, so they get retried.
+    let mut previous_rules_guard = previous.rules.lock().unwrap();
+    let mut previous_rules_v6_guard = previous.rules_v6.lock().unwrap();
+    let mut previous_allow_rules_guard = previous.allow_rules.lock().unwrap();
+    let mut previous_allow_rules_v6_guard = previous.allow_rules_v6.lock().unwrap();
+    if ipv4_changed {
+        *previous_rules_guard = settle_snapshot(current_rules, &failed_v4.added, &failed_v4.removed);
+        *previous.log_only.lock().unwrap() = log_only;
+    }
+    if ipv6_changed {
+        *previous_rules_v6_guard = settle_snapshot(current_rules_v6, &failed_v6.added, &failed_v6.removed);
+        *previous.log_only_v6.lock().unwrap() = log_only_v6;
+    }
+    if allow_ipv4_changed {
+        *previous_allow_rules_guard = settle_snapshot(current_allow_rules, &failed_v4.added_allow, &failed_v4.removed_allow);
+    }
+    if allow_ipv6_changed {
+        *previous_allow_rules_v6_guard = settle_snapshot(current_allow_rules_v6, &failed_v6.added_allow, &failed_v6.removed_allow);
+    }
+    if allow_ipv4_changed || allow_ipv6_changed {
+        publish_applied_allow(&previous_allow_rules_guard, &previous_allow_rules_v6_guard);
+    }
+
+    if let Some(tx) = snapshot_tx {
+        tx.send_replace(RuleSnapshot {
+            stats,
+            rules: previous_rules_guard.clone(),
+            rules_v6: previous_rules_v6_guard.clone(),
+            allow_rules: previous_allow_rules_guard.clone(),
+            allow_rules_v6: previous_allow_rules_v6_guard.clone(),
+        });
+    }
+
+    if config.dry_run {
+        return Ok(stats);
+    }
+
+    if let Some(temp) = &config.temporary_bans {
+        if ipv4_changed || ipv6_changed {
+            temp.set_synced(
+                previous_rules_guard.iter().map(|(net, prefix)| (IpAddr::V4(*net), *prefix))
+                    .chain(previous_rules_v6_guard.iter().map(|(net, prefix)| (IpAddr::V6(*net), *prefix))),
+            );
+        }
+    }
+
+    if let Some(path) = &config.state_file {
+        let state = PersistedRuleState {
+            version: RULE_STATE_VERSION,
+            block_v4: previous_rules_guard.iter().copied().collect(),
+            block_v6: previous_rules_v6_guard.iter().copied().collect(),
+            allow_v4: previous_allow_rules_guard.iter().copied().collect(),
+            allow_v6: previous_allow_rules_v6_guard.iter().copied().collect(),
+            log_only_v4: previous.log_only.lock().unwrap().iter().copied().collect(),
+            log_only_v6: previous.log_only_v6.lock().unwrap().iter().copied().collect(),
+            rule_hits: if config.monotonic_rule_hits {
+                persisted_rule_hits(&previous.rule_hit_totals.lock().unwrap(), &config.skeleton_ifaces)
+            } else {
+                Vec::new()
+            },
+        };
+        if let Err(e) = save_rule_state(path, &state) {
+            log::warn!("failed to persist access rules to {}: {}", path.display(), e);
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Pending block and allow changes for one address family
+struct FamilyDiff<T> {
+    block_changed: bool,
+    removed: Vec<(T, u32)>,
+    added: Vec<(T, u32)>,
+    removed_allow: Vec<(T, u32)>,
+    added_allow: Vec<(T, u32)>,
+    /// Block entries installed as log-only rather than dropped
+    log_only: HashSet<(T, u32)>,
+}
+
+impl<T: Copy + Eq + Hash> FamilyDiff<T> {
+    /// Every entry of the current sets as an addition, for a forced re-apply
+    fn full(rules: &HashSet<(T, u32)>, allow_rules: &HashSet<(T, u32)>, log_only: &HashSet<(T, u32)>, enabled: bool) -> Self {
+        let all = |set: &HashSet<(T, u32)>| if enabled { set.iter().copied().collect() } else { Vec::new() };
+        Self {
+            block_changed: enabled,
+            removed: Vec::new(),
+            added: all(rules),
+            removed_allow: Vec::new(),
+            added_allow: all(allow_rules),
+            log_only: log_only.clone(),
+        }
+    }
+
+    fn action(&self, net: T, prefix: u32) -> BanAction {
+        BanAction::of(self.log_only.contains(&(net, prefix)))
+    }
+
+    /// Block changes only, with nothing allowed and nothing log-only
+    #[cfg(test)]
+    fn blocks(removed: Vec<(T, u32)>, added: Vec<(T, u32)>) -> Self {
+        Self {
+            block_changed: true,
+            removed,
+            added,
+            removed_allow: Vec::new(),
+            added_allow: Vec::new(),
+            log_only: HashSet::new(),
+        }
+    }
+}
+
+impl<T> FamilyDiff<T> {
+    /// Number of pending block and allow changes
+    fn len(&self) -> usize {
+        let block = if self.block_changed { self.removed.len() + self.added.len() } else { 0 };
+        block + self.removed_allow.len() + self.added_allow.len()
+    }
+}
+
+impl<T: Ord> FamilyDiff<T> {
+    /// Order every change list by network, then prefix length
+    fn sort(&mut self) {
+        for changes in [&mut self.removed, &mut self.added, &mut self.removed_allow, &mut self.added_allow] {
+            changes.sort_unstable();
+        }
+    }
+}
+
+impl<T: std::fmt::Display> FamilyDiff<T> {
+    fn log_planned(&self, family: &str) {
+        for (net, prefix) in &self.added_allow { log::info!("would_allow {} {}/{}", family, net, prefix); }
+        for (net, prefix) in &self.removed { log::info!("would_unban {} {}/{}", family, net, prefix); }
+        for (net, prefix) in &self.added { log::info!("would_ban {} {}/{}", family, net, prefix); }
+        for (net, prefix) in &self.removed_allow { log::info!("would_remove_allow {} {}/{}", family, net, prefix); }
+    }
+}
+
+/// Entries of one family whose map operation failed on at least one skeleton
+struct FailedChanges<T> {
+    added: HashSet<(T, u32)>,
+    removed: HashSet<(T, u32)>,
+    added_allow: HashSet<(T, u32)>,
+    removed_allow: HashSet<(T, u32)>,
+}
+
+impl<T> Default for FailedChanges<T> {
+    fn default() -> Self {
+        Self {
+            added: HashSet::new(),
+            removed: HashSet::new(),
+            added_allow: HashSet::new(),
+            removed_allow: HashSet::new(),
+        }
+    }
+}
+
+/// What the maps actually hold after applying towards `intended`: failed additions are
+/// absent and failed removals still present. Storing this as the snapshot makes the
+/// next cycle's diff retry exactly the failed entries.
+fn settle_snapshot<T: Copy + Eq + Hash>(
+    mut intended: HashSet<(T, u32)>,
+    failed_added: &HashSet<(T, u32)>,
+    failed_removed: &HashSet<(T, u32)>,
+) -> HashSet<(T, u32)> {
+    intended.retain(|entry| !failed_added.contains(entry));
+    intended.extend(failed_removed.iter().copied());
+    intended
+}
+
+/// Entries in both `previous` and `current` whose log-only marking differs between the two
+fn retagged_rules<T: Copy + Eq + Hash>(
+    previous: &HashSet<(T, u32)>,
+    current: &HashSet<(T, u32)>,
+    previous_log_only: &HashSet<(T, u32)>,
+    log_only: &HashSet<(T, u32)>,
+) -> Vec<(T, u32)> {
+    previous_log_only
+        .symmetric_difference(log_only)
+        .filter(|rule| previous.contains(rule) && current.contains(rule))
+        .copied()
+        .collect()
+}
+
+/// The firewalls an apply cycle writes to, one per loaded skeleton, reporting the
+/// changes they write to `events`. Tests drive the same cycle against in-memory
+/// firewalls instead.
+trait FirewallTargets: Sync {
+    fn for_each(&self, events: Option<&EventSink>, f: &mut dyn FnMut(&mut dyn Firewall));
+}
+
+impl FirewallTargets for [Arc<bpf::FilterSkel<'_>>] {
+    fn for_each(&self, events: Option<&EventSink>, f: &mut dyn FnMut(&mut dyn Firewall)) {
+        for mut fw in skeleton_firewalls(self, events) {
+            f(&mut fw);
+        }
+    }
+}
+
+/// Interface of the skeleton at `idx`, or `skeleton <idx>` when none was recorded
+fn skeleton_name(ifaces: &[String], idx: usize) -> String {
+    ifaces.get(idx).cloned().unwrap_or_else(|| format!("skeleton {}", idx))
+}
+
+/// Index of the skeleton a `skeleton_name` refers to
+fn skeleton_index(ifaces: &[String], name: &str) -> Option<usize> {
+    ifaces
+        .iter()
+        .position(|iface| iface == name)
+        .or_else(|| name.strip_prefix("skeleton ")?.parse().ok())
+}
+
+/// Describe which skeletons a family's changes failed on when several are loaded, so a
+/// single misbehaving interface stands out from the summed error count. Their failed
+/// changes are retried on every skeleton, which is harmless for the ones that took them.
+fn skeleton_errors_summary(family: &str, errors: &[usize], ifaces: &[String]) -> Option<String> {
+    let failing: Vec<String> = errors
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(idx, count)| format!("{}: {}", skeleton_name(ifaces, idx), count))
+        .collect();
+    if errors.len() < 2 || failing.is_empty() {
+        return None;
+    }
+    Some(format!("{} changes failed on {} of {} skeletons ({})", family, failing.len(), errors.len(), failing.join(", ")))
+}
+
+fn log_skeleton_errors(family: &str, errors: &[usize], ifaces: &[String]) {
+    if let Some(summary) = skeleton_errors_summary(family, errors, ifaces) {
+        log::warn!("{}", summary);
+    }
+}
+
+/// Map operations of one family's changes, summed over the skeletons
+#[derive(Debug, Default, PartialEq, Eq)]
+struct FamilyCounts {
+    added: usize,
+    removed: usize,
+    errors: usize,
+}
+
+/// Apply one family's changes to all skeletons. New allow entries are installed before
+/// touching the block map and stale ones withdrawn last, so an address that stays
+/// allowed is never exposed.
+fn apply_changes<T: AddressFamily>(
+    targets: &(impl FirewallTargets + ?Sized),
+    diff: &FamilyDiff<T>,
+    events: Option<&EventSink>,
+    swap_min: Option<usize>,
+    ifaces: &[String],
+) -> (FamilyCounts, FailedChanges<T>) {
+    let mut counts = FamilyCounts::default();
+    let mut failed = FailedChanges::default();
+    let mut skeleton_errors = Vec::new();
+    targets.for_each(events, &mut |fw| {
+        let before = counts.errors;
+        apply_to_firewall(fw, diff, swap_min, &mut counts, &mut failed);
+        skeleton_errors.push(counts.errors - before);
+    });
+    log_skeleton_errors(T::NAME, &skeleton_errors, ifaces);
+    (counts, failed)
+}
+
+fn apply_to_firewall<T: AddressFamily>(
+    fw: &mut (impl Firewall + ?Sized),
+    diff: &FamilyDiff<T>,
+    swap_min: Option<usize>,
+    counts: &mut FamilyCounts,
+    failed: &mut FailedChanges<T>,
+) {
+    let m = metrics::access_rules();
+    let family = T::NAME;
+
+    for (net, prefix) in &diff.added_allow {
+        match T::allow(fw, *net, *prefix) {
+            Ok(()) => log::debug!(ip:% = net, prefix = *prefix, rule_source = "allow"; "{} allowed {}/{}", family, net, prefix),
+            Err(e) => {
+                counts.errors += 1;
+                failed.added_allow.insert((*net, *prefix));
+                log::error!(ip:% = net, prefix = *prefix, rule_source = "allow"; "{} allow: BPF map update failed for {}/{}: {}", family, net, prefix, e);
+            }
+        }
+    }
+
+    let block_changes = diff.removed.len() + diff.added.len();
+    if diff.block_changed && swap_min.is_some_and(|min| block_changes >= min) {
+        // Changes that fail are reported per entry; the rest of the batch still lands
+        match T::swap_block_changes(fw, &diff.added, &diff.removed, &diff.log_only) {
+            Ok(failures) => {
+                let added = diff.added.len() - failures.added.len();
+                let removed = diff.removed.len() - failures.removed.len();
+                m.bans_applied_total.fetch_add(added as u64, Ordering::Relaxed);
+                m.unbans_applied_total.fetch_add(removed as u64, Ordering::Relaxed);
+                counts.added += added;
+                counts.removed += removed;
+                counts.errors += failures.len();
+                for ((net, prefix), e) in failures.removed {
+                    failed.removed.insert((net, prefix));
+                    log::error!(ip:% = net, prefix = prefix, rule_source = "block"; "{} unban: BPF map delete failed for {}/{}: {}", family, net, prefix, e);
+                }
+                for ((net, prefix), e) in failures.added {
+                    failed.added.insert((net, prefix));
+                    log::error!(ip:% = net, prefix = prefix, rule_source = "block"; "{} ban: BPF map update failed for {}/{}: {}", family, net, prefix, e);
+                }
+            }
+            Err(e) => {
+                counts.errors += block_changes;
+                failed.added.extend(diff.added.iter().copied());
+                failed.removed.extend(diff.removed.iter().copied());
+                log::error!(rule_source = "block"; "{} block swap of {} changes failed: {}", family, block_changes, e);
+            }
+        }
+    } else if diff.block_changed {
+        for (net, prefix) in &diff.removed {
+            match T::unban_as(fw, *net, *prefix, BanSource::Sync) {
+                Ok(deleted) => {
+                    if deleted {
+                        log::debug!(ip:% = net, prefix = *prefix, rule_source = "block"; "{} unbanned {}/{}", family, net, prefix);
+                    } else {
+                        log::debug!(ip:% = net, prefix = *prefix, rule_source = "block"; "{} unban: {}/{} kept, still banned by another source", family, net, prefix);
+                    }
+                    m.unbans_applied_total.fetch_add(1, Ordering::Relaxed);
+                    counts.removed += 1;
+                }
+                Err(e) if is_missing_entry(&*e) => {}
+                Err(e) => {
+                    counts.errors += 1;
+                    failed.removed.insert((*net, *prefix));
+                    log::error!(ip:% = net, prefix = *prefix, rule_source = "block"; "{} unban: BPF map delete failed for {}/{}: {}", family, net, prefix, e);
+                }
+            }
+        }
+        for (net, prefix) in &diff.added {
+            match T::ban_with_action(fw, *net, *prefix, diff.action(*net, *prefix)) {
+                Ok(()) => {
+                    log::debug!(ip:% = net, prefix = *prefix, rule_source = "block"; "{} banned {}/{}", family, net, prefix);
+                    m.bans_applied_total.fetch_add(1, Ordering::Relaxed);
+                    counts.added += 1;
+                }
+                Err(e) => {
+                    counts.errors += 1;
+                    failed.added.insert((*net, *prefix));
+                    log::error!(ip:% = net, prefix = *prefix, rule_source = "block"; "{} ban: BPF map update failed for {}/{}: {}", family, net, prefix, e);
+                }
+            }
+        }
+    }
+
+    for (net, prefix) in &diff.removed_allow {
+        match T::remove_allowed(fw, *net, *prefix) {
+            Ok(()) => log::debug!(ip:% = net, prefix = *prefix, rule_source = "allow"; "{} removed allow for {}/{}", family, net, prefix),
+            Err(e) if is_missing_entry(&*e) => {}
+            Err(e) => {
+                counts.errors += 1;
+                failed.removed_allow.insert((*net, *prefix));
+                log::error!(ip:% = net, prefix = *prefix, rule_source = "allow"; "{} allow removal: BPF map delete failed for {}/{}: {}", family, net, prefix, e);
+            }
+        }
+    }
+}
+
+/// Allow entries of the last apply cycle, as installed in the BPF allow maps
+#[derive(Debug, Default)]
+struct AppliedAllow {
+    v4: HashSet<(Ipv4Addr, u32)>,
+    v6: HashSet<(Ipv6Addr, u32)>,
+}
+
+impl AppliedAllow {
+    /// Whether an entry covers `ip`, the way the datapath's longest prefix match finds it
+    fn covers(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => self.v4.contains(&(ip, 32)) || covered_by(&self.v4, ip, 32),
+            IpAddr::V6(ip) => self.v6.contains(&(ip, 128)) || covered_by(&self.v6, ip, 128),
+        }
+    }
+}
+
+static APPLIED_ALLOW: OnceLock<RwLock<AppliedAllow>> = OnceLock::new();
+
+fn applied_allow() -> &'static RwLock<AppliedAllow> {
+    APPLIED_ALLOW.get_or_init(RwLock::default)
+}
+
+/// Hand the applied allow snapshots to `is_ip_allowed_by_access_rules`
+fn publish_applied_allow(v4: &HashSet<(Ipv4Addr, u32)>, v6: &HashSet<(Ipv6Addr, u32)>) {
+    if let Ok(mut applied) = applied_allow().write() {
+        applied.v4 = v4.clone();
+        applied.v6 = v6.clone();
+    }
+}
+
+/// Check if an IP address is allowed by access rules
+/// Returns true if the IP is explicitly allowed, false otherwise. The check runs
+/// against the allow entries the last apply cycle installed, so the HTTP layer
+/// exempts exactly the sources the BPF allow maps let through.
+pub fn is_ip_allowed_by_access_rules(ip: IpAddr) -> bool {
+    applied_allow().read().is_ok_and(|applied| applied.covers(ip))
+}
+
+/// Config API response body blocking `ips`, with the per-entry `actions` of the block list
+#[cfg(test)]
+fn rules_response(ips: &[impl Serialize], actions: serde_json::Value) -> String {
+    serde_json::json!({
+        "success": true,
+        "config": {
+            "access_rules": {
+                "id": "r", "name": "test", "description": "", "allow": {},
+                "block": {"ips": ips, "actions": actions}
+            },
+            "waf_rules": {"rules": []},
+            "created_at": "2025-01-01T00:00:00Z",
+            "updated_at": "2025-01-01T00:00:00Z",
+            "last_modified": "2025-01-01T00:00:00Z"
+        }
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firewall::MemoryFirewall;
+
+    impl FirewallTargets for [Mutex<MemoryFirewall>] {
+        // Tests attach sinks to the firewalls themselves
+        fn for_each(&self, _: Option<&EventSink>, f: &mut dyn FnMut(&mut dyn Firewall)) {
+            for fw in self {
+                f(&mut *fw.lock().unwrap());
+            }
+        }
+    }
+
+    /// Drive recorded config responses through `apply_rules` against `targets`, sharing
+    /// the applied snapshots across cycles like the updater does. Returns the sorted
+    /// map writes of each cycle; the families apply concurrently, so order within a
+    /// cycle is not meaningful.
+    fn replay_on(
+        targets: &[Mutex<MemoryFirewall>],
+        previous: &PreviousRuleSets,
+        responses: &[&str],
+        config: &AccessRulesConfig,
+    ) -> Vec<Vec<(&'static str, IpAddr, u32)>> {
+        responses
+            .iter()
+            .map(|json| {
+                let response: ConfigApiResponse = serde_json::from_str(json).unwrap();
+                apply_rules(targets, Some(&response.config.access_rules), previous, config, None).unwrap();
+                let mut writes = std::mem::take(&mut targets[0].lock().unwrap().writes);
+                writes.sort();
+                writes
+            })
+            .collect()
+    }
+
+    fn replay(responses: &[&str], config: &AccessRulesConfig) -> Vec<Vec<(&'static str, IpAddr, u32)>> {
+        replay_on(&[Mutex::new(MemoryFirewall::default())], &PreviousRuleSets::default(), responses, config)
+    }
+
+    #[test]
+    fn test_replay_recorded_responses() {
+        let cycles = replay(
+            &[
+                include_str!("testdata/replay/01-initial.json"),
+                include_str!("testdata/replay/02-rotate.json"),
+                include_str!("testdata/replay/03-unchanged.json"),
+                include_str!("testdata/replay/04-empty.json"),
+            ],
+            &AccessRulesConfig::default(),
+        );
+        let v4 = |s: &str| s.parse::<Ipv4Addr>().unwrap().into();
+        let v6 = |s: &str| s.parse::<Ipv6Addr>().unwrap().into();
+        assert_eq!(cycles, vec![
+            vec![
+                ("allow", v4("198.51.100.7"), 32),
+                ("update", v4("10.0.0.0"), 8),
+                ("update", v4("192.0.2.1"), 32),
+                ("update", v6("2001:db8::"), 32),
+            ],
+            vec![
+                ("delete", v4("192.0.2.1"), 32),
+                ("disallow", v4("198.51.100.7"), 32),
+                ("update", v4("203.0.113.0"), 24),
+            ],
+            vec![],
+            vec![
+                ("delete", v4("10.0.0.0"), 8),
+                ("delete", v4("203.0.113.0"), 24),
+                ("delete", v6("2001:db8::"), 32),
+            ],
+        ]);
+    }
+
+    #[test]
+    fn test_domain_addresses_follow_resolution() {
+        let rule_set = config::RuleSet {
+            domains: vec!["Bad.Example.".to_string(), "not a domain".to_string()],
+            ..Default::default()
+        };
+        let host = Ipv4Addr::new(192, 0, 2, 7);
+        let mut resolved = ResolvedDomains::from([(
+            "bad.example".to_string(),
+            vec![IpAddr::V4(host), IpAddr::V4(Ipv4Addr::UNSPECIFIED), "2001:db8::7".parse().unwrap()],
+        )]);
+
+        let (mut rules, mut rules_v6) = (HashSet::new(), HashSet::new());
+        let rejected = collect_domains("block", &rule_set, &resolved, AddressFamilies::default(), &mut rules, &mut rules_v6);
+        assert_eq!(rules, HashSet::from([(host, 32)]));
+        assert_eq!(rules_v6, HashSet::from([("2001:db8::7".parse().unwrap(), 128)]));
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].rule_source, "block.domains");
+
+        // A shrunk answer drops the stale address from the desired set
+        resolved.insert("bad.example".to_string(), vec!["2001:db8::7".parse().unwrap()]);
+        let (mut rules, mut rules_v6) = (HashSet::new(), HashSet::new());
+        collect_domains("block", &rule_set, &resolved, AddressFamilies::default(), &mut rules, &mut rules_v6);
+        assert!(rules.is_empty());
+        assert_eq!(rules_v6.len(), 1);
+    }
+
+    #[test]
+    fn test_strict_check_reports_first_rejection() {
+        assert!(strict_check(Vec::new()).is_ok());
+        let rejected = vec![
+            RejectedEntry { rule_source: "block.ips".to_string(), entry: "10.0.0.0/99".to_string(), reason: "invalid IPv4 ip/cidr".to_string() },
+            RejectedEntry { rule_source: "allow.ips".to_string(), entry: "nope".to_string(), reason: "invalid IPv4 ip/cidr".to_string() },
+        ];
+        let err = strict_check(rejected).unwrap_err();
+        assert!(!err.is_retryable());
+        assert_eq!(err.to_string(), "invalid access rules entry \"10.0.0.0/99\" in block.ips: invalid IPv4 ip/cidr");
+    }
+
+    #[test]
+    fn test_log_rejected_summarizes_beyond_limit() {
+        let rejected: Vec<RejectedEntry> = (0..5)
+            .map(|i| RejectedEntry { rule_source: "block.ips".to_string(), entry: format!("10.0.0.{}/99", i), reason: "invalid IPv4 ip/cidr".to_string() })
+            .collect();
+        assert_eq!(log_rejected(&rejected, 2), 3);
+        assert_eq!(log_rejected(&rejected, 5), 0);
+        assert_eq!(log_rejected(&rejected, 0), 5);
+        assert_eq!(log_rejected(&[], 2), 0);
+    }
+
+    #[test]
+    fn test_family_diff_sort_orders_by_network_then_prefix() {
+        let mut diff = FamilyDiff::blocks(Vec::new(), vec![
+            (Ipv4Addr::new(198, 51, 100, 0), 24),
+            (Ipv4Addr::new(10, 0, 0, 0), 16),
+            (Ipv4Addr::new(10, 0, 0, 0), 8),
+        ]);
+        diff.sort();
+        assert_eq!(diff.added, vec![
+            (Ipv4Addr::new(10, 0, 0, 0), 8),
+            (Ipv4Addr::new(10, 0, 0, 0), 16),
+            (Ipv4Addr::new(198, 51, 100, 0), 24),
+        ]);
+    }
+
+    #[test]
+    fn test_utilization_crossings_warn_once_per_crossing() {
+        // Two interfaces, each with its own banned_ips map
+        let usage = |eth0, eth1| [
+            MapUtilization { iface: "eth0".to_string(), map: "banned_ips", used: eth0, capacity: 100 },
+            MapUtilization { iface: "eth1".to_string(), map: "banned_ips", used: eth1, capacity: 100 },
+        ];
+        let crossed = |maps: &[MapUtilization], over: &mut HashSet<_>| -> Vec<(String, bool)> {
+            utilization_crossings(maps, 90, over).into_iter().map(|(m, up)| (m.iface.clone(), up)).collect()
+        };
+        let mut over = HashSet::new();
+        assert!(crossed(&usage(89, 10), &mut over).is_empty());
+        // Only the second skeleton fills up, and it still warns
+        assert_eq!(crossed(&usage(10, 90), &mut over), vec![("eth1".to_string(), true)]);
+        assert!(crossed(&usage(10, 95), &mut over).is_empty());
+        assert_eq!(crossed(&usage(95, 95), &mut over), vec![("eth0".to_string(), true)]);
+        assert_eq!(crossed(&usage(95, 50), &mut over), vec![("eth1".to_string(), false)]);
+        assert!(utilization_crossings(&usage(100, 100), 0, &mut over).is_empty());
+    }
+
+    #[test]
+    fn test_key_filter_include_and_exclude() {
+        let all = KeyFilter::default();
+        assert!(all.admits("AS64500"));
+
+        let filter = KeyFilter::new(Some(&["AS64*".to_string()]), &["as64512".to_string()]);
+        assert!(filter.admits("as64500"));
+        assert!(!filter.admits("AS64512"));
+        assert!(!filter.admits("AS13335"));
+
+        let deny_only = KeyFilter::new(None, &["AS13335".to_string()]);
+        assert!(deny_only.admits("AS64500"));
+        assert!(!deny_only.admits("as13335"));
+    }
+
+    #[test]
+    fn test_canary_gates_large_changes_until_confirmed() {
+        let initial = rules_response(&["192.0.2.1", "192.0.2.2"], serde_json::json!({}));
+        let jump = rules_response(&["192.0.2.1", "192.0.2.2", "198.51.100.1", "198.51.100.2", "198.51.100.3", "198.51.100.4"], serde_json::json!({}));
+        let config = AccessRulesConfig { canary_change_percent: Some(100), canary_sample: 1, ..AccessRulesConfig::default() };
+        let cycles = replay(&[&initial, &jump, &jump], &config);
+        let ban = |s: &str| ("update", s.parse::<Ipv4Addr>().unwrap().into(), 32);
+
+        // The cold start is never gated
+        assert_eq!(cycles[0], vec![ban("192.0.2.1"), ban("192.0.2.2")]);
+        // Four additions against two installed entries: only the canary goes in
+        assert_eq!(cycles[1], vec![ban("198.51.100.1")]);
+        // The same feed again confirms the change and the rest follows
+        assert_eq!(cycles[2], vec![ban("198.51.100.2"), ban("198.51.100.3"), ban("198.51.100.4")]);
+
+        // A feed that keeps moving stays gated
+        let other = rules_response(&["192.0.2.1", "192.0.2.2", "203.0.113.1", "203.0.113.2", "203.0.113.3", "203.0.113.4"], serde_json::json!({}));
+        let cycles = replay(&[&initial, &jump, &other], &config);
+        assert_eq!(cycles[2].len(), 1);
+        assert!(!exceeds_canary_threshold(0, 1000, 100));
+        assert!(!exceeds_canary_threshold(10, 10, 100));
+    }
+
+    #[test]
+    fn test_overlapping_apply_defers_to_the_cycle_in_flight() {
+        let response: ConfigApiResponse = serde_json::from_str(&rules_response(&["192.0.2.1"], serde_json::json!({}))).unwrap();
+        let targets = [Mutex::new(MemoryFirewall::default())];
+        let previous = PreviousRuleSets::default();
+        let config = AccessRulesConfig::default();
+
+        let in_flight = previous.claim_apply().unwrap();
+        let deferred = apply_rules(&targets[..], Some(&response.config.access_rules), &previous, &config, None);
+        assert!(matches!(deferred, Err(AccessRulesError::ApplyInProgress)));
+        assert!(targets[0].lock().unwrap().writes.is_empty());
+        assert!(previous.rules.lock().unwrap().is_empty());
+
+        // Released once the cycle in flight is done, and the next cycle applies everything
+        drop(in_flight);
+        apply_rules(&targets[..], Some(&response.config.access_rules), &previous, &config, None).unwrap();
+        assert_eq!(targets[0].lock().unwrap().writes, vec![("update", "192.0.2.1".parse().unwrap(), 32)]);
+        assert!(previous.claim_apply().is_some());
+    }
+
+    #[test]
+    fn test_apply_stats_count_changes_and_errors_per_family() {
+        let targets = [Mutex::new(MemoryFirewall::default()), Mutex::new(MemoryFirewall::default())];
+        let previous = PreviousRuleSets::default();
+        let config = AccessRulesConfig::default();
+        let apply = |block: &[&str]| {
+            let response: ConfigApiResponse = serde_json::from_str(&rules_response(block, serde_json::json!({}))).unwrap();
+            apply_rules(&targets[..], Some(&response.config.access_rules), &previous, &config, None).unwrap()
+        };
+        let counts = |stats: ApplyStats| (stats.v4_added, stats.v4_removed, stats.v4_errors, stats.v6_added, stats.v6_removed, stats.v6_errors);
+
+        // Counts are summed over the firewalls
+        assert_eq!(counts(apply(&["192.0.2.1", "192.0.2.2", "2001:db8::1"])), (4, 0, 0, 2, 0, 0));
+
+        // A refused write counts once per firewall it failed on
+        targets[1].lock().unwrap().fail.insert(("2001:db8::2".parse().unwrap(), 128));
+        let next = ["192.0.2.1", "2001:db8::1", "2001:db8::2"];
+        assert_eq!(counts(apply(&next)), (0, 2, 0, 1, 0, 1));
+
+        // The failed entry is retried on every firewall, then nothing is left to do
+        targets[1].lock().unwrap().fail.clear();
+        assert_eq!(counts(apply(&next)), (0, 0, 0, 2, 0, 0));
+        assert!(apply(&next).is_empty());
+    }
+
+    #[test]
+    fn test_log_only_entries_are_promoted_in_place() {
+        let response = |actions| rules_response(&["192.0.2.1", "10.0.0.0/8", "10.1.0.0/16"], actions);
+        let trial = response(serde_json::json!({"192.0.2.1": "log", "10.1.0.0/16": "log", "10.0.0.0/8": "bogus"}));
+        let promoted = response(serde_json::json!({}));
+        let targets = [Mutex::new(MemoryFirewall::default())];
+        let previous = PreviousRuleSets::default();
+        let config = AccessRulesConfig::default();
+        let key = |s: &str, prefix| (s.parse::<Ipv4Addr>().unwrap().into(), prefix);
+        let update = |s: &str, prefix| ("update", s.parse::<Ipv4Addr>().unwrap().into(), prefix);
+        let flags = |s: &str, prefix| targets[0].lock().unwrap().banned[&key(s, prefix)];
+        let sync = BanSource::Sync as u8;
+
+        // The log-only /16 sits inside an enforced /8, so it is enforced too, and an
+        // unknown action falls back to drop
+        let cycles = replay_on(&targets, &previous, &[&trial, &trial], &config);
+        assert_eq!(cycles[0], vec![update("10.0.0.0", 8), update("10.1.0.0", 16), update("192.0.2.1", 32)]);
+        assert_eq!((flags("10.0.0.0", 8), flags("10.1.0.0", 16)), (sync, sync));
+        assert_eq!(flags("192.0.2.1", 32), sync | 0x80);
+        assert!(cycles[1].is_empty());
+        // Dropping the action rewrites the entry without unbanning it first
+        let cycles = replay_on(&targets, &previous, &[&promoted], &config);
+        assert_eq!(cycles[0], vec![update("192.0.2.1", 32)]);
+        assert_eq!(flags("192.0.2.1", 32), sync);
+    }
+
+    #[test]
+    fn test_damp_oscillations_holds_flapping_removals() {
+        let flapper = (Ipv4Addr::new(192, 0, 2, 1), 32);
+        let slow = (Ipv4Addr::new(198, 51, 100, 1), 32);
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut recent = HashMap::new();
+
+        let mut removed = vec![flapper, slow];
+        assert_eq!(damp_oscillations(&[], &mut removed, &mut recent, window, start), (0, vec![]));
+        assert_eq!(removed, vec![flapper, slow]);
+        let mut removed = vec![];
+        assert_eq!(damp_oscillations(&[flapper], &mut removed, &mut recent, window, start + window / 2), (1, vec![]));
+
+        // While its window runs the flapper stays installed
+        let mut removed = vec![flapper];
+        assert_eq!(damp_oscillations(&[], &mut removed, &mut recent, window, start + window), (0, vec![flapper]));
+        assert!(removed.is_empty());
+
+        // Forgotten once the window passed: the slow entry is not counted and the
+        // flapper is unbanned on its next removal
+        let mut removed = vec![flapper];
+        let later = start + window * 2;
+        assert_eq!(damp_oscillations(&[slow], &mut removed, &mut recent, window, later), (0, vec![]));
+        assert_eq!(removed, vec![flapper]);
+        assert_eq!(recent, HashMap::from([(flapper, RecentUnban::Unbanned(later))]));
+    }
+
+    #[test]
+    fn test_hold_removals_waits_out_the_grace() {
+        let flapping = (Ipv4Addr::new(192, 0, 2, 0), 24);
+        let gone = (Ipv4Addr::new(198, 51, 100, 0), 24);
+        let mut pending = HashMap::new();
+        let now = Instant::now();
+
+        let mut removed = vec![flapping, gone];
+        let mut held = hold_removals(&mut removed, &mut pending, 3, now);
+        held.sort();
+        assert!(removed.is_empty());
+        assert_eq!(held, vec![flapping, gone]);
+
+        // `flapping` is back in the feed, so only `gone` is still pending
+        for _ in 0..2 {
+            let mut removed = vec![gone];
+            assert_eq!(hold_removals(&mut removed, &mut pending, 3, now), vec![gone]);
+        }
+        assert!(!pending.contains_key(&flapping));
+
+        // Third cycle after the one it vanished in: the unban goes through
+        let mut removed = vec![gone];
+        assert!(hold_removals(&mut removed, &mut pending, 3, now).is_empty());
+        assert_eq!(removed, vec![gone]);
+        assert!(pending.is_empty());
+
+        // A reappeared entry starts counting from scratch when it vanishes again
+        let mut removed = vec![flapping];
+        assert_eq!(hold_removals(&mut removed, &mut pending, 3, now), vec![flapping]);
+        assert_eq!(pending[&flapping].cycles, 0);
+    }
+
+    #[test]
+    fn test_hold_removals_grace_of_one_holds_a_single_cycle() {
+        let gone = (Ipv4Addr::new(198, 51, 100, 0), 24);
+        let mut pending = HashMap::new();
+        let now = Instant::now();
+
+        let mut removed = vec![gone];
+        assert_eq!(hold_removals(&mut removed, &mut pending, 1, now), vec![gone]);
+        assert!(removed.is_empty());
+        let mut removed = vec![gone];
+        assert!(hold_removals(&mut removed, &mut pending, 1, now).is_empty());
+        assert_eq!(removed, vec![gone]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_full_reapply_due_after_interval() {
+        let previous = PreviousRuleSets::default();
+        let mut config = AccessRulesConfig::default();
+        assert!(!previous.full_reapply_due(&config));
+
+        config.force_reapply_every = Some(Duration::from_secs(60));
+        // The clock only starts with the first apply
+        assert!(!previous.full_reapply_due(&config));
+        *previous.last_full_apply.lock().unwrap() = Some(Instant::now());
+        assert!(!previous.full_reapply_due(&config));
+
+        config.force_reapply_every = Some(Duration::ZERO);
+        assert!(previous.full_reapply_due(&config));
+    }
+
+    #[test]
+    fn test_full_diff_adds_every_entry_of_enabled_families() {
+        let rules = HashSet::from([(Ipv4Addr::new(10, 0, 0, 0), 8)]);
+        let allow_rules = HashSet::from([(Ipv4Addr::new(10, 1, 0, 0), 16)]);
+        let diff = FamilyDiff::full(&rules, &allow_rules, &HashSet::new(), true);
+        assert!(diff.block_changed);
+        assert_eq!(diff.added, vec![(Ipv4Addr::new(10, 0, 0, 0), 8)]);
+        assert_eq!(diff.added_allow, vec![(Ipv4Addr::new(10, 1, 0, 0), 16)]);
+        assert!(diff.removed.is_empty() && diff.removed_allow.is_empty());
+
+        let disabled = FamilyDiff::full(&rules, &allow_rules, &HashSet::new(), false);
+        assert!(!disabled.block_changed && disabled.added.is_empty() && disabled.added_allow.is_empty());
+    }
+
+    #[test]
+    fn test_failed_bans_are_left_out_of_the_snapshot() {
+        let kept = (Ipv4Addr::new(10, 0, 0, 0), 8);
+        let stuck = (Ipv4Addr::new(172, 16, 0, 0), 12);
+        let good = (Ipv4Addr::new(192, 0, 2, 1), 32);
+        let full = (Ipv4Addr::new(198, 51, 100, 0), 24);
+        let mut fw = MemoryFirewall::with_rules(&[kept, stuck], &[]);
+        fw.fail.extend([(stuck.0.into(), stuck.1), (full.0.into(), full.1)]);
+        let intended: HashSet<(Ipv4Addr, u32)> = HashSet::from([kept, good, full]);
+        let diff = FamilyDiff::blocks(vec![stuck], vec![good, full]);
+
+        let mut counts = FamilyCounts::default();
+        let mut failed = FailedChanges::default();
+        apply_to_firewall(&mut fw, &diff, None, &mut counts, &mut failed);
+        assert_eq!((counts.added, counts.removed, counts.errors), (1, 0, 2));
+
+        let snapshot = settle_snapshot(intended.clone(), &failed.added, &failed.removed);
+        assert_eq!(snapshot, fw.rules());
+
+        // The next cycle diffs against the settled snapshot and retries both failures
+        let (removed, added) = diff_rules(&snapshot, &intended);
+        assert_eq!(removed, vec![stuck]);
+        assert_eq!(added, vec![full]);
+    }
+
+    #[test]
+    fn test_every_skeleton_gets_the_same_diff() {
+        let stale = (Ipv4Addr::new(172, 16, 0, 0), 12);
+        let new = (Ipv4Addr::new(192, 0, 2, 0), 24);
+        let targets = [
+            Mutex::new(MemoryFirewall::with_rules(&[stale], &[])),
+            Mutex::new(MemoryFirewall::with_rules(&[stale], &[])),
+        ];
+        let diff = FamilyDiff::blocks(vec![stale], vec![new]);
+
+        let (counts, failed) = apply_changes(&targets[..], &diff, None, None, &[]);
+
+        // Counts are summed over the skeletons
+        assert_eq!((counts.added, counts.removed, counts.errors), (2, 2, 0));
+        assert!(failed.added.is_empty() && failed.removed.is_empty());
+        for target in &targets {
+            assert_eq!(target.lock().unwrap().rules(), HashSet::from([new]));
+        }
+    }
+
+    #[test]
+    fn test_skeleton_errors_name_the_failing_interface() {
+        let ok = (Ipv4Addr::new(192, 0, 2, 0), 24);
+        let refused = (Ipv4Addr::new(198, 51, 100, 0), 24);
+        let targets = [Mutex::new(MemoryFirewall::default()), Mutex::new(MemoryFirewall::default())];
+        targets[1].lock().unwrap().fail.insert((refused.0.into(), refused.1));
+        let diff = FamilyDiff::blocks(Vec::new(), vec![ok, refused]);
+
+        let (counts, failed) = apply_changes(&targets[..], &diff, None, None, &[]);
+        assert_eq!((counts.added, counts.errors), (3, 1));
+        assert_eq!(failed.added, HashSet::from([refused]));
+        assert_eq!(targets[0].lock().unwrap().rules(), HashSet::from([ok, refused]));
+        assert_eq!(targets[1].lock().unwrap().rules(), HashSet::from([ok]));
+
+        let ifaces = ["eth0".to_string(), "eth1".to_string()];
+        assert_eq!(
+            skeleton_errors_summary("IPv4", &[0, 1], &ifaces).as_deref(),
+            Some("IPv4 changes failed on 1 of 2 skeletons (eth1: 1)")
+        );
+        // Without recorded interfaces the skeleton is named by its index
+        assert_eq!(
+            skeleton_errors_summary("IPv4", &[2, 0], &[]).as_deref(),
+            Some("IPv4 changes failed on 1 of 2 skeletons (skeleton 0: 2)")
+        );
+        // A single skeleton's errors are already the summed count
+        assert_eq!(skeleton_errors_summary("IPv4", &[1], &ifaces), None);
+        assert_eq!(skeleton_errors_summary("IPv4", &[0, 0], &ifaces), None);
+    }
+
+    #[test]
+    fn test_apply_against_in_memory_maps() {
+        let manual = (Ipv4Addr::new(203, 0, 113, 0), 24);
+        let gone = (Ipv4Addr::new(172, 16, 0, 0), 12);
+        let trial = (Ipv4Addr::new(192, 0, 2, 1), 32);
+        let allowed = (Ipv4Addr::new(198, 51, 100, 7), 32);
+        let mut fw = MemoryFirewall::default();
+        fw.ban_ip_as(manual.0, manual.1, BanSource::Manual).unwrap();
+        fw.ban_ip(manual.0, manual.1).unwrap();
+        fw.writes.clear();
+        let diff = FamilyDiff {
+            added_allow: vec![allowed],
+            log_only: HashSet::from([trial]),
+            ..FamilyDiff::blocks(vec![manual, gone], vec![trial])
+        };
+
+        let mut counts = FamilyCounts::default();
+        let mut failed = FailedChanges::default();
+        apply_to_firewall(&mut fw as &mut dyn Firewall, &diff, None, &mut counts, &mut failed);
+
+        // The already missing entry is not an error, and the operator's ban outlives the sync
+        assert_eq!((counts.added, counts.removed, counts.errors), (1, 1, 0));
+        assert_eq!(fw.banned, HashMap::from([
+            ((manual.0.into(), manual.1), BanSource::Manual as u8),
+            ((trial.0.into(), trial.1), BanSource::Sync as u8 | 0x80),
+        ]));
+        assert_eq!(fw.allowed, HashSet::from([(allowed.0.into(), allowed.1)]));
+        assert_eq!(fw.writes, vec![
+            ("allow", allowed.0.into(), allowed.1),
+            ("update", manual.0.into(), manual.1),
+            ("update", trial.0.into(), trial.1),
+        ]);
+    }
+
+    #[test]
+    fn test_swapped_batch_reports_failures_per_entry() {
+        let kept = (Ipv4Addr::new(10, 0, 0, 0), 8);
+        let stuck = (Ipv4Addr::new(172, 16, 0, 0), 12);
+        let good = (Ipv4Addr::new(192, 0, 2, 1), 32);
+        let mut fw = MemoryFirewall::with_rules(&[kept, stuck], &[]);
+        fw.fail.insert((stuck.0.into(), stuck.1));
+        let intended: HashSet<(Ipv4Addr, u32)> = HashSet::from([kept, good]);
+        let diff = FamilyDiff::blocks(vec![stuck], vec![good]);
+
+        let mut counts = FamilyCounts::default();
+        let mut failed = FailedChanges::default();
+        apply_to_firewall(&mut fw, &diff, Some(2), &mut counts, &mut failed);
+        assert_eq!((counts.added, counts.removed, counts.errors), (1, 0, 1));
+        assert_eq!(failed.removed, HashSet::from([stuck]));
+        assert!(failed.added.is_empty());
+        assert_eq!(settle_snapshot(intended, &failed.added, &failed.removed), fw.rules());
+
+        // Below the threshold the same changes go entry by entry
+        let mut counts = FamilyCounts::default();
+        let mut failed = FailedChanges::default();
+        apply_to_firewall(&mut fw, &diff, Some(3), &mut counts, &mut failed);
+        assert_eq!((counts.added, counts.removed, counts.errors), (1, 0, 1));
+    }
+
+    #[test]
+    fn test_merge_rule_hits_sums_across_skeletons() {
+        let net = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0));
+        let host = IpAddr::V6("2001:db8::1".parse().unwrap());
+        let merged = merge_rule_hits(vec![
+            vec![(net, 8, 5), (host, 128, 0)],
+            vec![(net, 8, 7)],
+        ]);
+        assert_eq!(merged, vec![("10.0.0.0/8".to_string(), 12), ("2001:db8::1/128".to_string(), 0)]);
+    }
+
+    #[test]
+    fn test_accumulate_rule_hits_survives_counter_resets() {
+        let net = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0));
+        let gone = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0));
+        let mut totals = HashMap::new();
+
+        assert_eq!(accumulate_rule_hits(&mut totals, vec![vec![(net, 8, 5), (gone, 24, 3)], vec![(net, 8, 2)]]),
+            vec![vec![(net, 8, 5), (gone, 24, 3)], vec![(net, 8, 2)]]);
+        // The first skeleton's counter was recreated, the second kept counting
+        assert_eq!(accumulate_rule_hits(&mut totals, vec![vec![(net, 8, 1)], vec![(net, 8, 4)]]),
+            vec![vec![(net, 8, 6)], vec![(net, 8, 4)]]);
+        assert_eq!(accumulate_rule_hits(&mut totals, vec![vec![(net, 8, 3)], vec![(net, 8, 4)]]),
+            vec![vec![(net, 8, 8)], vec![(net, 8, 4)]]);
+        let ifaces = ["eth0".to_string(), "eth1".to_string()];
+        let persisted = persisted_rule_hits(&totals, &ifaces);
+        assert_eq!(persisted, vec![("eth0".to_string(), net, 8, 8), ("eth1".to_string(), net, 8, 4)]);
+
+        // A restored total is the base of its own skeleton's counter, which starts from
+        // zero again, even when the interfaces are loaded in another order
+        let reordered = ["eth1".to_string(), "eth0".to_string()];
+        let mut restored = restored_rule_hits(persisted.clone(), &reordered);
+        assert_eq!(accumulate_rule_hits(&mut restored, vec![vec![(net, 8, 1)], vec![(net, 8, 2)]]),
+            vec![vec![(net, 8, 5)], vec![(net, 8, 10)]]);
+        // Totals of an interface that is no longer loaded are dropped
+        assert_eq!(restored_rule_hits(persisted, &ifaces[1..]).len(), 1);
+        assert_eq!(skeleton_index(&[], "skeleton 3"), Some(3));
+    }
+
+    #[test]
+    fn test_backoff_interval_doubles_and_caps() {
+        let base = Duration::from_secs(10);
+        let max = Duration::from_secs(300);
+
+        assert_eq!(backoff_interval(base, max, 0), Duration::from_secs(10));
+        assert_eq!(backoff_interval(base, max, 1), Duration::from_secs(20));
+        assert_eq!(backoff_interval(base, max, 2), Duration::from_secs(40));
+        assert_eq!(backoff_interval(base, max, 5), Duration::from_secs(300));
+        assert_eq!(backoff_interval(base, max, u32::MAX), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_api_endpoints_prefer_last_success() {
+        let mut endpoints = ApiEndpoints::new(vec!["a".into(), "b".into(), "c".into()]);
+        assert_eq!(endpoints.order(), vec![0, 1, 2]);
+        endpoints.preferred = 2;
+        assert_eq!(endpoints.order(), vec![2, 0, 1]);
+        assert!(ApiEndpoints::new(Vec::new()).order().is_empty());
+    }
+
+    #[test]
+    fn test_next_tick_after_missed_tick_behaviors() {
+        let start = Instant::now();
+        let period = Duration::from_secs(10);
+
+        // On time: every behavior keeps the cadence
+        for behavior in [MissedTickBehavior::Delay, MissedTickBehavior::Skip, MissedTickBehavior::Burst] {
+            assert_eq!(next_tick_after(behavior, start, period, start + Duration::from_secs(3)), start + period);
+        }
+
+        // The cycle overran by 25s
+        let now = start + Duration::from_secs(35);
+        assert_eq!(next_tick_after(MissedTickBehavior::Delay, start, period, now), now);
+        assert_eq!(next_tick_after(MissedTickBehavior::Skip, start, period, now), start + Duration::from_secs(40));
+        assert_eq!(next_tick_after(MissedTickBehavior::Burst, start, period, now), start + period);
+    }
+
+    #[test]
+    fn test_parse_missed_tick_behavior() {
+        assert_eq!(parse_missed_tick_behavior(" Skip "), Some(MissedTickBehavior::Skip));
+        assert_eq!(parse_missed_tick_behavior("burst"), Some(MissedTickBehavior::Burst));
+        assert_eq!(parse_missed_tick_behavior("sometimes"), None);
+    }
+
+    #[test]
+    fn test_jittered_interval_stays_in_range_and_is_seedable() {
+        let base = Duration::from_secs(10);
+        let mut rng = StdRng::seed_from_u64(7);
+        let samples: Vec<Duration> = (0..100).map(|_| jittered_interval(base, 20, &mut rng)).collect();
+        assert!(samples.iter().all(|d| *d >= Duration::from_secs(8) && *d <= Duration::from_secs(12)));
+        assert!(samples.iter().any(|d| *d != base));
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let replay: Vec<Duration> = (0..100).map(|_| jittered_interval(base, 20, &mut rng)).collect();
+        assert_eq!(samples, replay);
+
+        assert_eq!(jittered_interval(base, 0, &mut rng), base);
+        assert_eq!(initial_jitter(Duration::ZERO, &mut rng), Duration::ZERO);
+        assert!(initial_jitter(Duration::from_millis(500), &mut rng) <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_apply_breaker_opens_after_threshold_and_probes() {
+        let mut breaker = ApplyBreaker::default();
+        let cooldown = Duration::from_secs(60);
+        let start = Instant::now();
+
+        assert_eq!(breaker.record(true, start, 3, cooldown), BreakerState::Closed);
+        assert_eq!(breaker.record(true, start, 3, cooldown), BreakerState::Closed);
+        // A clean cycle in between starts the count over
+        assert_eq!(breaker.record(false, start, 3, cooldown), BreakerState::Closed);
+        for _ in 0..2 {
+            assert_eq!(breaker.record(true, start, 3, cooldown), BreakerState::Closed);
+        }
+        assert_eq!(breaker.record(true, start, 3, cooldown), BreakerState::Open);
+        assert_eq!(breaker.state(start + cooldown / 2), BreakerState::Open);
+
+        // A failed probe opens it for a whole new cooldown, a good one closes it
+        let probe = start + cooldown;
+        assert_eq!(breaker.state(probe), BreakerState::HalfOpen);
+        assert_eq!(breaker.record(true, probe, 3, cooldown), BreakerState::Open);
+        assert_eq!(breaker.state(probe + cooldown / 2), BreakerState::Open);
+        assert_eq!(breaker.record(false, probe + cooldown, 3, cooldown), BreakerState::Closed);
+        assert_eq!(breaker.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_startup_retry_delay_grows_and_caps() {
+        let base = Duration::from_secs(1);
+        let max = Duration::from_secs(10);
+        let mut rng = StdRng::seed_from_u64(7);
+        for (attempt, ceiling) in [(1, 1), (2, 2), (3, 4), (4, 8), (5, 10), (u32::MAX, 10)] {
+            let delay = startup_retry_delay(base, attempt, max, &mut rng);
+            let ceiling = Duration::from_secs(ceiling);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "attempt {attempt}: {delay:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_fetch_times_out_without_retrying() {
+        use std::sync::atomic::AtomicUsize;
+
+        let _ = crate::http_client::init_global_client();
+        // Accepts connections and holds them open without ever answering
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            let mut stalled = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                stalled.push(stream);
+            }
+        });
+        let config = AccessRulesConfig {
+            fetch_timeout: Duration::from_millis(200),
+            fetch_retries: 2,
+            fetch_retry_delay: Duration::ZERO,
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let err = fetch_config_with_retry(&base_url, "key", &config).await.unwrap_err();
+        assert!(matches!(err, AccessRulesError::Timeout { after } if after == config.fetch_timeout), "{err}");
+        assert!(err.is_retryable());
+        assert!(start.elapsed() < Duration::from_secs(5), "{:?}", start.elapsed());
+        // The stalled attempt is not retried within the cycle
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_shutdown_drain_stops_at_the_deadline() {
+        let only_sync = (Ipv4Addr::new(192, 0, 2, 0), 24);
+        let shared = (Ipv4Addr::new(198, 51, 100, 7), 32);
+        let refused = (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 128);
+        let targets = || {
+            let mut fw = MemoryFirewall::with_rules(&[only_sync, shared], &[refused]);
+            fw.banned.insert((shared.0.into(), shared.1), BanSource::Sync as u8 | BanSource::Manual as u8);
+            fw.fail.insert((refused.0.into(), refused.1));
+            [Mutex::new(fw), Mutex::new(MemoryFirewall::with_rules(&[only_sync, shared], &[refused]))]
+        };
+        let previous = || {
+            let previous = PreviousRuleSets::default();
+            *previous.rules.lock().unwrap() = HashSet::from([only_sync, shared]);
+            *previous.rules_v6.lock().unwrap() = HashSet::from([refused]);
+            previous
+        };
+
+        // An elapsed deadline stops the drain before it writes anything
+        let (stalled, stalled_previous) = (targets(), previous());
+        let summary = unban_all(&stalled[..], &stalled_previous, Some(Duration::ZERO)).unwrap();
+        assert_eq!(summary, DrainSummary { remaining: 6, ..DrainSummary::default() });
+        assert_eq!(stalled_previous.rules.lock().unwrap().len(), 2);
+        assert_eq!(stalled_previous.rules_v6.lock().unwrap().len(), 1);
+        assert!(stalled.iter().all(|fw| fw.lock().unwrap().writes.is_empty()));
+
+        // Without one every entry is drained; held and refused entries are counted apart
+        let (drained, drained_previous) = (targets(), previous());
+        let summary = unban_all(&drained[..], &drained_previous, None).unwrap();
+        assert_eq!(summary, DrainSummary { removed: 4, kept: 1, failed: 1, remaining: 0 });
+        assert!(drained_previous.rules.lock().unwrap().is_empty());
+        assert_eq!(drained[0].lock().unwrap().rules(), HashSet::from([shared]));
+        assert!(drained[1].lock().unwrap().rules().is_empty());
+
+        // An apply in flight keeps the drain from touching the maps
+        let _claim = drained_previous.claim_apply().unwrap();
+        assert_eq!(unban_all(&drained[..], &drained_previous, None), None);
+    }
+
+    #[test]
+    fn test_collapse_covered_v4_drops_contained_networks() {
+        let rules: HashSet<(Ipv4Addr, u32)> = [
+            (Ipv4Addr::new(10, 0, 0, 0), 8),
+            (Ipv4Addr::new(10, 1, 2, 3), 32),
+            (Ipv4Addr::new(10, 1, 0, 0), 16),
+            (Ipv4Addr::new(192, 168, 1, 0), 24),
+            (Ipv4Addr::new(192, 168, 2, 1), 32),
+        ]
+        .into_iter()
+        .collect();
+
+        let collapsed = collapse_covered(rules);
+        let expected: HashSet<(Ipv4Addr, u32)> = [
+            (Ipv4Addr::new(10, 0, 0, 0), 8),
+            (Ipv4Addr::new(192, 168, 1, 0), 24),
+            (Ipv4Addr::new(192, 168, 2, 1), 32),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(collapsed, expected);
+    }
+
+    #[test]
+    fn test_collapse_covered_v6_drops_contained_networks() {
+        let net: Ipv6Addr = "2001:db8::".parse().unwrap();
+        let host: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let other: Ipv6Addr = "2001:db9::1".parse().unwrap();
+        let rules: HashSet<(Ipv6Addr, u32)> =
+            [(net, 32), (host, 128), (other, 128)].into_iter().collect();
+
+        let collapsed = collapse_covered(rules);
+        assert!(collapsed.contains(&(net, 32)));
+        assert!(collapsed.contains(&(other, 128)));
+        assert!(!collapsed.contains(&(host, 128)));
+    }
+
+    #[test]
+    fn test_merge_adjacent_v4() {
+        let net = |a: u8, b: u8, c: u8, prefix: u32| (Ipv4Addr::new(a, b, c, 0), prefix);
+        let merge = |rules: &[(Ipv4Addr, u32)]| merge_adjacent_v4(rules.iter().copied().collect());
+
+        // Siblings combine into their parent
+        assert_eq!(merge(&[net(1, 2, 0, 24), net(1, 2, 1, 24)]), HashSet::from([net(1, 2, 0, 23)]));
+        // Adjacent but under different parents: 1.2.1.0/24 and 1.2.2.0/24 stay apart
+        assert_eq!(merge(&[net(1, 2, 1, 24), net(1, 2, 2, 24)]), HashSet::from([net(1, 2, 1, 24), net(1, 2, 2, 24)]));
+        // Same parent bits but different prefixes are not siblings
+        assert_eq!(merge(&[net(1, 2, 0, 24), net(1, 2, 1, 25)]), HashSet::from([net(1, 2, 0, 24), net(1, 2, 1, 25)]));
+        // A chain: four /24s become a /22, then the /22 pairs up with its sibling /22
+        let chain = merge(&[
+            net(10, 0, 0, 24), net(10, 0, 1, 24), net(10, 0, 2, 24), net(10, 0, 3, 24),
+            net(10, 0, 4, 22), net(10, 0, 9, 24),
+        ]);
+        assert_eq!(chain, HashSet::from([net(10, 0, 0, 21), net(10, 0, 9, 24)]));
+        // Three of four quarters only merge the complete pair
+        assert_eq!(
+            merge(&[net(10, 0, 0, 24), net(10, 0, 1, 24), net(10, 0, 2, 24)]),
+            HashSet::from([net(10, 0, 0, 23), net(10, 0, 2, 24)])
+        );
+        // Host routes merge too, and halves of the address space never become a catch-all
+        assert_eq!(
+            merge(&[(Ipv4Addr::new(192, 0, 2, 6), 32), (Ipv4Addr::new(192, 0, 2, 7), 32)]),
+            HashSet::from([(Ipv4Addr::new(192, 0, 2, 6), 31)])
+        );
+        assert_eq!(merge(&[net(0, 0, 0, 1), net(128, 0, 0, 1)]).len(), 2);
+    }
+
+    #[test]
+    fn test_merge_adjacent_v6() {
+        let net = |s: &str, prefix: u32| (s.parse::<Ipv6Addr>().unwrap(), prefix);
+        let rules: HashSet<(Ipv6Addr, u32)> = [
+            net("2001:db8::", 48), net("2001:db8:1::", 48), net("2001:db8:2::", 47),
+            net("2001:db8::1", 128), net("2001:db8::", 128),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            merge_adjacent_v6(rules),
+            HashSet::from([net("2001:db8::", 46), net("2001:db8::", 127)])
+        );
+    }
+
+    #[test]
+    fn test_collect_entry_routes_mapped_addresses_to_ipv4() {
+        let mut rules = HashSet::new();
+        let mut rules_v6 = HashSet::new();
+
+        collect_entry("::ffff:203.0.113.0/120", AddressFamilies::default(), &mut rules, &mut rules_v6);
+        collect_entry("2001:db8::/32", AddressFamilies::default(), &mut rules, &mut rules_v6);
+
+        assert!(rules.contains(&(Ipv4Addr::new(203, 0, 113, 0), 24)));
+        assert_eq!(rules_v6.len(), 1);
+    }
+
+    #[test]
+    fn test_collect_entry_translates_wildcards() {
+        let mut rules = HashSet::new();
+        let mut rules_v6 = HashSet::new();
+
+        assert_eq!(collect_entry("10.0.*.*  # legacy", AddressFamilies::default(), &mut rules, &mut rules_v6), None);
+        let reason = collect_entry("10.*.5.*", AddressFamilies::default(), &mut rules, &mut rules_v6).unwrap();
+        assert!(reason.starts_with("invalid wildcard pattern"), "{}", reason);
+
+        assert_eq!(rules, HashSet::from([(Ipv4Addr::new(10, 0, 0, 0), 16)]));
+        assert!(rules_v6.is_empty());
+    }
+
+    #[test]
+    fn test_recent_rejects_keeps_newest_entries() {
+        let reject = |entry: &str| RejectedEntry {
+            rule_source: "block.ips".to_string(),
+            entry: entry.to_string(),
+            reason: "invalid IPv4 ip/cidr".to_string(),
+        };
+        let recent = RecentRejects::new(3);
+        recent.record(&[reject("a"), reject("b")]);
+        recent.record(&[]);
+        recent.record(&[reject("c"), reject("d")]);
+        let entries: Vec<String> = recent.recent().into_iter().map(|r| r.rejected.entry).collect();
+        assert_eq!(entries, vec!["b", "c", "d"]);
+
+        recent.record(&[reject("e"), reject("f"), reject("g"), reject("h")]);
+        let entries: Vec<String> = recent.recent().into_iter().map(|r| r.rejected.entry).collect();
+        assert_eq!(entries, vec!["f", "g", "h"]);
+
+        let json = serde_json::to_value(&recent.recent()[0]).unwrap();
+        assert_eq!(json["rule_source"], "block.ips");
+        assert_eq!(json["entry"], "f");
+        assert!(json["timestamp"].is_string());
+    }
+
+    #[test]
+    fn test_expired_entries_are_dropped() {
+        let rule_set = config::RuleSet {
+            ips: vec!["192.0.2.0/24".to_string(), "198.51.100.0/24".to_string(), "203.0.113.0/24".to_string()],
+            country: vec![HashMap::from([("NL".to_string(), config::GroupCidrs::Flat(vec!["2001:db8::/32".to_string()]))])],
+            expires: HashMap::from([
+                ("192.0.2.0/24".to_string(), "2000-01-01T00:00:00Z".to_string()),
+                ("198.51.100.0/24".to_string(), "2999-01-01T00:00:00+02:00".to_string()),
+                ("203.0.113.0/24".to_string(), "tomorrow".to_string()),
+                ("2001:db8::/32".to_string(), "2000-01-01T00:00:00Z".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let mut rules = HashSet::new();
+        let mut rules_v6 = HashSet::new();
+        let rejected = collect_rule_set("block", &rule_set, AddressFamilies::default(), None, chrono::Utc::now(), &mut rules, &mut rules_v6);
+
+        assert!(rejected.is_empty());
+        // A malformed expiry keeps the entry enforced
+        assert_eq!(rules, HashSet::from([(Ipv4Addr::new(198, 51, 100, 0), 24), (Ipv4Addr::new(203, 0, 113, 0), 24)]));
+        assert!(rules_v6.is_empty());
+
+        let now = chrono::Utc::now();
+        let next = next_rule_expiry([&rule_set], now).unwrap();
+        assert_eq!(next.to_rfc3339(), "2998-12-31T22:00:00+00:00");
+        assert_eq!(next_rule_expiry([&config::RuleSet::default()], now), None);
+    }
+
+    #[test]
+    fn test_backward_clock_step_does_not_revive_or_remove_rules() {
+        let at = |s: &str| chrono::DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&chrono::Utc);
+        let rule_set = config::RuleSet {
+            ips: vec!["192.0.2.0/24".to_string(), "198.51.100.0/24".to_string()],
+            expires: HashMap::from([
+                ("192.0.2.0/24".to_string(), "2030-01-01T11:59:00Z".to_string()),
+                ("198.51.100.0/24".to_string(), "2030-01-01T12:10:00Z".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let collect = |now| {
+            let (mut rules, mut rules_v6) = (HashSet::new(), HashSet::new());
+            collect_rule_set("block", &rule_set, AddressFamilies::default(), None, now, &mut rules, &mut rules_v6);
+            rules
+        };
+        let active = HashSet::from([(Ipv4Addr::new(198, 51, 100, 0), 24)]);
+
+        let mut clock = ExpiryClock::default();
+        let start = Instant::now();
+        let now = clock.observe(at("2030-01-01T12:00:00Z"), start);
+        assert_eq!(collect(now), active);
+
+        // One minute later the system clock is stepped back an hour
+        let now = clock.observe(at("2030-01-01T11:01:00Z"), start + Duration::from_secs(60));
+        assert_eq!(now, at("2030-01-01T12:01:00Z"));
+        assert_eq!(collect(now), active);
+
+        // Time keeps advancing monotonically until the remaining entry is due
+        let now = clock.observe(at("2030-01-01T11:10:00Z"), start + Duration::from_secs(600));
+        assert_eq!(now, at("2030-01-01T12:10:00Z"));
+        assert!(collect(now).is_empty());
+
+        // A forward step, e.g. after a suspend, is taken as-is
+        let now = clock.observe(at("2030-01-02T00:00:00Z"), start + Duration::from_secs(660));
+        assert_eq!(now, at("2030-01-02T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_collect_entry_accepts_annotated_entries() {
+        let mut rules = HashSet::new();
+        let mut rules_v6 = HashSet::new();
+        let families = AddressFamilies::default();
+
+        assert_eq!(collect_entry("1.2.3.0/24 # known scanner", families, &mut rules, &mut rules_v6), None);
+        assert_eq!(collect_entry("5.6.7.8\t/\t32", families, &mut rules, &mut rules_v6), None);
+        assert_eq!(collect_entry("2001:db8::  /  32", families, &mut rules, &mut rules_v6), None);
+        assert_eq!(collect_entry("  # retired entry", families, &mut rules, &mut rules_v6), None);
+        assert!(collect_entry("1.2.3.0/33 # typo", families, &mut rules, &mut rules_v6).is_some());
+
+        assert_eq!(rules, HashSet::from([(Ipv4Addr::new(1, 2, 3, 0), 24), (Ipv4Addr::new(5, 6, 7, 8), 32)]));
+        assert_eq!(rules_v6, HashSet::from([("2001:db8::".parse().unwrap(), 32)]));
+    }
+
+    #[test]
+    fn test_collect_entry_normalizes_ipv6_host_bits() {
+        let mut rules = HashSet::new();
+        let mut rules_v6 = HashSet::new();
+
+        collect_entry("2001:db8::1/64", AddressFamilies::default(), &mut rules, &mut rules_v6);
+        collect_entry("2001:db8::2/64", AddressFamilies::default(), &mut rules, &mut rules_v6);
+
+        assert_eq!(rules_v6, HashSet::from([("2001:db8::".parse().unwrap(), 64)]));
+    }
+
+    #[test]
+    fn test_collect_entry_skips_scoped_ipv6() {
+        let mut rules = HashSet::new();
+        let mut rules_v6 = HashSet::new();
+
+        for entry in ["fe80::1%eth0", "fe80::/10", "ff02::1"] {
+            collect_entry(entry, AddressFamilies::default(), &mut rules, &mut rules_v6);
+        }
+        collect_entry("2001:db8::1", AddressFamilies::default(), &mut rules, &mut rules_v6);
+
+        assert!(rules.is_empty());
+        assert_eq!(rules_v6, HashSet::from([("2001:db8::1".parse().unwrap(), 128)]));
+    }
+
+    #[test]
+    fn test_applied_allow_matches_like_the_datapath() {
+        let applied = AppliedAllow {
+            v4: HashSet::from([(Ipv4Addr::new(192, 0, 2, 0), 24), (Ipv4Addr::new(198, 51, 100, 7), 32)]),
+            v6: HashSet::from([("2001:db8::".parse().unwrap(), 32)]),
+        };
+        assert!(applied.covers("192.0.2.200".parse().unwrap()));
+        assert!(applied.covers("198.51.100.7".parse().unwrap()));
+        assert!(!applied.covers("198.51.100.8".parse().unwrap()));
+        assert!(applied.covers("2001:db8:1::1".parse().unwrap()));
+        assert!(!applied.covers("2001:db9::1".parse().unwrap()));
+        // A dual-stack listener reports IPv4 peers as mapped addresses
+        assert!(applied.covers("::ffff:192.0.2.1".parse().unwrap()));
+    }
+
+    fn temp_state_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("moat-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_rule_state_round_trip() {
+        let path = temp_state_path("state-round-trip");
+        let state = PersistedRuleState {
+            version: RULE_STATE_VERSION,
+            block_v4: vec![(Ipv4Addr::new(10, 0, 0, 0), 8)],
+            block_v6: vec![("2001:db8::".parse().unwrap(), 32)],
+            allow_v4: vec![(Ipv4Addr::new(192, 168, 1, 1), 32)],
+            allow_v6: vec![],
+            log_only_v4: vec![],
+            log_only_v6: vec![("2001:db8::".parse().unwrap(), 32)],
+            rule_hits: vec![("eth0".to_string(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8, 42)],
+        };
+
+        save_rule_state(&path, &state).unwrap();
+        assert_eq!(load_rule_state(&path), Some(state));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_rule_state_rejects_missing_corrupt_and_unknown_version() {
+        let path = temp_state_path("state-invalid");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(load_rule_state(&path), None);
+
+        std::fs::write(&path, b"{not json").unwrap();
+        assert_eq!(load_rule_state(&path), None);
+
+        std::fs::write(&path, br#"{"version":99,"block_v4":[["10.0.0.0",8]]}"#).unwrap();
+        assert_eq!(load_rule_state(&path), None);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_restore_keeps_log_only_entries_unenforced() {
+        let path = temp_state_path("state-log-only");
+        let enforced = (Ipv4Addr::new(10, 0, 0, 0), 8);
+        let trial = (Ipv4Addr::new(192, 0, 2, 1), 32);
+        let state = PersistedRuleState {
+            version: RULE_STATE_VERSION,
+            block_v4: vec![enforced, trial],
+            log_only_v4: vec![trial],
+            ..PersistedRuleState::default()
+        };
+        save_rule_state(&path, &state).unwrap();
+        let targets = [Mutex::new(MemoryFirewall::default())];
+        let previous = PreviousRuleSets::default();
+
+        restore_rule_state(&targets[..], &previous, &path, &AccessRulesConfig::default());
+        let banned = targets[0].lock().unwrap().banned.clone();
+        let sync = BanSource::Sync as u8;
+        assert_eq!(banned[&(enforced.0.into(), enforced.1)], sync);
+        assert_eq!(banned[&(trial.0.into(), trial.1)], sync | 0x80);
+        assert_eq!(*previous.log_only.lock().unwrap(), HashSet::from([trial]));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_config_cache_round_trip() {
+        let path = temp_state_path("config-cache");
+        let response: ConfigApiResponse = serde_json::from_str(r#"{
+            "success": true,
+            "config": {
+                "access_rules": {
+                    "id": "r1", "name": "rules", "description": "",
+                    "allow": {}, "block": {"ips": ["192.0.2.0/24"]}
+                },
+                "waf_rules": {"rules": []},
+                "created_at": "", "updated_at": "", "last_modified": ""
+            }
+        }"#).unwrap();
+
+        save_config_cache(&path, &response).unwrap();
+        let cached = load_config_cache(&path).unwrap();
+        assert!(cached.fetched_at > 0);
+        assert_eq!(cached.response.config.access_rules.block.ips, vec!["192.0.2.0/24".to_string()]);
+
+        std::fs::write(&path, b"{not json").unwrap();
+        assert!(matches!(load_config_cache(&path), Err(AccessRulesError::Parse { .. })));
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(load_config_cache(&path), Err(AccessRulesError::Io { .. })));
+    }
+
+    #[test]
+    fn test_asn_and_country_key_validation() {
+        for key in ["AS13335", "as13335", "13335", "AS0", "4294967295"] {
+            assert!(is_valid_asn_key(key), "{key} should be valid");
+        }
+        for key in ["", "AS", "ASN13335", "AS-1", "AS 1", "+1", "13335a", "4294967296", "AS１"] {
+            assert!(!is_valid_asn_key(key), "{key} should be invalid");
+        }
+
+        for key in ["US", "de"] {
+            assert!(is_valid_country_key(key), "{key} should be valid");
+        }
+        for key in ["", "U", "USA", "U1", "??"] {
+            assert!(!is_valid_country_key(key), "{key} should be invalid");
+        }
+    }
+
+    #[test]
+    fn test_collect_rule_set_parses_each_unique_entry_once() {
+        use std::collections::HashMap;
+
+        let rule_set = config::RuleSet {
+            asn: vec![HashMap::from([("AS64500".to_string(), config::GroupCidrs::Flat(vec!["2001:db8::/32".to_string(), "bogus".to_string()]))])],
+            country: vec![
+                HashMap::from([("NL".to_string(), config::GroupCidrs::Flat(vec!["192.0.2.0/24".to_string(), " 2001:db8::/32".to_string()]))]),
+                HashMap::from([("DE".to_string(), config::GroupCidrs::Flat(vec!["192.0.2.0/24 ".to_string(), "bogus".to_string()]))]),
+            ],
+            ips: vec!["192.0.2.0/24".to_string(), "198.51.100.7".to_string(), "198.51.100.7".to_string()],
+            domains: vec![],
+            expires: HashMap::new(),
+            actions: HashMap::new(),
+        };
+        let (mut rules, mut rules_v6) = (HashSet::new(), HashSet::new());
+        let rejected = collect_rule_set("block", &rule_set, AddressFamilies::default(), None, chrono::Utc::now(), &mut rules, &mut rules_v6);
+
+        // Duplicates of either family still land in their own set, and a bad entry
+        // listed twice is reported once
+        assert_eq!(rules, HashSet::from([((Ipv4Addr::new(192, 0, 2, 0)), 24), (Ipv4Addr::new(198, 51, 100, 7), 32)]));
+        assert_eq!(rules_v6, HashSet::from([("2001:db8::".parse::<Ipv6Addr>().unwrap(), 32)]));
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].rule_source, "block.country");
+    }
+
+    #[test]
+    fn test_collect_rule_set_skips_malformed_groups() {
+        use std::collections::HashMap;
+
+        let rule_set = config::RuleSet {
+            asn: vec![HashMap::from([
+                ("AS64500".to_string(), config::GroupCidrs::Flat(vec!["198.51.100.0/24".to_string()])),
+                ("bogus".to_string(), config::GroupCidrs::Flat(vec!["203.0.113.0/24".to_string()])),
+            ])],
+            country: vec![HashMap::from([
+                ("NL".to_string(), config::GroupCidrs::Flat(vec!["192.0.2.0/24".to_string()])),
+                ("Netherlands".to_string(), config::GroupCidrs::Flat(vec!["2001:db8::/32".to_string()])),
+            ])],
+            ips: vec![],
+            domains: vec![],
+            expires: HashMap::new(),
+            actions: HashMap::new(),
+        };
+
+        let mut rules = HashSet::new();
+        let mut rules_v6 = HashSet::new();
+        collect_rule_set("block", &rule_set, AddressFamilies::default(), None, chrono::Utc::now(), &mut rules, &mut rules_v6);
+
+        let expected: HashSet<(Ipv4Addr, u32)> = [
+            (Ipv4Addr::new(198, 51, 100, 0), 24),
+            (Ipv4Addr::new(192, 0, 2, 0), 24),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(rules, expected);
+        assert!(rules_v6.is_empty());
+    }
+
+    #[test]
+    fn test_validate_rules_counts_and_lists_rejections() {
+        let rules: config::AccessRule = serde_json::from_str(r#"{
+            "id": "r1", "name": "rules", "description": "",
+            "allow": {"ips": ["192.0.2.10"]},
+            "block": {
+                "ips": ["192.0.2.0/24", "2001:db8::/32", "10.0.0.300", "0.0.0.0/0"],
+                "country": [{"us": ["198.51.100.0/24"], "not a code": ["203.0.113.0/24"]}]
+            },
+            "rate_limit": [{"ips": ["203.0.113.0/24"], "pps": 50}]
+        }"#).unwrap();
+
+        let report = validate_rules(&rules, &AccessRulesConfig::default());
+
+        assert_eq!((report.rules, report.rules_v6, report.allow_rules, report.rate_limits), (2, 1, 1, 1));
+        let rejected: Vec<(&str, &str)> = report.rejected.iter()
+            .map(|r| (r.rule_source.as_str(), r.entry.as_str()))
+            .collect();
+        assert_eq!(rejected.len(), 3);
+        assert!(rejected.contains(&("block.ips", "10.0.0.300")));
+        assert!(rejected.contains(&("block.country", "not a code")));
+        assert!(rejected.contains(&("block", "0.0.0.0/0")));
+    }
+
+    #[test]
+    fn test_collect_rate_limits_keeps_strictest_limit() {
+        let rules = vec![
+            config::RateLimitRule { ips: vec!["198.51.100.0/24".into(), "2001:db8::/32".into()], pps: 100 },
+            config::RateLimitRule { ips: vec!["198.51.100.7/24".into()], pps: 10 },
+            config::RateLimitRule { ips: vec!["203.0.113.1".into()], pps: 0 },
+        ];
+        let mut rejected = Vec::new();
+        let limits = collect_rate_limits(&rules, AddressFamilies::default(), &mut rejected);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(limits, HashMap::from([
+            ((IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)), 24), 10),
+            ((IpAddr::V6("2001:db8::".parse().unwrap()), 32), 100),
+        ]));
+    }
+
+    #[test]
+    fn test_rate_limits_do_not_touch_ban_snapshots() {
+        let previous = PreviousRuleSets::default();
+        let config = AccessRulesConfig { dry_run: true, ..AccessRulesConfig::default() };
+        let limits = HashMap::from([((IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)), 24), 50)]);
+
+        apply_rate_limits(&[], limits.clone(), &previous.rate_limits, &config);
+
+        assert_eq!(*previous.rate_limits.lock().unwrap(), limits);
+        assert!(previous.rules.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate_api_key_format() {
+        let config = AccessRulesConfig::default();
+        assert!(validate_api_key_format("ak_live_0123456789", &config).is_ok());
+        assert!(matches!(validate_api_key_format("", &config), Err(AccessRulesError::InvalidApiKey(_))));
+        assert!(matches!(validate_api_key_format("ak live", &config), Err(AccessRulesError::InvalidApiKey(_))));
+        assert!(matches!(validate_api_key_format("ak_live\n", &config), Err(AccessRulesError::InvalidApiKey(_))));
+
+        let manual = AccessRulesConfig { manual_rules: Some(Arc::new(ManualRules::default())), ..AccessRulesConfig::default() };
+        assert!(validate_api_key_format("", &manual).is_ok());
+    }
+
+    #[test]
+    fn test_check_skeletons_honors_require_skel() {
+        let mut config = AccessRulesConfig::default();
+        assert!(check_skeletons(&[], &config).is_ok());
+        config.require_skel = true;
+        assert!(matches!(check_skeletons(&[], &config), Err(AccessRulesError::MissingSkeleton)));
+    }
+
+    #[test]
+    fn test_manual_rules_ban_and_unban() {
+        let manual = ManualRules::default();
+        assert_eq!(manual.ban("203.0.113.7").unwrap(), 1);
+        assert_eq!(manual.ban("203.0.113.7").unwrap(), 0);
+        assert_eq!(manual.ban("2001:db8::/32").unwrap(), 1);
+        assert!(matches!(manual.ban("not-an-ip"), Err(AccessRulesError::Parse { .. })));
+        let generation = manual.generation();
+
+        let (v4, v6) = manual.snapshot();
+        assert_eq!(v4, HashSet::from([(Ipv4Addr::new(203, 0, 113, 7), 32)]));
+        assert_eq!(v6.len(), 1);
+
+        assert_eq!(manual.unban("198.51.100.1").unwrap(), 0);
+        assert_eq!(manual.generation(), generation);
+        assert_eq!(manual.unban("203.0.113.7").unwrap(), 1);
+        assert!(manual.generation() > generation);
+        assert!(manual.snapshot().0.is_empty());
+    }
+
+    #[test]
+    fn test_local_rules_parse_with_missing_sections() {
+        let local: LocalRules = serde_yaml::from_str(
+            "block:\n  ips:\n    - 203.0.113.0/24\n  asn:\n    - AS64500:\n        - 198.51.100.0/24\n",
+        )
+        .unwrap();
+
+        assert_eq!(local.block.ips, vec!["203.0.113.0/24".to_string()]);
+        assert!(local.block.country.is_empty());
+        assert_eq!(local.block.asn.len(), 1);
+        assert!(local.allow.ips.is_empty());
+    }
+
+    #[test]
+    fn test_collect_entry_skips_disabled_family() {
+        let v4_only = AddressFamilies { ipv4: true, ipv6: false };
+        let mut rules = HashSet::new();
+        let mut rules_v6 = HashSet::new();
+
+        collect_entry("2001:db8::/32", v4_only, &mut rules, &mut rules_v6);
+        collect_entry("not-an-ip:", v4_only, &mut rules, &mut rules_v6);
+        collect_entry("::ffff:203.0.113.7", v4_only, &mut rules, &mut rules_v6);
+        collect_entry("192.0.2.0/24", v4_only, &mut rules, &mut rules_v6);
+
+        assert!(rules_v6.is_empty());
+        assert_eq!(rules.len(), 2);
+
+        let stats = ApplyStats::for_families(v4_only);
+        assert!(stats.v6_skipped && !stats.v4_skipped);
+        assert!(stats.is_empty());
+    }
+
+    #[test]
+    fn test_broadest_rules_orders_by_prefix() {
+        let rules: HashSet<(Ipv4Addr, u32)> = [
+            (Ipv4Addr::new(192, 0, 2, 1), 32),
+            (Ipv4Addr::new(10, 0, 0, 0), 8),
+            (Ipv4Addr::new(128, 0, 0, 0), 1),
+            (Ipv4Addr::new(172, 16, 0, 0), 12),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            broadest_rules(&rules, 2),
+            vec![(Ipv4Addr::new(128, 0, 0, 0), 1), (Ipv4Addr::new(10, 0, 0, 0), 8)]
+        );
+        assert_eq!(broadest_rules(&rules, 10).len(), 4);
+        assert!(broadest_rules(&rules, 0).is_empty());
+    }
+
+    #[test]
+    fn test_drop_default_route_blocks() {
+        let mut rules: HashSet<(Ipv4Addr, u32)> =
+            [(Ipv4Addr::UNSPECIFIED, 0), (Ipv4Addr::new(0, 0, 0, 0), 1), (Ipv4Addr::new(10, 0, 0, 0), 8)]
+                .into_iter()
+                .collect();
+        drop_default_route_blocks(&mut rules, "IPv4");
+        assert_eq!(rules.len(), 2);
+        assert!(!rules.contains(&(Ipv4Addr::UNSPECIFIED, 0)));
+
+        let mut rules_v6: HashSet<(Ipv6Addr, u32)> = [(Ipv6Addr::UNSPECIFIED, 0)].into_iter().collect();
+        drop_default_route_blocks(&mut rules_v6, "IPv6");
+        assert!(rules_v6.is_empty());
+    }
+
+    #[test]
+    fn test_collect_rule_set_applies_country_filter() {
+        use std::collections::HashMap;
+
+        let rule_set = config::RuleSet {
+            asn: vec![],
+            country: vec![HashMap::from([
+                ("RU".to_string(), config::GroupCidrs::Flat(vec!["198.51.100.0/24".to_string()])),
+                ("nl".to_string(), config::GroupCidrs::Flat(vec!["192.0.2.0/24".to_string()])),
+            ])],
+            ips: vec!["203.0.113.1".to_string()],
+            domains: vec![],
+            expires: HashMap::new(),
+            actions: HashMap::new(),
+        };
+        let config = AccessRulesConfig {
+            country_filter: Some(["RU".to_string()].into_iter().collect()),
+            ..Default::default()
+        };
+        let mut groups = GroupSelection::new(&config);
+
+        let mut rules = HashSet::new();
+        let mut rules_v6 = HashSet::new();
+        collect_rule_set("block", &rule_set, AddressFamilies::default(), Some(&mut groups), chrono::Utc::now(), &mut rules, &mut rules_v6);
+        assert_eq!(groups.skipped, 1);
+
+        let expected: HashSet<(Ipv4Addr, u32)> = [
+            (Ipv4Addr::new(198, 51, 100, 0), 24),
+            (Ipv4Addr::new(203, 0, 113, 1), 32),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(rules, expected);
+    }
+
+    #[test]
+    fn test_cap_rules_keeps_broadest_prefixes() {
+        let rules: HashSet<(Ipv4Addr, u32)> = [
+            (Ipv4Addr::new(192, 0, 2, 1), 32),
+            (Ipv4Addr::new(192, 0, 2, 2), 32),
+            (Ipv4Addr::new(10, 0, 0, 0), 8),
+            (Ipv4Addr::new(172, 16, 0, 0), 12),
+        ]
+        .into_iter()
+        .collect();
+
+        let capped = cap_rules(rules.clone(), &HashMap::new(), 3, "IPv4");
+        let expected: HashSet<(Ipv4Addr, u32)> = [
+            (Ipv4Addr::new(10, 0, 0, 0), 8),
+            (Ipv4Addr::new(172, 16, 0, 0), 12),
+            (Ipv4Addr::new(192, 0, 2, 1), 32),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(capped, expected);
+        assert_eq!(cap_rules(rules, &HashMap::new(), 10, "IPv4").len(), 4);
+    }
+
+    #[test]
+    fn test_cap_rules_drops_lower_priority_sources_first() {
+        let ip_rule = (Ipv4Addr::new(192, 0, 2, 1), 32);
+        let asn_rule = (Ipv4Addr::new(10, 0, 0, 0), 8);
+        let country_rule = (Ipv4Addr::new(172, 16, 0, 0), 12);
+        let rules: HashSet<(Ipv4Addr, u32)> = [ip_rule, asn_rule, country_rule].into_iter().collect();
+
+        // ips > country > asn: the broad ASN /8 is dropped before the /32
+        let ranks = HashMap::from([(ip_rule, 1), (country_rule, 2), (asn_rule, 3)]);
+        let capped = cap_rules(rules, &ranks, 2, "IPv4");
+        assert_eq!(capped, [ip_rule, country_rule].into_iter().collect());
+    }
+
+    #[test]
+    fn test_parse_source_priority_fills_missing_and_skips_unknown() {
+        let names = vec!["ASN".to_string(), "bogus".to_string(), "asn".to_string(), "ips".to_string()];
+        assert_eq!(
+            parse_source_priority(&names),
+            vec![RuleSource::Asn, RuleSource::Ips, RuleSource::Country]
+        );
+        assert_eq!(parse_source_priority(&[]), RuleSource::ALL.to_vec());
+    }
+}
@@ -0,0 +1,502 @@
+//! Parsers for the address entries of access rule sets
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Match `text` against a glob where `*` matches any run of characters and `?` exactly one
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it currently absorbs up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Strip an inline `#` comment, the surrounding whitespace and the whitespace around
+/// `/` and `-` from a feed entry, so annotated or tab-separated entries such as
+/// `1.2.3.0 / 24  # scanner` parse like `1.2.3.0/24`. Any other whitespace is kept as a
+/// single space, which no address parser accepts, so `10.0.0.1 5` is rejected rather
+/// than read as `10.0.0.15`. Returns None when nothing is left, for entries that are
+/// only a comment.
+pub(crate) fn normalize_entry(entry: &str) -> Option<String> {
+    let entry = entry.split('#').next().unwrap_or_default();
+    let is_separator = |c: char| c == '/' || c == '-';
+    let mut normalized = String::with_capacity(entry.len());
+    for part in entry.split_whitespace() {
+        let joined = normalized.ends_with(is_separator) || part.starts_with(is_separator);
+        if !normalized.is_empty() && !joined {
+            normalized.push(' ');
+        }
+        normalized.push_str(part);
+    }
+    (!normalized.is_empty()).then_some(normalized)
+}
+
+/// Parse an IPv4 address or IPv4/CIDR into (network, prefix), masking host bits
+pub(crate) fn parse_ipv4_ip_or_cidr(entry: &str) -> Option<(Ipv4Addr, u32)> {
+    let s = entry.trim();
+    if s.is_empty() {
+        return None;
+    }
+    if s.contains(':') {
+        // IPv6 not supported by IPv4 map
+        return None;
+    }
+    if !s.contains('/') {
+        return Ipv4Addr::from_str(s).ok().map(|ip| (ip, 32));
+    }
+    let mut parts = s.split('/');
+    let ip_str = parts.next()?.trim();
+    let prefix_str = parts.next()?.trim();
+    if parts.next().is_some() {
+        // malformed
+        return None;
+    }
+    let ip = Ipv4Addr::from_str(ip_str).ok()?;
+    let prefix: u32 = prefix_str.parse::<u8>().ok()? as u32;
+    if prefix > 32 {
+        return None;
+    }
+    let ip_u32 = u32::from(ip);
+    let mask = if prefix == 0 {
+        0
+    } else {
+        u32::MAX.checked_shl(32 - prefix).unwrap_or(0)
+    };
+    let net = Ipv4Addr::from(ip_u32 & mask);
+    Some((net, prefix))
+}
+
+/// Parse an IPv6 address or IPv6/CIDR into (network, prefix), masking host bits
+pub(crate) fn parse_ipv6_ip_or_cidr(entry: &str) -> Option<(Ipv6Addr, u32)> {
+    let s = entry.trim();
+    if s.is_empty() {
+        return None;
+    }
+    if !s.contains(':') {
+        // IPv4 not supported by IPv6 map
+        return None;
+    }
+    if !s.contains('/') {
+        return Ipv6Addr::from_str(s).ok().map(|ip| (ip, 128));
+    }
+    let mut parts = s.split('/');
+    let ip_str = parts.next()?.trim();
+    let prefix_str = parts.next()?.trim();
+    if parts.next().is_some() {
+        // malformed
+        return None;
+    }
+    let ip = Ipv6Addr::from_str(ip_str).ok()?;
+    let prefix: u32 = prefix_str.parse::<u8>().ok()? as u32;
+    if prefix > 128 {
+        return None;
+    }
+    let mask = if prefix == 0 {
+        0
+    } else {
+        u128::MAX.checked_shl(128 - prefix).unwrap_or(0)
+    };
+    let net = Ipv6Addr::from(u128::from(ip) & mask);
+    Some((net, prefix))
+}
+
+/// Network of a single IP or CIDR entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IpNetwork {
+    V4(Ipv4Addr, u32),
+    V6(Ipv6Addr, u32),
+}
+
+/// Parse an IP or CIDR of either family by trying IPv4 first and then IPv6, rather than
+/// guessing the family from the characters in the entry. IPv4-mapped IPv6 networks come
+/// back as IPv4, except a /96 of the whole mapped space, which would become an IPv4
+/// catch-all and is rejected. The error names the family the entry most likely meant.
+pub(crate) fn parse_ip_or_cidr(entry: &str) -> Result<IpNetwork, &'static str> {
+    if let Some((net, prefix)) = parse_ipv4_ip_or_cidr(entry) {
+        return Ok(IpNetwork::V4(net, prefix));
+    }
+    if let Some((net, prefix)) = parse_ipv6_ip_or_cidr(entry) {
+        return match ipv4_from_mapped_ipv6(net, prefix) {
+            Some((_, 0)) => Err("IPv4-mapped /96 covers every IPv4 address"),
+            Some((mapped, mapped_prefix)) => Ok(IpNetwork::V4(mapped, mapped_prefix)),
+            None => Ok(IpNetwork::V6(net, prefix)),
+        };
+    }
+    Err(if entry.contains(':') { "invalid IPv6 ip/cidr" } else { "invalid IPv4 ip/cidr" })
+}
+
+/// Parse a dotted-wildcard IPv4 pattern into its CIDR, e.g. `10.0.*.*` into
+/// `10.0.0.0/16`. Only whole trailing octets may be wildcards; returns the reason on
+/// failure.
+pub(crate) fn parse_ipv4_wildcard(entry: &str) -> Result<(Ipv4Addr, u32), &'static str> {
+    let s = entry.trim();
+    if s.contains('/') {
+        return Err("wildcard pattern with a prefix length");
+    }
+    let octets: Vec<&str> = s.split('.').collect();
+    if octets.len() != 4 {
+        return Err("wildcard pattern without four octets");
+    }
+    let fixed = octets.iter().take_while(|octet| **octet != "*").count();
+    if octets[fixed..].iter().any(|octet| *octet != "*") {
+        return Err("wildcards are not contiguous and right-aligned");
+    }
+    let mut bytes = [0u8; 4];
+    for (byte, octet) in bytes.iter_mut().zip(&octets[..fixed]) {
+        *byte = octet.parse().map_err(|_| "malformed octet in wildcard pattern")?;
+    }
+    Ok((Ipv4Addr::from(bytes), fixed as u32 * 8))
+}
+
+/// CIDRs covering a `start-end` address range
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RangeCidrs {
+    V4(Vec<(Ipv4Addr, u32)>),
+    V6(Vec<(Ipv6Addr, u32)>),
+}
+
+/// Parse a `start-end` range of two IPv4 or two IPv6 addresses into its minimal set of
+/// covering CIDRs. Returns the reason on failure.
+pub(crate) fn parse_ip_range(entry: &str) -> Result<RangeCidrs, &'static str> {
+    let (start, end) = entry.trim().split_once('-').ok_or("missing '-'")?;
+    let start = IpAddr::from_str(start.trim()).map_err(|_| "malformed start address")?;
+    let end = IpAddr::from_str(end.trim()).map_err(|_| "malformed end address")?;
+    match (start, end) {
+        (IpAddr::V4(start), IpAddr::V4(end)) => {
+            if start > end {
+                return Err("start is greater than end");
+            }
+            let cidrs = range_to_cidrs(u32::from(start).into(), u32::from(end).into(), 32)
+                .into_iter()
+                .map(|(net, prefix)| (Ipv4Addr::from(net as u32), prefix))
+                .collect();
+            Ok(RangeCidrs::V4(cidrs))
+        }
+        (IpAddr::V6(start), IpAddr::V6(end)) => {
+            if start > end {
+                return Err("start is greater than end");
+            }
+            let cidrs = range_to_cidrs(start.into(), end.into(), 128)
+                .into_iter()
+                .map(|(net, prefix)| (Ipv6Addr::from(net), prefix))
+                .collect();
+            Ok(RangeCidrs::V6(cidrs))
+        }
+        _ => Err("start and end are different address families"),
+    }
+}
+
+/// Decompose the inclusive range `start..=end` of `bits`-wide addresses into the minimal
+/// list of aligned CIDR blocks, taking the largest block that fits at each step
+fn range_to_cidrs(start: u128, end: u128, bits: u32) -> Vec<(u128, u32)> {
+    let span = |host_bits: u32| if host_bits >= 128 { u128::MAX } else { (1u128 << host_bits) - 1 };
+    let mut cidrs = Vec::new();
+    let mut cur = start;
+    loop {
+        // Largest block aligned at `cur`, shrunk until it no longer runs past `end`
+        let mut host_bits = if cur == 0 { bits } else { cur.trailing_zeros().min(bits) };
+        while span(host_bits) > end - cur {
+            host_bits -= 1;
+        }
+        cidrs.push((cur, bits - host_bits));
+
+        let last = cur + span(host_bits);
+        if last >= end {
+            break;
+        }
+        cur = last + 1;
+    }
+    cidrs
+}
+
+/// Translate an IPv4-mapped (`::ffff:a.b.c.d`) or IPv4-compatible (`::a.b.c.d`) network
+/// into the equivalent IPv4 network, e.g. `::ffff:10.0.0.0/104` becomes `10.0.0.0/8`.
+/// Returns None for native IPv6 networks and for prefixes shorter than /96, which
+/// cover more than the embedded IPv4 space. `::` and `::1` are never treated as IPv4.
+pub(crate) fn ipv4_from_mapped_ipv6(ip: Ipv6Addr, prefix: u32) -> Option<(Ipv4Addr, u32)> {
+    if !(96..=128).contains(&prefix) {
+        return None;
+    }
+    let bits = u128::from(ip);
+    let embedded = match bits >> 32 {
+        0xffff => Ipv4Addr::from(bits as u32),
+        0 if (bits as u32) > 1 => Ipv4Addr::from(bits as u32),
+        _ => return None,
+    };
+    parse_ipv4_ip_or_cidr(&format!("{}/{}", embedded, prefix - 96))
+}
+
+/// Why an IPv6 entry has no place in a global blocklist
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ipv6Scope {
+    /// Carries a `%zone` identifier, e.g. `fe80::1%eth0`
+    Zoned,
+    /// Link-local unicast, `fe80::/10`
+    LinkLocal,
+    /// Multicast, `ff00::/8`
+    Multicast,
+}
+
+impl Ipv6Scope {
+    pub(crate) fn describe(self) -> &'static str {
+        match self {
+            Ipv6Scope::Zoned => "zoned",
+            Ipv6Scope::LinkLocal => "link-local",
+            Ipv6Scope::Multicast => "multicast",
+        }
+    }
+}
+
+/// Detect IPv6 entries that are scoped to a link or are multicast. Such entries never
+/// match a remote source address, so the caller skips them with a specific reason.
+pub(crate) fn ipv6_non_global_scope(entry: &str) -> Option<Ipv6Scope> {
+    let s = entry.trim();
+    if s.contains('%') {
+        return Some(Ipv6Scope::Zoned);
+    }
+    let ip = Ipv6Addr::from_str(s.split('/').next()?.trim()).ok()?;
+    if ip.is_multicast() {
+        Some(Ipv6Scope::Multicast)
+    } else if ip.segments()[0] & 0xffc0 == 0xfe80 {
+        Some(Ipv6Scope::LinkLocal)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_entry_strips_comments_and_whitespace() {
+        assert_eq!(normalize_entry("1.2.3.0/24 # known scanner").as_deref(), Some("1.2.3.0/24"));
+        assert_eq!(normalize_entry("1.2.3.0/24#scanner").as_deref(), Some("1.2.3.0/24"));
+        assert_eq!(normalize_entry("\t1.2.3.0\t/\t24\t").as_deref(), Some("1.2.3.0/24"));
+        assert_eq!(normalize_entry("10.0.0.1  -   10.0.0.9").as_deref(), Some("10.0.0.1-10.0.0.9"));
+        assert_eq!(normalize_entry("2001:db8:: / 32 ").as_deref(), Some("2001:db8::/32"));
+        assert_eq!(normalize_entry("   # just a comment"), None);
+        assert_eq!(normalize_entry(" \t "), None);
+    }
+
+    #[test]
+    fn test_normalize_entry_keeps_whitespace_inside_addresses() {
+        assert_eq!(normalize_entry("10.0.0.1 5").as_deref(), Some("10.0.0.1 5"));
+        assert_eq!(normalize_entry("10.0.0.1\t\t5 # typo").as_deref(), Some("10.0.0.1 5"));
+        assert!(parse_ip_or_cidr(&normalize_entry("10.0.0.1 5").unwrap()).is_err());
+        assert!(parse_ip_range(&normalize_entry("10.0.0.1 - 10.0. 0.9").unwrap()).is_err());
+        assert_eq!(normalize_entry("2001:db8:: 1/128").as_deref(), Some("2001:db8:: 1/128"));
+    }
+
+    #[test]
+    fn test_parse_ip_or_cidr_dispatches_by_parsing() {
+        assert_eq!(parse_ip_or_cidr("10.1.2.3/8"), Ok(IpNetwork::V4(Ipv4Addr::new(10, 0, 0, 0), 8)));
+        assert_eq!(parse_ip_or_cidr(" 2001:db8::1/32 "), Ok(IpNetwork::V6("2001:db8::".parse().unwrap(), 32)));
+        assert_eq!(parse_ip_or_cidr("::ffff:203.0.113.7"), Ok(IpNetwork::V4(Ipv4Addr::new(203, 0, 113, 7), 32)));
+        assert_eq!(parse_ip_or_cidr("::ffff:0.0.0.0/96"), Err("IPv4-mapped /96 covers every IPv4 address"));
+        assert_eq!(parse_ip_or_cidr("::ffff:0.0.0.0/97"), Ok(IpNetwork::V4(Ipv4Addr::UNSPECIFIED, 1)));
+        assert_eq!(parse_ip_or_cidr("10.0.0.1:"), Err("invalid IPv6 ip/cidr"));
+        assert_eq!(parse_ip_or_cidr("10.0.0.0/33"), Err("invalid IPv4 ip/cidr"));
+        assert_eq!(parse_ip_or_cidr(""), Err("invalid IPv4 ip/cidr"));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("as64*", "as64500"));
+        assert!(glob_match("as64*", "as64"));
+        assert!(glob_match("r?", "ru"));
+        assert!(!glob_match("r?", "rus"));
+        assert!(glob_match("*cloud*", "as13335-cloudflare"));
+        assert!(glob_match("as13335", "as13335"));
+        assert!(!glob_match("as13335", "as133350"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("a*b", "acbd"));
+    }
+
+    #[test]
+    fn test_ipv6_non_global_scope() {
+        assert_eq!(ipv6_non_global_scope("fe80::1%eth0"), Some(Ipv6Scope::Zoned));
+        assert_eq!(ipv6_non_global_scope("fe80::1%eth0/64"), Some(Ipv6Scope::Zoned));
+        assert_eq!(ipv6_non_global_scope("fe80::1"), Some(Ipv6Scope::LinkLocal));
+        assert_eq!(ipv6_non_global_scope("febf::/16"), Some(Ipv6Scope::LinkLocal));
+        assert_eq!(ipv6_non_global_scope("ff02::1"), Some(Ipv6Scope::Multicast));
+        assert_eq!(ipv6_non_global_scope("2001:db8::1"), None);
+        assert_eq!(ipv6_non_global_scope("fec0::1"), None);
+        assert_eq!(ipv6_non_global_scope("not-an-ip"), None);
+    }
+
+    #[test]
+    fn test_parse_ipv4_trims_whitespace() {
+        assert_eq!(parse_ipv4_ip_or_cidr(" 10.0.0.1 "), Some((Ipv4Addr::new(10, 0, 0, 1), 32)));
+        assert_eq!(parse_ipv4_ip_or_cidr("\t10.0.0.0 / 8\n"), Some((Ipv4Addr::new(10, 0, 0, 0), 8)));
+    }
+
+    #[test]
+    fn test_parse_ipv4_rejects_malformed_cidrs() {
+        assert_eq!(parse_ipv4_ip_or_cidr(""), None);
+        assert_eq!(parse_ipv4_ip_or_cidr("   "), None);
+        assert_eq!(parse_ipv4_ip_or_cidr("10.0.0.0//8"), None);
+        assert_eq!(parse_ipv4_ip_or_cidr("10.0.0.0/8/8"), None);
+        assert_eq!(parse_ipv4_ip_or_cidr("10.0.0.0/"), None);
+        assert_eq!(parse_ipv4_ip_or_cidr("/8"), None);
+        assert_eq!(parse_ipv4_ip_or_cidr("10.0.0.0/33"), None);
+        assert_eq!(parse_ipv4_ip_or_cidr("10.0.0.0/256"), None);
+        assert_eq!(parse_ipv4_ip_or_cidr("10.0.0.0/-1"), None);
+        assert_eq!(parse_ipv4_ip_or_cidr("10.0.0"), None);
+        assert_eq!(parse_ipv4_ip_or_cidr("256.0.0.1"), None);
+    }
+
+    #[test]
+    fn test_parse_ipv4_masks_host_bits() {
+        assert_eq!(parse_ipv4_ip_or_cidr("10.1.2.3/8"), Some((Ipv4Addr::new(10, 0, 0, 0), 8)));
+        assert_eq!(parse_ipv4_ip_or_cidr("10.1.2.3/0"), Some((Ipv4Addr::UNSPECIFIED, 0)));
+        assert_eq!(parse_ipv4_ip_or_cidr("10.1.2.3/32"), Some((Ipv4Addr::new(10, 1, 2, 3), 32)));
+    }
+
+    #[test]
+    fn test_parse_ipv6_trims_and_rejects_malformed() {
+        assert_eq!(parse_ipv6_ip_or_cidr(" 2001:db8::1 "), Some(("2001:db8::1".parse().unwrap(), 128)));
+        assert_eq!(parse_ipv6_ip_or_cidr("2001:db8:: / 32"), Some(("2001:db8::".parse().unwrap(), 32)));
+        assert_eq!(parse_ipv6_ip_or_cidr(""), None);
+        assert_eq!(parse_ipv6_ip_or_cidr("2001:db8:://32"), None);
+        assert_eq!(parse_ipv6_ip_or_cidr("2001:db8::/32/32"), None);
+        assert_eq!(parse_ipv6_ip_or_cidr("2001:db8::/"), None);
+        assert_eq!(parse_ipv6_ip_or_cidr("2001:db8::/129"), None);
+        assert_eq!(parse_ipv6_ip_or_cidr("2001:db8:::1"), None);
+        assert_eq!(parse_ipv6_ip_or_cidr("::/0"), Some((Ipv6Addr::UNSPECIFIED, 0)));
+    }
+
+    #[test]
+    fn test_parse_ipv6_masks_host_bits() {
+        let net: Ipv6Addr = "2001:db8::".parse().unwrap();
+        assert_eq!(parse_ipv6_ip_or_cidr("2001:db8::1/64"), Some((net, 64)));
+        assert_eq!(parse_ipv6_ip_or_cidr("2001:db8::2/64"), Some((net, 64)));
+        assert_eq!(parse_ipv6_ip_or_cidr("2001:db8::1/0"), Some((Ipv6Addr::UNSPECIFIED, 0)));
+        assert_eq!(parse_ipv6_ip_or_cidr("2001:db8::1/128"), Some(("2001:db8::1".parse().unwrap(), 128)));
+    }
+
+    #[test]
+    fn test_parsers_route_by_family() {
+        // Dotted IPv4 embedded in IPv6 notation is an IPv6 entry
+        assert_eq!(parse_ipv4_ip_or_cidr("::ffff:192.0.2.1"), None);
+        assert_eq!(
+            parse_ipv6_ip_or_cidr("::ffff:192.0.2.1"),
+            Some(("::ffff:192.0.2.1".parse().unwrap(), 128))
+        );
+        assert_eq!(parse_ipv6_ip_or_cidr("192.0.2.1"), None);
+        assert_eq!(parse_ipv6_ip_or_cidr("192.0.2.0/24"), None);
+    }
+
+    #[test]
+    fn test_ipv4_mapped_ipv6_prefix_translation() {
+        let mapped = |s: &str| {
+            let (net, prefix) = parse_ipv6_ip_or_cidr(s).unwrap();
+            ipv4_from_mapped_ipv6(net, prefix)
+        };
+
+        assert_eq!(mapped("::ffff:192.168.1.1"), Some((Ipv4Addr::new(192, 168, 1, 1), 32)));
+        assert_eq!(mapped("::ffff:192.168.1.0/120"), Some((Ipv4Addr::new(192, 168, 1, 0), 24)));
+        assert_eq!(mapped("::ffff:10.0.0.0/104"), Some((Ipv4Addr::new(10, 0, 0, 0), 8)));
+        // host bits are masked after translation
+        assert_eq!(mapped("::ffff:10.1.2.3/104"), Some((Ipv4Addr::new(10, 0, 0, 0), 8)));
+        // edge prefixes
+        assert_eq!(mapped("::ffff:1.2.3.4/128"), Some((Ipv4Addr::new(1, 2, 3, 4), 32)));
+        assert_eq!(mapped("::ffff:0.0.0.0/96"), Some((Ipv4Addr::UNSPECIFIED, 0)));
+        assert_eq!(mapped("::ffff:0.0.0.0/95"), None);
+        // IPv4-compatible form
+        assert_eq!(mapped("::192.168.1.1"), Some((Ipv4Addr::new(192, 168, 1, 1), 32)));
+        assert_eq!(mapped("::10.0.0.0/104"), Some((Ipv4Addr::new(10, 0, 0, 0), 8)));
+        // native IPv6, unspecified and loopback stay IPv6
+        assert_eq!(mapped("2001:db8::1"), None);
+        assert_eq!(mapped("::"), None);
+        assert_eq!(mapped("::1"), None);
+    }
+
+    #[test]
+    fn test_parse_ipv4_wildcard() {
+        let v4 = |a: [u8; 4], p: u32| Ok((Ipv4Addr::from(a), p));
+
+        assert_eq!(parse_ipv4_wildcard("10.0.*.*"), v4([10, 0, 0, 0], 16));
+        assert_eq!(parse_ipv4_wildcard("192.168.1.*"), v4([192, 168, 1, 0], 24));
+        assert_eq!(parse_ipv4_wildcard("10.*.*.*"), v4([10, 0, 0, 0], 8));
+        assert_eq!(parse_ipv4_wildcard("*.*.*.*"), v4([0, 0, 0, 0], 0));
+        assert_eq!(parse_ipv4_wildcard(" 172.16.*.* "), v4([172, 16, 0, 0], 16));
+    }
+
+    #[test]
+    fn test_parse_ipv4_wildcard_rejects_invalid_placements() {
+        assert_eq!(parse_ipv4_wildcard("10.*.5.*"), Err("wildcards are not contiguous and right-aligned"));
+        assert_eq!(parse_ipv4_wildcard("*.0.0.1"), Err("wildcards are not contiguous and right-aligned"));
+        assert_eq!(parse_ipv4_wildcard("10.0.1*.*"), Err("malformed octet in wildcard pattern"));
+        assert_eq!(parse_ipv4_wildcard("300.0.*.*"), Err("malformed octet in wildcard pattern"));
+        assert_eq!(parse_ipv4_wildcard("10.*.*"), Err("wildcard pattern without four octets"));
+        assert_eq!(parse_ipv4_wildcard("10.0.*.*/16"), Err("wildcard pattern with a prefix length"));
+    }
+
+    #[test]
+    fn test_parse_ip_range_decomposition() {
+        let v4 = |a: [u8; 4], p: u32| (Ipv4Addr::from(a), p);
+
+        assert_eq!(
+            parse_ip_range("192.168.1.10-192.168.1.50"),
+            Ok(RangeCidrs::V4(vec![
+                v4([192, 168, 1, 10], 31),
+                v4([192, 168, 1, 12], 30),
+                v4([192, 168, 1, 16], 28),
+                v4([192, 168, 1, 32], 28),
+                v4([192, 168, 1, 48], 31),
+                v4([192, 168, 1, 50], 32),
+            ]))
+        );
+        assert_eq!(parse_ip_range("10.0.0.0 - 10.0.0.255"), Ok(RangeCidrs::V4(vec![v4([10, 0, 0, 0], 24)])));
+        assert_eq!(parse_ip_range("1.2.3.4-1.2.3.4"), Ok(RangeCidrs::V4(vec![v4([1, 2, 3, 4], 32)])));
+        assert_eq!(
+            parse_ip_range("0.0.0.0-255.255.255.255"),
+            Ok(RangeCidrs::V4(vec![v4([0, 0, 0, 0], 0)]))
+        );
+        assert_eq!(
+            parse_ip_range("255.255.255.254-255.255.255.255"),
+            Ok(RangeCidrs::V4(vec![v4([255, 255, 255, 254], 31)]))
+        );
+
+        assert_eq!(
+            parse_ip_range("2001:db8::-2001:db8::ffff"),
+            Ok(RangeCidrs::V6(vec![("2001:db8::".parse().unwrap(), 112)]))
+        );
+        assert_eq!(
+            parse_ip_range("2001:db8::1-2001:db8::2"),
+            Ok(RangeCidrs::V6(vec![
+                ("2001:db8::1".parse().unwrap(), 128),
+                ("2001:db8::2".parse().unwrap(), 128),
+            ]))
+        );
+        assert_eq!(
+            parse_ip_range(":: - ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"),
+            Ok(RangeCidrs::V6(vec![(Ipv6Addr::UNSPECIFIED, 0)]))
+        );
+    }
+
+    #[test]
+    fn test_parse_ip_range_rejects_invalid() {
+        assert!(parse_ip_range("192.168.1.50-192.168.1.10").is_err());
+        assert!(parse_ip_range("192.168.1.1-2001:db8::1").is_err());
+        assert!(parse_ip_range("192.168.1.1-").is_err());
+        assert!(parse_ip_range("192.168.1.1-192.168.1.2-192.168.1.3").is_err());
+    }
+}
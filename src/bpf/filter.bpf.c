@@ -10,6 +10,9 @@
 #define IP_MF           0x2000
 #define IP_OFFSET       0x1FFF
 #define NEXTHDR_FRAGMENT    44
+#define NSEC_PER_SEC        1000000000ULL
+#define TC_ACT_OK           0
+#define TC_ACT_SHOT         2
 
 // TCP fingerprinting constants
 #define TCP_FINGERPRINT_MAX_ENTRIES    10000
@@ -39,6 +42,20 @@ struct lpm_key_v6 {
     __u8 addr[16];
 };
 
+// Port-scoped ban keys: the destination port comes first so one trie lookup matches
+// the exact port and the longest address prefix; prefixlen is 16 + address prefix
+struct lpm_port_key {
+    __u32 prefixlen;
+    __be16 port;
+    __u8 addr[4];
+};
+
+struct lpm_port_key_v6 {
+    __u32 prefixlen;
+    __be16 port;
+    __u8 addr[16];
+};
+
 // TCP fingerprinting structures
 struct tcp_fingerprint_key {
     __be32 src_ip;      // Source IP address (IPv4)
@@ -64,6 +81,13 @@ struct tcp_fingerprint_data {
     __u8 options[TCP_FP_MAX_OPTION_LEN]; // TCP options data
 };
 
+// Token bucket of one rate-limited source; tokens are kept in nanopackets so the
+// refill needs no division
+struct token_bucket {
+    __u64 tokens;
+    __u64 last_refill_ns;
+};
+
 struct tcp_syn_stats {
     __u64 total_syns;
     __u64 unique_fingerprints;
@@ -76,7 +100,7 @@ struct {
 	__uint(max_entries, CITADEL_IP_MAP_MAX);
     __uint(map_flags, BPF_F_NO_PREALLOC);
 	__type(key, struct lpm_key);           // IPv4 address in network byte order
-	__type(value, ip_flag_t);     // presence flag (1)
+	__type(value, struct ban_value);       // source flags and prefix length
 } banned_ips SEC(".maps");
 
 struct {
@@ -93,7 +117,7 @@ struct {
 	__uint(max_entries, CITADEL_IP_MAP_MAX);
     __uint(map_flags, BPF_F_NO_PREALLOC);
 	__type(key, struct lpm_key_v6);
-	__type(value, ip_flag_t);
+	__type(value, struct ban_value);
 } banned_ips_v6 SEC(".maps");
 
 struct {
@@ -104,6 +128,130 @@ struct {
 	__type(value, ip_flag_t);
 } recently_banned_ips_v6 SEC(".maps");
 
+// Swap slots for the banned maps. While userspace rewrites a large change set into
+// banned_ips, it parks a fully populated copy of the new rule set in slot 0 and
+// lookups read that copy instead, so a half-written trie is never consulted. The
+// slot is emptied again once banned_ips holds the same entries.
+struct banned_ips_inner {
+	__uint(type, BPF_MAP_TYPE_LPM_TRIE);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, struct lpm_key);
+	__type(value, struct ban_value);
+};
+
+struct banned_ips_v6_inner {
+	__uint(type, BPF_MAP_TYPE_LPM_TRIE);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, struct lpm_key_v6);
+	__type(value, struct ban_value);
+};
+
+struct {
+	__uint(type, BPF_MAP_TYPE_ARRAY_OF_MAPS);
+	__uint(max_entries, 1);
+	__type(key, __u32);
+	__array(values, struct banned_ips_inner);
+} banned_ips_swap SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_ARRAY_OF_MAPS);
+	__uint(max_entries, 1);
+	__type(key, __u32);
+	__array(values, struct banned_ips_v6_inner);
+} banned_ips_v6_swap SEC(".maps");
+
+// Port-scoped ban maps: drop TCP/UDP traffic to one destination port only
+struct {
+	__uint(type, BPF_MAP_TYPE_LPM_TRIE);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, struct lpm_port_key);
+	__type(value, ip_flag_t);
+} banned_ip_ports SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_LPM_TRIE);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, struct lpm_port_key_v6);
+	__type(value, ip_flag_t);
+} banned_ip_ports_v6 SEC(".maps");
+
+// Rate-limit rules: packets per second allowed from each source inside the prefix
+struct {
+	__uint(type, BPF_MAP_TYPE_LPM_TRIE);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, struct lpm_key);
+	__type(value, __u32);
+} rate_limited_ips SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_LPM_TRIE);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, struct lpm_key_v6);
+	__type(value, __u32);
+} rate_limited_ips_v6 SEC(".maps");
+
+// Token bucket state per rate-limited source address
+struct {
+	__uint(type, BPF_MAP_TYPE_LRU_HASH);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+	__type(key, __be32);
+	__type(value, struct token_bucket);
+} rate_limit_buckets SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_LRU_HASH);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+	__type(key, __u8[16]);
+	__type(value, struct token_bucket);
+} rate_limit_buckets_v6 SEC(".maps");
+
+// Allow-list maps, valued with the entry's prefix length. A match bypasses every
+// other check unless the precedence map lets a matching block entry win.
+struct {
+	__uint(type, BPF_MAP_TYPE_LPM_TRIE);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, struct lpm_key);
+	__type(value, ip_flag_t);
+} allowed_ips SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_LPM_TRIE);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, struct lpm_key_v6);
+	__type(value, ip_flag_t);
+} allowed_ips_v6 SEC(".maps");
+
+// One of the PRECEDENCE_* modes, written by userspace after load
+struct {
+	__uint(type, BPF_MAP_TYPE_ARRAY);
+	__uint(max_entries, 1);
+	__type(key, __u32);
+	__type(value, __u32);
+} precedence SEC(".maps");
+
+// Per-rule drop counters keyed by the matched banned map entry
+struct {
+	__uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+	__type(key, struct lpm_key);
+	__type(value, __u64);
+} rule_hits SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+	__uint(max_entries, CITADEL_IP_MAP_MAX);
+	__type(key, struct lpm_key_v6);
+	__type(value, __u64);
+} rule_hits_v6 SEC(".maps");
+
 // Remove dynptr helpers, not used in XDP manual parsing
 // extern int bpf_dynptr_from_skb(struct __sk_buff *skb, __u64 flags,
 //                   struct bpf_dynptr *ptr__uninit) __ksym;
@@ -211,6 +359,73 @@ static void *parse_and_advance(void **cursor, void *end, __u32 len)
     return current;
 }
 
+/*
+ * Reads the TCP/UDP destination port of the L4 header at `l4` without moving the
+ * cursor. Returns 0 and sets `port` (network byte order), or -1 for other protocols.
+ */
+static int parse_dest_port(void *l4, void *end, __u8 protocol, __be16 *port)
+{
+    if (protocol != IPPROTO_TCP && protocol != IPPROTO_UDP)
+        return -1;
+    // TCP and UDP headers both start with the source and destination ports
+    if (l4 + 2 * sizeof(__be16) > end)
+        return -1;
+    *port = ((__be16 *)l4)[1];
+    return 0;
+}
+
+/*
+ * Start of the L4 header of an IPv4 packet, past any IP options. NULL for a header
+ * length below the minimum and for non-first fragments, which carry no L4 header.
+ */
+static __always_inline void *ipv4_l4_header(struct iphdr *iph)
+{
+    __u32 hdr_len = iph->ihl * 4;
+
+    if (hdr_len < sizeof(*iph) || (iph->frag_off & bpf_htons(IP_OFFSET)))
+        return NULL;
+    return (void *)iph + hdr_len;
+}
+
+/*
+ * Takes one packet from the source's token bucket, refilling it at `pps` packets per
+ * second up to a one second burst. Returns true when the packet may pass. A source
+ * seen for the first time starts with a full bucket. Updates from different CPUs are
+ * not serialized, so the enforced rate is approximate under contention.
+ */
+static __always_inline bool take_rate_limit_token(void *buckets, const void *source, __u32 pps)
+{
+    if (pps == 0)
+        return false;
+
+    __u64 now = bpf_ktime_get_ns();
+    __u64 capacity = (__u64)pps * NSEC_PER_SEC;
+    struct token_bucket *bucket = bpf_map_lookup_elem(buckets, source);
+    if (!bucket) {
+        struct token_bucket fresh = {
+            .tokens = capacity - NSEC_PER_SEC,
+            .last_refill_ns = now,
+        };
+        bpf_map_update_elem(buckets, source, &fresh, BPF_ANY);
+        return true;
+    }
+
+    __u64 elapsed = now - bucket->last_refill_ns;
+    if (elapsed > NSEC_PER_SEC)
+        elapsed = NSEC_PER_SEC;
+    __u64 tokens = bucket->tokens + elapsed * pps;
+    if (tokens > capacity)
+        tokens = capacity;
+    bucket->last_refill_ns = now;
+
+    if (tokens < NSEC_PER_SEC) {
+        bucket->tokens = tokens;
+        return false;
+    }
+    bucket->tokens = tokens - NSEC_PER_SEC;
+    return true;
+}
+
 /*
  * Helper functions for incrementing statistics counters
  */
@@ -293,6 +508,143 @@ static void increment_dropped_ipv6_address(struct in6_addr ip_addr)
     }
 }
 
+static __always_inline void increment_percpu_counter(void *map, const void *key)
+{
+    // Per-CPU slot, so no atomic is needed
+    __u64 *value = bpf_map_lookup_elem(map, key);
+    if (value) {
+        *value += 1;
+    } else {
+        __u64 initial_count = 1;
+        bpf_map_update_elem(map, key, &initial_count, BPF_NOEXIST);
+    }
+}
+
+// Banned map lookups go through the swap slot when userspace parked a map there.
+// Datapath escalations keep writing to the live maps, which take over again as
+// soon as the slot is emptied.
+static __always_inline struct ban_value *lookup_banned(struct lpm_key *key)
+{
+    __u32 slot = 0;
+    void *swapped = bpf_map_lookup_elem(&banned_ips_swap, &slot);
+    if (swapped)
+        return bpf_map_lookup_elem(swapped, key);
+    return bpf_map_lookup_elem(&banned_ips, key);
+}
+
+static __always_inline struct ban_value *lookup_banned_v6(struct lpm_key_v6 *key)
+{
+    __u32 slot = 0;
+    void *swapped = bpf_map_lookup_elem(&banned_ips_v6_swap, &slot);
+    if (swapped)
+        return bpf_map_lookup_elem(swapped, key);
+    return bpf_map_lookup_elem(&banned_ips_v6, key);
+}
+
+static __always_inline int ban_is_log_only(const struct ban_value *ban)
+{
+    return (ban->flag & BAN_ACTION_LOG_ONLY) &&
+           !(ban->flag & (BAN_SOURCE_MANUAL | BAN_SOURCE_TEMPORARY | BAN_SOURCE_DATAPATH));
+}
+
+// Add the datapath's reference to the host entry of a source in a live banned map.
+// The flags other sources hold on an exact entry are kept, so a log-only sync entry
+// stays tagged as the sync's; an LPM lookup also returns covering entries, which are
+// told apart by their prefix length and left alone.
+static __always_inline void escalate_to_ban(void *map, const void *key, __u8 prefixlen)
+{
+    struct ban_value value = { .flag = BAN_SOURCE_DATAPATH, .prefixlen = prefixlen };
+    struct ban_value *existing = bpf_map_lookup_elem(map, key);
+    if (existing && existing->prefixlen == prefixlen)
+        value.flag |= existing->flag;
+    bpf_map_update_elem(map, key, &value, BPF_ANY);
+}
+
+// Longest enforced banned entry covering `key`. A log-only match does not hide a
+// broader entry another source enforces: the lookup is repeated below its prefix
+// until an enforced entry or none is found, and the narrowest log-only match is
+// handed back in `log_only` for counting. Each round shortens the prefix, so the
+// loop ends within 33 rounds.
+static __always_inline struct ban_value *lookup_enforced(struct lpm_key *key, struct ban_value **log_only)
+{
+    struct lpm_key probe = *key;
+    *log_only = NULL;
+    for (int i = 0; i <= 32; i++) {
+        struct ban_value *ban = lookup_banned(&probe);
+        if (!ban || !ban_is_log_only(ban))
+            return ban;
+        if (!*log_only)
+            *log_only = ban;
+        if (ban->prefixlen == 0)
+            return NULL;
+        probe.prefixlen = ban->prefixlen - 1;
+    }
+    return NULL;
+}
+
+static __always_inline struct ban_value *lookup_enforced_v6(struct lpm_key_v6 *key, struct ban_value **log_only)
+{
+    struct lpm_key_v6 probe = *key;
+    *log_only = NULL;
+    for (int i = 0; i <= 128; i++) {
+        struct ban_value *ban = lookup_banned_v6(&probe);
+        if (!ban || !ban_is_log_only(ban))
+            return ban;
+        if (!*log_only)
+            *log_only = ban;
+        if (ban->prefixlen == 0)
+            return NULL;
+        probe.prefixlen = ban->prefixlen - 1;
+    }
+    return NULL;
+}
+
+// Whether the matched allow entry, valued with its prefix length, lets the source
+// through despite an enforced block entry. Equal prefixes go to the allow entry.
+static __always_inline int allow_overrides(const ip_flag_t *allow, const struct ban_value *ban)
+{
+    __u32 zero = 0;
+    __u32 *mode = bpf_map_lookup_elem(&precedence, &zero);
+    if (!mode || *mode == PRECEDENCE_ALLOW_WINS)
+        return 1;
+    if (*mode == PRECEDENCE_BLOCK_WINS)
+        return 0;
+    return *allow >= ban->prefixlen;
+}
+
+static void increment_rule_hits(const struct ban_value *ban, __be32 saddr)
+{
+    __u32 prefixlen = ban->prefixlen;
+    if (prefixlen > 32)
+        return;
+
+    struct lpm_key rule = {
+        .prefixlen = prefixlen,
+        .addr = prefixlen ? saddr & bpf_htonl(~0U << (32 - prefixlen)) : 0,
+    };
+    increment_percpu_counter(&rule_hits, &rule);
+}
+
+static void increment_rule_hits_v6(const struct ban_value *ban, const struct in6_addr *saddr)
+{
+    __u32 prefixlen = ban->prefixlen;
+    if (prefixlen > 128)
+        return;
+
+    struct lpm_key_v6 rule = {
+        .prefixlen = prefixlen,
+    };
+    const __u8 *addr = (const __u8 *)saddr;
+    for (int i = 0; i < 16; i++) {
+        __u32 bits = i * 8;
+        if (prefixlen >= bits + 8)
+            rule.addr[i] = addr[i];
+        else if (prefixlen > bits)
+            rule.addr[i] = addr[i] & (0xff << (8 - (prefixlen - bits)));
+    }
+    increment_percpu_counter(&rule_hits_v6, &rule);
+}
+
 /*
  * TCP fingerprinting helper functions
  */
@@ -458,15 +810,14 @@ static void record_tcp_fingerprint(__be32 src_ip, __be16 src_port,
     }
 }
 
-SEC("xdp")
-int arxignis_xdp_filter(struct xdp_md *ctx)
+// Shared by the XDP and TC entry points; returns XDP_PASS or XDP_DROP
+static __always_inline int filter_packet(void *data, void *data_end)
 {
     // This filter is designed to only block incoming traffic
     // It should be attached only to ingress hooks, not egress
     // The filtering logic below blocks packets based on source IP addresses
 
-    void *data_end = (void *)(long)ctx->data_end;
-    void *cursor = (void *)(long)ctx->data;
+    void *cursor = data;
 
     // Debug: Count all packets
     __u32 zero = 0;
@@ -494,14 +845,49 @@ int arxignis_xdp_filter(struct xdp_md *ctx)
             .addr = iph->saddr,
         };
 
-        if (bpf_map_lookup_elem(&banned_ips, &key)) {
+        // Allow-listed sources short-circuit every block check, unless the configured
+        // precedence hands the decision to a matching enforced block entry
+        ip_flag_t *allow = bpf_map_lookup_elem(&allowed_ips, &key);
+        struct ban_value *log_only;
+        struct ban_value *ban = lookup_enforced(&key, &log_only);
+        if (allow && (!ban || allow_overrides(allow, ban))) {
+            return XDP_PASS;
+        }
+
+        if (log_only) {
+            // Counted only; an enforced covering entry still drops below
+            increment_rule_hits(log_only, iph->saddr);
+        }
+        if (ban) {
             increment_ipv4_banned_stats();
+            increment_rule_hits(ban, iph->saddr);
             increment_total_packets_dropped();
             increment_dropped_ipv4_address(iph->saddr);
             //bpf_printk("XDP: BLOCKED incoming permanently banned IPv4 %pI4", &iph->saddr);
             return XDP_DROP;
         }
 
+        struct lpm_port_key port_key = {
+            .prefixlen = 16 + 32,
+        };
+        __builtin_memcpy(port_key.addr, &iph->saddr, 4);
+        void *l4 = ipv4_l4_header(iph);
+        if (l4 && parse_dest_port(l4, data_end, iph->protocol, &port_key.port) == 0 &&
+            bpf_map_lookup_elem(&banned_ip_ports, &port_key)) {
+            increment_ipv4_banned_stats();
+            increment_total_packets_dropped();
+            increment_dropped_ipv4_address(iph->saddr);
+            return XDP_DROP;
+        }
+
+        __be32 saddr = iph->saddr;
+        __u32 *pps = bpf_map_lookup_elem(&rate_limited_ips, &key);
+        if (pps && !take_rate_limit_token(&rate_limit_buckets, &saddr, *pps)) {
+            increment_total_packets_dropped();
+            increment_dropped_ipv4_address(iph->saddr);
+            return XDP_DROP;
+        }
+
         if (bpf_map_lookup_elem(&recently_banned_ips, &key)) {
             increment_ipv4_recently_banned_stats();
             // Block UDP and ICMP from recently banned IPs, but allow DNS
@@ -511,8 +897,7 @@ int arxignis_xdp_filter(struct xdp_md *ctx)
                     return XDP_PASS; // Allow DNS responses
                 }
                 // Block other UDP traffic
-                ip_flag_t one = 1;
-                bpf_map_update_elem(&banned_ips, &key, &one, BPF_ANY);
+                escalate_to_ban(&banned_ips, &key, 32);
                 bpf_map_delete_elem(&recently_banned_ips, &key);
                 increment_total_packets_dropped();
                 increment_dropped_ipv4_address(iph->saddr);
@@ -520,8 +905,7 @@ int arxignis_xdp_filter(struct xdp_md *ctx)
                 return XDP_DROP;
             }
             if (iph->protocol == IPPROTO_ICMP) {
-                ip_flag_t one = 1;
-                bpf_map_update_elem(&banned_ips, &key, &one, BPF_ANY);
+                escalate_to_ban(&banned_ips, &key, 32);
                 bpf_map_delete_elem(&recently_banned_ips, &key);
                 increment_total_packets_dropped();
                 increment_dropped_ipv4_address(iph->saddr);
@@ -540,8 +924,7 @@ int arxignis_xdp_filter(struct xdp_md *ctx)
                     }
 
                     if (tcph->fin || tcph->rst) {
-                        ip_flag_t one = 1;
-                        bpf_map_update_elem(&banned_ips, &key, &one, BPF_ANY);
+                        escalate_to_ban(&banned_ips, &key, 32);
                         bpf_map_delete_elem(&recently_banned_ips, &key);
                         increment_total_packets_dropped();
                         increment_dropped_ipv4_address(iph->saddr);
@@ -585,14 +968,47 @@ int arxignis_xdp_filter(struct xdp_md *ctx)
         };
         __builtin_memcpy(key6.addr, &ip6h->saddr, 16);
 
-        if (bpf_map_lookup_elem(&banned_ips_v6, &key6)) {
+        // Allow-listed sources short-circuit every block check, unless the configured
+        // precedence hands the decision to a matching enforced block entry
+        ip_flag_t *allow6 = bpf_map_lookup_elem(&allowed_ips_v6, &key6);
+        struct ban_value *log_only6;
+        struct ban_value *ban6 = lookup_enforced_v6(&key6, &log_only6);
+        if (allow6 && (!ban6 || allow_overrides(allow6, ban6))) {
+            return XDP_PASS;
+        }
+
+        if (log_only6) {
+            // Counted only; an enforced covering entry still drops below
+            increment_rule_hits_v6(log_only6, &ip6h->saddr);
+        }
+        if (ban6) {
             increment_ipv6_banned_stats();
+            increment_rule_hits_v6(ban6, &ip6h->saddr);
             increment_total_packets_dropped();
             increment_dropped_ipv6_address(ip6h->saddr);
             //bpf_printk("XDP: BLOCKED incoming permanently banned IPv6");
             return XDP_DROP;
         }
 
+        struct lpm_port_key_v6 port_key6 = {
+            .prefixlen = 16 + 128,
+        };
+        __builtin_memcpy(port_key6.addr, &ip6h->saddr, 16);
+        if (parse_dest_port((void *)(ip6h + 1), data_end, ip6h->nexthdr, &port_key6.port) == 0 &&
+            bpf_map_lookup_elem(&banned_ip_ports_v6, &port_key6)) {
+            increment_ipv6_banned_stats();
+            increment_total_packets_dropped();
+            increment_dropped_ipv6_address(ip6h->saddr);
+            return XDP_DROP;
+        }
+
+        __u32 *pps6 = bpf_map_lookup_elem(&rate_limited_ips_v6, &key6);
+        if (pps6 && !take_rate_limit_token(&rate_limit_buckets_v6, key6.addr, *pps6)) {
+            increment_total_packets_dropped();
+            increment_dropped_ipv6_address(ip6h->saddr);
+            return XDP_DROP;
+        }
+
         if (bpf_map_lookup_elem(&recently_banned_ips_v6, &key6)) {
             increment_ipv6_recently_banned_stats();
             // Block UDP and ICMP from recently banned IPv6 IPs, but allow DNS
@@ -602,8 +1018,7 @@ int arxignis_xdp_filter(struct xdp_md *ctx)
                     return XDP_PASS; // Allow DNS responses
                 }
                 // Block other UDP traffic
-                ip_flag_t one = 1;
-                bpf_map_update_elem(&banned_ips_v6, &key6, &one, BPF_ANY);
+                escalate_to_ban(&banned_ips_v6, &key6, 128);
                 bpf_map_delete_elem(&recently_banned_ips_v6, &key6);
                 increment_total_packets_dropped();
                 increment_dropped_ipv6_address(ip6h->saddr);
@@ -611,8 +1026,7 @@ int arxignis_xdp_filter(struct xdp_md *ctx)
                 return XDP_DROP;
             }
             if (ip6h->nexthdr == 58) { // 58 = IPPROTO_ICMPV6
-                ip_flag_t one = 1;
-                bpf_map_update_elem(&banned_ips_v6, &key6, &one, BPF_ANY);
+                escalate_to_ban(&banned_ips_v6, &key6, 128);
                 bpf_map_delete_elem(&recently_banned_ips_v6, &key6);
                 increment_total_packets_dropped();
                 increment_dropped_ipv6_address(ip6h->saddr);
@@ -624,8 +1038,7 @@ int arxignis_xdp_filter(struct xdp_md *ctx)
                 struct tcphdr *tcph = parse_and_advance(&cursor, data_end, sizeof(*tcph));
                 if (tcph) {
                     if (tcph->fin || tcph->rst) {
-                        ip_flag_t one = 1;
-                        bpf_map_update_elem(&banned_ips_v6, &key6, &one, BPF_ANY);
+                        escalate_to_ban(&banned_ips_v6, &key6, 128);
                         bpf_map_delete_elem(&recently_banned_ips_v6, &key6);
                         increment_total_packets_dropped();
                         increment_dropped_ipv6_address(ip6h->saddr);
@@ -714,4 +1127,20 @@ int arxignis_xdp_filter(struct xdp_md *ctx)
     // return XDP_ABORTED;
 }
 
+SEC("xdp")
+int arxignis_xdp_filter(struct xdp_md *ctx)
+{
+    return filter_packet((void *)(long)ctx->data, (void *)(long)ctx->data_end);
+}
+
+// clsact ingress variant for NICs and kernels where XDP is unavailable or unwanted.
+// Only the linear part of the skb is inspected, which holds the headers of ordinary
+// ingress packets.
+SEC("tc")
+int arxignis_tc_filter(struct __sk_buff *skb)
+{
+    int verdict = filter_packet((void *)(long)skb->data, (void *)(long)skb->data_end);
+    return verdict == XDP_DROP ? TC_ACT_SHOT : TC_ACT_OK;
+}
+
 char _license[] SEC("license") = "GPL";
@@ -9,3 +9,27 @@
 // Map value is a simple flag (present = 1)
 typedef __u8 ip_flag_t;
 
+
+// Sources referencing a banned map entry, one bit each in ban_value.flag. Userspace
+// only deletes an entry once no source is left; the datapath only tests presence.
+#define BAN_SOURCE_SYNC      0x01
+#define BAN_SOURCE_MANUAL    0x02
+#define BAN_SOURCE_TEMPORARY 0x04
+#define BAN_SOURCE_DATAPATH  0x08
+
+// Qualifies the sync's reference: a match on an entry no other source bans is counted
+// in rule_hits and passed instead of dropped, for trialing rules before enforcing them
+#define BAN_ACTION_LOG_ONLY  0x80
+
+// Which entry decides when a source matches both an allow entry and an enforced block
+// entry, read from slot 0 of the precedence map. The zeroed default lets the allow win.
+#define PRECEDENCE_ALLOW_WINS    0
+#define PRECEDENCE_MOST_SPECIFIC 1
+#define PRECEDENCE_BLOCK_WINS    2
+
+// Banned map value: the source flags plus the entry's own prefix length, so the
+// datapath can rebuild the matched LPM key when counting per-rule hits
+struct ban_value {
+    ip_flag_t flag;
+    __u8 prefixlen;
+};
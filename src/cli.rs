@@ -1,7 +1,8 @@
-use std::{net::SocketAddr, path::PathBuf, env};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, env};
+use std::os::unix::fs::PermissionsExt;
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
@@ -24,6 +25,12 @@ pub struct Config {
     pub tcp_fingerprint: TcpFingerprintConfig,
     #[serde(default)]
     pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub access_rules: AccessRulesConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub control_api: ControlApiConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -96,14 +103,70 @@ pub struct RedisConfig {
 pub struct NetworkConfig {
     pub iface: String,
     pub ifaces: Vec<String>,
+    /// Interfaces (or `*`/`?` patterns) the filter is never attached to, even when listed
+    #[serde(default)]
+    pub exclude_ifaces: Vec<String>,
     pub disable_xdp: bool,
+    /// Filter attach point: auto, native, generic or tc
+    #[serde(default = "default_attach_mode")]
+    pub attach_mode: String,
+    /// Creation flags of the filter's hash maps, applied before the skeleton is loaded
+    #[serde(default)]
+    pub bpf_maps: BpfMapTuning,
+}
+
+/// Memory/latency tradeoffs of the BPF hash maps. The LPM tries holding the rules are
+/// always created without preallocation, which the kernel requires for that map type.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BpfMapTuning {
+    /// Preallocate every entry of the per-CPU rule hit counters up front: no allocation
+    /// on the packet path, at the cost of max_entries x CPUs of memory from load time
+    pub prealloc_rule_hits: bool,
+    /// Give each CPU its own LRU list in the rate limit bucket maps
+    /// (`BPF_F_NO_COMMON_LRU`), trading eviction accuracy for less lock contention
+    pub per_cpu_lru: bool,
+}
+
+impl Default for BpfMapTuning {
+    fn default() -> Self {
+        Self { prealloc_rule_hits: true, per_cpu_lru: false }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ArxignisConfig {
+    #[serde(default)]
     pub api_key: String,
+    /// File holding `api_key` and optionally `base_url`, read at startup in place of the
+    /// inline values so the key stays out of process arguments and the environment
+    #[serde(default)]
+    pub credentials_path: Option<PathBuf>,
     #[serde(default = "default_base_url")]
     pub base_url: String,
+    /// Mirrors of `base_url` the access rules updater fails over to, in order
+    #[serde(default)]
+    pub fallback_base_urls: Vec<String>,
+    /// User-Agent sent with config fetches instead of the default `Moat/<version>`
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// Extra headers attached to every config fetch
+    #[serde(default)]
+    pub request_headers: HashMap<String, String>,
+    /// Client certificate and server CA for config fetches over mutual TLS
+    #[serde(default)]
+    pub tls: Option<ApiTlsConfig>,
+    /// Proxy for config fetches (`http://`, `https://` or `socks5://`, credentials allowed),
+    /// taking precedence over `HTTP_PROXY`/`HTTPS_PROXY`
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Largest config response accepted, checked both as received and once decompressed
+    #[serde(default = "default_max_config_response_size")]
+    pub max_config_response_size: usize,
+    /// Base64 Ed25519 public key config responses must be signed with; unsigned
+    /// responses are accepted when unset
+    #[serde(default)]
+    pub signing_public_key: Option<String>,
     #[serde(default = "default_log_sending_enabled")]
     pub log_sending_enabled: bool,
     #[serde(default = "default_include_response_body")]
@@ -113,6 +176,67 @@ pub struct ArxignisConfig {
     pub captcha: CaptchaConfig,
 }
 
+/// Contents of the file at `arxignis.credentials_path`
+#[derive(Debug, Deserialize)]
+struct ApiCredentials {
+    api_key: String,
+    #[serde(default)]
+    base_url: Option<String>,
+}
+
+impl ArxignisConfig {
+    /// Replaces `api_key`, and `base_url` when the file has one, with the contents of
+    /// `credentials_path`. Runs before logging is set up, so a file its group or other
+    /// users can access is reported on stderr rather than refused.
+    pub fn load_credentials(&mut self) -> Result<()> {
+        let Some(path) = &self.credentials_path else {
+            return Ok(());
+        };
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read credentials file {}", path.display()))?;
+        if let Some(warning) = credentials_mode_warning(path, metadata.permissions().mode()) {
+            eprintln!("{}", warning);
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read credentials file {}", path.display()))?;
+        let credentials: ApiCredentials = serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid credentials file {}", path.display()))?;
+        if credentials.api_key.is_empty() {
+            return Err(anyhow::anyhow!("Credentials file {} has an empty api_key", path.display()));
+        }
+        self.api_key = credentials.api_key;
+        if let Some(base_url) = credentials.base_url {
+            self.base_url = base_url;
+        }
+        Ok(())
+    }
+}
+
+/// Warning for a credentials file whose `mode` grants its group or other users any access
+fn credentials_mode_warning(path: &Path, mode: u32) -> Option<String> {
+    (mode & 0o077 != 0).then(|| {
+        format!(
+            "Warning: credentials file {} is accessible to other users (mode {:o}); restrict it with chmod 600",
+            path.display(),
+            mode & 0o777
+        )
+    })
+}
+
+/// PEM files for authenticating config fetches with a client certificate
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiTlsConfig {
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /// CA bundle the API server certificate is verified against, instead of the system roots
+    pub ca_bundle: Option<PathBuf>,
+}
+
+fn default_attach_mode() -> String {
+    "auto".to_string()
+}
+
 fn default_base_url() -> String {
     "https://api.arxignis.com/v1".to_string()
 }
@@ -125,6 +249,10 @@ fn default_include_response_body() -> bool {
     true
 }
 
+fn default_max_config_response_size() -> usize {
+    crate::config::DEFAULT_MAX_RESPONSE_SIZE
+}
+
 fn default_max_body_size() -> usize {
     1024 * 1024 // 1MB
 }
@@ -213,11 +341,22 @@ impl Config {
             network: NetworkConfig {
                 iface: "eth0".to_string(),
                 ifaces: vec![],
+                exclude_ifaces: vec![],
                 disable_xdp: false,
+                attach_mode: default_attach_mode(),
+                bpf_maps: BpfMapTuning::default(),
             },
             arxignis: ArxignisConfig {
                 api_key: "".to_string(),
+                credentials_path: None,
                 base_url: "https://api.arxignis.com/v1".to_string(),
+                fallback_base_urls: vec![],
+                user_agent: None,
+                request_headers: HashMap::new(),
+                tls: None,
+                proxy: None,
+                max_config_response_size: default_max_config_response_size(),
+                signing_public_key: None,
                 log_sending_enabled: true,
                 include_response_body: true,
                 max_body_size: 1024 * 1024, // 1MB
@@ -250,6 +389,9 @@ impl Config {
             bpf_stats: BpfStatsConfig::default(),
             tcp_fingerprint: TcpFingerprintConfig::default(),
             daemon: DaemonConfig::default(),
+            access_rules: AccessRulesConfig::default(),
+            metrics: MetricsConfig::default(),
+            control_api: ControlApiConfig::default(),
         }
     }
 
@@ -267,9 +409,15 @@ impl Config {
         if !args.ifaces.is_empty() {
             self.network.ifaces = args.ifaces.clone();
         }
+        if let Some(attach_mode) = &args.attach_mode {
+            self.network.attach_mode = attach_mode.clone();
+        }
         if let Some(api_key) = &args.arxignis_api_key {
             self.arxignis.api_key = api_key.clone();
         }
+        if args.arxignis_credentials_path.is_some() {
+            self.arxignis.credentials_path = args.arxignis_credentials_path.clone();
+        }
         if let Some(upstream) = &args.upstream {
             self.server.upstream = upstream.clone();
         }
@@ -342,13 +490,22 @@ impl Config {
 
         // Check if arxignis API key is provided either via CLI args or config file
         if args.arxignis_api_key.is_none() && self.arxignis.api_key.is_empty() {
-            return Err(anyhow::anyhow!("Arxignis API key is required. Provide it via --arxignis-api-key argument or in config file"));
+            return Err(anyhow::anyhow!("Arxignis API key is required. Provide it via --arxignis-api-key, --arxignis-credentials-path or in config file"));
         }
 
         Ok(())
     }
 
     pub fn load_from_args(args: &Args) -> Result<Self> {
+        let mut config = Self::load_for_command(args)?;
+        config.arxignis.load_credentials()?;
+        config.validate_required_fields(args)?;
+        Ok(config)
+    }
+
+    /// Configuration for a one-shot command: the config file with the command line and
+    /// environment overrides, without the credentials and checks only the proxy needs
+    pub fn load_for_command(args: &Args) -> Result<Self> {
         let mut config = if let Some(config_path) = &args.config {
             Self::load_from_file(config_path)?
         } else {
@@ -357,7 +514,6 @@ impl Config {
 
         config.merge_with_args(args);
         config.apply_env_overrides();
-        config.validate_required_fields(args)?;
         Ok(config)
     }
 
@@ -443,9 +599,15 @@ impl Config {
         if let Ok(val) = env::var("AX_NETWORK_IFACES") {
             self.network.ifaces = val.split(',').map(|s| s.trim().to_string()).collect();
         }
+        if let Ok(val) = env::var("AX_NETWORK_EXCLUDE_IFACES") {
+            self.network.exclude_ifaces = val.split(',').map(|s| s.trim().to_string()).collect();
+        }
         if let Ok(val) = env::var("AX_NETWORK_DISABLE_XDP") {
             self.network.disable_xdp = val.parse().unwrap_or(false);
         }
+        if let Ok(val) = env::var("AX_NETWORK_ATTACH_MODE") {
+            self.network.attach_mode = val;
+        }
 
         // Arxignis configuration overrides
         if let Ok(val) = env::var("AX_ARXIGNIS_API_KEY") {
@@ -454,6 +616,18 @@ impl Config {
         if let Ok(val) = env::var("AX_ARXIGNIS_BASE_URL") {
             self.arxignis.base_url = val;
         }
+        if let Ok(val) = env::var("AX_ARXIGNIS_CREDENTIALS_PATH") {
+            self.arxignis.credentials_path = Some(PathBuf::from(val));
+        }
+        if let Ok(val) = env::var("AX_ARXIGNIS_PROXY") {
+            self.arxignis.proxy = Some(val);
+        }
+        if let Ok(val) = env::var("AX_ARXIGNIS_MAX_CONFIG_RESPONSE_SIZE") {
+            self.arxignis.max_config_response_size = val.parse().unwrap_or_else(|_| default_max_config_response_size());
+        }
+        if let Ok(val) = env::var("AX_ARXIGNIS_SIGNING_PUBLIC_KEY") {
+            self.arxignis.signing_public_key = Some(val);
+        }
         if let Ok(val) = env::var("AX_ARXIGNIS_LOG_SENDING_ENABLED") {
             if let Ok(parsed) = val.parse::<bool>() {
                 self.arxignis.log_sending_enabled = parsed;
@@ -636,6 +810,10 @@ pub struct Args {
     #[arg(long)]
     pub arxignis_api_key: Option<String>,
 
+    /// File with the Arxignis API key (and optionally base URL), kept out of the process arguments
+    #[arg(long)]
+    pub arxignis_credentials_path: Option<PathBuf>,
+
     /// Base URL for Arxignis API.
     #[arg(long, default_value = "https://api.arxignis.com/v1")]
     pub arxignis_base_url: String,
@@ -660,6 +838,10 @@ pub struct Args {
     #[arg(long, default_value_t = false)]
     pub disable_xdp: bool,
 
+    /// Filter attach point: auto, native (XDP driver mode), generic (XDP SKB mode) or tc
+    #[arg(long)]
+    pub attach_mode: Option<String>,
+
     /// Captcha site key for security verification
     #[arg(long)]
     pub captcha_site_key: Option<String>,
@@ -720,6 +902,25 @@ pub struct Args {
     /// Group to run daemon as
     #[arg(long)]
     pub daemon_group: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// One-shot commands run instead of starting the proxy
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Report whether an IP is currently blocked by the XDP banned maps
+    Status {
+        /// Address to look up
+        ip: IpAddr,
+    },
+    /// Check a config API response file offline: report how many rules it would install
+    /// and every entry that would be rejected. Exits non-zero when any entry is invalid.
+    ValidateRules {
+        /// JSON file in the config API response shape
+        file: PathBuf,
+    },
 }
 
 #[derive(Copy, Clone, Debug, ValueEnum)]
@@ -807,3 +1008,337 @@ fn default_daemon_working_directory() -> String { "/".to_string() }
 fn default_daemon_stdout() -> String { "/var/log/moat.out".to_string() }
 fn default_daemon_stderr() -> String { "/var/log/moat.err".to_string() }
 fn default_daemon_chown_pid_file() -> bool { true }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessRulesConfig {
+    #[serde(default = "default_access_rules_poll_interval")]
+    pub poll_interval_secs: u64,
+    #[serde(default = "default_access_rules_max_backoff")]
+    pub max_backoff_secs: u64,
+    #[serde(default = "default_access_rules_collapse_cidrs")]
+    pub collapse_cidrs: bool,
+    #[serde(default)]
+    pub merge_adjacent_cidrs: bool,
+    #[serde(default = "default_access_rules_dry_run")]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub state_file: Option<String>,
+    #[serde(default = "default_access_rules_unban_on_shutdown")]
+    pub unban_on_shutdown: bool,
+    #[serde(default)]
+    pub shutdown_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub cache_path: Option<String>,
+    #[serde(default)]
+    pub local_rules_file: Option<String>,
+    #[serde(default = "default_access_rules_dns_concurrency")]
+    pub dns_concurrency: usize,
+    #[serde(default = "default_access_rules_dns_timeout")]
+    pub dns_timeout_secs: u64,
+    #[serde(default = "default_access_rules_enable_ipv4")]
+    pub enable_ipv4: bool,
+    #[serde(default = "default_access_rules_enable_ipv6")]
+    pub enable_ipv6: bool,
+    #[serde(default = "default_access_rules_fetch_timeout")]
+    pub fetch_timeout_secs: u64,
+    #[serde(default = "default_access_rules_fetch_retries")]
+    pub fetch_retries: u32,
+    #[serde(default = "default_access_rules_fetch_retry_delay")]
+    pub fetch_retry_delay_ms: u64,
+    #[serde(default)]
+    pub log_broad_rules: Option<usize>,
+    #[serde(default = "default_access_rules_broad_rule_warn_prefix_v4")]
+    pub broad_rule_warn_prefix_v4: u32,
+    #[serde(default = "default_access_rules_broad_rule_warn_prefix_v6")]
+    pub broad_rule_warn_prefix_v6: u32,
+    #[serde(default = "default_access_rules_allow_default_route_block")]
+    pub allow_default_route_block: bool,
+    #[serde(default = "default_access_rules_reconcile_every_cycles")]
+    pub reconcile_every_cycles: u32,
+    #[serde(default = "default_access_rules_reconcile_replace")]
+    pub reconcile_replace: bool,
+    #[serde(default)]
+    pub force_reapply_every_secs: Option<u64>,
+    #[serde(default)]
+    pub initial_apply_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub event_sink: Option<EventSinkConfig>,
+    #[serde(default = "default_access_rules_unban_grace_cycles")]
+    pub unban_grace_cycles: u32,
+    #[serde(default = "default_access_rules_oscillation_window")]
+    pub oscillation_window_secs: u64,
+    #[serde(default = "default_access_rules_strict_parsing")]
+    pub strict_parsing: bool,
+    #[serde(default)]
+    pub canary_change_percent: Option<u32>,
+    #[serde(default = "default_access_rules_canary_sample")]
+    pub canary_sample: usize,
+    #[serde(default)]
+    pub atomic_swap_min_changes: Option<usize>,
+    #[serde(default = "default_access_rules_map_utilization_warn_percent")]
+    pub map_utilization_warn_percent: u32,
+    #[serde(default = "default_access_rules_apply_breaker_threshold")]
+    pub apply_breaker_threshold: u32,
+    #[serde(default = "default_access_rules_apply_breaker_cooldown")]
+    pub apply_breaker_cooldown_secs: u64,
+    #[serde(default = "default_access_rules_deterministic_order")]
+    pub deterministic_order: bool,
+    #[serde(default)]
+    pub log_unchanged_cycles: bool,
+    #[serde(default = "default_access_rules_delta_sync")]
+    pub delta_sync: bool,
+    #[serde(default = "default_access_rules_monotonic_rule_hits")]
+    pub monotonic_rule_hits: bool,
+    #[serde(default = "default_access_rules_recent_rejects")]
+    pub recent_rejects: usize,
+    #[serde(default = "default_access_rules_invalid_entry_log_limit")]
+    pub invalid_entry_log_limit: usize,
+    #[serde(default)]
+    pub country_filter: Option<Vec<String>>,
+    #[serde(default)]
+    pub country_include: Option<Vec<String>>,
+    #[serde(default)]
+    pub country_exclude: Vec<String>,
+    #[serde(default)]
+    pub asn_include: Option<Vec<String>>,
+    #[serde(default)]
+    pub asn_exclude: Vec<String>,
+    #[serde(default)]
+    pub max_rules: Option<usize>,
+    #[serde(default = "default_access_rules_block_source_priority")]
+    pub block_source_priority: Vec<String>,
+    #[serde(default = "default_access_rules_allow_block_precedence")]
+    pub allow_block_precedence: String,
+    #[serde(default = "default_access_rules_require_skel")]
+    pub require_skel: bool,
+    #[serde(default = "default_access_rules_poll_jitter_percent")]
+    pub poll_jitter_percent: u32,
+    #[serde(default = "default_access_rules_initial_jitter_max_ms")]
+    pub initial_jitter_max_ms: u64,
+    #[serde(default = "default_access_rules_startup_retries")]
+    pub startup_retries: u32,
+    #[serde(default = "default_access_rules_startup_retry_delay")]
+    pub startup_retry_delay_ms: u64,
+    #[serde(default)]
+    pub jitter_seed: Option<u64>,
+    #[serde(default = "default_access_rules_missed_tick_behavior")]
+    pub missed_tick_behavior: String,
+}
+
+impl Default for AccessRulesConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: default_access_rules_poll_interval(),
+            max_backoff_secs: default_access_rules_max_backoff(),
+            collapse_cidrs: default_access_rules_collapse_cidrs(),
+            merge_adjacent_cidrs: false,
+            dry_run: default_access_rules_dry_run(),
+            state_file: None,
+            unban_on_shutdown: default_access_rules_unban_on_shutdown(),
+            shutdown_timeout_secs: None,
+            cache_path: None,
+            local_rules_file: None,
+            dns_concurrency: default_access_rules_dns_concurrency(),
+            dns_timeout_secs: default_access_rules_dns_timeout(),
+            enable_ipv4: default_access_rules_enable_ipv4(),
+            enable_ipv6: default_access_rules_enable_ipv6(),
+            fetch_timeout_secs: default_access_rules_fetch_timeout(),
+            fetch_retries: default_access_rules_fetch_retries(),
+            fetch_retry_delay_ms: default_access_rules_fetch_retry_delay(),
+            log_broad_rules: None,
+            broad_rule_warn_prefix_v4: default_access_rules_broad_rule_warn_prefix_v4(),
+            broad_rule_warn_prefix_v6: default_access_rules_broad_rule_warn_prefix_v6(),
+            allow_default_route_block: default_access_rules_allow_default_route_block(),
+            reconcile_every_cycles: default_access_rules_reconcile_every_cycles(),
+            reconcile_replace: default_access_rules_reconcile_replace(),
+            force_reapply_every_secs: None,
+            initial_apply_timeout_secs: None,
+            event_sink: None,
+            unban_grace_cycles: default_access_rules_unban_grace_cycles(),
+            oscillation_window_secs: default_access_rules_oscillation_window(),
+            strict_parsing: default_access_rules_strict_parsing(),
+            canary_change_percent: None,
+            canary_sample: default_access_rules_canary_sample(),
+            atomic_swap_min_changes: None,
+            map_utilization_warn_percent: default_access_rules_map_utilization_warn_percent(),
+            apply_breaker_threshold: default_access_rules_apply_breaker_threshold(),
+            apply_breaker_cooldown_secs: default_access_rules_apply_breaker_cooldown(),
+            deterministic_order: default_access_rules_deterministic_order(),
+            log_unchanged_cycles: false,
+            delta_sync: default_access_rules_delta_sync(),
+            monotonic_rule_hits: default_access_rules_monotonic_rule_hits(),
+            recent_rejects: default_access_rules_recent_rejects(),
+            invalid_entry_log_limit: default_access_rules_invalid_entry_log_limit(),
+            country_filter: None,
+            country_include: None,
+            country_exclude: Vec::new(),
+            asn_include: None,
+            asn_exclude: Vec::new(),
+            max_rules: None,
+            block_source_priority: default_access_rules_block_source_priority(),
+            allow_block_precedence: default_access_rules_allow_block_precedence(),
+            require_skel: default_access_rules_require_skel(),
+            poll_jitter_percent: default_access_rules_poll_jitter_percent(),
+            initial_jitter_max_ms: default_access_rules_initial_jitter_max_ms(),
+            startup_retries: default_access_rules_startup_retries(),
+            startup_retry_delay_ms: default_access_rules_startup_retry_delay(),
+            jitter_seed: None,
+            missed_tick_behavior: default_access_rules_missed_tick_behavior(),
+        }
+    }
+}
+
+fn default_access_rules_poll_interval() -> u64 { 10 }
+fn default_access_rules_max_backoff() -> u64 { 300 }
+fn default_access_rules_collapse_cidrs() -> bool { false }
+fn default_access_rules_dry_run() -> bool { false }
+fn default_access_rules_unban_on_shutdown() -> bool { false }
+fn default_access_rules_enable_ipv4() -> bool { true }
+fn default_access_rules_enable_ipv6() -> bool { true }
+fn default_access_rules_dns_concurrency() -> usize { 8 }
+fn default_access_rules_dns_timeout() -> u64 { 10 }
+fn default_access_rules_fetch_timeout() -> u64 { 15 }
+fn default_access_rules_fetch_retries() -> u32 { 2 }
+fn default_access_rules_fetch_retry_delay() -> u64 { 500 }
+fn default_access_rules_broad_rule_warn_prefix_v4() -> u32 { 8 }
+fn default_access_rules_broad_rule_warn_prefix_v6() -> u32 { 16 }
+fn default_access_rules_allow_default_route_block() -> bool { false }
+fn default_access_rules_reconcile_every_cycles() -> u32 { 0 }
+fn default_access_rules_reconcile_replace() -> bool { false }
+fn default_access_rules_block_source_priority() -> Vec<String> {
+    vec!["ips".to_string(), "country".to_string(), "asn".to_string()]
+}
+fn default_access_rules_allow_block_precedence() -> String { "allow-always-wins".to_string() }
+fn default_access_rules_require_skel() -> bool { false }
+fn default_access_rules_poll_jitter_percent() -> u32 { 0 }
+fn default_access_rules_initial_jitter_max_ms() -> u64 { 0 }
+fn default_access_rules_startup_retries() -> u32 { 3 }
+fn default_access_rules_startup_retry_delay() -> u64 { 1000 }
+fn default_access_rules_missed_tick_behavior() -> String { "delay".to_string() }
+fn default_access_rules_unban_grace_cycles() -> u32 { 0 }
+fn default_access_rules_oscillation_window() -> u64 { 600 }
+fn default_access_rules_strict_parsing() -> bool { false }
+fn default_access_rules_canary_sample() -> usize { 10 }
+fn default_access_rules_map_utilization_warn_percent() -> u32 { 90 }
+fn default_access_rules_apply_breaker_threshold() -> u32 { 5 }
+fn default_access_rules_apply_breaker_cooldown() -> u64 { 300 }
+fn default_access_rules_deterministic_order() -> bool { false }
+fn default_access_rules_delta_sync() -> bool { false }
+fn default_access_rules_monotonic_rule_hits() -> bool { false }
+fn default_access_rules_recent_rejects() -> usize { 100 }
+fn default_access_rules_invalid_entry_log_limit() -> usize { 20 }
+
+/// Where the JSON line audit trail of rule changes is written
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventSinkConfig {
+    /// `file` to append to `path`, or `unix_datagram` to send to the socket at `path`
+    #[serde(rename = "type", default = "default_event_sink_kind")]
+    pub kind: String,
+    pub path: String,
+}
+
+fn default_event_sink_kind() -> String { "file".to_string() }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default = "default_metrics_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_metrics_bind")]
+    pub bind: String,
+    #[serde(default = "default_metrics_health_max_staleness")]
+    pub health_max_staleness_secs: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_metrics_enabled(),
+            bind: default_metrics_bind(),
+            health_max_staleness_secs: default_metrics_health_max_staleness(),
+        }
+    }
+}
+
+fn default_metrics_enabled() -> bool { false }
+fn default_metrics_bind() -> String { "127.0.0.1:9090".to_string() }
+fn default_metrics_health_max_staleness() -> u64 { 300 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlApiConfig {
+    #[serde(default = "default_control_api_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_control_api_bind")]
+    pub bind: String,
+    /// Shared secret clients send as `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Directory `POST /rules/export` may write files into; unset, exports are only
+    /// returned in the response body
+    #[serde(default)]
+    pub export_dir: Option<String>,
+    /// Directory `POST /rules/ban-file` may read files from; unset, the endpoint is off
+    #[serde(default)]
+    pub ban_file_dir: Option<String>,
+}
+
+impl Default for ControlApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_control_api_enabled(),
+            bind: default_control_api_bind(),
+            token: None,
+            export_dir: None,
+            ban_file_dir: None,
+        }
+    }
+}
+
+fn default_control_api_enabled() -> bool { false }
+fn default_control_api_bind() -> String { "127.0.0.1:9091".to_string() }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials_file(name: &str, contents: &str, mode: u32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("moat-credentials-{}-{}.yaml", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    fn load(path: &Path) -> Result<ArxignisConfig> {
+        let mut arxignis = Config::default().arxignis;
+        arxignis.credentials_path = Some(path.to_path_buf());
+        arxignis.load_credentials().map(|_| arxignis)
+    }
+
+    #[test]
+    fn test_credentials_mode_warning_covers_group_and_other_access() {
+        let path = Path::new("/etc/moat/credentials.yaml");
+        assert_eq!(credentials_mode_warning(path, 0o100600), None);
+        assert_eq!(credentials_mode_warning(path, 0o400), None);
+        for mode in [0o640, 0o660, 0o604, 0o610] {
+            let warning = credentials_mode_warning(path, 0o100000 | mode).unwrap();
+            assert!(warning.contains(&format!("(mode {:o})", mode)), "{}", warning);
+        }
+    }
+
+    #[test]
+    fn test_load_credentials_reads_key_and_base_url() {
+        let path = credentials_file("ok", "api_key: \"secret\"\nbase_url: \"https://mirror.example.com/v1\"\n", 0o640);
+        let arxignis = load(&path).unwrap();
+        assert_eq!(arxignis.api_key, "secret");
+        assert_eq!(arxignis.base_url, "https://mirror.example.com/v1");
+        assert!(credentials_mode_warning(&path, std::fs::metadata(&path).unwrap().permissions().mode()).is_some());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_load_credentials_rejects_an_empty_api_key() {
+        let path = credentials_file("empty", "api_key: \"\"\n", 0o600);
+        let err = load(&path).unwrap_err();
+        assert!(err.to_string().contains("empty api_key"), "{}", err);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,9 +1,12 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use hyper::StatusCode;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Read;
-use flate2::read::GzDecoder;
-use std::sync::{Arc, OnceLock, RwLock};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 use crate::content_scanning::ContentScanningConfig;
 use crate::http_client::get_global_reqwest_client;
 
@@ -24,6 +27,20 @@ pub struct Config {
     pub created_at: String,
     pub updated_at: String,
     pub last_modified: String,
+    /// Rule set version, when the API provides one
+    #[serde(default)]
+    pub version: Option<String>,
+    /// ETag of the response this config was parsed from
+    #[serde(skip)]
+    pub etag: Option<String>,
+}
+
+impl Config {
+    /// Identifier of this rule set revision: the response ETag, falling back to `version`.
+    /// None when the API provides neither, in which case every fetch must be diffed.
+    pub fn rule_version(&self) -> Option<&str> {
+        self.etag.as_deref().or(self.version.as_deref())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -33,6 +50,17 @@ pub struct AccessRule {
     pub description: String,
     pub allow: RuleSet,
     pub block: RuleSet,
+    /// Sources to throttle rather than block
+    #[serde(default)]
+    pub rate_limit: Vec<RateLimitRule>,
+}
+
+/// Entries whose sources may each send at most `pps` packets per second
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimitRule {
+    pub ips: Vec<String>,
+    pub pps: u32,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -50,11 +78,189 @@ pub struct WafRule {
     pub expression: String,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct RuleSet {
-    pub asn: Vec<HashMap<String, Vec<String>>>,
-    pub country: Vec<HashMap<String, Vec<String>>>,
+    pub asn: Vec<HashMap<String, GroupCidrs>>,
+    pub country: Vec<HashMap<String, GroupCidrs>>,
     pub ips: Vec<String>,
+    /// Hostnames whose A/AAAA records are resolved every cycle and applied like `ips`
+    pub domains: Vec<String>,
+    /// RFC 3339 expiry per entry of `ips`, `country` or `asn`. An entry is removed once
+    /// its expiry passes, even while the feed still lists it.
+    pub expires: HashMap<String, String>,
+    /// Action per entry of `ips` of a block rule set: `drop`, the
+    /// default, or `log` to count matches without dropping them
+    pub actions: HashMap<String, String>,
+}
+
+impl RuleSet {
+    fn groups(&self) -> impl Iterator<Item = &GroupCidrs> {
+        self.country.iter().chain(&self.asn).flat_map(|groups| groups.values())
+    }
+}
+
+/// CIDRs of one country or ASN group. The original feed lists them flat; newer feeds
+/// nest them as `{"ips": [...], "asn": {"AS64500": [...]}}`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum GroupCidrs {
+    Flat(Vec<String>),
+    Nested(NestedGroupCidrs),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NestedGroupCidrs {
+    pub ips: Vec<String>,
+    pub asn: HashMap<String, GroupCidrs>,
+}
+
+impl GroupCidrs {
+    /// Every IP string of the group, those of nested ASN groups included
+    pub fn entries(&self) -> Vec<&String> {
+        match self {
+            GroupCidrs::Flat(ips) => ips.iter().collect(),
+            GroupCidrs::Nested(nested) => nested
+                .ips
+                .iter()
+                .chain(nested.asn.values().flat_map(|group| group.entries()))
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            GroupCidrs::Flat(ips) => ips.len(),
+            GroupCidrs::Nested(nested) => nested.ips.len() + nested.asn.values().map(GroupCidrs::len).sum::<usize>(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Shape of the country and ASN groups of an access rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupFormat {
+    Flat,
+    Nested,
+    Mixed,
+}
+
+impl std::fmt::Display for GroupFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            GroupFormat::Flat => "flat",
+            GroupFormat::Nested => "nested",
+            GroupFormat::Mixed => "mixed flat and nested",
+        })
+    }
+}
+
+impl AccessRule {
+    /// Format of the country and ASN groups across both rule sets, None without groups
+    pub fn group_format(&self) -> Option<GroupFormat> {
+        let (mut flat, mut nested) = (false, false);
+        for group in self.block.groups().chain(self.allow.groups()) {
+            match group {
+                GroupCidrs::Flat(_) => flat = true,
+                GroupCidrs::Nested(_) => nested = true,
+            }
+        }
+        match (flat, nested) {
+            (true, false) => Some(GroupFormat::Flat),
+            (false, true) => Some(GroupFormat::Nested),
+            (true, true) => Some(GroupFormat::Mixed),
+            (false, false) => None,
+        }
+    }
+}
+
+/// Group format of the last parsed response, so a change is logged once
+static GROUP_FORMAT: Mutex<Option<GroupFormat>> = Mutex::new(None);
+
+fn log_group_format(rule: &AccessRule) {
+    let Some(format) = rule.group_format() else { return };
+    let mut last = GROUP_FORMAT.lock().unwrap_or_else(|e| e.into_inner());
+    if *last != Some(format) {
+        log::info!("access rules country/ASN groups use the {} format", format);
+        *last = Some(format);
+    }
+}
+
+/// Entries added to and removed from the `ips` of a rule set
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RuleSetDelta {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Expiries of added entries, merged into `RuleSet::expires`
+    pub expires: HashMap<String, String>,
+}
+
+/// Changes between two rule set versions, returned instead of the full config when
+/// the API supports `since_version`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConfigDelta {
+    /// Version the changes apply on top of, the `since_version` that was sent
+    pub base_version: String,
+    /// Version of the config after the changes
+    pub version: String,
+    #[serde(default)]
+    pub block: RuleSetDelta,
+    #[serde(default)]
+    pub allow: RuleSetDelta,
+}
+
+impl ConfigDelta {
+    fn added(&self) -> usize {
+        self.block.added.len() + self.allow.added.len()
+    }
+
+    fn removed(&self) -> usize {
+        self.block.removed.len() + self.allow.removed.len()
+    }
+
+    /// The config after the delta, given the config it was computed against. Fails when
+    /// `base` is at another version or does not hold what the delta expects, so the
+    /// caller falls back to a full snapshot instead of drifting.
+    pub fn apply_to(&self, base: &Config) -> Result<Config, String> {
+        if base.rule_version() != Some(self.base_version.as_str()) {
+            return Err(format!("held config is at version {}", base.rule_version().unwrap_or("<none>")));
+        }
+        let mut merged = base.clone();
+        apply_rule_set_delta(&mut merged.access_rules.block, &self.block, "block")?;
+        apply_rule_set_delta(&mut merged.access_rules.allow, &self.allow, "allow")?;
+        // The merged config was never served with an ETag, so it is identified by version
+        merged.version = Some(self.version.clone());
+        merged.etag = None;
+        Ok(merged)
+    }
+}
+
+fn apply_rule_set_delta(rule_set: &mut RuleSet, delta: &RuleSetDelta, which: &str) -> Result<(), String> {
+    let ips = &mut rule_set.ips;
+    let mut present: HashSet<String> = ips.iter().map(|ip| ip.trim().to_string()).collect();
+    for entry in &delta.removed {
+        if !present.remove(entry.trim()) {
+            return Err(format!("removed {} entry {} is not in the held config", which, entry));
+        }
+    }
+    for entry in &delta.added {
+        if !present.insert(entry.trim().to_string()) {
+            return Err(format!("added {} entry {} is already in the held config", which, entry));
+        }
+    }
+    // Keep the surviving entries in order, then append the additions
+    ips.retain(|ip| present.remove(ip.trim()));
+    ips.extend(delta.added.iter().filter(|entry| present.contains(entry.trim())).cloned());
+    for entry in &delta.removed {
+        rule_set.expires.remove(entry.trim());
+    }
+    rule_set.expires.extend(delta.expires.iter().map(|(entry, at)| (entry.clone(), at.clone())));
+    Ok(())
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -64,6 +270,97 @@ pub struct ErrorResponse {
     pub success: bool,
 }
 
+// Client for config fetches when the API needs its own TLS setup, built once at startup
+static FETCH_CLIENT: OnceLock<Arc<reqwest::Client>> = OnceLock::new();
+
+/// Use `client` for every config fetch instead of the shared HTTP client
+pub fn set_fetch_client(client: reqwest::Client) -> Result<(), String> {
+    FETCH_CLIENT.set(Arc::new(client)).map_err(|_| "config fetch client already set".to_string())
+}
+
+// Headers attached to every config fetch, set once at startup
+static FETCH_HEADERS: OnceLock<HeaderMap> = OnceLock::new();
+
+/// Validate and install the User-Agent override and extra headers sent with every
+/// config fetch. Without a User-Agent the shared client's `Moat/<version>` is sent.
+pub fn set_fetch_headers(user_agent: Option<&str>, extra: &HashMap<String, String>) -> Result<(), String> {
+    let headers = build_fetch_headers(user_agent, extra)?;
+    FETCH_HEADERS.set(headers).map_err(|_| "config fetch headers already set".to_string())
+}
+
+fn build_fetch_headers(user_agent: Option<&str>, extra: &HashMap<String, String>) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in extra {
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|e| format!("invalid request header name '{}': {}", name, e))?;
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|e| format!("invalid value for request header {}: {}", name, e))?;
+        headers.insert(name, value);
+    }
+    if let Some(user_agent) = user_agent {
+        let value = HeaderValue::from_str(user_agent.trim())
+            .map_err(|e| format!("invalid user_agent '{}': {}", user_agent, e))?;
+        headers.insert(USER_AGENT, value);
+    }
+    Ok(headers)
+}
+
+/// Default cap on a config response, applied to the body as received and once decompressed
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+// Response size cap for config fetches, set once at startup
+static MAX_RESPONSE_SIZE: OnceLock<usize> = OnceLock::new();
+
+/// Cap config responses at `limit` bytes instead of [`DEFAULT_MAX_RESPONSE_SIZE`]
+pub fn set_max_response_size(limit: usize) -> Result<(), String> {
+    if limit == 0 {
+        return Err("max_config_response_size must be greater than zero".to_string());
+    }
+    MAX_RESPONSE_SIZE.set(limit).map_err(|_| "config response size limit already set".to_string())
+}
+
+fn max_response_size() -> usize {
+    MAX_RESPONSE_SIZE.get().copied().unwrap_or(DEFAULT_MAX_RESPONSE_SIZE)
+}
+
+/// Response header carrying the base64 Ed25519 signature of the decoded config body
+pub const SIGNATURE_HEADER: &str = "x-arxignis-signature";
+
+// Ed25519 public key config responses must be signed with, set once at startup
+static SIGNING_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Require every config response to carry a valid [`SIGNATURE_HEADER`] made with the
+/// private half of `public_key`, a base64 raw 32-byte Ed25519 public key
+pub fn set_signing_key(public_key: &str) -> Result<(), String> {
+    let key = parse_signing_key(public_key)?;
+    SIGNING_KEY.set(key).map_err(|_| "config signing key already set".to_string())
+}
+
+/// Whether config responses must carry a valid signature
+pub fn signing_required() -> bool {
+    SIGNING_KEY.get().is_some()
+}
+
+fn parse_signing_key(public_key: &str) -> Result<Vec<u8>, String> {
+    let key = BASE64.decode(public_key.trim())
+        .map_err(|e| format!("signing public key is not valid base64: {}", e))?;
+    if key.len() != 32 {
+        return Err(format!("signing public key must be 32 bytes, got {}", key.len()));
+    }
+    Ok(key)
+}
+
+/// Check the detached `signature` over `body`. A missing or bad signature rejects the
+/// whole response, so the rules already applied stay in place.
+fn verify_signature(public_key: &[u8], body: &[u8], signature: Option<&str>) -> Result<(), String> {
+    let signature = signature.ok_or_else(|| format!("config response has no {} header", SIGNATURE_HEADER))?;
+    let signature = BASE64.decode(signature.trim())
+        .map_err(|e| format!("config response signature is not valid base64: {}", e))?;
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+        .verify(body, &signature)
+        .map_err(|_| "config response signature does not match the configured signing key".to_string())
+}
+
 // Global configuration store accessible across services
 static GLOBAL_CONFIG: OnceLock<Arc<RwLock<Option<Config>>>> = OnceLock::new();
 
@@ -80,74 +377,141 @@ pub fn set_global_config(cfg: Config) {
     }
 }
 
+/// Fetch the config, asking for the changes since `since_version` when given. A delta
+/// response is merged into the held global config; when the server answers with a full
+/// snapshot instead, or the delta does not fit the held config, the full snapshot is used.
+/// The ETag of the held config is sent as `If-None-Match`, and a `304 Not Modified`
+/// answer returns the held config without reading or parsing a body.
 pub async fn fetch_config(
     base_url: String,
     api_key: String,
+    since_version: Option<String>,
 ) -> Result<ConfigApiResponse, Box<dyn std::error::Error>> {
-    // Use shared HTTP client with keepalive instead of creating new client
-    let client = get_global_reqwest_client()
-        .map_err(|e| anyhow::anyhow!("Failed to get global HTTP client: {}", e))?;
+    let held = global_config().read().ok().and_then(|guard| guard.clone());
+    let held_etag = held.as_ref().and_then(|cfg| cfg.etag.as_deref());
+
+    if let Some(since) = since_version {
+        match request_config(&base_url, &api_key, Some(&since), held_etag).await? {
+            ConfigBody::NotModified => return not_modified(held),
+            ConfigBody::Full(body) => {
+                set_global_config(body.config.clone());
+                return Ok(body);
+            }
+            ConfigBody::Delta(delta) => {
+                let merged = held.clone()
+                    .ok_or_else(|| "no config is held".to_string())
+                    .and_then(|base| delta.apply_to(&base));
+                match merged {
+                    Ok(config) => {
+                        log::info!(
+                            "applied config delta {} -> {}: {} added, {} removed",
+                            delta.base_version, delta.version, delta.added(), delta.removed()
+                        );
+                        set_global_config(config.clone());
+                        return Ok(ConfigApiResponse { success: true, config });
+                    }
+                    Err(reason) => log::warn!(
+                        "discarding config delta {} -> {}: {}, fetching a full snapshot",
+                        delta.base_version, delta.version, reason
+                    ),
+                }
+            }
+        }
+    }
+
+    match request_config(&base_url, &api_key, None, held_etag).await? {
+        ConfigBody::Full(body) => {
+            // Update global config snapshot
+            set_global_config(body.config.clone());
+            Ok(body)
+        }
+        ConfigBody::Delta(_) => Err("API returned a config delta to a full snapshot request".into()),
+        ConfigBody::NotModified => not_modified(held),
+    }
+}
+
+/// The held config, answered for a `304 Not Modified`; the global snapshot is left as is
+fn not_modified(held: Option<Config>) -> Result<ConfigApiResponse, Box<dyn std::error::Error>> {
+    let config = held.ok_or("API answered 304 Not Modified but no config is held")?;
+    Ok(ConfigApiResponse { success: true, config })
+}
+
+/// A config response: the full snapshot, the changes since a version, or confirmation
+/// that the revision named by `If-None-Match` is still current
+enum ConfigBody {
+    Full(ConfigApiResponse),
+    Delta(ConfigDelta),
+    NotModified,
+}
+
+#[derive(Deserialize)]
+struct ConfigDeltaResponse {
+    delta: ConfigDelta,
+}
+
+async fn request_config(
+    base_url: &str,
+    api_key: &str,
+    since_version: Option<&str>,
+    if_none_match: Option<&str>,
+) -> Result<ConfigBody, Box<dyn std::error::Error>> {
+    // Use the dedicated or shared HTTP client with keepalive instead of creating new client
+    let client = match FETCH_CLIENT.get() {
+        Some(client) => client.clone(),
+        None => get_global_reqwest_client()
+            .map_err(|e| anyhow::anyhow!("Failed to get global HTTP client: {}", e))?,
+    };
 
     let url = format!("{}/config", base_url);
 
-    let response = client
+    let mut request = client
         .get(url)
         .header("Authorization", format!("Bearer {}", api_key))
-        .header("Accept-Encoding", "gzip")
-        .send()
-        .await?;
+        .header("Accept-Encoding", "gzip, deflate");
+    if let Some(headers) = FETCH_HEADERS.get() {
+        request = request.headers(headers.clone());
+    }
+    if let Some(since) = since_version {
+        request = request.query(&[("since_version", since)]);
+    }
+    if let Some(etag) = if_none_match {
+        request = request.header("If-None-Match", etag);
+    }
+    let mut response = request.send().await?;
 
     match response.status() {
+        // Checked before the body is read, so an unchanged revision costs no download or parse
+        StatusCode::NOT_MODIFIED if if_none_match.is_some() => Ok(ConfigBody::NotModified),
         StatusCode::OK => {
-            // Check if response is gzipped by looking at Content-Encoding header first
+            // Content-Encoding, together with the payload itself, decides how the body is decoded
             let content_encoding = response.headers()
                 .get("content-encoding")
                 .and_then(|h| h.to_str().ok())
                 .unwrap_or("")
                 .to_string(); // Convert to owned String to avoid borrow issues
+            let etag = response.headers()
+                .get("etag")
+                .and_then(|h| h.to_str().ok())
+                .map(|s| s.to_string());
+            let signature = response.headers()
+                .get(SIGNATURE_HEADER)
+                .and_then(|h| h.to_str().ok())
+                .map(|s| s.to_string());
 
-            let bytes = response.bytes().await?;
-
-            let json_text = if content_encoding.contains("gzip") ||
-                (bytes.len() >= 2 && bytes[0] == 0x1f && bytes[1] == 0x8b) {
-                // Response is gzipped, decompress it
-                let mut decoder = GzDecoder::new(&bytes[..]);
-                let mut decompressed_bytes = Vec::new();
-                decoder.read_to_end(&mut decompressed_bytes)
-                    .map_err(|e| format!("Failed to decompress gzipped response: {}", e))?;
-
-                // Check if the decompressed content is also gzipped (double compression)
-                let final_bytes = if decompressed_bytes.len() >= 2 && decompressed_bytes[0] == 0x1f && decompressed_bytes[1] == 0x8b {
-                    let mut second_decoder = GzDecoder::new(&decompressed_bytes[..]);
-                    let mut final_bytes = Vec::new();
-                    second_decoder.read_to_end(&mut final_bytes)
-                        .map_err(|e| format!("Failed to decompress second gzip layer: {}", e))?;
-                    final_bytes
-                } else {
-                    decompressed_bytes
-                };
-
-                // Try to convert to UTF-8 string
-                match String::from_utf8(final_bytes) {
-                    Ok(text) => text,
-                    Err(e) => {
-                        return Err(format!("Final decompressed response contains invalid UTF-8: {}", e).into());
-                    }
-                }
-            } else {
-                // Response is not gzipped, use as-is
-                String::from_utf8(bytes.to_vec())
-                    .map_err(|e| format!("Response contains invalid UTF-8: {}", e))?
-            };
-
-            let body: ConfigApiResponse = serde_json::from_str(&json_text)
-                .map_err(|e| format!("Failed to parse JSON response: {}", e))?;
-            // Update global config snapshot
-            set_global_config(body.config.clone());
-            Ok(body)
+            let limit = max_response_size();
+            let bytes = read_capped_body(&mut response, limit).await?;
+            let decoded = decode_response_body(&content_encoding, &bytes, limit)?;
+            // Verified before parsing, so an unsigned payload never reaches the firewall
+            if let Some(key) = SIGNING_KEY.get() {
+                verify_signature(key, &decoded, signature.as_deref())?;
+            }
+            let json_text = String::from_utf8(decoded)
+                .map_err(|e| format!("Response contains invalid UTF-8: {}", e))?;
+
+            parse_config_body(&json_text, etag).map_err(Into::into)
         }
         StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND | StatusCode::INTERNAL_SERVER_ERROR => {
-            let body: ErrorResponse = serde_json::from_str(&response.text().await?)?;
+            let body: ErrorResponse = serde_json::from_slice(&read_capped_body(&mut response, max_response_size()).await?)?;
             Err(format!("API Error: {}", body.error).into())
         }
 
@@ -161,6 +525,124 @@ pub async fn fetch_config(
     }
 }
 
+/// Read a response body chunk by chunk so an oversized one is cut off rather than buffered
+async fn read_capped_body(response: &mut reqwest::Response, limit: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if let Some(length) = response.content_length().filter(|length| *length > limit as u64) {
+        return Err(format!("config response of {} bytes exceeds the {} byte limit", length, limit).into());
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if bytes.len() + chunk.len() > limit {
+            return Err(format!("config response exceeds the {} byte limit", limit).into());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Newest `schema_version` of the config API this build understands. Responses without
+/// one predate versioning and are treated as version 1.
+pub const SUPPORTED_SCHEMA_VERSION: u64 = 1;
+
+/// Newer schema version last warned about, so the warning is logged once per version
+static NEWER_SCHEMA_VERSION: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Schema version of a response body, warning once when it is newer than supported.
+/// Unknown fields are ignored on parsing, so a newer response is still applied with the
+/// fields this build knows about.
+fn check_schema_version(value: &serde_json::Value) -> u64 {
+    let version = value.get("schema_version").and_then(serde_json::Value::as_u64).unwrap_or(1);
+    if version > SUPPORTED_SCHEMA_VERSION {
+        let mut last = NEWER_SCHEMA_VERSION.lock().unwrap_or_else(|e| e.into_inner());
+        if *last != Some(version) {
+            log::warn!(
+                schema_version = version, supported = SUPPORTED_SCHEMA_VERSION;
+                "config API sent schema version {}, this build understands up to {}; applying the fields it recognizes, upgrade moat to use the rest",
+                version, SUPPORTED_SCHEMA_VERSION
+            );
+            *last = Some(version);
+        }
+    }
+    version
+}
+
+/// Note on a parse error of a response in a newer schema, whose changes are the likely cause
+fn schema_hint(version: u64) -> String {
+    if version > SUPPORTED_SCHEMA_VERSION {
+        format!(" (response schema version {} is newer than the supported {})", version, SUPPORTED_SCHEMA_VERSION)
+    } else {
+        String::new()
+    }
+}
+
+/// A body with a top-level `delta` object is a delta, anything else a full snapshot
+fn parse_config_body(json_text: &str, etag: Option<String>) -> Result<ConfigBody, String> {
+    let value: serde_json::Value = serde_json::from_str(json_text)
+        .map_err(|e| format!("Failed to parse JSON response: {}", e))?;
+    let schema_version = check_schema_version(&value);
+    if value.get("delta").is_some() {
+        let body: ConfigDeltaResponse = serde_json::from_value(value)
+            .map_err(|e| format!("Failed to parse config delta: {}{}", e, schema_hint(schema_version)))?;
+        return Ok(ConfigBody::Delta(body.delta));
+    }
+    let mut body: ConfigApiResponse = serde_json::from_value(value)
+        .map_err(|e| format!("Failed to parse JSON response: {}{}", e, schema_hint(schema_version)))?;
+    body.config.etag = etag;
+    log_group_format(&body.config.access_rules);
+    Ok(ConfigBody::Full(body))
+}
+
+fn is_gzip(bytes: &[u8]) -> bool {
+    bytes.len() >= 2 && bytes[0] == 0x1f && bytes[1] == 0x8b
+}
+
+/// RFC 1950 header: deflate method with a checksum that makes the first two bytes a
+/// multiple of 31
+fn is_zlib(bytes: &[u8]) -> bool {
+    bytes.len() >= 2 && bytes[0] & 0x0f == 8 && (u16::from(bytes[0]) << 8 | u16::from(bytes[1])) % 31 == 0
+}
+
+/// Decompress at most `limit` bytes, failing instead of inflating a decompression bomb
+fn inflate<R: Read>(decoder: R, what: &str, limit: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    decoder.take(limit as u64 + 1).read_to_end(&mut out)
+        .map_err(|e| format!("Failed to decompress {} response: {}", what, e))?;
+    if out.len() > limit {
+        return Err(format!("decompressed {} response exceeds the {} byte limit", what, limit));
+    }
+    Ok(out)
+}
+
+/// Undo the `Content-Encoding` of a config response. The payload is checked before
+/// decoding: a body advertised as compressed that is plain JSON is passed through, and
+/// a gzip body is detected even without the header. A second gzip layer, as produced
+/// by some mirrors, is removed as well. No layer may decompress to more than `limit` bytes.
+fn decode_response_body(content_encoding: &str, bytes: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let encoding = content_encoding.to_ascii_lowercase();
+    let looks_like_json = |b: &[u8]| b.iter().find(|c| !c.is_ascii_whitespace()).is_some_and(|c| *c == b'{' || *c == b'[');
+
+    let decoded = if is_gzip(bytes) {
+        inflate(GzDecoder::new(bytes), "gzipped", limit)?
+    } else if encoding.contains("deflate") && !looks_like_json(bytes) {
+        // "deflate" is zlib-wrapped by the spec, but raw deflate streams are common too
+        if is_zlib(bytes) {
+            inflate(ZlibDecoder::new(bytes), "deflated", limit)?
+        } else {
+            inflate(DeflateDecoder::new(bytes), "deflated", limit)?
+        }
+    } else {
+        if encoding.contains("gzip") || encoding.contains("deflate") {
+            log::debug!("config response advertised {} encoding but is not compressed", content_encoding);
+        }
+        return Ok(bytes.to_vec());
+    };
+
+    if is_gzip(&decoded) {
+        return inflate(GzDecoder::new(&decoded[..]), "second gzip layer of the", limit);
+    }
+    Ok(decoded)
+}
+
 /// Fetch config and run a user-provided callback to apply it.
 /// The callback can update WAF rules, BPF maps, caches, etc.
 pub async fn fetch_and_apply<F>(
@@ -171,7 +653,244 @@ pub async fn fetch_and_apply<F>(
 where
     F: FnMut(&ConfigApiResponse) -> Result<(), Box<dyn std::error::Error>>,
 {
-    let resp = fetch_config(base_url, api_key).await?;
+    let resp = fetch_config(base_url, api_key, None).await?;
     on_config(&resp)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_read_capped_body_cuts_off_oversized_bodies() {
+        let error = |body: &str| reqwest::Response::from(
+            hyper::Response::builder().status(500).body(body.to_string()).unwrap(),
+        );
+        let body = read_capped_body(&mut error(r#"{"success": false, "error": "boom", "details": {}}"#), 64).await.unwrap();
+        assert_eq!(serde_json::from_slice::<ErrorResponse>(&body).unwrap().error, "boom");
+        let err = read_capped_body(&mut error(&"x".repeat(65)), 64).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds the 64 byte limit"));
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_is_answered_without_a_body() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let _ = crate::http_client::init_global_client();
+        // Answers every request with 304 and reports whether it carried the held ETag
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, mut conditional) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0u8; 4096];
+                let n = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..n]).to_ascii_lowercase();
+                let _ = tx.send(request.contains("if-none-match: \"r1\""));
+                stream.write_all(b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n").await.unwrap();
+            }
+        });
+
+        let body = request_config(&base_url, "key", None, Some("\"r1\"")).await.unwrap();
+        assert!(matches!(body, ConfigBody::NotModified));
+        assert!(conditional.recv().await.unwrap());
+        // A 304 to an unconditional request is not taken as "unchanged"
+        assert!(request_config(&base_url, "key", None, None).await.is_err());
+        assert!(!conditional.recv().await.unwrap());
+        assert!(not_modified(None).is_err());
+    }
+
+    #[test]
+    fn test_decode_response_body_handles_each_encoding() {
+        use flate2::Compression;
+        use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+
+        let json = br#"{"success": true}"#;
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(json).unwrap();
+        let gz = gz.finish().unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(json).unwrap();
+        let zlib = zlib.finish().unwrap();
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(json).unwrap();
+        let raw = raw.finish().unwrap();
+
+        assert_eq!(decode_response_body("gzip", &gz, DEFAULT_MAX_RESPONSE_SIZE).unwrap(), json);
+        // gzip is recognized by its magic bytes even without the header
+        assert_eq!(decode_response_body("", &gz, DEFAULT_MAX_RESPONSE_SIZE).unwrap(), json);
+        assert_eq!(decode_response_body("deflate", &zlib, DEFAULT_MAX_RESPONSE_SIZE).unwrap(), json);
+        assert_eq!(decode_response_body("Deflate", &raw, DEFAULT_MAX_RESPONSE_SIZE).unwrap(), json);
+        // Advertised but not actually compressed
+        assert_eq!(decode_response_body("gzip", json, DEFAULT_MAX_RESPONSE_SIZE).unwrap(), json);
+        assert_eq!(decode_response_body("deflate", json, DEFAULT_MAX_RESPONSE_SIZE).unwrap(), json);
+
+        let mut double = GzEncoder::new(Vec::new(), Compression::default());
+        double.write_all(&gz).unwrap();
+        assert_eq!(decode_response_body("gzip", &double.finish().unwrap(), DEFAULT_MAX_RESPONSE_SIZE).unwrap(), json);
+
+        assert!(decode_response_body("deflate", b"\x00garbage", DEFAULT_MAX_RESPONSE_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_decode_response_body_enforces_size_limit() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        // A small gzip body that inflates far past the limit
+        let mut bomb = GzEncoder::new(Vec::new(), Compression::best());
+        bomb.write_all(&vec![b' '; 1024 * 1024]).unwrap();
+        let bomb = bomb.finish().unwrap();
+        assert!(bomb.len() < 4096);
+        let err = decode_response_body("gzip", &bomb, 64 * 1024).unwrap_err();
+        assert!(err.contains("65536 byte limit"), "{}", err);
+        assert_eq!(decode_response_body("gzip", &bomb, 1024 * 1024).unwrap().len(), 1024 * 1024);
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let key = parse_signing_key(&BASE64.encode(pair.public_key().as_ref())).unwrap();
+        let body = br#"{"success": true}"#;
+        let signature = BASE64.encode(pair.sign(body).as_ref());
+
+        assert!(verify_signature(&key, body, Some(&signature)).is_ok());
+        assert!(verify_signature(&key, br#"{"success": false}"#, Some(&signature)).is_err());
+        assert!(verify_signature(&key, body, None).unwrap_err().contains(SIGNATURE_HEADER));
+        assert!(verify_signature(&key, body, Some("not base64!")).is_err());
+        assert!(parse_signing_key(&BASE64.encode([0u8; 16])).is_err());
+    }
+
+    fn delta_base() -> Config {
+        serde_json::from_str(r#"{
+            "access_rules": {"id": "r1", "name": "rules", "description": "",
+                "allow": {"ips": ["192.0.2.1"]}, "block": {"ips": ["10.0.0.0/8", "198.51.100.0/24"], "expires": {"10.0.0.0/8": "2999-01-01T00:00:00Z"}}},
+            "waf_rules": {"rules": []},
+            "created_at": "", "updated_at": "", "last_modified": "",
+            "version": "7"
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn test_newer_schema_version_keeps_known_fields() {
+        let body = r#"{"success": true, "schema_version": 3, "new_top_level": {"x": 1},
+            "config": {
+                "access_rules": {"id": "r1", "name": "rules", "description": "", "priority": 5,
+                    "allow": {"ips": []}, "block": {"ips": ["10.0.0.0/8"], "reputation": {"min": 10}}},
+                "waf_rules": {"rules": []},
+                "created_at": "", "updated_at": "", "last_modified": "", "signed_by": "ci"
+            }}"#;
+        let ConfigBody::Full(full) = parse_config_body(body, None).unwrap() else {
+            panic!("expected a full config");
+        };
+        assert_eq!(full.config.access_rules.block.ips, vec!["10.0.0.0/8"]);
+
+        // A field this build requires but the newer schema dropped still fails, with a hint
+        let err = parse_config_body(r#"{"success": true, "schema_version": 3}"#, None).err().unwrap();
+        assert!(err.contains("schema version 3"), "{}", err);
+        let err = parse_config_body(r#"{"success": true}"#, None).err().unwrap();
+        assert!(!err.contains("schema version"), "{}", err);
+    }
+
+    #[test]
+    fn test_config_delta_merges_into_base_version() {
+        let body = r#"{"success": true, "delta": {"base_version": "7", "version": "8",
+            "block": {"added": ["203.0.113.0/24"], "removed": ["10.0.0.0/8"],
+                "expires": {"203.0.113.0/24": "2999-06-01T00:00:00Z"}}}}"#;
+        let ConfigBody::Delta(delta) = parse_config_body(body, None).unwrap() else {
+            panic!("expected a delta");
+        };
+        let merged = delta.apply_to(&delta_base()).unwrap();
+        assert_eq!(merged.access_rules.block.ips, vec!["198.51.100.0/24", "203.0.113.0/24"]);
+        // The expiry of a removed entry goes with it
+        assert_eq!(
+            merged.access_rules.block.expires,
+            HashMap::from([("203.0.113.0/24".to_string(), "2999-06-01T00:00:00Z".to_string())])
+        );
+        assert_eq!(merged.access_rules.allow.ips, vec!["192.0.2.1"]);
+        assert_eq!(merged.rule_version(), Some("8"));
+
+        // Another base version, or entries the held config does not agree with
+        let mut stale = delta.clone();
+        stale.base_version = "6".to_string();
+        assert!(stale.apply_to(&delta_base()).is_err());
+        let mut desync = delta.clone();
+        desync.block.removed = vec!["172.16.0.0/12".to_string()];
+        assert!(desync.apply_to(&delta_base()).is_err());
+        let mut duplicate = delta;
+        duplicate.block.added = vec!["198.51.100.0/24".to_string()];
+        assert!(duplicate.apply_to(&delta_base()).is_err());
+    }
+
+    #[test]
+    fn test_full_snapshot_body_is_not_a_delta() {
+        let body = serde_json::json!({"success": true, "config": delta_base()}).to_string();
+        let ConfigBody::Full(full) = parse_config_body(&body, Some("\"e1\"".to_string())).unwrap() else {
+            panic!("expected a full snapshot");
+        };
+        assert_eq!(full.config.rule_version(), Some("\"e1\""));
+    }
+
+    #[test]
+    fn test_group_cidrs_accept_flat_and_nested_shapes() {
+        let rule: AccessRule = serde_json::from_value(serde_json::json!({
+            "id": "r", "name": "n", "description": "", "allow": {},
+            "block": {
+                "country": [{
+                    "NL": ["192.0.2.0/24"],
+                    "US": {"ips": ["198.51.100.0/24"], "asn": {"AS64500": ["203.0.113.0/24"]}}
+                }]
+            }
+        }))
+        .unwrap();
+        let groups = &rule.block.country[0];
+        assert_eq!(groups["NL"].entries(), vec!["192.0.2.0/24"]);
+        let mut us = groups["US"].entries();
+        us.sort();
+        assert_eq!(us, vec!["198.51.100.0/24", "203.0.113.0/24"]);
+        assert_eq!(groups["US"].len(), 2);
+        assert_eq!(rule.group_format(), Some(GroupFormat::Mixed));
+
+        let flat: AccessRule = serde_json::from_value(serde_json::json!({
+            "id": "r", "name": "n", "description": "", "allow": {},
+            "block": {"asn": [{"AS64500": ["203.0.113.0/24"]}]}
+        }))
+        .unwrap();
+        assert_eq!(flat.group_format(), Some(GroupFormat::Flat));
+    }
+
+    #[test]
+    fn test_fetch_headers_override_user_agent() {
+        let extra = HashMap::from([("X-Tenant".to_string(), "acme".to_string())]);
+        let headers = build_fetch_headers(Some("moat-edge/1.0"), &extra).unwrap();
+        assert_eq!(headers["x-tenant"], "acme");
+        assert_eq!(headers[USER_AGENT], "moat-edge/1.0");
+        assert!(!build_fetch_headers(None, &extra).unwrap().contains_key(USER_AGENT));
+
+        let bad = HashMap::from([("X Tenant".to_string(), "acme".to_string())]);
+        assert!(build_fetch_headers(None, &bad).is_err());
+    }
+
+    #[test]
+    fn test_rule_version_prefers_etag_over_body_version() {
+        let mut cfg: Config = serde_json::from_str(r#"{
+            "access_rules": {"id": "r1", "name": "rules", "description": "", "allow": {}, "block": {}},
+            "waf_rules": {"rules": []},
+            "created_at": "", "updated_at": "", "last_modified": "",
+            "version": "42"
+        }"#).unwrap();
+        assert_eq!(cfg.rule_version(), Some("42"));
+
+        cfg.etag = Some("\"abc\"".to_string());
+        assert_eq!(cfg.rule_version(), Some("\"abc\""));
+
+        cfg.etag = None;
+        cfg.version = None;
+        assert_eq!(cfg.rule_version(), None);
+    }
+}
@@ -0,0 +1,705 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Body, Bytes};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+
+use crate::access_rules::export::{ExportFormat, export_rows, export_rules, render};
+use crate::access_rules::events::EventSink;
+use crate::access_rules::{ApplyPause, ManualRules, RecentRejects, RuleSnapshot};
+use crate::bpf::FilterSkel;
+use crate::cli::ControlApiConfig;
+use crate::firewall::{BanFile, MOATFirewall, TemporaryBans, skeleton_firewalls};
+
+/// Largest request body accepted by the control API
+const MAX_BODY_BYTES: usize = 64 * 1024;
+/// Longest TTL a temporary ban may be given; longer bans belong in the rule sets
+const MAX_BAN_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Shared state of the control API handlers
+pub struct ControlApiState {
+    token: String,
+    manual_rules: Arc<ManualRules>,
+    apply_pause: Arc<ApplyPause>,
+    temporary_bans: Arc<TemporaryBans>,
+    /// Skeletons the incident response operations write to directly
+    skels: Vec<Arc<FilterSkel<'static>>>,
+    refresh_tx: mpsc::Sender<()>,
+    snapshot_rx: watch::Receiver<RuleSnapshot>,
+    /// Served by `GET /rejects`; None when the buffer is disabled
+    recent_rejects: Option<Arc<RecentRejects>>,
+    /// Directory exports may be written into; None only returns them in the response
+    export_dir: Option<PathBuf>,
+    /// Directory ban files may be read from; None disables `POST /rules/ban-file`
+    ban_file_dir: Option<PathBuf>,
+    /// Report what the incident response operations would change without writing
+    dry_run: bool,
+    /// Audit trail the bans of the incident response operations are reported to
+    event_sink: Option<Arc<EventSink>>,
+}
+
+impl ControlApiState {
+    pub fn new(
+        token: String,
+        manual_rules: Arc<ManualRules>,
+        apply_pause: Arc<ApplyPause>,
+        temporary_bans: Arc<TemporaryBans>,
+        skels: Vec<Arc<FilterSkel<'static>>>,
+        refresh_tx: mpsc::Sender<()>,
+        snapshot_rx: watch::Receiver<RuleSnapshot>,
+    ) -> Self {
+        Self {
+            token,
+            manual_rules,
+            apply_pause,
+            temporary_bans,
+            skels,
+            refresh_tx,
+            snapshot_rx,
+            recent_rejects: None,
+            export_dir: None,
+            ban_file_dir: None,
+            dry_run: false,
+            event_sink: None,
+        }
+    }
+
+    /// Serve the recently rejected rule entries on `GET /rejects`
+    pub fn with_recent_rejects(mut self, recent_rejects: Option<Arc<RecentRejects>>) -> Self {
+        self.recent_rejects = recent_rejects;
+        self
+    }
+
+    /// Let `POST /rules/export` write files below `export_dir`
+    pub fn with_export_dir(mut self, export_dir: Option<PathBuf>) -> Self {
+        self.export_dir = export_dir;
+        self
+    }
+
+    /// Let `POST /rules/ban-file` read files below `ban_file_dir`
+    pub fn with_ban_file_dir(mut self, ban_file_dir: Option<PathBuf>) -> Self {
+        self.ban_file_dir = ban_file_dir;
+        self
+    }
+
+    /// Only count what clearing and banning from a file would change
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Report the bans written from ban files to `event_sink`
+    pub fn with_event_sink(mut self, event_sink: Option<Arc<EventSink>>) -> Self {
+        self.event_sink = event_sink;
+        self
+    }
+
+    /// Check the `Authorization: Bearer <token>` header in constant time
+    fn is_authorized<B>(&self, req: &Request<B>) -> bool {
+        let Some(provided) = req
+            .headers()
+            .get("authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        let (a, b) = (provided.as_bytes(), self.token.as_bytes());
+        a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleRequest {
+    ip: String,
+    /// Ban only for this many seconds instead of until unbanned
+    #[serde(default)]
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportRequest {
+    /// File below `export_dir` the applied rule set is written to; without one the
+    /// export is returned in the response body
+    #[serde(default)]
+    path: Option<PathBuf>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[derive(Debug, Deserialize)]
+struct BanFileRequest {
+    /// File below `ban_file_dir` with one IP, CIDR or range per line
+    path: PathBuf,
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, error: &str) -> Response<Full<Bytes>> {
+    json_response(status, json!({ "success": false, "error": error }))
+}
+
+fn format_rules_v4<'a>(rules: impl IntoIterator<Item = &'a (Ipv4Addr, u32)>) -> Vec<String> {
+    let mut out: Vec<String> = rules.into_iter().map(|(net, prefix)| format!("{}/{}", net, prefix)).collect();
+    out.sort();
+    out
+}
+
+fn format_rules_v6<'a>(rules: impl IntoIterator<Item = &'a (Ipv6Addr, u32)>) -> Vec<String> {
+    let mut out: Vec<String> = rules.into_iter().map(|(net, prefix)| format!("{}/{}", net, prefix)).collect();
+    out.sort();
+    out
+}
+
+async fn read_json_body<T, B>(req: Request<B>) -> Result<T, Response<Full<Bytes>>>
+where
+    T: DeserializeOwned,
+    B: Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let bytes = Limited::new(req.into_body(), MAX_BODY_BYTES)
+        .collect()
+        .await
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, &format!("failed to read body: {}", e)))?
+        .to_bytes();
+    serde_json::from_slice(&bytes)
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, &format!("invalid JSON body: {}", e)))
+}
+
+async fn handle_request<B>(state: Arc<ControlApiState>, req: Request<B>) -> Result<Response<Full<Bytes>>>
+where
+    B: Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    if !state.is_authorized(&req) {
+        return Ok(error_response(StatusCode::UNAUTHORIZED, "missing or invalid bearer token"));
+    }
+
+    let ban = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/rules") => {
+            let (manual, manual_v6) = state.manual_rules.snapshot();
+            let applied = state.snapshot_rx.borrow().clone();
+            return Ok(json_response(StatusCode::OK, json!({
+                "success": true,
+                "paused": state.apply_pause.is_paused(),
+                "manual": { "ipv4": format_rules_v4(&manual), "ipv6": format_rules_v6(&manual_v6) },
+                "applied": { "ipv4": format_rules_v4(&applied.rules), "ipv6": format_rules_v6(&applied.rules_v6) },
+            })));
+        }
+        (&Method::GET, "/rejects") => {
+            let Some(recent) = &state.recent_rejects else {
+                return Ok(error_response(StatusCode::NOT_FOUND, "recent rejects buffer is disabled"));
+            };
+            return Ok(json_response(StatusCode::OK, json!({ "success": true, "rejects": recent.recent() })));
+        }
+        (&Method::POST, "/rules/clear") => return Ok(clear_all(&state)),
+        (&Method::POST, "/rules/pause") => {
+            let changed = state.apply_pause.pause();
+            return Ok(json_response(StatusCode::OK, json!({ "success": true, "paused": true, "changed": changed })));
+        }
+        (&Method::POST, "/rules/resume") => {
+            let changed = state.apply_pause.resume();
+            // Apply the queued changes now instead of on the next tick
+            if changed {
+                let _ = state.refresh_tx.try_send(());
+            }
+            return Ok(json_response(StatusCode::OK, json!({ "success": true, "paused": false, "changed": changed })));
+        }
+        (&Method::POST, "/rules/ban-file") => {
+            return Ok(match read_json_body::<BanFileRequest, _>(req).await {
+                Ok(body) => ban_from_file(&state, &body),
+                Err(resp) => resp,
+            });
+        }
+        (&Method::POST, "/rules/export") => {
+            return Ok(match read_json_body::<ExportRequest, _>(req).await {
+                Ok(body) => export(&state, &body),
+                Err(resp) => resp,
+            });
+        }
+        (&Method::POST, "/rules/ban") => true,
+        (&Method::POST, "/rules/unban") => false,
+        (_, "/rules") | (_, "/rules/ban") | (_, "/rules/unban") | (_, "/rules/clear") | (_, "/rules/ban-file")
+        | (_, "/rules/pause") | (_, "/rules/resume") | (_, "/rules/export") | (_, "/rejects") => {
+            return Ok(error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"));
+        }
+        _ => return Ok(error_response(StatusCode::NOT_FOUND, "not found")),
+    };
+
+    let body: RuleRequest = match read_json_body(req).await {
+        Ok(body) => body,
+        Err(resp) => return Ok(resp),
+    };
+    if let (true, Some(ttl_secs)) = (ban, body.ttl_secs) {
+        if ttl_secs > MAX_BAN_TTL_SECS {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("ttl_secs {} exceeds the maximum of {}", ttl_secs, MAX_BAN_TTL_SECS),
+            ));
+        }
+        return Ok(temporary_ban(&state, &body.ip, Duration::from_secs(ttl_secs)));
+    }
+    let result = if ban {
+        state.manual_rules.ban(&body.ip)
+    } else {
+        state.manual_rules.unban(&body.ip)
+    };
+    let changed = match result {
+        Ok(changed) => changed,
+        Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+    };
+
+    log::info!(
+        ip = body.ip.as_str(), action = if ban { "ban" } else { "unban" };
+        "control API {} {} ({} entries changed)", if ban { "ban" } else { "unban" }, body.ip, changed
+    );
+    // A full refresh is already queued when the channel is full
+    if changed > 0 {
+        let _ = state.refresh_tx.try_send(());
+    }
+    Ok(json_response(StatusCode::OK, json!({ "success": true, "changed": changed })))
+}
+
+/// Ban every network of `entry` until `ttl` elapses. Temporary bans bypass the manual
+/// rule set and are written to the maps right away; with `dry_run` they are only counted.
+fn temporary_ban(state: &ControlApiState, entry: &str, ttl: Duration) -> Response<Full<Bytes>> {
+    let (v4, v6) = match ManualRules::parse(entry) {
+        Ok(nets) => nets,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let nets = v4.into_iter().map(|(net, prefix)| (IpAddr::V4(net), prefix))
+        .chain(v6.into_iter().map(|(net, prefix)| (IpAddr::V6(net), prefix)));
+    let mut banned = 0;
+    for (net, prefix) in nets {
+        if state.dry_run {
+            banned += 1;
+            continue;
+        }
+        if let Err(e) = state.temporary_bans.ban_ip_for(net, prefix, ttl) {
+            log::error!(ip:% = net, prefix = prefix; "control API temporary ban of {}/{} failed: {}", net, prefix, e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("failed to ban {}/{}: {}", net, prefix, e));
+        }
+        banned += 1;
+    }
+    log::info!(
+        ip = entry, action = "temporary_ban";
+        "{}control API temporary ban {} for {}s ({} entries)",
+        if state.dry_run { "dry-run: " } else { "" }, entry, ttl.as_secs(), banned
+    );
+    json_response(StatusCode::OK, json!({ "success": true, "changed": banned, "ttl_secs": ttl.as_secs(), "dry_run": state.dry_run }))
+}
+
+/// Incident response: delete every block entry from every skeleton, bypassing the sync
+fn clear_all(state: &ControlApiState) -> Response<Full<Bytes>> {
+    let mut deleted = 0;
+    for skel in &state.skels {
+        match MOATFirewall::new(skel).clear_all(state.dry_run) {
+            Ok(n) => deleted += n,
+            Err(e) => {
+                log::error!("control API clear of all block entries failed: {}", e);
+                return error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("failed to clear block entries: {}", e));
+            }
+        }
+    }
+    if state.dry_run {
+        log::warn!(action = "clear_all"; "dry-run: control API clear would delete {} block entries", deleted);
+    } else {
+        log::warn!(action = "clear_all"; "control API cleared all block entries ({} deleted)", deleted);
+    }
+    json_response(StatusCode::OK, json!({ "success": true, "changed": deleted, "dry_run": state.dry_run }))
+}
+
+/// Incident response: ban every entry of a file below the ban file directory on every
+/// skeleton, bypassing the sync. Rejected lines are reported by number only.
+fn ban_from_file(state: &ControlApiState, body: &BanFileRequest) -> Response<Full<Bytes>> {
+    let Some(dir) = &state.ban_file_dir else {
+        return error_response(StatusCode::FORBIDDEN, "ban_file_dir is not configured");
+    };
+    let path = match confine(dir, &body.path) {
+        Ok(path) => path,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &format!("refusing ban file path {}: {}", body.path.display(), e)),
+    };
+    let file = match std::fs::read_to_string(&path) {
+        Ok(contents) => BanFile::parse(&contents),
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &format!("failed to read {}: {}", body.path.display(), e)),
+    };
+    let mut rejected = file.rejected.clone();
+    let mut banned = file.networks();
+    if !state.dry_run {
+        for mut fw in skeleton_firewalls(&state.skels, state.event_sink.as_deref()) {
+            let report = fw.ban_from_file(&file);
+            banned = banned.min(report.banned);
+            for line in report.rejected {
+                if !rejected.iter().any(|r| r.line == line.line) {
+                    rejected.push(line);
+                }
+            }
+        }
+    }
+    rejected.sort_by_key(|r| r.line);
+    log::warn!(
+        action = "ban_from_file";
+        "{}control API banned {} entries from {} ({} lines rejected)",
+        if state.dry_run { "dry-run: " } else { "" }, banned, path.display(), rejected.len()
+    );
+    let rejected: Vec<_> = rejected.iter().map(|r| json!({ "line": r.line, "reason": r.reason })).collect();
+    json_response(StatusCode::OK, json!({ "success": true, "changed": banned, "rejected": rejected, "dry_run": state.dry_run }))
+}
+
+/// Resolve `requested`, relative to `dir`, to a path that stays inside `dir`: no
+/// absolute path or `..` component, no symlink as the file itself, and the parent
+/// must still be below `dir` once its symlinks are resolved
+fn confine(dir: &Path, requested: &Path) -> Result<PathBuf, String> {
+    if !requested.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err("path must be relative to the configured directory, without '..'".to_string());
+    }
+    let name = requested.file_name().ok_or("path does not name a file")?;
+    let root = dir.canonicalize().map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let joined = root.join(requested);
+    let parent = joined.parent().unwrap_or(&root);
+    let parent = parent.canonicalize().map_err(|e| format!("{}: {}", parent.display(), e))?;
+    if !parent.starts_with(&root) {
+        return Err("path leaves the configured directory".to_string());
+    }
+    let path = parent.join(name);
+    if path.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()) {
+        return Err("path is a symlink".to_string());
+    }
+    Ok(path)
+}
+
+/// Return the rule set of the last applied cycle, or write it to a file below the
+/// configured export directory
+fn export(state: &ControlApiState, body: &ExportRequest) -> Response<Full<Bytes>> {
+    let applied = state.snapshot_rx.borrow().clone();
+    let Some(requested) = &body.path else {
+        let rows = export_rows(&applied);
+        return match render(&rows, body.format) {
+            Ok(rendered) => Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", match body.format {
+                    ExportFormat::Json => "application/json",
+                    ExportFormat::Csv => "text/csv",
+                })
+                .body(Full::new(Bytes::from(rendered)))
+                .unwrap(),
+            Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("failed to render export: {}", e)),
+        };
+    };
+    let Some(dir) = &state.export_dir else {
+        return error_response(StatusCode::FORBIDDEN, "export_dir is not configured; omit path to receive the export");
+    };
+    let path = match confine(dir, requested) {
+        Ok(path) => path,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &format!("refusing export path {}: {}", requested.display(), e)),
+    };
+    match export_rules(&applied, body.format, &path) {
+        Ok(exported) => {
+            log::info!(action = "export"; "control API exported {} rules to {}", exported, path.display());
+            json_response(StatusCode::OK, json!({ "success": true, "exported": exported }))
+        }
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("failed to write {}: {}", path.display(), e),
+        ),
+    }
+}
+
+/// Start the control API server if enabled. Ban and unban requests update the manual
+/// rule set and trigger an access rules refresh, which applies them to the BPF maps.
+pub async fn start_control_api_server(
+    config: ControlApiConfig,
+    state: Arc<ControlApiState>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> Result<()> {
+    let addr = config.bind.parse::<SocketAddr>()
+        .map_err(|e| anyhow!("Invalid control API bind address '{}': {}", config.bind, e))?;
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow!("Failed to bind control API server to {}: {}", addr, e))?;
+
+    log::info!("Control API listening on http://{}", addr);
+
+    loop {
+        tokio::select! {
+            result = listener.accept() => {
+                match result {
+                    Ok((stream, _)) => {
+                        let state = state.clone();
+                        tokio::spawn(async move {
+                            let io = TokioIo::new(stream);
+                            let service = service_fn(move |req| handle_request(state.clone(), req));
+                            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                                log::error!("Control API connection error: {}", err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        log::error!("Control API accept error: {}", err);
+                    }
+                }
+            }
+            _ = shutdown_rx.changed() => {
+                if *shutdown_rx.borrow() {
+                    log::info!("Control API server shutting down");
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> (Arc<ControlApiState>, mpsc::Receiver<()>) {
+        let (refresh_tx, refresh_rx) = mpsc::channel(1);
+        let (_snapshot_tx, snapshot_rx) = watch::channel(RuleSnapshot::default());
+        let state = ControlApiState::new(
+            "secret".to_string(),
+            Arc::new(ManualRules::default()),
+            Arc::new(ApplyPause::default()),
+            Arc::new(TemporaryBans::new(Vec::new())),
+            Vec::new(),
+            refresh_tx,
+            snapshot_rx,
+        );
+        (Arc::new(state), refresh_rx)
+    }
+
+    fn request(method: Method, path: &str, token: Option<&str>, body: &str) -> Request<Full<Bytes>> {
+        let mut builder = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        builder.body(Full::new(Bytes::from(body.to_string()))).unwrap()
+    }
+
+    async fn body_json(resp: Response<Full<Bytes>>) -> serde_json::Value {
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rejects_missing_or_wrong_token() {
+        let (state, _rx) = test_state();
+        let resp = handle_request(state.clone(), request(Method::GET, "/rules", None, "")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = handle_request(state, request(Method::GET, "/rules", Some("wrong"), "")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_ban_unban_and_list() {
+        let (state, mut refresh_rx) = test_state();
+
+        let resp = handle_request(
+            state.clone(),
+            request(Method::POST, "/rules/ban", Some("secret"), r#"{"ip": "203.0.113.0/24"}"#),
+        ).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body_json(resp).await["changed"], 1);
+        assert!(refresh_rx.try_recv().is_ok());
+
+        let resp = handle_request(state.clone(), request(Method::GET, "/rules", Some("secret"), "")).await.unwrap();
+        let body = body_json(resp).await;
+        assert_eq!(body["manual"]["ipv4"], json!(["203.0.113.0/24"]));
+        assert_eq!(body["applied"]["ipv4"], json!([]));
+
+        let resp = handle_request(
+            state.clone(),
+            request(Method::POST, "/rules/unban", Some("secret"), r#"{"ip": "203.0.113.0/24"}"#),
+        ).await.unwrap();
+        assert_eq!(body_json(resp).await["changed"], 1);
+        assert!(state.manual_rules.snapshot().0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recent_rejects() {
+        let (state, _rx) = test_state();
+        let resp = handle_request(state, request(Method::GET, "/rejects", Some("secret"), "")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let recent = Arc::new(RecentRejects::new(10));
+        recent.record(&[crate::access_rules::RejectedEntry {
+            rule_source: "block.ips".to_string(),
+            entry: "10.0.0.0/99".to_string(),
+            reason: "invalid IPv4 ip/cidr".to_string(),
+        }]);
+        let (state, _rx) = test_state();
+        let state = Arc::new(Arc::into_inner(state).unwrap().with_recent_rejects(Some(recent)));
+        let resp = handle_request(state.clone(), request(Method::GET, "/rejects", Some("secret"), "")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_json(resp).await;
+        assert_eq!(body["rejects"][0]["entry"], "10.0.0.0/99");
+        assert_eq!(body["rejects"][0]["reason"], "invalid IPv4 ip/cidr");
+        let resp = handle_request(state, request(Method::POST, "/rejects", Some("secret"), "")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        let (state, mut refresh_rx) = test_state();
+
+        let resp = handle_request(state.clone(), request(Method::POST, "/rules/pause", Some("secret"), "")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body_json(resp).await["changed"], true);
+        assert!(state.apply_pause.is_paused());
+        let resp = handle_request(state.clone(), request(Method::POST, "/rules/pause", Some("secret"), "")).await.unwrap();
+        assert_eq!(body_json(resp).await["changed"], false);
+        let resp = handle_request(state.clone(), request(Method::GET, "/rules", Some("secret"), "")).await.unwrap();
+        assert_eq!(body_json(resp).await["paused"], true);
+        assert!(refresh_rx.try_recv().is_err());
+
+        let resp = handle_request(state.clone(), request(Method::POST, "/rules/resume", Some("secret"), "")).await.unwrap();
+        assert_eq!(body_json(resp).await["changed"], true);
+        assert!(!state.apply_pause.is_paused());
+        assert!(refresh_rx.try_recv().is_ok());
+        let resp = handle_request(state, request(Method::GET, "/rules/pause", Some("secret"), "")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_export_applied_rules() {
+        let (state, _rx) = test_state();
+        let resp = handle_request(state.clone(), request(Method::POST, "/rules/export", Some("secret"), r#"{"format": "csv"}"#)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["Content-Type"], "text/csv");
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&bytes[..], b"ip,prefix,family,source\n");
+
+        // Without an export directory nothing is written to disk
+        let body = json!({ "path": "rules.csv", "format": "csv" }).to_string();
+        let resp = handle_request(state.clone(), request(Method::POST, "/rules/export", Some("secret"), &body)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let body = json!({ "format": "xml" }).to_string();
+        let resp = handle_request(state, request(Method::POST, "/rules/export", Some("secret"), &body)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_export_stays_in_export_dir() {
+        let dir = std::env::temp_dir().join(format!("moat-control-export-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let (state, _rx) = test_state();
+        let state = Arc::new(Arc::into_inner(state).unwrap().with_export_dir(Some(dir.clone())));
+        let export = |path: &str| {
+            let body = json!({ "path": path, "format": "csv" }).to_string();
+            handle_request(state.clone(), request(Method::POST, "/rules/export", Some("secret"), &body))
+        };
+
+        assert_eq!(export("sub/rules.csv").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(std::fs::read_to_string(dir.join("sub/rules.csv")).unwrap(), "ip,prefix,family,source\n");
+        for escape in ["../rules.csv", "sub/../../rules.csv", "/etc/passwd", ""] {
+            assert_eq!(export(escape).await.unwrap().status(), StatusCode::BAD_REQUEST, "{}", escape);
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(std::env::temp_dir(), dir.join("out")).unwrap();
+            std::os::unix::fs::symlink(dir.join("sub/rules.csv"), dir.join("link.csv")).unwrap();
+            assert_eq!(export("out/rules.csv").await.unwrap().status(), StatusCode::BAD_REQUEST);
+            assert_eq!(export("link.csv").await.unwrap().status(), StatusCode::BAD_REQUEST);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_ban_file_is_confined_and_never_echoed() {
+        let dir = std::env::temp_dir().join(format!("moat-control-ban-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("incident.txt"), "192.0.2.0/24\nroot:$6$secret\n").unwrap();
+        let ban_file = |state: &Arc<ControlApiState>, path: &str| {
+            let body = json!({ "path": path }).to_string();
+            handle_request(state.clone(), request(Method::POST, "/rules/ban-file", Some("secret"), &body))
+        };
+
+        let (state, _rx) = test_state();
+        assert_eq!(ban_file(&state, "incident.txt").await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let state = Arc::new(Arc::into_inner(state).unwrap().with_ban_file_dir(Some(dir.clone())));
+        let resp = ban_file(&state, "incident.txt").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_json(resp).await;
+        assert_eq!(body["changed"], 1);
+        assert_eq!(body["rejected"], json!([{ "line": 2, "reason": "invalid IPv6 ip/cidr" }]));
+        assert!(!body.to_string().contains("secret"));
+        for escape in ["../../etc/shadow", "/etc/shadow"] {
+            assert_eq!(ban_file(&state, escape).await.unwrap().status(), StatusCode::BAD_REQUEST, "{}", escape);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_ban_with_ttl_is_temporary() {
+        let (state, mut refresh_rx) = test_state();
+
+        let resp = handle_request(
+            state.clone(),
+            request(Method::POST, "/rules/ban", Some("secret"), r#"{"ip": "192.0.2.1", "ttl_secs": 1800}"#),
+        ).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(state.temporary_bans.is_active(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 32));
+        assert!(state.manual_rules.snapshot().0.is_empty());
+        assert!(refresh_rx.try_recv().is_err());
+
+        // A dry run reports the ban without installing or scheduling it
+        let (state, _rx) = test_state();
+        let state = Arc::new(Arc::into_inner(state).unwrap().with_dry_run(true));
+        let resp = handle_request(
+            state.clone(),
+            request(Method::POST, "/rules/ban", Some("secret"), r#"{"ip": "192.0.2.1", "ttl_secs": 1800}"#),
+        ).await.unwrap();
+        let body = body_json(resp).await;
+        assert_eq!((body["changed"].clone(), body["dry_run"].clone()), (json!(1), json!(true)));
+        assert!(!state.temporary_bans.is_active(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 32));
+
+        // A TTL past the cap is rejected before anything is scheduled
+        let resp = handle_request(
+            state.clone(),
+            request(Method::POST, "/rules/ban", Some("secret"), r#"{"ip": "192.0.2.1", "ttl_secs": 18446744073709551615}"#),
+        ).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!state.temporary_bans.is_active(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 32));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_invalid_requests() {
+        let (state, mut refresh_rx) = test_state();
+
+        let resp = handle_request(state.clone(), request(Method::POST, "/rules/ban", Some("secret"), "not json")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = handle_request(
+            state.clone(),
+            request(Method::POST, "/rules/ban", Some("secret"), r#"{"ip": "nope"}"#),
+        ).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = handle_request(state.clone(), request(Method::DELETE, "/rules", Some("secret"), "")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let resp = handle_request(state, request(Method::GET, "/other", Some("secret"), "")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(refresh_rx.try_recv().is_err());
+    }
+}
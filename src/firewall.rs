@@ -1,7 +1,18 @@
-use std::{error::Error, net::{Ipv4Addr, Ipv6Addr}};
+use std::{error::Error, net::{IpAddr, Ipv4Addr, Ipv6Addr}};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::os::fd::{AsFd, AsRawFd};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
-use libbpf_rs::{MapCore, MapFlags};
+use libbpf_rs::{MapCore, MapFlags, MapHandle, MapType};
+use tokio::sync::{Notify, watch};
+use tokio::time::{Duration, Instant, sleep_until};
 
+use crate::access_rules::events::{EventSink, RuleEvent};
+use crate::access_rules::parse::{IpNetwork, RangeCidrs, normalize_entry, parse_ip_or_cidr, parse_ip_range};
 use crate::{bpf::FilterSkel, utils};
 
 pub trait Firewall {
@@ -15,119 +26,1991 @@ pub trait Firewall {
     fn ban_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>>;
     fn unban_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>>;
     fn check_if_notice_ipv6(&mut self, ip: Ipv6Addr) -> Result<bool, Box<dyn Error>>;
+
+    // Source-tagged bans: an entry banned by several sources stays installed until every
+    // one of them released it. The release returns whether it deleted the entry.
+    // Implementations without source tracking fall back to a plain ban and unban.
+    fn ban_ip_as(&mut self, ip: Ipv4Addr, prefixlen: u32, source: BanSource) -> Result<(), Box<dyn Error>> {
+        let _ = source;
+        self.ban_ip(ip, prefixlen)
+    }
+    fn unban_ip_as(&mut self, ip: Ipv4Addr, prefixlen: u32, source: BanSource) -> Result<bool, Box<dyn Error>> {
+        let _ = source;
+        self.unban_ip(ip, prefixlen).map(|()| true)
+    }
+    fn ban_ipv6_as(&mut self, ip: Ipv6Addr, prefixlen: u32, source: BanSource) -> Result<(), Box<dyn Error>> {
+        let _ = source;
+        self.ban_ipv6(ip, prefixlen)
+    }
+    fn unban_ipv6_as(&mut self, ip: Ipv6Addr, prefixlen: u32, source: BanSource) -> Result<bool, Box<dyn Error>> {
+        let _ = source;
+        self.unban_ipv6(ip, prefixlen).map(|()| true)
+    }
+
+    // Sync bans with an explicit action. Implementations without log-only support
+    // enforce every entry, so traffic meant to be dropped is never let through.
+    fn ban_ip_with_action(&mut self, ip: Ipv4Addr, prefixlen: u32, action: BanAction) -> Result<(), Box<dyn Error>> {
+        let _ = action;
+        self.ban_ip(ip, prefixlen)
+    }
+    fn ban_ipv6_with_action(&mut self, ip: Ipv6Addr, prefixlen: u32, action: BanAction) -> Result<(), Box<dyn Error>> {
+        let _ = action;
+        self.ban_ipv6(ip, prefixlen)
+    }
+
+    // Port-scoped bans: drop only TCP/UDP traffic to `port`, other ports stay open.
+    // Non-first IPv4 fragments carry no port and are not matched.
+    fn ban_ip_port(&mut self, ip: Ipv4Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>>;
+    fn unban_ip_port(&mut self, ip: Ipv4Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>>;
+    fn ban_ipv6_port(&mut self, ip: Ipv6Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>>;
+    fn unban_ipv6_port(&mut self, ip: Ipv6Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>>;
+
+    // Rate limits: a source inside the prefix may send `pps` packets per second, the
+    // rest is dropped. Each source address gets its own token bucket.
+    fn set_rate_limit(&mut self, ip: Ipv4Addr, prefixlen: u32, pps: u32) -> Result<(), Box<dyn Error>>;
+    fn remove_rate_limit(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>>;
+    fn set_rate_limit_v6(&mut self, ip: Ipv6Addr, prefixlen: u32, pps: u32) -> Result<(), Box<dyn Error>>;
+    fn remove_rate_limit_v6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>>;
+
+    // Allow-list methods
+    fn allow_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>>;
+    fn remove_allowed_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>>;
+    fn allow_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>>;
+    fn remove_allowed_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>>;
+
+    // Enumerate the entries currently installed in the banned maps
+    fn list_rules(&self) -> Result<Vec<(Ipv4Addr, u32)>, Box<dyn Error>>;
+    fn list_rules_v6(&self) -> Result<Vec<(Ipv6Addr, u32)>, Box<dyn Error>>;
+
+    // Enumerate the installed banned entries that lie inside `net`/`prefix`, including
+    // an entry for exactly that network
+    fn list_rules_under(&self, net: Ipv4Addr, prefix: u32) -> Result<Vec<(Ipv4Addr, u32)>, Box<dyn Error>> {
+        Ok(self.list_rules()?.into_iter().filter(|&(ip, len)| within_v4(ip, len, net, prefix)).collect())
+    }
+    fn list_rules_under_v6(&self, net: Ipv6Addr, prefix: u32) -> Result<Vec<(Ipv6Addr, u32)>, Box<dyn Error>> {
+        Ok(self.list_rules_v6()?.into_iter().filter(|&(ip, len)| within_v6(ip, len, net, prefix)).collect())
+    }
+
+    // Packets dropped by each installed banned map entry, summed over all CPUs
+    fn rule_hits(&self) -> Result<Vec<(IpAddr, u32, u64)>, Box<dyn Error>>;
+
+    // Source flags of every installed banned entry. Implementations without source
+    // tracking report every entry as banned by the sync.
+    fn list_rule_sources(&self) -> Result<Vec<(IpAddr, u32, u8)>, Box<dyn Error>> {
+        let v4 = self.list_rules()?.into_iter().map(|(ip, prefix)| (IpAddr::V4(ip), prefix));
+        let v6 = self.list_rules_v6()?.into_iter().map(|(ip, prefix)| (IpAddr::V6(ip), prefix));
+        Ok(v4.chain(v6).map(|(ip, prefix)| (ip, prefix, BanSource::Sync as u8)).collect())
+    }
+
+    // Make the sync's entries of the banned maps exactly `v4` and `v6`. The diff against
+    // the installed sync entries is computed here, and new entries go in before stale
+    // ones are released so a rule present in both sets is never missing from the map.
+    // Entries the sync does not hold are never touched, and a stale entry another
+    // source also bans only loses the sync's reference.
+    fn replace_all(&mut self, v4: &[(Ipv4Addr, u32)], v6: &[(Ipv6Addr, u32)]) -> Result<ReplaceSummary, Box<dyn Error>> {
+        let (mut installed, mut installed_v6) = (HashSet::new(), HashSet::new());
+        for (ip, prefix, flags) in self.list_rule_sources()? {
+            match ip {
+                _ if flags & BanSource::Sync as u8 == 0 => {}
+                IpAddr::V4(ip) => { installed.insert((ip, prefix)); }
+                IpAddr::V6(ip) => { installed_v6.insert((ip, prefix)); }
+            }
+        }
+        let wanted: HashSet<(Ipv4Addr, u32)> = v4.iter().copied().collect();
+        let wanted_v6: HashSet<(Ipv6Addr, u32)> = v6.iter().copied().collect();
+
+        let mut summary = ReplaceSummary::default();
+        for &(ip, prefix) in wanted.difference(&installed) {
+            self.ban_ip(ip, prefix)?;
+            summary.added += 1;
+        }
+        for &(ip, prefix) in wanted_v6.difference(&installed_v6) {
+            self.ban_ipv6(ip, prefix)?;
+            summary.added += 1;
+        }
+        for &(ip, prefix) in installed.difference(&wanted) {
+            self.unban_ip_as(ip, prefix, BanSource::Sync)?;
+            summary.removed += 1;
+        }
+        for &(ip, prefix) in installed_v6.difference(&wanted_v6) {
+            self.unban_ipv6_as(ip, prefix, BanSource::Sync)?;
+            summary.removed += 1;
+        }
+        Ok(summary)
+    }
+
+    // Apply a batch of sync block changes so the datapath moves from the old rule set
+    // to the new one in a single step instead of seeing the trie half written.
+    // Implementations that cannot swap maps apply the batch in place, entry by entry.
+    // Entries of `added` that are also in `log_only` are installed as log-only. A
+    // change that fails does not stop the others; the failures are returned per
+    // entry. An error means nothing of the batch was written.
+    fn swap_block_changes(
+        &mut self,
+        added: &[(Ipv4Addr, u32)],
+        removed: &[(Ipv4Addr, u32)],
+        log_only: &HashSet<(Ipv4Addr, u32)>,
+    ) -> Result<BatchFailures<Ipv4Addr>, Box<dyn Error>> {
+        Ok(block_changes_in_place(self, added, removed, log_only))
+    }
+    fn swap_block_changes_v6(
+        &mut self,
+        added: &[(Ipv6Addr, u32)],
+        removed: &[(Ipv6Addr, u32)],
+        log_only: &HashSet<(Ipv6Addr, u32)>,
+    ) -> Result<BatchFailures<Ipv6Addr>, Box<dyn Error>> {
+        Ok(block_changes_in_place(self, added, removed, log_only))
+    }
+}
+
+/// A delete of an entry that is already gone leaves the map as intended, which happens
+/// when a removal that failed on another skeleton is retried
+pub(crate) fn is_missing_entry(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<libbpf_rs::Error>().is_some_and(|e| e.kind() == libbpf_rs::ErrorKind::NotFound)
+}
+
+/// Changes of a block batch that could not be written, each with its error
+#[derive(Debug)]
+pub struct BatchFailures<T> {
+    pub added: Vec<((T, u32), Box<dyn Error>)>,
+    pub removed: Vec<((T, u32), Box<dyn Error>)>,
+}
+
+impl<T> Default for BatchFailures<T> {
+    fn default() -> Self {
+        Self { added: Vec::new(), removed: Vec::new() }
+    }
+}
+
+impl<T> BatchFailures<T> {
+    pub fn len(&self) -> usize {
+        self.added.len() + self.removed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// An address family of the rule maps. Code handling both families is written once
+/// over this trait, which builds the family's map keys and reaches the matching IPv4
+/// or IPv6 method of a `Firewall`.
+pub trait AddressFamily: Copy + Eq + Hash + Display + Into<IpAddr> + 'static {
+    /// Family name used in logs
+    const NAME: &'static str;
+
+    /// The network of `self` keeping its first `prefix` bits
+    fn masked(self, prefix: u32) -> Self;
+    /// Key of `self`/`prefix` in the family's LPM trie maps
+    fn map_key(self, prefix: u32) -> Box<[u8]>;
+
+    fn list_rules<F: Firewall + ?Sized>(fw: &F) -> Result<Vec<(Self, u32)>, Box<dyn Error>>;
+    fn ban<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32) -> Result<(), Box<dyn Error>>;
+    fn ban_with_action<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32, action: BanAction) -> Result<(), Box<dyn Error>>;
+    fn unban_as<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32, source: BanSource) -> Result<bool, Box<dyn Error>>;
+    fn allow<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32) -> Result<(), Box<dyn Error>>;
+    fn remove_allowed<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32) -> Result<(), Box<dyn Error>>;
+    fn swap_block_changes<F: Firewall + ?Sized>(
+        fw: &mut F,
+        added: &[(Self, u32)],
+        removed: &[(Self, u32)],
+        log_only: &HashSet<(Self, u32)>,
+    ) -> Result<BatchFailures<Self>, Box<dyn Error>>;
+}
+
+impl AddressFamily for Ipv4Addr {
+    const NAME: &'static str = "IPv4";
+
+    fn masked(self, prefix: u32) -> Self {
+        Ipv4Addr::from(u32::from(self) & u32::MAX.checked_shl(32 - prefix.min(32)).unwrap_or(0))
+    }
+    fn map_key(self, prefix: u32) -> Box<[u8]> {
+        utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(self, prefix)
+    }
+
+    fn list_rules<F: Firewall + ?Sized>(fw: &F) -> Result<Vec<(Self, u32)>, Box<dyn Error>> {
+        fw.list_rules()
+    }
+    fn ban<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32) -> Result<(), Box<dyn Error>> {
+        fw.ban_ip(ip, prefix)
+    }
+    fn ban_with_action<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32, action: BanAction) -> Result<(), Box<dyn Error>> {
+        fw.ban_ip_with_action(ip, prefix, action)
+    }
+    fn unban_as<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32, source: BanSource) -> Result<bool, Box<dyn Error>> {
+        fw.unban_ip_as(ip, prefix, source)
+    }
+    fn allow<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32) -> Result<(), Box<dyn Error>> {
+        fw.allow_ip(ip, prefix)
+    }
+    fn remove_allowed<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32) -> Result<(), Box<dyn Error>> {
+        fw.remove_allowed_ip(ip, prefix)
+    }
+    fn swap_block_changes<F: Firewall + ?Sized>(
+        fw: &mut F,
+        added: &[(Self, u32)],
+        removed: &[(Self, u32)],
+        log_only: &HashSet<(Self, u32)>,
+    ) -> Result<BatchFailures<Self>, Box<dyn Error>> {
+        fw.swap_block_changes(added, removed, log_only)
+    }
+}
+
+impl AddressFamily for Ipv6Addr {
+    const NAME: &'static str = "IPv6";
+
+    fn masked(self, prefix: u32) -> Self {
+        Ipv6Addr::from(u128::from(self) & u128::MAX.checked_shl(128 - prefix.min(128)).unwrap_or(0))
+    }
+    fn map_key(self, prefix: u32) -> Box<[u8]> {
+        utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(self, prefix)
+    }
+
+    fn list_rules<F: Firewall + ?Sized>(fw: &F) -> Result<Vec<(Self, u32)>, Box<dyn Error>> {
+        fw.list_rules_v6()
+    }
+    fn ban<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32) -> Result<(), Box<dyn Error>> {
+        fw.ban_ipv6(ip, prefix)
+    }
+    fn ban_with_action<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32, action: BanAction) -> Result<(), Box<dyn Error>> {
+        fw.ban_ipv6_with_action(ip, prefix, action)
+    }
+    fn unban_as<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32, source: BanSource) -> Result<bool, Box<dyn Error>> {
+        fw.unban_ipv6_as(ip, prefix, source)
+    }
+    fn allow<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32) -> Result<(), Box<dyn Error>> {
+        fw.allow_ipv6(ip, prefix)
+    }
+    fn remove_allowed<F: Firewall + ?Sized>(fw: &mut F, ip: Self, prefix: u32) -> Result<(), Box<dyn Error>> {
+        fw.remove_allowed_ipv6(ip, prefix)
+    }
+    fn swap_block_changes<F: Firewall + ?Sized>(
+        fw: &mut F,
+        added: &[(Self, u32)],
+        removed: &[(Self, u32)],
+        log_only: &HashSet<(Self, u32)>,
+    ) -> Result<BatchFailures<Self>, Box<dyn Error>> {
+        fw.swap_block_changes_v6(added, removed, log_only)
+    }
+}
+
+/// `swap_block_changes` without a swap: release `removed`, then tag `added`, as the sync
+fn block_changes_in_place<T: AddressFamily>(
+    fw: &mut (impl Firewall + ?Sized),
+    added: &[(T, u32)],
+    removed: &[(T, u32)],
+    log_only: &HashSet<(T, u32)>,
+) -> BatchFailures<T> {
+    let mut failures = BatchFailures::default();
+    for &(ip, prefix) in removed {
+        match T::unban_as(fw, ip, prefix, BanSource::Sync) {
+            Err(e) if !is_missing_entry(&*e) => failures.removed.push(((ip, prefix), e)),
+            _ => {}
+        }
+    }
+    for &(ip, prefix) in added {
+        if let Err(e) = T::ban_with_action(fw, ip, prefix, BanAction::of(log_only.contains(&(ip, prefix)))) {
+            failures.added.push(((ip, prefix), e));
+        }
+    }
+    failures
+}
+
+/// Origin of a banned map entry, kept as one bit of the entry's flag byte. Matches the
+/// `BAN_SOURCE_*` defines of the datapath.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BanSource {
+    /// The access rules sync. Plain `ban_ip` calls and entries written before sources
+    /// were tracked carry this bit.
+    Sync = 0x01,
+    /// Written directly by an operator, e.g. through `ban_from_file`
+    Manual = 0x02,
+    /// Banned with an expiry through `TemporaryBans`
+    Temporary = 0x04,
+    /// Escalated from the recently banned maps by the datapath itself, which ORs this
+    /// bit into an existing host entry instead of replacing its flags
+    Datapath = 0x08,
+}
+
+impl BanSource {
+    /// Name reported in rule events
+    pub fn name(self) -> &'static str {
+        match self {
+            BanSource::Sync => "sync",
+            BanSource::Manual => "manual",
+            BanSource::Temporary => "temporary",
+            BanSource::Datapath => "datapath",
+        }
+    }
+}
+
+/// Report a ban or unban by `source` to `events`, when a sink is attached
+fn emit_event(events: Option<&EventSink>, action: &str, ip: IpAddr, prefix: u32, source: BanSource) {
+    if let Some(events) = events {
+        events.emit(&RuleEvent::now(action, ip, prefix, source.name()));
+    }
+}
+
+/// What the datapath does with packets matching a sync block entry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BanAction {
+    #[default]
+    Drop,
+    /// Count matches in the rule hit counters but let the packets through, so a rule can
+    /// be watched before it is enforced. Any other source banning the entry still drops.
+    LogOnly,
+}
+
+impl BanAction {
+    pub fn of(log_only: bool) -> Self {
+        if log_only { Self::LogOnly } else { Self::Drop }
+    }
+}
+
+/// Which entry decides when a source matches both an allow entry and an enforced block
+/// entry. Matches the `PRECEDENCE_*` defines of the datapath.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Precedence {
+    /// Any matching allow entry lets the source through, so an allow-listed operator
+    /// can never be locked out by a feed entry
+    #[default]
+    AllowAlwaysWins = 0,
+    /// The entry with the longer prefix decides; on equal prefixes the allow wins
+    MostSpecificWins = 1,
+    /// Any matching block entry drops the source, allow entries only exempt it from
+    /// the port, rate limit and recently banned checks
+    BlockAlwaysWins = 2,
+}
+
+impl FromStr for Precedence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "allow-always-wins" => Ok(Precedence::AllowAlwaysWins),
+            "most-specific-wins" => Ok(Precedence::MostSpecificWins),
+            "block-always-wins" => Ok(Precedence::BlockAlwaysWins),
+            other => Err(format!(
+                "unknown precedence '{}', expected allow-always-wins, most-specific-wins or block-always-wins",
+                other
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Precedence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Precedence::AllowAlwaysWins => "allow-always-wins",
+            Precedence::MostSpecificWins => "most-specific-wins",
+            Precedence::BlockAlwaysWins => "block-always-wins",
+        })
+    }
+}
+
+/// Flag bit marking the sync's reference as log-only. Matches `BAN_ACTION_LOG_ONLY`.
+const LOG_ONLY_FLAG: u8 = 0x80;
+
+/// Flags left on an entry once `source` released it; the entry is deleted at zero
+fn remaining_sources(flags: u8, source: BanSource) -> u8 {
+    // The log-only bit qualifies the sync's reference and goes with it
+    let released = match source {
+        BanSource::Sync => source as u8 | LOG_ONLY_FLAG,
+        _ => source as u8,
+    };
+    flags & !released
+}
+
+/// Flags of an entry after the sync tagged it with `action`
+fn sync_flags(flags: u8, action: BanAction) -> u8 {
+    let flags = flags | BanSource::Sync as u8;
+    match action {
+        BanAction::Drop => flags & !LOG_ONLY_FLAG,
+        BanAction::LogOnly => flags | LOG_ONLY_FLAG,
+    }
+}
+
+/// Source flags of the entry stored under exactly `key`. An LPM lookup also matches
+/// shorter covering entries, which are told apart by the prefix length in the value.
+fn exact_flags<M: MapCore>(map: &M, key: &[u8], prefixlen: u32) -> Result<Option<u8>, Box<dyn Error>> {
+    Ok(map
+        .lookup(key, MapFlags::ANY)?
+        .filter(|value| value.get(1) == Some(&(prefixlen as u8)))
+        .and_then(|value| value.first().copied()))
+}
+
+/// Add `source` to the flags of the entry under `key`, creating it when absent. Returns
+/// whether `source` is new to the entry.
+fn tag_entry<M: MapCore>(map: &M, key: &[u8], prefixlen: u32, source: BanSource) -> Result<bool, Box<dyn Error>> {
+    let flags = exact_flags(map, key, prefixlen)?.unwrap_or(0);
+    map.update(key, &ban_value(prefixlen, flags | source as u8), MapFlags::ANY)?;
+    Ok(flags & source as u8 == 0)
+}
+
+/// Tag the entry under `key` as banned by the sync with `action`, creating it when
+/// absent. Returns whether the sync is new to the entry.
+fn tag_sync_entry<M: MapCore>(map: &M, key: &[u8], prefixlen: u32, action: BanAction) -> Result<bool, Box<dyn Error>> {
+    let flags = exact_flags(map, key, prefixlen)?.unwrap_or(0);
+    map.update(key, &ban_value(prefixlen, sync_flags(flags, action)), MapFlags::ANY)?;
+    Ok(flags & BanSource::Sync as u8 == 0)
+}
+
+/// Remove `source` from the flags of the entry under `key`, deleting the entry once no
+/// source references it. Returns whether it was deleted and whether `source` had been
+/// banning it; a missing entry fails like a plain delete would.
+fn release_entry<M: MapCore>(map: &M, key: &[u8], prefixlen: u32, source: BanSource) -> Result<(bool, bool), Box<dyn Error>> {
+    let flags = exact_flags(map, key, prefixlen)?;
+    let held = flags.is_some_and(|flags| flags & source as u8 != 0);
+    let remaining = flags.map_or(0, |flags| remaining_sources(flags, source));
+    if remaining == 0 {
+        map.delete(key)?;
+        return Ok((true, held));
+    }
+    map.update(key, &ban_value(prefixlen, remaining), MapFlags::ANY)?;
+    Ok((false, held))
+}
+
+/// Why a map swap did not happen. Nothing the datapath reads was changed in either case.
+enum SwapError {
+    /// The kernel refused to build or install the swap map; the batch can still be
+    /// applied in place
+    Unsupported(Box<dyn Error>),
+    Failed(Box<dyn Error>),
+}
+
+const BPF_F_NO_PREALLOC: u32 = 1 << 0;
+
+/// Sync block changes keyed by raw map key and prefix length
+type KeyedChanges = [(Box<[u8]>, u32)];
+/// Sync block additions keyed like `KeyedChanges`, with their action
+type KeyedAdditions = [(Box<[u8]>, u32, BanAction)];
+
+/// Keys `swap_through` deleted from the live map, and the changes it could not write
+/// there, by index into its `added` and `removed`
+#[derive(Default)]
+struct SwapOutcome {
+    deleted: Vec<Box<[u8]>>,
+    /// Changes written that started or ended the sync's ban of their entry
+    banned: Vec<usize>,
+    unbanned: Vec<usize>,
+    failed_added: Vec<(usize, libbpf_rs::Error)>,
+    failed_removed: Vec<(usize, libbpf_rs::Error)>,
+}
+
+/// Apply `added` and `removed` to `live` with the datapath reading a fully populated
+/// copy of the result from `slot` meanwhile:
+/// 1. the current entries of `live` are copied into a fresh trie with the changes applied
+/// 2. the fresh trie is installed in `slot`, which switches every lookup over at once
+/// 3. `live` is brought up to the same state in place, unobserved
+/// 4. `slot` is emptied, handing lookups back to `live`
+///
+/// A change that fails in step 3 is skipped and reported, the others are still
+/// written, so once the slot is emptied `live` differs from the intended rule set by
+/// exactly the reported changes.
+fn swap_through<M: MapCore, S: MapCore>(
+    live: &M,
+    slot: &S,
+    added: &KeyedAdditions,
+    removed: &KeyedChanges,
+) -> Result<SwapOutcome, SwapError> {
+    let mut entries: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    for key in live.keys() {
+        if let Some(value) = live.lookup(&key, MapFlags::ANY).map_err(|e| SwapError::Failed(e.into()))? {
+            entries.insert(key, value);
+        }
+    }
+    let flags_of = |entries: &HashMap<Vec<u8>, Vec<u8>>, key: &[u8], prefixlen: u32| {
+        entries
+            .get(key)
+            .filter(|value| value.get(1) == Some(&(prefixlen as u8)))
+            .and_then(|value| value.first().copied())
+            .unwrap_or(0)
+    };
+
+    // Final value of every changed key, None deleting it, with the change it comes
+    // from: an index into `removed` or, offset by its length, into `added`, and whether
+    // it starts or ends the sync's ban of the entry
+    let sync = BanSource::Sync as u8;
+    let mut writes: Vec<(&[u8], Option<[u8; 2]>, usize, bool)> = Vec::with_capacity(added.len() + removed.len());
+    for (idx, (key, prefixlen)) in removed.iter().enumerate() {
+        if !entries.contains_key(&key[..]) {
+            continue;
+        }
+        let flags = flags_of(&entries, key, *prefixlen);
+        let remaining = remaining_sources(flags, BanSource::Sync);
+        writes.push((&key[..], (remaining != 0).then(|| ban_value(*prefixlen, remaining)), idx, flags & sync != 0));
+    }
+    for (idx, (key, prefixlen, action)) in added.iter().enumerate() {
+        let flags = flags_of(&entries, key, *prefixlen);
+        writes.push((&key[..], Some(ban_value(*prefixlen, sync_flags(flags, *action))), removed.len() + idx, flags & sync == 0));
+    }
+    for (key, value, _, _) in &writes {
+        match value {
+            Some(value) => entries.insert(key.to_vec(), value.to_vec()),
+            None => entries.remove(*key),
+        };
+    }
+
+    let opts = libbpf_rs::libbpf_sys::bpf_map_create_opts {
+        sz: std::mem::size_of::<libbpf_rs::libbpf_sys::bpf_map_create_opts>() as _,
+        // LPM tries are only created without preallocation
+        map_flags: BPF_F_NO_PREALLOC,
+        ..Default::default()
+    };
+    let fresh = MapHandle::create(MapType::LpmTrie, Some("banned_swap"), live.key_size(), live.value_size(), live.max_entries(), &opts)
+        .map_err(|e| SwapError::Unsupported(e.into()))?;
+    for (key, value) in &entries {
+        fresh.update(key, value, MapFlags::ANY).map_err(|e| SwapError::Failed(e.into()))?;
+    }
+    let slot_key = 0u32.to_ne_bytes();
+    let fd = fresh.as_fd().as_raw_fd() as u32;
+    slot.update(&slot_key, &fd.to_ne_bytes(), MapFlags::ANY).map_err(|e| SwapError::Unsupported(e.into()))?;
+
+    let mut outcome = SwapOutcome::default();
+    for (key, value, change, toggles) in &writes {
+        let written = match value {
+            Some(value) => live.update(key, value, MapFlags::ANY),
+            None => match live.delete(key) {
+                Ok(()) => {
+                    outcome.deleted.push(Box::from(*key));
+                    Ok(())
+                }
+                Err(e) if e.kind() == libbpf_rs::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e),
+            },
+        };
+        match (written, change.checked_sub(removed.len())) {
+            (Ok(()), Some(idx)) if *toggles => outcome.banned.push(idx),
+            (Ok(()), None) if *toggles => outcome.unbanned.push(*change),
+            (Ok(()), _) => {}
+            (Err(e), Some(idx)) => outcome.failed_added.push((idx, e)),
+            (Err(e), None) => outcome.failed_removed.push((*change, e)),
+        }
+    }
+    // `live` is written either way, so this is not a failure of the batch; a slot that
+    // stays installed serves the swapped copy, which misses later in-place changes
+    // until the next swap replaces it
+    if let Err(e) = slot.delete(&slot_key) {
+        log::error!("failed to empty the banned map swap slot, lookups keep reading the swapped copy: {}", e);
+    }
+    Ok(outcome)
+}
+
+/// Entries written and deleted by `Firewall::replace_all`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplaceSummary {
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// A line of a ban file that could not be banned. Only the line number is kept, never
+/// the line itself, so reports cannot echo back the contents of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedLine {
+    /// 1-based line number
+    pub line: usize,
+    pub reason: String,
+}
+
+/// Networks of each line of a ban file, with the lines that did not parse
+#[derive(Debug, Default)]
+pub struct BanFile {
+    /// 1-based line number with the networks of its entry
+    pub entries: Vec<(usize, Vec<(Ipv4Addr, u32)>, Vec<(Ipv6Addr, u32)>)>,
+    pub rejected: Vec<RejectedLine>,
+}
+
+impl BanFile {
+    /// Parse one IP, CIDR or range per line. Blank lines and `#` comments are skipped.
+    pub fn parse(contents: &str) -> Self {
+        let mut file = BanFile::default();
+        for (idx, line) in contents.lines().enumerate() {
+            let Some(entry) = normalize_entry(line) else {
+                continue;
+            };
+            match parse_ban_entry(&entry) {
+                Ok((v4, v6)) => file.entries.push((idx + 1, v4, v6)),
+                Err(reason) => file.rejected.push(RejectedLine { line: idx + 1, reason }),
+            }
+        }
+        file
+    }
+
+    /// Number of networks the file bans
+    pub fn networks(&self) -> usize {
+        self.entries.iter().map(|(_, v4, v6)| v4.len() + v6.len()).sum()
+    }
+}
+
+/// Outcome of `MOATFirewall::ban_from_file`
+#[derive(Debug, Default)]
+pub struct BanFileReport {
+    pub banned: usize,
+    pub rejected: Vec<RejectedLine>,
+}
+
+pub struct MOATFirewall<'a> {
+    skel: &'a FilterSkel<'a>,
+    events: Option<&'a EventSink>,
+}
+
+impl<'a> MOATFirewall<'a> {
+    pub fn new(skel: &'a FilterSkel<'a>) -> Self {
+        Self { skel, events: None }
+    }
+
+    /// Report the bans and unbans written through this firewall to `events`
+    pub fn with_events(mut self, events: Option<&'a EventSink>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Longest-prefix match of `ip` against the banned maps, returning the matching
+    /// rule's prefix length and flag, or None when the address is not blocked
+    pub fn is_banned(&self, ip: IpAddr) -> Result<Option<(u32, u8)>, Box<dyn Error>> {
+        match ip {
+            IpAddr::V4(ip) => lookup_banned_v4(&self.skel.maps.banned_ips, ip),
+            IpAddr::V6(ip) => lookup_banned_v6(&self.skel.maps.banned_ips_v6, ip),
+        }
+    }
+
+    /// `swap_block_changes` of one family, through its banned map `live`, the swap
+    /// map `slot` and the hit counters `hits`
+    #[allow(clippy::too_many_arguments)]
+    fn swap_family_changes<T: AddressFamily, M: MapCore, S: MapCore, H: MapCore>(
+        &mut self,
+        live: &M,
+        slot: &S,
+        hits: &H,
+        map_name: &str,
+        added: &[(T, u32)],
+        removed: &[(T, u32)],
+        log_only: &HashSet<(T, u32)>,
+    ) -> Result<BatchFailures<T>, Box<dyn Error>> {
+        let additions: Vec<_> = added
+            .iter()
+            .map(|&(ip, prefix)| (ip.map_key(prefix), prefix, BanAction::of(log_only.contains(&(ip, prefix)))))
+            .collect();
+        let removals: Vec<_> = removed.iter().map(|&(ip, prefix)| (ip.map_key(prefix), prefix)).collect();
+        match swap_through(live, slot, &additions, &removals) {
+            Ok(outcome) => {
+                for key in outcome.deleted {
+                    let _ = hits.delete(&key);
+                }
+                for idx in outcome.unbanned {
+                    emit_event(self.events, "unban", removed[idx].0.into(), removed[idx].1, BanSource::Sync);
+                }
+                for idx in outcome.banned {
+                    emit_event(self.events, "ban", added[idx].0.into(), added[idx].1, BanSource::Sync);
+                }
+                Ok(BatchFailures {
+                    added: outcome.failed_added.into_iter().map(|(idx, e)| (added[idx], e.into())).collect(),
+                    removed: outcome.failed_removed.into_iter().map(|(idx, e)| (removed[idx], e.into())).collect(),
+                })
+            }
+            Err(SwapError::Unsupported(e)) => {
+                log::debug!("{} swap unavailable ({}), applying {} changes in place", map_name, e, added.len() + removed.len());
+                Ok(block_changes_in_place(self, added, removed, log_only))
+            }
+            Err(SwapError::Failed(e)) => Err(e),
+        }
+    }
+
+    /// Set how the datapath settles a source matching both an allow and a block entry
+    pub fn set_precedence(&mut self, precedence: Precedence) -> Result<(), Box<dyn Error>> {
+        self.skel
+            .maps
+            .precedence
+            .update(&0_u32.to_ne_bytes(), &(precedence as u32).to_ne_bytes(), MapFlags::ANY)?;
+        Ok(())
+    }
+
+    /// Installed keys and `max_entries` of the banned and allow-list maps, keyed by
+    /// map name. Walks every map, so entries written from outside the sync count too.
+    pub fn map_usage(&self) -> [(&'static str, usize, u32); 4] {
+        let maps = &self.skel.maps;
+        [
+            ("banned_ips", maps.banned_ips.keys().count(), maps.banned_ips.max_entries()),
+            ("banned_ips_v6", maps.banned_ips_v6.keys().count(), maps.banned_ips_v6.max_entries()),
+            ("allowed_ips", maps.allowed_ips.keys().count(), maps.allowed_ips.max_entries()),
+            ("allowed_ips_v6", maps.allowed_ips_v6.keys().count(), maps.allowed_ips_v6.max_entries()),
+        ]
+    }
+
+    /// Delete every block entry of both families: the banned, recently banned,
+    /// port-scoped and rate limit maps, with their hit counters. The allow-list is
+    /// left alone. Bypasses the access rules sync, whose snapshots still list the
+    /// entries, so they only come back once the feed changes them. Returns the number
+    /// of block entries deleted; with `dry_run` they are only counted.
+    pub fn clear_all(&mut self, dry_run: bool) -> Result<usize, Box<dyn Error>> {
+        let maps = &self.skel.maps;
+        let mut deleted = 0;
+        for map in [
+            &maps.banned_ips,
+            &maps.banned_ips_v6,
+            &maps.recently_banned_ips,
+            &maps.recently_banned_ips_v6,
+            &maps.banned_ip_ports,
+            &maps.banned_ip_ports_v6,
+            &maps.rate_limited_ips,
+            &maps.rate_limited_ips_v6,
+        ] {
+            deleted += if dry_run { map.keys().count() } else { delete_all_keys(map)? };
+        }
+        if dry_run {
+            return Ok(deleted);
+        }
+        for map in [&maps.rule_hits, &maps.rule_hits_v6] {
+            delete_all_keys(map)?;
+        }
+        Ok(deleted)
+    }
+
+    /// Ban every parsed entry of a ban file. Entries are written directly, outside the
+    /// access rules sync; lines that fail to apply are reported with their number.
+    pub fn ban_from_file(&mut self, file: &BanFile) -> BanFileReport {
+        let mut report = BanFileReport::default();
+        for (line, v4, v6) in &file.entries {
+            let applied = v4
+                .iter()
+                .try_for_each(|&(ip, prefix)| self.ban_ip_as(ip, prefix, BanSource::Manual))
+                .and_then(|()| v6.iter().try_for_each(|&(ip, prefix)| self.ban_ipv6_as(ip, prefix, BanSource::Manual)));
+            match applied {
+                Ok(()) => report.banned += v4.len() + v6.len(),
+                Err(e) => report.rejected.push(RejectedLine { line: *line, reason: e.to_string() }),
+            }
+        }
+        report
+    }
+}
+
+/// One firewall per skeleton. `events` is attached to the first only, so a change
+/// written to every interface is reported once.
+pub fn skeleton_firewalls<'a>(
+    skels: &'a [Arc<FilterSkel<'a>>],
+    events: Option<&'a EventSink>,
+) -> impl Iterator<Item = MOATFirewall<'a>> {
+    skels
+        .iter()
+        .enumerate()
+        .map(move |(idx, skel)| MOATFirewall::new(skel).with_events(events.filter(|_| idx == 0)))
+}
+
+/// Delete every key of a map, collecting the keys first since deleting while iterating
+/// an LPM trie skips entries
+fn delete_all_keys<M: MapCore>(map: &M) -> Result<usize, Box<dyn Error>> {
+    let keys: Vec<Vec<u8>> = map.keys().collect();
+    for key in &keys {
+        map.delete(key)?;
+    }
+    Ok(keys.len())
+}
+
+/// Parse an IP, CIDR or range into per-family networks, routing IPv4-mapped IPv6
+/// entries to IPv4 like the access rules parser does
+fn parse_ban_entry(entry: &str) -> Result<(Vec<(Ipv4Addr, u32)>, Vec<(Ipv6Addr, u32)>), String> {
+    if entry.contains('-') {
+        return match parse_ip_range(entry)? {
+            RangeCidrs::V4(cidrs) => Ok((cidrs, Vec::new())),
+            RangeCidrs::V6(cidrs) => Ok((Vec::new(), cidrs)),
+        };
+    }
+    Ok(match parse_ip_or_cidr(entry)? {
+        IpNetwork::V4(net, prefix) => (vec![(net, prefix)], Vec::new()),
+        IpNetwork::V6(net, prefix) => (Vec::new(), vec![(net, prefix)]),
+    })
+}
+
+/// Expiry bookkeeping for temporary bans: a min-heap of deadlines plus the current
+/// deadline of every active entry. Re-banning an entry moves its deadline; the stale
+/// heap item is skipped when it comes due.
+#[derive(Debug, Default)]
+struct ExpirySchedule {
+    heap: BinaryHeap<Reverse<(Instant, IpAddr, u32)>>,
+    deadlines: HashMap<(IpAddr, u32), Instant>,
+}
+
+impl ExpirySchedule {
+    fn insert(&mut self, ip: IpAddr, prefix: u32, deadline: Instant) {
+        self.deadlines.insert((ip, prefix), deadline);
+        self.heap.push(Reverse((deadline, ip, prefix)));
+    }
+
+    fn contains(&self, ip: IpAddr, prefix: u32) -> bool {
+        self.deadlines.contains_key(&(ip, prefix))
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse((deadline, _, _))| *deadline)
+    }
+
+    /// Remove and return every entry whose current deadline is at or before `now`
+    fn pop_due(&mut self, now: Instant) -> Vec<(IpAddr, u32)> {
+        let mut due = Vec::new();
+        while let Some(Reverse((deadline, ip, prefix))) = self.heap.peek().copied() {
+            if deadline > now {
+                break;
+            }
+            self.heap.pop();
+            if self.deadlines.get(&(ip, prefix)) == Some(&deadline) {
+                self.deadlines.remove(&(ip, prefix));
+                due.push((ip, prefix));
+            }
+        }
+        due
+    }
+}
+
+/// Bans that are lifted automatically once their TTL elapses. They are tracked apart
+/// from the API-synced rule sets: the sync never removes an active temporary ban, and
+/// an expiring ban is left in place when the sync installed the same entry.
+pub struct TemporaryBans {
+    skels: Vec<Arc<FilterSkel<'static>>>,
+    schedule: Mutex<ExpirySchedule>,
+    /// Block entries currently installed by the access rules sync
+    synced: Mutex<HashSet<(IpAddr, u32)>>,
+    notify: Notify,
+    events: Option<Arc<EventSink>>,
+}
+
+impl TemporaryBans {
+    pub fn new(skels: Vec<Arc<FilterSkel<'static>>>) -> Self {
+        Self {
+            skels,
+            schedule: Mutex::new(ExpirySchedule::default()),
+            synced: Mutex::new(HashSet::new()),
+            notify: Notify::new(),
+            events: None,
+        }
+    }
+
+    /// Report temporary bans and their expiry to `events`
+    pub fn with_events(mut self, events: Option<Arc<EventSink>>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Ban `ip/prefix` on every skeleton until `ttl` elapses. Banning an entry that is
+    /// already temporarily banned replaces its expiry. The expiry is scheduled before
+    /// the maps are written, so skeletons banned before a failing one are still lifted.
+    pub fn ban_ip_for(&self, ip: IpAddr, prefix: u32, ttl: Duration) -> Result<(), Box<dyn Error>> {
+        let deadline = Instant::now().checked_add(ttl).ok_or("ban TTL out of range")?;
+        self.schedule.lock().unwrap().insert(ip, prefix, deadline);
+        self.notify.notify_one();
+        for mut fw in skeleton_firewalls(&self.skels, self.events.as_deref()) {
+            match ip {
+                IpAddr::V4(v4) => fw.ban_ip_as(v4, prefix, BanSource::Temporary)?,
+                IpAddr::V6(v6) => fw.ban_ipv6_as(v6, prefix, BanSource::Temporary)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `ip/prefix` is currently temporarily banned
+    pub fn is_active(&self, ip: IpAddr, prefix: u32) -> bool {
+        self.schedule.lock().unwrap().contains(ip, prefix)
+    }
+
+    /// Record the block entries the access rules sync has installed
+    pub fn set_synced(&self, rules: impl IntoIterator<Item = (IpAddr, u32)>) {
+        *self.synced.lock().unwrap() = rules.into_iter().collect();
+    }
+
+    fn expire_due(&self, now: Instant) {
+        let due = self.schedule.lock().unwrap().pop_due(now);
+        if due.is_empty() {
+            return;
+        }
+        let synced = self.synced.lock().unwrap();
+        for (ip, prefix) in due {
+            // Only the temporary reference is released; the entry stays while the sync
+            // or another source still bans it
+            let mut kept = false;
+            for mut fw in skeleton_firewalls(&self.skels, self.events.as_deref()) {
+                let result = match ip {
+                    IpAddr::V4(v4) => fw.unban_ip_as(v4, prefix, BanSource::Temporary),
+                    IpAddr::V6(v6) => fw.unban_ipv6_as(v6, prefix, BanSource::Temporary),
+                };
+                match result {
+                    Ok(deleted) => kept |= !deleted,
+                    Err(e) => log::error!(ip:% = ip, prefix = prefix; "failed to lift temporary ban of {}/{}: {}", ip, prefix, e),
+                }
+            }
+            if synced.contains(&(ip, prefix)) {
+                log::info!(ip:% = ip, prefix = prefix; "temporary ban of {}/{} expired, kept as an access rule", ip, prefix);
+            } else if kept {
+                log::info!(ip:% = ip, prefix = prefix; "temporary ban of {}/{} expired, kept for its other ban sources", ip, prefix);
+            } else {
+                log::info!(ip:% = ip, prefix = prefix; "temporary ban of {}/{} expired", ip, prefix);
+            }
+        }
+    }
+
+    /// Lift temporary bans as they expire until shutdown
+    pub async fn run_expiry(self: Arc<Self>, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            let next = self.schedule.lock().unwrap().next_deadline();
+            tokio::select! {
+                _ = async {
+                    match next {
+                        Some(deadline) => sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                } => self.expire_due(Instant::now()),
+                // A new ban may expire before the current deadline
+                _ = self.notify.notified() => {}
+                _ = shutdown.changed() => {
+                    if *shutdown.borrow() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
 }
 
-pub struct MOATFirewall<'a> {
-    skel: &'a FilterSkel<'a>,
-}
+/// Handles to the banned maps of an XDP program loaded by another moat process
+pub struct LoadedBanMaps {
+    banned_ips: MapHandle,
+    banned_ips_v6: MapHandle,
+}
+
+impl LoadedBanMaps {
+    /// Locate the banned maps among the BPF maps currently loaded in the kernel
+    pub fn find() -> Result<Self, Box<dyn Error>> {
+        let mut banned_ips = None;
+        let mut banned_ips_v6 = None;
+        for info in libbpf_rs::query::MapInfoIter::default() {
+            match info.name.to_str() {
+                Ok("banned_ips") if banned_ips.is_none() => banned_ips = Some(info.id),
+                Ok("banned_ips_v6") if banned_ips_v6.is_none() => banned_ips_v6 = Some(info.id),
+                _ => {}
+            }
+        }
+        let (Some(v4), Some(v6)) = (banned_ips, banned_ips_v6) else {
+            return Err("banned_ips maps not found, is moat running with XDP enabled?".into());
+        };
+        Ok(Self {
+            banned_ips: MapHandle::from_map_id(v4)?,
+            banned_ips_v6: MapHandle::from_map_id(v6)?,
+        })
+    }
+
+    /// Same lookup as `MOATFirewall::is_banned`
+    pub fn is_banned(&self, ip: IpAddr) -> Result<Option<(u32, u8)>, Box<dyn Error>> {
+        match ip {
+            IpAddr::V4(ip) => lookup_banned_v4(&self.banned_ips, ip),
+            IpAddr::V6(ip) => lookup_banned_v6(&self.banned_ips_v6, ip),
+        }
+    }
+}
+
+// The trie lookup only returns the value, so the matched prefix is recovered from the
+// installed keys once the address is known to be covered.
+fn lookup_banned_v4<M: MapCore>(map: &M, ip: Ipv4Addr) -> Result<Option<(u32, u8)>, Box<dyn Error>> {
+    let key = utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, 32);
+    let Some(val) = map.lookup(&key, MapFlags::ANY)? else {
+        return Ok(None);
+    };
+    let entries = map.keys().filter_map(|k| utils::bpf_utils::convert_bpf_map_key_bytes_into_ip(&k));
+    let prefix = longest_covering_prefix_v4(ip, entries).unwrap_or(32);
+    Ok(Some((prefix, val.first().copied().unwrap_or(0))))
+}
+
+fn lookup_banned_v6<M: MapCore>(map: &M, ip: Ipv6Addr) -> Result<Option<(u32, u8)>, Box<dyn Error>> {
+    let key = utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, 128);
+    let Some(val) = map.lookup(&key, MapFlags::ANY)? else {
+        return Ok(None);
+    };
+    let entries = map.keys().filter_map(|k| utils::bpf_utils::convert_bpf_map_key_bytes_into_ipv6(&k));
+    let prefix = longest_covering_prefix_v6(ip, entries).unwrap_or(128);
+    Ok(Some((prefix, val.first().copied().unwrap_or(0))))
+}
+
+fn longest_covering_prefix_v4(ip: Ipv4Addr, entries: impl Iterator<Item = (Ipv4Addr, u32)>) -> Option<u32> {
+    let ip = u32::from(ip);
+    entries
+        .filter(|(net, prefix)| {
+            let mask = u32::MAX.checked_shl(32 - (*prefix).min(32)).unwrap_or(0);
+            ip & mask == u32::from(*net) & mask
+        })
+        .map(|(_, prefix)| prefix)
+        .max()
+}
+
+fn longest_covering_prefix_v6(ip: Ipv6Addr, entries: impl Iterator<Item = (Ipv6Addr, u32)>) -> Option<u32> {
+    let ip = u128::from(ip);
+    entries
+        .filter(|(net, prefix)| {
+            let mask = u128::MAX.checked_shl(128 - (*prefix).min(128)).unwrap_or(0);
+            ip & mask == u128::from(*net) & mask
+        })
+        .map(|(_, prefix)| prefix)
+        .max()
+}
+
+/// Whether `ip`/`len` is `net`/`prefix` itself or one of its subnets
+fn within_v4(ip: Ipv4Addr, len: u32, net: Ipv4Addr, prefix: u32) -> bool {
+    let prefix = prefix.min(32);
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    len >= prefix && u32::from(ip) & mask == u32::from(net) & mask
+}
+
+fn within_v6(ip: Ipv6Addr, len: u32, net: Ipv6Addr, prefix: u32) -> bool {
+    let prefix = prefix.min(128);
+    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+    len >= prefix && u128::from(ip) & mask == u128::from(net) & mask
+}
+
+impl<'a> Firewall for MOATFirewall<'a> {
+    fn ban_ip_with_notice(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefixlen);
+        let flag = 1_u8;
+
+        self.skel
+            .maps
+            .recently_banned_ips
+            .update(ip_bytes, &flag.to_le_bytes(), MapFlags::ANY)?;
+
+        Ok(())
+    }
+
+    fn ban_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.ban_ip_as(ip, prefixlen, BanSource::Sync)
+    }
+
+    fn ban_ip_as(&mut self, ip: Ipv4Addr, prefixlen: u32, source: BanSource) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefixlen);
+        if tag_entry(&self.skel.maps.banned_ips, ip_bytes, prefixlen, source)? {
+            emit_event(self.events, "ban", ip.into(), prefixlen, source);
+        }
+        Ok(())
+    }
+
+    fn unban_ip_as(&mut self, ip: Ipv4Addr, prefixlen: u32, source: BanSource) -> Result<bool, Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefixlen);
+        let (deleted, held) = release_entry(&self.skel.maps.banned_ips, ip_bytes, prefixlen, source)?;
+        if deleted {
+            let _ = self.skel.maps.rule_hits.delete(ip_bytes);
+        }
+        if held {
+            emit_event(self.events, "unban", ip.into(), prefixlen, source);
+        }
+        Ok(deleted)
+    }
+
+    fn ban_ip_with_action(&mut self, ip: Ipv4Addr, prefixlen: u32, action: BanAction) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefixlen);
+        if tag_sync_entry(&self.skel.maps.banned_ips, ip_bytes, prefixlen, action)? {
+            emit_event(self.events, "ban", ip.into(), prefixlen, BanSource::Sync);
+        }
+        Ok(())
+    }
+
+    fn check_if_notice(&mut self, ip: Ipv4Addr) -> Result<bool, Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, 32);
+
+        if let Some(val) = self
+            .skel
+            .maps
+            .recently_banned_ips
+            .lookup(ip_bytes, MapFlags::ANY)?
+        {
+            if val[0] == 1_u8 {
+                return Ok(true);
+            } else {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn unban_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefixlen);
+
+        self.skel.maps.banned_ips.delete(ip_bytes)?;
+        // The counter may not exist yet when the rule never matched
+        let _ = self.skel.maps.rule_hits.delete(ip_bytes);
+
+        Ok(())
+    }
+
+    // IPv6 implementations
+    fn ban_ipv6_with_notice(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefixlen);
+        let flag = 1_u8;
+
+        self.skel
+            .maps
+            .recently_banned_ips_v6
+            .update(ip_bytes, &flag.to_le_bytes(), MapFlags::ANY)?;
+
+        Ok(())
+    }
+
+    fn ban_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.ban_ipv6_as(ip, prefixlen, BanSource::Sync)
+    }
+
+    fn ban_ipv6_as(&mut self, ip: Ipv6Addr, prefixlen: u32, source: BanSource) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefixlen);
+        if tag_entry(&self.skel.maps.banned_ips_v6, ip_bytes, prefixlen, source)? {
+            emit_event(self.events, "ban", ip.into(), prefixlen, source);
+        }
+        Ok(())
+    }
+
+    fn unban_ipv6_as(&mut self, ip: Ipv6Addr, prefixlen: u32, source: BanSource) -> Result<bool, Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefixlen);
+        let (deleted, held) = release_entry(&self.skel.maps.banned_ips_v6, ip_bytes, prefixlen, source)?;
+        if deleted {
+            let _ = self.skel.maps.rule_hits_v6.delete(ip_bytes);
+        }
+        if held {
+            emit_event(self.events, "unban", ip.into(), prefixlen, source);
+        }
+        Ok(deleted)
+    }
+
+    fn ban_ipv6_with_action(&mut self, ip: Ipv6Addr, prefixlen: u32, action: BanAction) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefixlen);
+        if tag_sync_entry(&self.skel.maps.banned_ips_v6, ip_bytes, prefixlen, action)? {
+            emit_event(self.events, "ban", ip.into(), prefixlen, BanSource::Sync);
+        }
+        Ok(())
+    }
+
+    fn check_if_notice_ipv6(&mut self, ip: Ipv6Addr) -> Result<bool, Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, 128);
+
+        if let Some(val) = self
+            .skel
+            .maps
+            .recently_banned_ips_v6
+            .lookup(ip_bytes, MapFlags::ANY)?
+        {
+            if val[0] == 1_u8 {
+                return Ok(true);
+            } else {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn unban_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefixlen);
+
+        self.skel.maps.banned_ips_v6.delete(ip_bytes)?;
+        // The counter may not exist yet when the rule never matched
+        let _ = self.skel.maps.rule_hits_v6.delete(ip_bytes);
+
+        Ok(())
+    }
+
+    fn swap_block_changes(
+        &mut self,
+        added: &[(Ipv4Addr, u32)],
+        removed: &[(Ipv4Addr, u32)],
+        log_only: &HashSet<(Ipv4Addr, u32)>,
+    ) -> Result<BatchFailures<Ipv4Addr>, Box<dyn Error>> {
+        let skel = self.skel;
+        let maps = &skel.maps;
+        self.swap_family_changes(&maps.banned_ips, &maps.banned_ips_swap, &maps.rule_hits, "banned_ips", added, removed, log_only)
+    }
+
+    fn swap_block_changes_v6(
+        &mut self,
+        added: &[(Ipv6Addr, u32)],
+        removed: &[(Ipv6Addr, u32)],
+        log_only: &HashSet<(Ipv6Addr, u32)>,
+    ) -> Result<BatchFailures<Ipv6Addr>, Box<dyn Error>> {
+        let skel = self.skel;
+        let maps = &skel.maps;
+        self.swap_family_changes(&maps.banned_ips_v6, &maps.banned_ips_v6_swap, &maps.rule_hits_v6, "banned_ips_v6", added, removed, log_only)
+    }
+
+    // Port-scoped ban implementations
+    fn ban_ip_port(&mut self, ip: Ipv4Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>> {
+        let key = &utils::bpf_utils::convert_ip_port_into_bpf_map_key_bytes(ip, prefixlen, port);
+        let flag = 1_u8;
+
+        self.skel
+            .maps
+            .banned_ip_ports
+            .update(key, &flag.to_le_bytes(), MapFlags::ANY)?;
+
+        Ok(())
+    }
+
+    fn unban_ip_port(&mut self, ip: Ipv4Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>> {
+        let key = &utils::bpf_utils::convert_ip_port_into_bpf_map_key_bytes(ip, prefixlen, port);
+
+        self.skel.maps.banned_ip_ports.delete(key)?;
+
+        Ok(())
+    }
+
+    fn ban_ipv6_port(&mut self, ip: Ipv6Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>> {
+        let key = &utils::bpf_utils::convert_ipv6_port_into_bpf_map_key_bytes(ip, prefixlen, port);
+        let flag = 1_u8;
+
+        self.skel
+            .maps
+            .banned_ip_ports_v6
+            .update(key, &flag.to_le_bytes(), MapFlags::ANY)?;
 
-impl<'a> MOATFirewall<'a> {
-    pub fn new(skel: &'a FilterSkel<'a>) -> Self {
-        Self { skel }
+        Ok(())
     }
-}
 
-impl<'a> Firewall for MOATFirewall<'a> {
-    fn ban_ip_with_notice(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+    fn unban_ipv6_port(&mut self, ip: Ipv6Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>> {
+        let key = &utils::bpf_utils::convert_ipv6_port_into_bpf_map_key_bytes(ip, prefixlen, port);
+
+        self.skel.maps.banned_ip_ports_v6.delete(key)?;
+
+        Ok(())
+    }
+
+    // Rate limit implementations
+    fn set_rate_limit(&mut self, ip: Ipv4Addr, prefixlen: u32, pps: u32) -> Result<(), Box<dyn Error>> {
         let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefixlen);
-        let flag = 1_u8;
 
         self.skel
             .maps
-            .recently_banned_ips
-            .update(ip_bytes, &flag.to_le_bytes(), MapFlags::ANY)?;
+            .rate_limited_ips
+            .update(ip_bytes, &pps.to_ne_bytes(), MapFlags::ANY)?;
 
         Ok(())
     }
 
-    fn ban_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+    fn remove_rate_limit(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
         let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefixlen);
-        let flag = 1_u8;
+
+        self.skel.maps.rate_limited_ips.delete(ip_bytes)?;
+
+        Ok(())
+    }
+
+    fn set_rate_limit_v6(&mut self, ip: Ipv6Addr, prefixlen: u32, pps: u32) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefixlen);
 
         self.skel
             .maps
-            .banned_ips
-            .update(ip_bytes, &flag.to_le_bytes(), MapFlags::ANY)?;
+            .rate_limited_ips_v6
+            .update(ip_bytes, &pps.to_ne_bytes(), MapFlags::ANY)?;
 
         Ok(())
     }
 
-    fn check_if_notice(&mut self, ip: Ipv4Addr) -> Result<bool, Box<dyn Error>> {
-        let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, 32);
+    fn remove_rate_limit_v6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefixlen);
 
-        if let Some(val) = self
-            .skel
+        self.skel.maps.rate_limited_ips_v6.delete(ip_bytes)?;
+
+        Ok(())
+    }
+
+    // Allow-list implementations
+    fn allow_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefixlen);
+
+        // The datapath compares the prefix length against the matched block entry
+        self.skel
             .maps
-            .recently_banned_ips
-            .lookup(ip_bytes, MapFlags::ANY)?
-        {
-            if val[0] == 1_u8 {
-                return Ok(true);
-            } else {
-                return Ok(false);
-            }
-        }
+            .allowed_ips
+            .update(ip_bytes, &[prefixlen as u8], MapFlags::ANY)?;
 
-        Ok(true)
+        Ok(())
     }
 
-    fn unban_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+    fn remove_allowed_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
         let ip_bytes = &utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefixlen);
 
-        self.skel.maps.banned_ips.delete(ip_bytes)?;
+        self.skel.maps.allowed_ips.delete(ip_bytes)?;
 
         Ok(())
     }
 
-    // IPv6 implementations
-    fn ban_ipv6_with_notice(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+    fn allow_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
         let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefixlen);
-        let flag = 1_u8;
 
+        // The datapath compares the prefix length against the matched block entry
         self.skel
             .maps
-            .recently_banned_ips_v6
-            .update(ip_bytes, &flag.to_le_bytes(), MapFlags::ANY)?;
+            .allowed_ips_v6
+            .update(ip_bytes, &[prefixlen as u8], MapFlags::ANY)?;
 
         Ok(())
     }
 
-    fn ban_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+    fn remove_allowed_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
         let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefixlen);
-        let flag = 1_u8;
 
+        self.skel.maps.allowed_ips_v6.delete(ip_bytes)?;
+
+        Ok(())
+    }
+
+    fn list_rules(&self) -> Result<Vec<(Ipv4Addr, u32)>, Box<dyn Error>> {
+        self.skel
+            .maps
+            .banned_ips
+            .keys()
+            .map(|key| {
+                utils::bpf_utils::convert_bpf_map_key_bytes_into_ip(&key)
+                    .ok_or_else(|| format!("malformed banned_ips key of {} bytes", key.len()).into())
+            })
+            .collect()
+    }
+
+    fn list_rules_v6(&self) -> Result<Vec<(Ipv6Addr, u32)>, Box<dyn Error>> {
         self.skel
             .maps
             .banned_ips_v6
-            .update(ip_bytes, &flag.to_le_bytes(), MapFlags::ANY)?;
+            .keys()
+            .map(|key| {
+                utils::bpf_utils::convert_bpf_map_key_bytes_into_ipv6(&key)
+                    .ok_or_else(|| format!("malformed banned_ips_v6 key of {} bytes", key.len()).into())
+            })
+            .collect()
+    }
 
-        Ok(())
+    // Filter while walking the trie keys instead of collecting the whole map first; the
+    // kernel offers no range lookup on LPM tries, but most keys are dropped undecoded
+    fn list_rules_under(&self, net: Ipv4Addr, prefix: u32) -> Result<Vec<(Ipv4Addr, u32)>, Box<dyn Error>> {
+        let mut rules = Vec::new();
+        for key in self.skel.maps.banned_ips.keys() {
+            if key_prefixlen(&key).is_some_and(|len| len < prefix) {
+                continue;
+            }
+            let (ip, len) = utils::bpf_utils::convert_bpf_map_key_bytes_into_ip(&key)
+                .ok_or_else(|| format!("malformed banned_ips key of {} bytes", key.len()))?;
+            if within_v4(ip, len, net, prefix) {
+                rules.push((ip, len));
+            }
+        }
+        Ok(rules)
     }
 
-    fn check_if_notice_ipv6(&mut self, ip: Ipv6Addr) -> Result<bool, Box<dyn Error>> {
-        let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, 128);
+    fn list_rules_under_v6(&self, net: Ipv6Addr, prefix: u32) -> Result<Vec<(Ipv6Addr, u32)>, Box<dyn Error>> {
+        let mut rules = Vec::new();
+        for key in self.skel.maps.banned_ips_v6.keys() {
+            if key_prefixlen(&key).is_some_and(|len| len < prefix) {
+                continue;
+            }
+            let (ip, len) = utils::bpf_utils::convert_bpf_map_key_bytes_into_ipv6(&key)
+                .ok_or_else(|| format!("malformed banned_ips_v6 key of {} bytes", key.len()))?;
+            if within_v6(ip, len, net, prefix) {
+                rules.push((ip, len));
+            }
+        }
+        Ok(rules)
+    }
 
-        if let Some(val) = self
-            .skel
-            .maps
-            .recently_banned_ips_v6
-            .lookup(ip_bytes, MapFlags::ANY)?
-        {
-            if val[0] == 1_u8 {
-                return Ok(true);
-            } else {
-                return Ok(false);
+    fn list_rule_sources(&self) -> Result<Vec<(IpAddr, u32, u8)>, Box<dyn Error>> {
+        let mut sources = Vec::new();
+        for (ip, prefix) in self.list_rules()? {
+            let key = utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefix);
+            if let Some(flags) = exact_flags(&self.skel.maps.banned_ips, &key, prefix)? {
+                sources.push((IpAddr::V4(ip), prefix, flags));
+            }
+        }
+        for (ip, prefix) in self.list_rules_v6()? {
+            let key = utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefix);
+            if let Some(flags) = exact_flags(&self.skel.maps.banned_ips_v6, &key, prefix)? {
+                sources.push((IpAddr::V6(ip), prefix, flags));
             }
         }
+        Ok(sources)
+    }
+
+    fn rule_hits(&self) -> Result<Vec<(IpAddr, u32, u64)>, Box<dyn Error>> {
+        let mut hits = Vec::new();
+        for (ip, prefix) in self.list_rules()? {
+            let key = utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(ip, prefix);
+            let count = self.skel.maps.rule_hits.lookup_percpu(&key, MapFlags::ANY)?;
+            hits.push((IpAddr::V4(ip), prefix, count.map_or(0, |values| sum_percpu_counters(&values))));
+        }
+        for (ip, prefix) in self.list_rules_v6()? {
+            let key = utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefix);
+            let count = self.skel.maps.rule_hits_v6.lookup_percpu(&key, MapFlags::ANY)?;
+            hits.push((IpAddr::V6(ip), prefix, count.map_or(0, |values| sum_percpu_counters(&values))));
+        }
+        Ok(hits)
+    }
+}
 
-        Ok(true)
+/// Prefix length of a raw LPM trie key, which leads the key in host byte order
+fn key_prefixlen(key: &[u8]) -> Option<u32> {
+    key.get(..4)?.try_into().ok().map(u32::from_ne_bytes)
+}
+
+/// Value written to the banned maps: the source flags followed by the entry's prefix
+/// length, which the datapath uses to key the per-rule hit counters
+fn ban_value(prefixlen: u32, flags: u8) -> [u8; 2] {
+    [flags, prefixlen as u8]
+}
+
+/// Sum a per-CPU u64 counter. libbpf returns one slot per possible CPU; CPUs that are
+/// offline or never ran the program hold zero, so summing every slot is exact.
+fn sum_percpu_counters(values: &[Vec<u8>]) -> u64 {
+    values
+        .iter()
+        .filter_map(|value| value.get(..8)?.try_into().ok().map(u64::from_ne_bytes))
+        .fold(0, u64::saturating_add)
+}
+
+/// In-memory stand-in for the BPF maps behind `MOATFirewall`, so apply cycles can be
+/// exercised without a kernel. Entries carry the same source flags as the real banned
+/// maps, and every insert and delete is recorded in `writes`. Writes to a banned key
+/// in `fail` are refused, as a full or busy map refuses them. Bans and unbans are
+/// reported to `events` like `MOATFirewall::with_events` does.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct MemoryFirewall {
+    pub banned: HashMap<(IpAddr, u32), u8>,
+    pub fail: HashSet<(IpAddr, u32)>,
+    pub allowed: HashSet<(IpAddr, u32)>,
+    pub port_bans: HashSet<(IpAddr, u32, u16)>,
+    pub rate_limits: HashMap<(IpAddr, u32), u32>,
+    pub notices: HashSet<IpAddr>,
+    pub hits: HashMap<(IpAddr, u32), u64>,
+    pub writes: Vec<(&'static str, IpAddr, u32)>,
+    pub events: Option<Arc<EventSink>>,
+}
+
+#[cfg(test)]
+impl MemoryFirewall {
+    /// Firewall holding `v4` and `v6` as sync bans, without recording them as writes
+    pub fn with_rules(v4: &[(Ipv4Addr, u32)], v6: &[(Ipv6Addr, u32)]) -> Self {
+        let v4 = v4.iter().map(|&(ip, prefix)| ((ip.into(), prefix), BanSource::Sync as u8));
+        let v6 = v6.iter().map(|&(ip, prefix)| ((ip.into(), prefix), BanSource::Sync as u8));
+        Self { banned: v4.chain(v6).collect(), ..Self::default() }
     }
 
-    fn unban_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
-        let ip_bytes = &utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes(ip, prefixlen);
+    /// Installed IPv4 block entries, whatever their sources
+    pub fn rules(&self) -> HashSet<(Ipv4Addr, u32)> {
+        self.list_rules().unwrap_or_default().into_iter().collect()
+    }
 
-        self.skel.maps.banned_ips_v6.delete(ip_bytes)?;
+    /// Installed IPv6 block entries, whatever their sources
+    pub fn rules_v6(&self) -> HashSet<(Ipv6Addr, u32)> {
+        self.list_rules_v6().unwrap_or_default().into_iter().collect()
+    }
+
+    /// Error of a delete of a missing key, as libbpf reports it
+    fn missing() -> Box<dyn Error> {
+        Box::new(libbpf_rs::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound)))
+    }
+
+    fn check_writable(&self, key: (IpAddr, u32)) -> Result<(), Box<dyn Error>> {
+        if self.fail.contains(&key) {
+            return Err(format!("{}/{}: map full", key.0, key.1).into());
+        }
+        Ok(())
+    }
+
+    fn update_banned(&mut self, key: (IpAddr, u32), flags: u8) -> Result<(), Box<dyn Error>> {
+        self.check_writable(key)?;
+        self.banned.insert(key, flags);
+        self.writes.push(("update", key.0, key.1));
+        Ok(())
+    }
+
+    fn delete_banned(&mut self, key: (IpAddr, u32)) -> Result<(), Box<dyn Error>> {
+        self.check_writable(key)?;
+        self.banned.remove(&key).ok_or_else(Self::missing)?;
+        self.hits.remove(&key);
+        self.writes.push(("delete", key.0, key.1));
+        Ok(())
+    }
+
+    fn tag(&mut self, key: (IpAddr, u32), source: BanSource) -> Result<(), Box<dyn Error>> {
+        let flags = self.banned.get(&key).copied().unwrap_or(0);
+        self.update_banned(key, flags | source as u8)?;
+        if flags & source as u8 == 0 {
+            emit_event(self.events.as_deref(), "ban", key.0, key.1, source);
+        }
+        Ok(())
+    }
+
+    fn tag_sync(&mut self, key: (IpAddr, u32), action: BanAction) -> Result<(), Box<dyn Error>> {
+        let flags = self.banned.get(&key).copied().unwrap_or(0);
+        self.update_banned(key, sync_flags(flags, action))?;
+        if flags & BanSource::Sync as u8 == 0 {
+            emit_event(self.events.as_deref(), "ban", key.0, key.1, BanSource::Sync);
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, key: (IpAddr, u32), source: BanSource) -> Result<bool, Box<dyn Error>> {
+        let flags = self.banned.get(&key).copied();
+        let remaining = flags.map_or(0, |flags| remaining_sources(flags, source));
+        let deleted = remaining == 0;
+        if deleted {
+            self.delete_banned(key)?;
+        } else {
+            self.update_banned(key, remaining)?;
+        }
+        if flags.is_some_and(|flags| flags & source as u8 != 0) {
+            emit_event(self.events.as_deref(), "unban", key.0, key.1, source);
+        }
+        Ok(deleted)
+    }
+
+    fn allow(&mut self, key: (IpAddr, u32)) -> Result<(), Box<dyn Error>> {
+        self.allowed.insert(key);
+        self.writes.push(("allow", key.0, key.1));
+        Ok(())
+    }
+
+    fn disallow(&mut self, key: (IpAddr, u32)) -> Result<(), Box<dyn Error>> {
+        if !self.allowed.remove(&key) {
+            return Err(Self::missing());
+        }
+        self.writes.push(("disallow", key.0, key.1));
+        Ok(())
+    }
+
+    fn ban_port(&mut self, key: (IpAddr, u32), port: u16) -> Result<(), Box<dyn Error>> {
+        self.port_bans.insert((key.0, key.1, port));
+        Ok(())
+    }
+
+    fn unban_port(&mut self, key: (IpAddr, u32), port: u16) -> Result<(), Box<dyn Error>> {
+        if !self.port_bans.remove(&(key.0, key.1, port)) {
+            return Err(Self::missing());
+        }
+        Ok(())
+    }
+
+    fn remove_limit(&mut self, key: (IpAddr, u32)) -> Result<(), Box<dyn Error>> {
+        self.rate_limits.remove(&key).map(|_| ()).ok_or_else(Self::missing)
+    }
+}
 
+#[cfg(test)]
+impl Firewall for MemoryFirewall {
+    fn ban_ip_with_notice(&mut self, ip: Ipv4Addr, _: u32) -> Result<(), Box<dyn Error>> {
+        self.notices.insert(ip.into());
+        Ok(())
+    }
+    fn ban_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.tag((ip.into(), prefixlen), BanSource::Sync)
+    }
+    fn unban_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.delete_banned((ip.into(), prefixlen))
+    }
+    fn check_if_notice(&mut self, ip: Ipv4Addr) -> Result<bool, Box<dyn Error>> {
+        Ok(!self.notices.contains(&IpAddr::V4(ip)))
+    }
+    fn ban_ipv6_with_notice(&mut self, ip: Ipv6Addr, _: u32) -> Result<(), Box<dyn Error>> {
+        self.notices.insert(ip.into());
+        Ok(())
+    }
+    fn ban_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.tag((ip.into(), prefixlen), BanSource::Sync)
+    }
+    fn unban_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.delete_banned((ip.into(), prefixlen))
+    }
+    fn check_if_notice_ipv6(&mut self, ip: Ipv6Addr) -> Result<bool, Box<dyn Error>> {
+        Ok(!self.notices.contains(&IpAddr::V6(ip)))
+    }
+    fn ban_ip_as(&mut self, ip: Ipv4Addr, prefixlen: u32, source: BanSource) -> Result<(), Box<dyn Error>> {
+        self.tag((ip.into(), prefixlen), source)
+    }
+    fn unban_ip_as(&mut self, ip: Ipv4Addr, prefixlen: u32, source: BanSource) -> Result<bool, Box<dyn Error>> {
+        self.release((ip.into(), prefixlen), source)
+    }
+    fn ban_ipv6_as(&mut self, ip: Ipv6Addr, prefixlen: u32, source: BanSource) -> Result<(), Box<dyn Error>> {
+        self.tag((ip.into(), prefixlen), source)
+    }
+    fn unban_ipv6_as(&mut self, ip: Ipv6Addr, prefixlen: u32, source: BanSource) -> Result<bool, Box<dyn Error>> {
+        self.release((ip.into(), prefixlen), source)
+    }
+    fn ban_ip_with_action(&mut self, ip: Ipv4Addr, prefixlen: u32, action: BanAction) -> Result<(), Box<dyn Error>> {
+        self.tag_sync((ip.into(), prefixlen), action)
+    }
+    fn ban_ipv6_with_action(&mut self, ip: Ipv6Addr, prefixlen: u32, action: BanAction) -> Result<(), Box<dyn Error>> {
+        self.tag_sync((ip.into(), prefixlen), action)
+    }
+    fn ban_ip_port(&mut self, ip: Ipv4Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>> {
+        self.ban_port((ip.into(), prefixlen), port)
+    }
+    fn unban_ip_port(&mut self, ip: Ipv4Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>> {
+        self.unban_port((ip.into(), prefixlen), port)
+    }
+    fn ban_ipv6_port(&mut self, ip: Ipv6Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>> {
+        self.ban_port((ip.into(), prefixlen), port)
+    }
+    fn unban_ipv6_port(&mut self, ip: Ipv6Addr, prefixlen: u32, port: u16) -> Result<(), Box<dyn Error>> {
+        self.unban_port((ip.into(), prefixlen), port)
+    }
+    fn set_rate_limit(&mut self, ip: Ipv4Addr, prefixlen: u32, pps: u32) -> Result<(), Box<dyn Error>> {
+        self.rate_limits.insert((ip.into(), prefixlen), pps);
+        Ok(())
+    }
+    fn remove_rate_limit(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.remove_limit((ip.into(), prefixlen))
+    }
+    fn set_rate_limit_v6(&mut self, ip: Ipv6Addr, prefixlen: u32, pps: u32) -> Result<(), Box<dyn Error>> {
+        self.rate_limits.insert((ip.into(), prefixlen), pps);
         Ok(())
     }
+    fn remove_rate_limit_v6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.remove_limit((ip.into(), prefixlen))
+    }
+    fn allow_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.allow((ip.into(), prefixlen))
+    }
+    fn remove_allowed_ip(&mut self, ip: Ipv4Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.disallow((ip.into(), prefixlen))
+    }
+    fn allow_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.allow((ip.into(), prefixlen))
+    }
+    fn remove_allowed_ipv6(&mut self, ip: Ipv6Addr, prefixlen: u32) -> Result<(), Box<dyn Error>> {
+        self.disallow((ip.into(), prefixlen))
+    }
+    fn list_rule_sources(&self) -> Result<Vec<(IpAddr, u32, u8)>, Box<dyn Error>> {
+        Ok(self.banned.iter().map(|(&(ip, prefix), &flags)| (ip, prefix, flags)).collect())
+    }
+    fn list_rules(&self) -> Result<Vec<(Ipv4Addr, u32)>, Box<dyn Error>> {
+        Ok(self.banned.keys().filter_map(|&(ip, prefix)| match ip {
+            IpAddr::V4(ip) => Some((ip, prefix)),
+            IpAddr::V6(_) => None,
+        }).collect())
+    }
+    fn list_rules_v6(&self) -> Result<Vec<(Ipv6Addr, u32)>, Box<dyn Error>> {
+        Ok(self.banned.keys().filter_map(|&(ip, prefix)| match ip {
+            IpAddr::V6(ip) => Some((ip, prefix)),
+            IpAddr::V4(_) => None,
+        }).collect())
+    }
+    fn rule_hits(&self) -> Result<Vec<(IpAddr, u32, u64)>, Box<dyn Error>> {
+        Ok(self.banned.keys().map(|&(ip, prefix)| (ip, prefix, self.hits.get(&(ip, prefix)).copied().unwrap_or(0))).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ban_entry() {
+        assert_eq!(parse_ban_entry("192.0.2.0/24"), Ok((vec![(Ipv4Addr::new(192, 0, 2, 0), 24)], vec![])));
+        assert_eq!(parse_ban_entry("::ffff:192.0.2.1"), Ok((vec![(Ipv4Addr::new(192, 0, 2, 1), 32)], vec![])));
+        assert_eq!(parse_ban_entry("2001:db8::/32"), Ok((vec![], vec![("2001:db8::".parse().unwrap(), 32)])));
+        assert_eq!(
+            parse_ban_entry("10.0.0.0-10.0.0.3"),
+            Ok((vec![(Ipv4Addr::new(10, 0, 0, 0), 30)], vec![]))
+        );
+        assert_eq!(parse_ban_entry("10.0.0.0/33"), Err("invalid IPv4 ip/cidr".to_string()));
+        assert!(parse_ban_entry("2001:db8::/129").is_err());
+    }
+
+    #[test]
+    fn test_ban_file_reports_rejected_lines_by_number() {
+        let file = BanFile::parse("# incident 42\n192.0.2.0/24\n\nroot:$6$secret:19000\n10.0.0.0-10.0.0.3 # scanners\n");
+        assert_eq!(file.entries, vec![
+            (2, vec![(Ipv4Addr::new(192, 0, 2, 0), 24)], vec![]),
+            (5, vec![(Ipv4Addr::new(10, 0, 0, 0), 30)], vec![]),
+        ]);
+        assert_eq!(file.networks(), 2);
+        assert_eq!(file.rejected, vec![RejectedLine { line: 4, reason: "invalid IPv6 ip/cidr".to_string() }]);
+    }
+
+    #[test]
+    fn test_sum_percpu_counters() {
+        let values = vec![
+            3_u64.to_ne_bytes().to_vec(),
+            0_u64.to_ne_bytes().to_vec(),
+            39_u64.to_ne_bytes().to_vec(),
+        ];
+        assert_eq!(sum_percpu_counters(&values), 42);
+        assert_eq!(sum_percpu_counters(&[]), 0);
+        assert_eq!(sum_percpu_counters(&[u64::MAX.to_ne_bytes().to_vec(), 1_u64.to_ne_bytes().to_vec()]), u64::MAX);
+    }
+
+    #[test]
+    fn test_port_key_puts_port_before_address() {
+        let key = utils::bpf_utils::convert_ip_port_into_bpf_map_key_bytes(Ipv4Addr::new(1, 2, 3, 0), 24, 22);
+        assert_eq!(&key[..4], &40_u32.to_ne_bytes());
+        assert_eq!(&key[4..10], &[0, 22, 1, 2, 3, 0]);
+
+        let key = utils::bpf_utils::convert_ipv6_port_into_bpf_map_key_bytes("2001:db8::".parse().unwrap(), 32, 443);
+        assert_eq!(&key[..4], &48_u32.to_ne_bytes());
+        assert_eq!(&key[4..10], &[0x01, 0xbb, 0x20, 0x01, 0x0d, 0xb8]);
+    }
+
+    #[test]
+    fn test_ban_value_carries_prefix() {
+        assert_eq!(ban_value(24, BanSource::Sync as u8), [1, 24]);
+        assert_eq!(ban_value(128, BanSource::Sync as u8), [1, 128]);
+    }
+
+    #[test]
+    fn test_released_sources_leave_the_others() {
+        let both = BanSource::Sync as u8 | BanSource::Manual as u8;
+        assert_eq!(remaining_sources(both, BanSource::Sync), BanSource::Manual as u8);
+        assert_eq!(remaining_sources(BanSource::Manual as u8, BanSource::Manual), 0);
+        // Releasing a source that never referenced the entry keeps it
+        assert_eq!(remaining_sources(BanSource::Datapath as u8, BanSource::Sync), BanSource::Datapath as u8);
+    }
+
+    #[test]
+    fn test_precedence_round_trips_through_its_name() {
+        for precedence in [Precedence::AllowAlwaysWins, Precedence::MostSpecificWins, Precedence::BlockAlwaysWins] {
+            assert_eq!(precedence.to_string().parse::<Precedence>(), Ok(precedence));
+        }
+        assert_eq!(" Most-Specific-Wins ".parse::<Precedence>(), Ok(Precedence::MostSpecificWins));
+        assert!("allow-wins".parse::<Precedence>().is_err());
+        assert_eq!(Precedence::default() as u32, 0);
+    }
+
+    #[test]
+    fn test_log_only_bit_follows_the_sync_reference() {
+        let manual = BanSource::Manual as u8;
+        let trial = sync_flags(manual, BanAction::LogOnly);
+        assert_eq!(trial, manual | BanSource::Sync as u8 | LOG_ONLY_FLAG);
+        // Promoting the rule clears the bit, demoting sets it again
+        assert_eq!(sync_flags(trial, BanAction::Drop), manual | BanSource::Sync as u8);
+        assert_eq!(remaining_sources(trial, BanSource::Sync), manual);
+        assert_eq!(remaining_sources(sync_flags(0, BanAction::LogOnly), BanSource::Sync), 0);
+        assert_eq!(remaining_sources(trial, BanSource::Manual), BanSource::Sync as u8 | LOG_ONLY_FLAG);
+    }
+
+    #[test]
+    fn test_expiry_schedule_pops_in_deadline_order() {
+        let start = Instant::now();
+        let a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0));
+        let mut schedule = ExpirySchedule::default();
+        schedule.insert(a, 32, start + Duration::from_secs(30));
+        schedule.insert(b, 24, start + Duration::from_secs(10));
+
+        assert_eq!(schedule.next_deadline(), Some(start + Duration::from_secs(10)));
+        assert!(schedule.pop_due(start).is_empty());
+        assert_eq!(schedule.pop_due(start + Duration::from_secs(10)), vec![(b, 24)]);
+        assert!(schedule.contains(a, 32));
+        assert!(!schedule.contains(b, 24));
+        assert_eq!(schedule.pop_due(start + Duration::from_secs(60)), vec![(a, 32)]);
+        assert_eq!(schedule.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_expiry_schedule_reban_extends_deadline() {
+        let start = Instant::now();
+        let ip = IpAddr::V6("2001:db8::1".parse().unwrap());
+        let mut schedule = ExpirySchedule::default();
+        schedule.insert(ip, 128, start + Duration::from_secs(10));
+        schedule.insert(ip, 128, start + Duration::from_secs(60));
+
+        assert!(schedule.pop_due(start + Duration::from_secs(10)).is_empty());
+        assert!(schedule.contains(ip, 128));
+        assert_eq!(schedule.pop_due(start + Duration::from_secs(60)), vec![(ip, 128)]);
+    }
+
+    #[test]
+    fn test_temporary_ban_rejects_overflowing_ttl() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let bans = TemporaryBans::new(Vec::new());
+        assert!(bans.ban_ip_for(ip, 32, Duration::from_secs(u64::MAX)).is_err());
+        assert!(!bans.is_active(ip, 32));
+        // The schedule is still usable after the rejected ban
+        bans.ban_ip_for(ip, 32, Duration::from_secs(60)).unwrap();
+        assert!(bans.is_active(ip, 32));
+    }
+
+    #[test]
+    fn test_longest_covering_prefix_reports_covering_rule() {
+        let ip = Ipv4Addr::new(203, 0, 113, 5);
+        let entries = vec![(Ipv4Addr::new(203, 0, 0, 0), 16)];
+        assert_eq!(longest_covering_prefix_v4(ip, entries.into_iter()), Some(16));
+
+        let entries = vec![
+            (Ipv4Addr::new(203, 0, 0, 0), 8),
+            (Ipv4Addr::new(203, 0, 0, 0), 16),
+            (Ipv4Addr::new(198, 51, 100, 0), 24),
+        ];
+        assert_eq!(longest_covering_prefix_v4(ip, entries.into_iter()), Some(16));
+        assert_eq!(longest_covering_prefix_v4(Ipv4Addr::new(192, 0, 2, 1), std::iter::empty()), None);
+    }
+
+    #[test]
+    fn test_longest_covering_prefix_v6() {
+        let ip: Ipv6Addr = "2001:db8:1::5".parse().unwrap();
+        let entries = vec![
+            ("2001:db8::".parse().unwrap(), 32),
+            ("2001:db9::".parse().unwrap(), 32),
+            ("::".parse().unwrap(), 0),
+        ];
+        assert_eq!(longest_covering_prefix_v6(ip, entries.into_iter()), Some(32));
+    }
+
+    #[test]
+    fn test_replace_all_adds_before_removing() {
+        let kept = (Ipv4Addr::new(10, 0, 0, 0), 8);
+        let stale = (Ipv4Addr::new(192, 0, 2, 0), 24);
+        let stale_v6: (Ipv6Addr, u32) = ("2001:db8::".parse().unwrap(), 32);
+        let new = (Ipv4Addr::new(198, 51, 100, 0), 24);
+        let new_v6: (Ipv6Addr, u32) = ("2001:db9::".parse().unwrap(), 32);
+        let mut fw = MemoryFirewall::with_rules(&[kept, stale], &[stale_v6]);
+
+        let summary = fw.replace_all(&[kept, new], &[new_v6]).unwrap();
+
+        assert_eq!(summary, ReplaceSummary { added: 2, removed: 2 });
+        let ops: Vec<_> = fw.writes.iter().map(|(op, _, _)| *op).collect();
+        assert_eq!(ops, vec!["update", "update", "delete", "delete"]);
+        assert_eq!(fw.rules(), HashSet::from([kept, new]));
+        assert_eq!(fw.rules_v6(), HashSet::from([new_v6]));
+    }
+
+    #[test]
+    fn test_swap_block_changes_falls_back_to_in_place() {
+        let kept = (Ipv4Addr::new(10, 0, 0, 0), 8);
+        let stale = (Ipv4Addr::new(192, 0, 2, 0), 24);
+        let new = (Ipv4Addr::new(198, 51, 100, 0), 24);
+        let mut fw = MemoryFirewall::with_rules(&[kept, stale], &[]);
+
+        let failures = fw.swap_block_changes(&[new], &[stale], &HashSet::new()).unwrap();
+        assert!(failures.is_empty());
+
+        assert_eq!(fw.writes, vec![("delete", stale.0.into(), stale.1), ("update", new.0.into(), new.1)]);
+        assert_eq!(fw.rules(), HashSet::from([kept, new]));
+    }
+
+    #[test]
+    fn test_swap_block_changes_keeps_going_past_failures() {
+        let kept = (Ipv4Addr::new(10, 0, 0, 0), 8);
+        let stuck = (Ipv4Addr::new(192, 0, 2, 0), 24);
+        let full = (Ipv4Addr::new(198, 51, 100, 0), 24);
+        let new = (Ipv4Addr::new(203, 0, 113, 0), 24);
+        let mut fw = MemoryFirewall::with_rules(&[kept, stuck], &[]);
+        fw.fail.extend([(stuck.0.into(), stuck.1), (full.0.into(), full.1)]);
+
+        let failures = fw.swap_block_changes(&[full, new], &[stuck], &HashSet::new()).unwrap();
+
+        assert_eq!(failures.removed.iter().map(|(entry, _)| *entry).collect::<Vec<_>>(), vec![stuck]);
+        assert_eq!(failures.added.iter().map(|(entry, _)| *entry).collect::<Vec<_>>(), vec![full]);
+        assert_eq!(fw.rules(), HashSet::from([kept, stuck, new]));
+    }
+
+    #[test]
+    fn test_list_rules_under_filters_to_subtree() {
+        let fw = MemoryFirewall::with_rules(
+            &[
+                (Ipv4Addr::new(10, 0, 0, 0), 8),
+                (Ipv4Addr::new(10, 1, 0, 0), 16),
+                (Ipv4Addr::new(10, 1, 2, 3), 32),
+                (Ipv4Addr::new(11, 0, 0, 0), 8),
+                (Ipv4Addr::new(0, 0, 0, 0), 0),
+            ],
+            &[("2001:db8:1::".parse().unwrap(), 48), ("2001:db9::".parse().unwrap(), 32)],
+        );
+
+        let mut under = fw.list_rules_under(Ipv4Addr::new(10, 0, 0, 0), 8).unwrap();
+        under.sort();
+        assert_eq!(under, vec![
+            (Ipv4Addr::new(10, 0, 0, 0), 8),
+            (Ipv4Addr::new(10, 1, 0, 0), 16),
+            (Ipv4Addr::new(10, 1, 2, 3), 32),
+        ]);
+        assert_eq!(fw.list_rules_under(Ipv4Addr::new(10, 1, 2, 0), 24).unwrap(), vec![(Ipv4Addr::new(10, 1, 2, 3), 32)]);
+        assert_eq!(fw.list_rules_under(Ipv4Addr::new(0, 0, 0, 0), 0).unwrap().len(), 5);
+        assert_eq!(
+            fw.list_rules_under_v6("2001:db8::".parse().unwrap(), 32).unwrap(),
+            vec![("2001:db8:1::".parse().unwrap(), 48)]
+        );
+    }
+
+    #[test]
+    fn test_key_prefixlen_reads_leading_field() {
+        let key = utils::bpf_utils::convert_ip_into_bpf_map_key_bytes(Ipv4Addr::new(10, 0, 0, 0), 8);
+        assert_eq!(key_prefixlen(&key), Some(8));
+        let key = utils::bpf_utils::convert_ipv6_into_bpf_map_key_bytes("2001:db8::".parse().unwrap(), 48);
+        assert_eq!(key_prefixlen(&key), Some(48));
+        assert_eq!(key_prefixlen(&[0, 1]), None);
+    }
+
+    #[test]
+    fn test_replace_all_leaves_other_sources_alone() {
+        let synced = (Ipv4Addr::new(10, 0, 0, 0), 8);
+        let stale = (Ipv4Addr::new(192, 0, 2, 0), 24);
+        let shared = (Ipv4Addr::new(198, 51, 100, 0), 24);
+        let manual = (Ipv4Addr::new(203, 0, 113, 7), 32);
+        let temporary = (Ipv4Addr::new(203, 0, 113, 8), 32);
+        let mut fw = MemoryFirewall::default();
+        for (ip, prefix) in [synced, stale, shared] {
+            fw.ban_ip(ip, prefix).unwrap();
+        }
+        fw.ban_ip_as(shared.0, shared.1, BanSource::Manual).unwrap();
+        fw.ban_ip_as(manual.0, manual.1, BanSource::Manual).unwrap();
+        fw.ban_ip_as(temporary.0, temporary.1, BanSource::Temporary).unwrap();
+
+        let summary = fw.replace_all(&[synced], &[]).unwrap();
+
+        assert_eq!(summary, ReplaceSummary { added: 0, removed: 2 });
+        assert_eq!(fw.banned, HashMap::from([
+            ((synced.0.into(), synced.1), BanSource::Sync as u8),
+            ((shared.0.into(), shared.1), BanSource::Manual as u8),
+            ((manual.0.into(), manual.1), BanSource::Manual as u8),
+            ((temporary.0.into(), temporary.1), BanSource::Temporary as u8),
+        ]));
+    }
+
+    #[test]
+    fn test_replace_all_is_noop_when_in_sync() {
+        let rule = (Ipv4Addr::new(10, 0, 0, 0), 8);
+        let mut fw = MemoryFirewall::with_rules(&[rule], &[]);
+
+        assert_eq!(fw.replace_all(&[rule], &[]).unwrap(), ReplaceSummary::default());
+        assert!(fw.writes.is_empty());
+    }
+
+    #[test]
+    fn test_events_carry_the_source_and_skip_entries_in_place() {
+        let path = std::env::temp_dir().join(format!("moat-firewall-events-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = crate::cli::EventSinkConfig { kind: "file".to_string(), path: path.display().to_string() };
+        let synced = (Ipv4Addr::new(10, 0, 0, 0), 8);
+        let manual = (Ipv4Addr::new(192, 0, 2, 1), 32);
+        let temporary: (Ipv6Addr, u32) = ("2001:db8::1".parse().unwrap(), 128);
+        let mut fw = MemoryFirewall::with_rules(&[synced], &[]);
+        fw.events = Some(Arc::new(EventSink::open(&config).unwrap()));
+
+        // A forced reapply of an entry the sync already bans reports nothing
+        fw.ban_ip_with_action(synced.0, synced.1, BanAction::Drop).unwrap();
+        fw.ban_ip_as(manual.0, manual.1, BanSource::Manual).unwrap();
+        fw.ban_ip_as(manual.0, manual.1, BanSource::Manual).unwrap();
+        fw.ban_ipv6_as(temporary.0, temporary.1, BanSource::Temporary).unwrap();
+        fw.unban_ipv6_as(temporary.0, temporary.1, BanSource::Temporary).unwrap();
+        // Releasing a source that never banned the entry reports nothing either
+        fw.unban_ip_as(manual.0, manual.1, BanSource::Sync).unwrap();
+        fw.unban_ip_as(synced.0, synced.1, BanSource::Sync).unwrap();
+        // Dropping the last sender lets the writer drain, flush and exit
+        fw.events = None;
+
+        let lines = crate::access_rules::events::written_lines(&path, 4);
+        let events: Vec<(String, String, String)> = lines
+            .iter()
+            .map(|line| {
+                let event: serde_json::Value = serde_json::from_str(line).unwrap();
+                let field = |name: &str| event[name].as_str().unwrap().to_string();
+                (field("action"), field("ip"), field("source"))
+            })
+            .collect();
+        let event = |action: &str, ip: &str, source: &str| (action.to_string(), ip.to_string(), source.to_string());
+        assert_eq!(events, vec![
+            event("ban", "192.0.2.1", "manual"),
+            event("ban", "2001:db8::1", "temporary"),
+            event("unban", "2001:db8::1", "temporary"),
+            event("unban", "10.0.0.0", "sync"),
+        ]);
+    }
 }
@@ -45,15 +45,17 @@ pub mod bpf_stats;
 pub mod tcp_fingerprint;
 pub mod ja4_plus;
 pub mod event_queue;
+pub mod metrics;
+pub mod control_api;
 
-use tokio::signal;
 use tokio::sync::watch;
 
 use crate::app_state::AppState;
 use crate::bpf_stats::BpfStatsCollector;
 use crate::tcp_fingerprint::TcpFingerprintCollector;
 use crate::tcp_fingerprint::TcpFingerprintConfig;
-use crate::cli::{Args, Config};
+use crate::cli::{Args, Command, Config};
+use crate::firewall::LoadedBanMaps;
 use crate::domain_filter::DomainFilter;
 use crate::http::{
     SharedTlsState, TlsMode, install_ring_crypto_provider, load_custom_server_config,
@@ -74,13 +76,18 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
 
+    // One-shot commands never start the proxy
+    if let Some(command) = &args.command {
+        return run_command(command, &args);
+    }
+
     // Validate required arguments when no config file is provided
     if args.config.is_none() {
         if args.upstream.is_none() {
             return Err(anyhow::anyhow!("--upstream is required when no config file is provided"));
         }
-        if args.arxignis_api_key.is_none() {
-            return Err(anyhow::anyhow!("--arxignis-api-key is required when no config file is provided"));
+        if args.arxignis_api_key.is_none() && args.arxignis_credentials_path.is_none() {
+            return Err(anyhow::anyhow!("--arxignis-api-key or --arxignis-credentials-path is required when no config file is provided"));
         }
     }
 
@@ -144,6 +151,56 @@ fn main() -> Result<()> {
         .block_on(async_main(args, config))
 }
 
+fn run_command(command: &Command, args: &Args) -> Result<()> {
+    match command {
+        Command::Status { ip } => {
+            let maps = LoadedBanMaps::find()
+                .map_err(|e| anyhow!("Failed to open banned maps: {}", e))?;
+            match maps.is_banned(*ip).map_err(|e| anyhow!("Failed to look up {}: {}", ip, e))? {
+                Some((prefix, flag)) => println!("{} blocked by {}/{} (flag {})", ip, mask_ip(*ip, prefix), prefix, flag),
+                None => println!("{} not blocked", ip),
+            }
+            Ok(())
+        }
+        Command::ValidateRules { file } => {
+            let data = std::fs::read(file)
+                .with_context(|| format!("Failed to read {}", file.display()))?;
+            let response: config::ConfigApiResponse = serde_json::from_slice(&data)
+                .with_context(|| format!("Failed to parse {} as a config API response", file.display()))?;
+            // Validate with the configured families, catch-all and cap settings
+            let config = Config::load_for_command(args).context("Failed to load configuration")?;
+            let rules_config = access_rules::AccessRulesConfig::from_cli_config(&config.access_rules);
+            let report = access_rules::validate_rules(&response.config.access_rules, &rules_config);
+            println!("IPv4 block rules: {}", report.rules);
+            println!("IPv6 block rules: {}", report.rules_v6);
+            println!("IPv4 allow rules: {}", report.allow_rules);
+            println!("IPv6 allow rules: {}", report.allow_rules_v6);
+            println!("rate limit rules: {}", report.rate_limits);
+            for rejected in &report.rejected {
+                println!("rejected {} {:?}: {}", rejected.rule_source, rejected.entry, rejected.reason);
+            }
+            if !report.rejected.is_empty() {
+                return Err(anyhow!("{} invalid entries in {}", report.rejected.len(), file.display()));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Network address of `ip` with only the first `prefix` bits kept
+fn mask_ip(ip: std::net::IpAddr, prefix: u32) -> std::net::IpAddr {
+    match ip {
+        std::net::IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - prefix.min(32)).unwrap_or(0);
+            std::net::Ipv4Addr::from(u32::from(v4) & mask).into()
+        }
+        std::net::IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - prefix.min(128)).unwrap_or(0);
+            std::net::Ipv6Addr::from(u128::from(v6) & mask).into()
+        }
+    }
+}
+
 #[allow(clippy::too_many_lines)]
 async fn async_main(args: Args, config: Config) -> Result<()> {
 
@@ -157,17 +214,78 @@ async fn async_main(args: Args, config: Config) -> Result<()> {
     } else {
         log::info!("Global HTTP client initialized with keepalive configuration");
     }
+    crate::config::set_fetch_headers(config.arxignis.user_agent.as_deref(), &config.arxignis.request_headers)
+        .map_err(|e| anyhow!("invalid arxignis request headers: {}", e))?;
+    crate::config::set_max_response_size(config.arxignis.max_config_response_size)
+        .map_err(|e| anyhow!("invalid arxignis config: {}", e))?;
+    if let Some(public_key) = &config.arxignis.signing_public_key {
+        crate::config::set_signing_key(public_key).map_err(|e| anyhow!("invalid arxignis config: {}", e))?;
+        log::info!("Config responses must carry a valid {} signature", crate::config::SIGNATURE_HEADER);
+    }
+    // Build the dedicated fetch client now, so a bad certificate or proxy URL fails startup
+    // rather than the first fetch
+    let fetch_client_config = crate::http_client::HttpClientConfig {
+        proxy: config.arxignis.proxy.clone(),
+        ..Default::default()
+    };
+    if let Some(tls) = &config.arxignis.tls {
+        let client = crate::http_client::build_tls_client(&fetch_client_config, tls)
+            .context("failed to set up arxignis API TLS")?;
+        crate::config::set_fetch_client(client).map_err(|e| anyhow!(e))?;
+        log::info!("Config fetches use the configured TLS client certificate");
+    } else if config.arxignis.proxy.is_some() {
+        let client = crate::http_client::SharedHttpClient::new(fetch_client_config)
+            .context("failed to set up arxignis API proxy")?;
+        crate::config::set_fetch_client(client.client().clone()).map_err(|e| anyhow!(e))?;
+    }
+    if let Some(proxy) = &config.arxignis.proxy {
+        log::info!("Config fetches go through proxy {}", crate::http_client::redact_proxy_url(proxy));
+    }
 
 
-    let iface_names: Vec<String> = if !config.network.ifaces.is_empty() {
+    let requested_ifaces: Vec<String> = if !config.network.ifaces.is_empty() {
         config.network.ifaces.clone()
     } else {
         vec![config.network.iface.clone()]
     };
+    let iface_names = bpf_utils::select_interfaces(
+        &requested_ifaces,
+        &config.network.exclude_ifaces,
+        &bpf_utils::host_interfaces(),
+    );
+
+    let mut access_rules_config = access_rules::AccessRulesConfig::from_cli_config(&config.access_rules);
+    // The rule event sink starts a writer thread, so it is only opened for the daemon
+    access_rules_config.event_sink = config.access_rules.event_sink.as_ref().and_then(|sink| {
+        match access_rules::events::EventSink::open(sink) {
+            Ok(events) => Some(Arc::new(events)),
+            Err(e) => {
+                log::warn!("failed to open rule event sink {}: {}", sink.path, e);
+                None
+            }
+        }
+    });
+
+    // The control API feeds manual bans into the access rules updater and reads back
+    // what was applied
+    let control_api_token = match (&config.control_api.enabled, &config.control_api.token) {
+        (true, Some(token)) if !token.is_empty() => Some(token.clone()),
+        (true, _) => {
+            log::error!("control API enabled without a token, not starting it");
+            None
+        }
+        _ => None,
+    };
+    let manual_rules = control_api_token.as_ref().map(|_| Arc::new(access_rules::ManualRules::default()));
+    access_rules_config.manual_rules = manual_rules.clone();
+    let apply_pause = Arc::new(access_rules::ApplyPause::default());
+    access_rules_config.apply_pause = Some(apply_pause.clone());
 
     let mut skels: Vec<Arc<bpf::FilterSkel<'static>>> = Vec::new();
     let mut ifindices: Vec<i32> = Vec::new();
 
+    let attach_mode = bpf_utils::AttachMode::from_str(&config.network.attach_mode)
+        .map_err(|e| anyhow!("invalid network.attach_mode: {}", e))?;
     if config.network.disable_xdp {
         log::info!("XDP disabled by config, skipping BPF attachment");
     } else {
@@ -175,7 +293,11 @@ async fn async_main(args: Args, config: Config) -> Result<()> {
             let boxed_open: Box<MaybeUninit<libbpf_rs::OpenObject>> = Box::new(MaybeUninit::uninit());
             let open_object: &'static mut MaybeUninit<libbpf_rs::OpenObject> = Box::leak(boxed_open);
             let skel_builder = bpf::FilterSkelBuilder::default();
-            match skel_builder.open(open_object).and_then(|o| o.load()) {
+            let opened = skel_builder.open(open_object).and_then(|mut o| {
+                bpf_utils::tune_maps(&mut o, &config.network.bpf_maps)?;
+                o.load()
+            });
+            match opened {
                 Ok(mut skel) => {
                     let ifindex = match if_nametoindex(iface.as_str()) {
                         Ok(index) => index as i32,
@@ -184,14 +306,17 @@ async fn async_main(args: Args, config: Config) -> Result<()> {
                             continue;
                         }
                     };
-                    if let Err(e) = bpf_utils::bpf_attach_to_xdp(&mut skel, ifindex) {
-                        log::error!("Failed to attach XDP to '{}': {}", iface, e);
-                        log::info!("Hint: If IPv6 is not available, you can either enable it or set 'disable_xdp: true' in config.yaml");
-                        continue;
+                    match bpf_utils::bpf_attach(&mut skel, ifindex, attach_mode) {
+                        Ok(used) => log::info!("BPF sucessfully attached to {} in {} mode", iface, used),
+                        Err(e) => {
+                            log::error!("Failed to attach BPF filter to '{}' in {} mode: {}", iface, attach_mode, e);
+                            log::info!("Hint: If IPv6 is not available, you can either enable it or set 'disable_xdp: true' in config.yaml");
+                            continue;
+                        }
                     }
-                    log::info!("BPF sucessfully attached to xdp on {}", iface);
                     skels.push(Arc::new(skel));
                     ifindices.push(ifindex);
+                    access_rules_config.skeleton_ifaces.push(iface);
                 }
                 Err(e) => {
                     log::warn!("failed to load BPF skeleton for '{}': {e}", iface);
@@ -201,10 +326,13 @@ async fn async_main(args: Args, config: Config) -> Result<()> {
 
         // Initialize access rules immediately after XDP attachment
         if !skels.is_empty() {
-            let _ = access_rules::init_access_rules_from_global(&skels);
+            let _ = access_rules::init_access_rules_from_global(&skels, &access_rules_config);
         }
     }
 
+    access_rules::check_skeletons(&skels, &access_rules_config)
+        .map_err(|e| anyhow!("{}", e))?;
+
     // Convert config TLS mode string to enum
     let tls_mode = TlsMode::from_str(&config.tls.mode)
         .context("Invalid TLS mode in config")?;
@@ -246,7 +374,9 @@ async fn async_main(args: Args, config: Config) -> Result<()> {
     };
 
 
-    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    // SIGTERM/SIGINT drain and stop every task, SIGHUP refreshes the access rules
+    let access_rules::SignalHandles { shutdown: shutdown_rx, refresh_tx, refresh_rx } =
+        access_rules::install_signal_handlers().context("failed to install signal handlers")?;
 
     // Initialize Redis manager if Redis URL is provided
     if !config.redis.url.is_empty() {
@@ -376,9 +506,55 @@ async fn async_main(args: Args, config: Config) -> Result<()> {
     let access_rules_handle = if !state.skels.is_empty() {
         let skels = state.skels.clone();
         let api_key = config.arxignis.api_key.clone();
-        let base_url = config.arxignis.base_url.clone();
+        let mut base_urls = vec![config.arxignis.base_url.clone()];
+        for url in &config.arxignis.fallback_base_urls {
+            if !base_urls.contains(url) {
+                base_urls.push(url.clone());
+            }
+        }
         let shutdown = shutdown_rx.clone();
-        Some(access_rules::start_access_rules_updater(base_url, skels, api_key, shutdown))
+
+        // Temporary bans expire on their own task, independently of the sync cycle
+        let temporary_bans = Arc::new(
+            firewall::TemporaryBans::new(skels.clone()).with_events(access_rules_config.event_sink.clone()),
+        );
+        tokio::spawn(temporary_bans.clone().run_expiry(shutdown_rx.clone()));
+        access_rules_config.temporary_bans = Some(temporary_bans.clone());
+
+        let snapshot_tx = match (&control_api_token, &manual_rules) {
+            (Some(token), Some(manual_rules)) => {
+                let (snapshot_tx, snapshot_rx) = watch::channel(access_rules::RuleSnapshot::default());
+                let control_state = Arc::new(control_api::ControlApiState::new(
+                    token.clone(),
+                    manual_rules.clone(),
+                    apply_pause.clone(),
+                    temporary_bans,
+                    skels.clone(),
+                    refresh_tx,
+                    snapshot_rx,
+                ).with_recent_rejects(access_rules_config.recent_rejects.clone())
+                .with_export_dir(config.control_api.export_dir.as_ref().map(std::path::PathBuf::from))
+                .with_ban_file_dir(config.control_api.ban_file_dir.as_ref().map(std::path::PathBuf::from))
+                .with_dry_run(access_rules_config.dry_run)
+                .with_event_sink(access_rules_config.event_sink.clone()));
+                let control_config = config.control_api.clone();
+                let control_shutdown = shutdown_rx.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = control_api::start_control_api_server(control_config, control_state, control_shutdown).await {
+                        log::error!("Control API server error: {}", err);
+                    }
+                });
+                Some(snapshot_tx)
+            }
+            _ => None,
+        };
+        match access_rules::start_access_rules_updater(base_urls, skels, api_key, access_rules_config, snapshot_tx, Some(refresh_rx), shutdown).await {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                log::error!("Not starting access rules updater: {}", e);
+                None
+            }
+        }
     } else {
         log::info!("Skipping access rules updater (XDP disabled)");
         None
@@ -632,9 +808,24 @@ async fn async_main(args: Args, config: Config) -> Result<()> {
         })
     };
 
-    signal::ctrl_c().await?;
+    // Start Prometheus metrics server
+    let metrics_handle = {
+        let shutdown = shutdown_rx.clone();
+        let metrics_config = config.metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::start_metrics_server(metrics_config, shutdown).await {
+                log::error!("Metrics server error: {}", err);
+            }
+        })
+    };
+
+    let mut shutdown = shutdown_rx.clone();
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            break;
+        }
+    }
     log::info!("Shutdown signal received, stopping servers...");
-    let _ = shutdown_tx.send(true);
 
     if let Some(handle) = tls_handle
         && let Err(err) = handle.await
@@ -676,12 +867,20 @@ async fn async_main(args: Args, config: Config) -> Result<()> {
         log::error!("health-check task join error: {err}");
     }
 
-    // Detach XDP programs from interfaces
+    if let Err(err) = metrics_handle.await {
+        log::error!("metrics task join error: {err}");
+    }
+
+    // Detach the filter programs from interfaces
     if !ifindices.is_empty() {
-        log::info!("Detaching XDP programs from {} interfaces...", ifindices.len());
-        for ifindex in ifindices {
-            if let Err(e) = bpf_utils::bpf_detach_from_xdp(ifindex) {
-                log::error!("Failed to detach XDP from interface {}: {}", ifindex, e);
+        log::info!("Detaching BPF filters from {} interfaces...", ifindices.len());
+        for (ifindex, skel) in ifindices.into_iter().zip(&skels) {
+            let detached = match attach_mode {
+                bpf_utils::AttachMode::Tc => bpf_utils::bpf_detach_from_tc(skel, ifindex),
+                _ => bpf_utils::bpf_detach_from_xdp(ifindex),
+            };
+            if let Err(e) = detached {
+                log::error!("Failed to detach BPF filter from interface {}: {}", ifindex, e);
             }
         }
     }
@@ -0,0 +1,426 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+use crate::cli::MetricsConfig;
+
+/// Upper bounds, in seconds, of the update duration histogram buckets
+const UPDATE_DURATION_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Fixed-bucket histogram of durations; bucket counts are stored per bucket and made
+/// cumulative when rendered
+#[derive(Debug)]
+pub struct DurationHistogram {
+    buckets: [AtomicU64; UPDATE_DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl DurationHistogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; UPDATE_DURATION_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(idx) = UPDATE_DURATION_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Occupancy of one BPF rule map of one interface's skeleton
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapUtilization {
+    pub iface: String,
+    pub map: &'static str,
+    pub used: u64,
+    pub capacity: u64,
+}
+
+impl MapUtilization {
+    /// Used entries as a percentage of capacity; 0 for a zero-capacity map
+    pub fn percent(&self) -> u64 {
+        if self.capacity == 0 { 0 } else { self.used * 100 / self.capacity }
+    }
+}
+
+/// Access rules updater metrics, exported in Prometheus text format
+#[derive(Debug)]
+pub struct AccessRulesMetrics {
+    pub rules_ipv4_total: AtomicU64,
+    pub rules_ipv6_total: AtomicU64,
+    pub update_cycles_total: AtomicU64,
+    pub update_failures_total: AtomicU64,
+    pub bans_applied_total: AtomicU64,
+    pub unbans_applied_total: AtomicU64,
+    /// Block entries listed again within `oscillation_window` of their unban
+    pub oscillations_total: AtomicU64,
+    /// Packets dropped per installed block rule, keyed by CIDR, as of the last update cycle
+    pub rule_hits: Mutex<Vec<(String, u64)>>,
+    /// Entries in use and `max_entries` per BPF rule map, as of the last apply
+    pub map_utilization: Mutex<Vec<MapUtilization>>,
+    /// Time spent fetching the config API per update cycle
+    pub update_fetch_duration: DurationHistogram,
+    /// Time spent parsing and applying rules to the BPF maps per update cycle
+    pub update_apply_duration: DurationHistogram,
+    /// Seconds since the Unix epoch of the last update cycle that succeeded; 0 before the first
+    pub last_success_unix: AtomicU64,
+    /// Update cycles that failed since the last success
+    pub consecutive_failures: AtomicU64,
+    /// Seconds since the Unix epoch until which the apply circuit breaker holds back map
+    /// writes; 0 while it is closed
+    pub apply_breaker_open_until_unix: AtomicU64,
+}
+
+impl AccessRulesMetrics {
+    const fn new() -> Self {
+        Self {
+            rules_ipv4_total: AtomicU64::new(0),
+            rules_ipv6_total: AtomicU64::new(0),
+            update_cycles_total: AtomicU64::new(0),
+            update_failures_total: AtomicU64::new(0),
+            bans_applied_total: AtomicU64::new(0),
+            unbans_applied_total: AtomicU64::new(0),
+            oscillations_total: AtomicU64::new(0),
+            rule_hits: Mutex::new(Vec::new()),
+            map_utilization: Mutex::new(Vec::new()),
+            update_fetch_duration: DurationHistogram::new(),
+            update_apply_duration: DurationHistogram::new(),
+            last_success_unix: AtomicU64::new(0),
+            consecutive_failures: AtomicU64::new(0),
+            apply_breaker_open_until_unix: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the outcome of an update cycle for the health endpoint
+    pub fn record_cycle(&self, succeeded: bool) {
+        if succeeded {
+            self.last_success_unix.store(unix_now(), Ordering::Relaxed);
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+        } else {
+            self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Publish the apply circuit breaker state: open for `open_for` from now, or closed
+    pub fn set_apply_breaker(&self, open_for: Option<Duration>) {
+        let until = open_for.map_or(0, |cooldown| unix_now() + cooldown.as_secs());
+        self.apply_breaker_open_until_unix.store(until, Ordering::Relaxed);
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+static ACCESS_RULES_METRICS: AccessRulesMetrics = AccessRulesMetrics::new();
+
+/// Get the global access rules metrics
+pub fn access_rules() -> &'static AccessRulesMetrics {
+    &ACCESS_RULES_METRICS
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, series: &[(&str, &DurationHistogram)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (phase, histogram) in series {
+        let mut cumulative = 0;
+        for (le, bucket) in UPDATE_DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{phase=\"{}\",le=\"{}\"}} {}", name, phase, le, cumulative);
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{{phase=\"{}\",le=\"+Inf\"}} {}", name, phase, count);
+        let _ = writeln!(out, "{}_sum{{phase=\"{}\"}} {}", name, phase, sum);
+        let _ = writeln!(out, "{}_count{{phase=\"{}\"}} {}", name, phase, count);
+    }
+}
+
+fn write_rule_hits(out: &mut String, hits: &[(String, u64)]) {
+    let name = "moat_rule_hits_total";
+    let _ = writeln!(out, "# HELP {} Packets dropped by each installed block rule", name);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (rule, count) in hits {
+        let _ = writeln!(out, "{}{{rule=\"{}\"}} {}", name, rule, count);
+    }
+}
+
+fn write_map_utilization(out: &mut String, maps: &[MapUtilization]) {
+    let series: [(&str, &str, fn(&MapUtilization) -> u64); 2] = [
+        ("moat_map_capacity", "Maximum number of entries of each BPF rule map per interface", |m| m.capacity),
+        ("moat_map_used", "Number of entries in use in each BPF rule map per interface", |m| m.used),
+    ];
+    for (name, help, value) in series {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for map in maps {
+            let _ = writeln!(out, "{}{{iface=\"{}\",map=\"{}\"}} {}", name, map.iface, map.map, value(map));
+        }
+    }
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let m = access_rules();
+    let mut out = String::new();
+
+    write_metric(&mut out, "moat_rules_ipv4_total", "gauge",
+        "Number of IPv4 block rules currently applied",
+        m.rules_ipv4_total.load(Ordering::Relaxed));
+    write_metric(&mut out, "moat_rules_ipv6_total", "gauge",
+        "Number of IPv6 block rules currently applied",
+        m.rules_ipv6_total.load(Ordering::Relaxed));
+    write_metric(&mut out, "moat_update_cycles_total", "counter",
+        "Number of access rules update cycles run",
+        m.update_cycles_total.load(Ordering::Relaxed));
+    write_metric(&mut out, "moat_update_failures_total", "counter",
+        "Number of access rules update cycles that failed",
+        m.update_failures_total.load(Ordering::Relaxed));
+    write_metric(&mut out, "moat_bans_applied_total", "counter",
+        "Number of ban operations successfully written to BPF maps",
+        m.bans_applied_total.load(Ordering::Relaxed));
+    write_metric(&mut out, "moat_unbans_applied_total", "counter",
+        "Number of unban operations successfully written to BPF maps",
+        m.unbans_applied_total.load(Ordering::Relaxed));
+    write_metric(&mut out, "moat_rule_oscillations_total", "counter",
+        "Number of block entries listed again shortly after they were unbanned",
+        m.oscillations_total.load(Ordering::Relaxed));
+    write_histogram(&mut out, "moat_update_duration_seconds",
+        "Duration of access rules update cycle phases",
+        &[("fetch", &m.update_fetch_duration), ("apply", &m.update_apply_duration)]);
+    if let Ok(hits) = m.rule_hits.lock() {
+        write_rule_hits(&mut out, &hits);
+    }
+    if let Ok(maps) = m.map_utilization.lock() {
+        write_map_utilization(&mut out, &maps);
+    }
+
+    out
+}
+
+/// Health of the access rules sync as of `now_unix`: healthy when the last successful
+/// cycle is at most `max_staleness` old and the apply circuit breaker is not holding
+/// back map writes. Returns the verdict and the JSON body.
+fn health(m: &AccessRulesMetrics, now_unix: u64, max_staleness: Duration) -> (bool, serde_json::Value) {
+    let last_success = m.last_success_unix.load(Ordering::Relaxed);
+    let breaker_until = m.apply_breaker_open_until_unix.load(Ordering::Relaxed);
+    let breaker = match breaker_until {
+        0 => "closed",
+        until if now_unix < until => "open",
+        _ => "half_open",
+    };
+    let healthy = last_success > 0
+        && now_unix.saturating_sub(last_success) <= max_staleness.as_secs()
+        && breaker != "open";
+    let body = serde_json::json!({
+        "healthy": healthy,
+        "last_success_unix": (last_success > 0).then_some(last_success),
+        "consecutive_failures": m.consecutive_failures.load(Ordering::Relaxed),
+        "apply_breaker": breaker,
+        "apply_breaker_open_until_unix": (breaker_until > 0).then_some(breaker_until),
+        "rules_ipv4": m.rules_ipv4_total.load(Ordering::Relaxed),
+        "rules_ipv6": m.rules_ipv6_total.load(Ordering::Relaxed),
+    });
+    (healthy, body)
+}
+
+async fn handle_request(req: Request<Incoming>, max_staleness: Duration) -> Result<Response<Full<Bytes>>> {
+    if req.uri().path() == "/healthz" && req.method() == Method::GET {
+        let (healthy, body) = health(access_rules(), unix_now(), max_staleness);
+        let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        return Ok(Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap());
+    }
+
+    if req.uri().path() != "/metrics" {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::from("Not Found")))
+            .unwrap());
+    }
+
+    if req.method() != Method::GET {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Full::new(Bytes::from("Method Not Allowed")))
+            .unwrap());
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Full::new(Bytes::from(render())))
+        .unwrap())
+}
+
+/// Start the Prometheus metrics server if enabled
+pub async fn start_metrics_server(
+    config: MetricsConfig,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> Result<()> {
+    if !config.enabled {
+        log::info!("Metrics server disabled");
+        return Ok(());
+    }
+
+    let addr = config.bind.parse::<SocketAddr>()
+        .map_err(|e| anyhow!("Invalid metrics bind address '{}': {}", config.bind, e))?;
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow!("Failed to bind metrics server to {}: {}", addr, e))?;
+
+    log::info!("Metrics server listening on http://{}/metrics", addr);
+    let max_staleness = Duration::from_secs(config.health_max_staleness_secs);
+
+    loop {
+        tokio::select! {
+            result = listener.accept() => {
+                match result {
+                    Ok((stream, _)) => {
+                        tokio::spawn(async move {
+                            let io = TokioIo::new(stream);
+                            if let Err(err) = http1::Builder::new()
+                                .serve_connection(io, service_fn(move |req| handle_request(req, max_staleness)))
+                                .await
+                            {
+                                log::error!("Metrics connection error: {}", err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        log::error!("Metrics accept error: {}", err);
+                    }
+                }
+            }
+            _ = shutdown_rx.changed() => {
+                if *shutdown_rx.borrow() {
+                    log::info!("Metrics server shutting down");
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_contains_all_metrics() {
+        access_rules().update_cycles_total.fetch_add(1, Ordering::Relaxed);
+        let output = render();
+
+        for name in [
+            "moat_rules_ipv4_total",
+            "moat_rules_ipv6_total",
+            "moat_update_cycles_total",
+            "moat_update_failures_total",
+            "moat_bans_applied_total",
+            "moat_unbans_applied_total",
+        ] {
+            assert!(output.contains(&format!("# TYPE {} ", name)));
+        }
+        assert!(output.contains("# TYPE moat_update_cycles_total counter"));
+        assert!(output.contains("# TYPE moat_rule_hits_total counter"));
+        assert!(output.contains("# TYPE moat_update_duration_seconds histogram"));
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let histogram = DurationHistogram::new();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(200));
+        histogram.observe(Duration::from_secs(120));
+
+        let mut out = String::new();
+        write_histogram(&mut out, "d", "help", &[("fetch", &histogram)]);
+        assert!(out.contains("d_bucket{phase=\"fetch\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("d_bucket{phase=\"fetch\",le=\"0.25\"} 2\n"));
+        assert!(out.contains("d_bucket{phase=\"fetch\",le=\"30\"} 2\n"));
+        assert!(out.contains("d_bucket{phase=\"fetch\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("d_sum{phase=\"fetch\"} 120.203\n"));
+        assert!(out.contains("d_count{phase=\"fetch\"} 3\n"));
+    }
+
+    #[test]
+    fn test_health_tracks_staleness_and_failures() {
+        let m = AccessRulesMetrics::new();
+        let window = Duration::from_secs(60);
+        let (healthy, body) = health(&m, 1_000, window);
+        assert!(!healthy);
+        assert!(body["last_success_unix"].is_null());
+
+        m.last_success_unix.store(1_000, Ordering::Relaxed);
+        m.consecutive_failures.store(2, Ordering::Relaxed);
+        let (healthy, body) = health(&m, 1_060, window);
+        assert!(healthy);
+        assert_eq!(body["last_success_unix"], 1_000);
+        assert_eq!(body["consecutive_failures"], 2);
+
+        assert!(!health(&m, 1_061, window).0);
+
+        m.apply_breaker_open_until_unix.store(1_100, Ordering::Relaxed);
+        let (healthy, body) = health(&m, 1_050, window);
+        assert!(!healthy);
+        assert_eq!(body["apply_breaker"], "open");
+        let (_, body) = health(&m, 1_100, window);
+        assert_eq!(body["apply_breaker"], "half_open");
+
+        m.record_cycle(true);
+        assert_eq!(m.consecutive_failures.load(Ordering::Relaxed), 0);
+        m.record_cycle(false);
+        assert_eq!(m.consecutive_failures.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_write_map_utilization_labels_each_map() {
+        let mut out = String::new();
+        let usage = MapUtilization { iface: "eth0".to_string(), map: "banned_ips", used: 9, capacity: 10 };
+        write_map_utilization(&mut out, std::slice::from_ref(&usage));
+        assert!(out.contains("# TYPE moat_map_capacity gauge\n"));
+        assert!(out.contains("moat_map_capacity{iface=\"eth0\",map=\"banned_ips\"} 10\n"));
+        assert!(out.contains("moat_map_used{iface=\"eth0\",map=\"banned_ips\"} 9\n"));
+        assert_eq!(usage.percent(), 90);
+    }
+
+    #[test]
+    fn test_write_rule_hits_labels_each_rule() {
+        let mut out = String::new();
+        write_rule_hits(&mut out, &[("10.0.0.0/8".to_string(), 42), ("2001:db8::/32".to_string(), 0)]);
+        assert!(out.contains("moat_rule_hits_total{rule=\"10.0.0.0/8\"} 42\n"));
+        assert!(out.contains("moat_rule_hits_total{rule=\"2001:db8::/32\"} 0\n"));
+    }
+}
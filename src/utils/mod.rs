@@ -1,11 +1,180 @@
 pub mod bpf_utils {
+    use std::fmt;
     use std::net::{Ipv4Addr, Ipv6Addr};
     use std::os::fd::AsFd;
+    use std::str::FromStr;
 
-    use crate::bpf::{self, FilterSkel};
-    use libbpf_rs::{Xdp, XdpFlags};
+    use crate::access_rules::parse::glob_match;
+    use crate::bpf::{self, FilterSkel, OpenFilterSkel};
+    use crate::cli::BpfMapTuning;
+    use libbpf_rs::{TC_INGRESS, TcHookBuilder, Xdp, XdpFlags};
     use nix::libc;
 
+    /// tc filter handle and priority of the clsact ingress attachment
+    const TC_HANDLE: u32 = 1;
+    const TC_PRIORITY: u32 = 1;
+
+    /// Interfaces to attach the filter to: every `requested` name, where a name with `*`
+    /// or `?` stands for each of the `available` interfaces it matches, minus anything
+    /// matching an `exclude` pattern. Exclusion wins even over an explicitly named
+    /// interface, so a management NIC listed there is never filtered.
+    pub fn select_interfaces(requested: &[String], exclude: &[String], available: &[String]) -> Vec<String> {
+        let is_pattern = |name: &str| name.contains(['*', '?']);
+        let mut selected: Vec<String> = Vec::new();
+        for name in requested.iter().map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            let matches: Vec<&str> = if is_pattern(name) {
+                available.iter().map(String::as_str).filter(|iface| glob_match(name, iface)).collect()
+            } else {
+                vec![name]
+            };
+            if matches.is_empty() {
+                log::warn!("interface pattern '{}' matches no interface on this host", name);
+            }
+            for iface in matches {
+                if let Some(pattern) = exclude.iter().find(|pattern| glob_match(pattern.trim(), iface)) {
+                    log::info!("not attaching to interface {}: excluded by '{}'", iface, pattern);
+                    continue;
+                }
+                if !selected.iter().any(|s| s == iface) {
+                    selected.push(iface.to_string());
+                }
+            }
+        }
+        selected
+    }
+
+    /// Names of the host's network interfaces, for expanding interface patterns
+    pub fn host_interfaces() -> Vec<String> {
+        match nix::net::if_::if_nameindex() {
+            Ok(interfaces) => interfaces.iter().map(|iface| iface.name().to_string_lossy().into_owned()).collect(),
+            Err(e) => {
+                log::warn!("failed to list network interfaces: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Map creation flags from uapi/linux/bpf.h
+    const BPF_F_NO_PREALLOC: u32 = 1 << 0;
+    const BPF_F_NO_COMMON_LRU: u32 = 1 << 1;
+
+    /// Set the creation flags of the tunable hash maps on an opened, not yet loaded skeleton
+    pub fn tune_maps(open: &mut OpenFilterSkel<'_>, tuning: &BpfMapTuning) -> libbpf_rs::Result<()> {
+        for map in [&mut open.maps.rule_hits, &mut open.maps.rule_hits_v6] {
+            let flags = with_flag(map.map_flags(), BPF_F_NO_PREALLOC, !tuning.prealloc_rule_hits);
+            map.set_map_flags(flags)?;
+        }
+        for map in [&mut open.maps.rate_limit_buckets, &mut open.maps.rate_limit_buckets_v6] {
+            let flags = with_flag(map.map_flags(), BPF_F_NO_COMMON_LRU, tuning.per_cpu_lru);
+            map.set_map_flags(flags)?;
+        }
+        Ok(())
+    }
+
+    fn with_flag(flags: u32, flag: u32, set: bool) -> u32 {
+        if set { flags | flag } else { flags & !flag }
+    }
+
+    /// Where the filter program is attached
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum AttachMode {
+        /// Hardware offload, then driver, then generic XDP, whichever the NIC takes first
+        Auto,
+        /// Driver (native) XDP, falling back to generic XDP when the driver lacks support
+        Native,
+        /// Generic (SKB) XDP, available on every interface
+        Generic,
+        /// tc clsact ingress, for drivers and kernels where XDP is unavailable
+        Tc,
+    }
+
+    impl FromStr for AttachMode {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s.trim().to_ascii_lowercase().as_str() {
+                "auto" => Ok(Self::Auto),
+                "native" | "xdp" | "drv" => Ok(Self::Native),
+                "generic" | "skb" => Ok(Self::Generic),
+                "tc" => Ok(Self::Tc),
+                other => Err(format!("unknown attach mode '{}', expected auto, native, generic or tc", other)),
+            }
+        }
+    }
+
+    impl fmt::Display for AttachMode {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(match self {
+                Self::Auto => "auto",
+                Self::Native => "native",
+                Self::Generic => "generic",
+                Self::Tc => "tc",
+            })
+        }
+    }
+
+    /// Attach the filter in the requested mode and return the mode actually used, which
+    /// is generic when native XDP had to fall back
+    pub fn bpf_attach(skel: &mut FilterSkel<'_>, ifindex: i32, mode: AttachMode) -> Result<AttachMode, Box<dyn std::error::Error>> {
+        match mode {
+            AttachMode::Auto => bpf_attach_to_xdp(skel, ifindex).map(|()| AttachMode::Auto),
+            AttachMode::Native => {
+                let xdp = Xdp::new(skel.progs.arxignis_xdp_filter.as_fd().into());
+                match xdp.attach(ifindex, XdpFlags::DRV_MODE) {
+                    Ok(()) => {
+                        log::info!("XDP program attached in driver mode");
+                        Ok(AttachMode::Native)
+                    }
+                    Err(e) => {
+                        log::warn!("driver mode XDP not supported on interface {}, falling back to generic mode: {}", ifindex, e);
+                        xdp.attach(ifindex, XdpFlags::SKB_MODE)?;
+                        log::info!("XDP program attached in generic SKB mode");
+                        Ok(AttachMode::Generic)
+                    }
+                }
+            }
+            AttachMode::Generic => {
+                let xdp = Xdp::new(skel.progs.arxignis_xdp_filter.as_fd().into());
+                xdp.attach(ifindex, XdpFlags::SKB_MODE)?;
+                log::info!("XDP program attached in generic SKB mode");
+                Ok(AttachMode::Generic)
+            }
+            AttachMode::Tc => {
+                bpf_attach_to_tc(skel, ifindex)?;
+                Ok(AttachMode::Tc)
+            }
+        }
+    }
+
+    /// Attach the tc variant of the filter to the clsact ingress hook, creating the
+    /// qdisc if the interface has none yet
+    pub fn bpf_attach_to_tc(skel: &mut FilterSkel<'_>, ifindex: i32) -> Result<(), Box<dyn std::error::Error>> {
+        let mut hook = TcHookBuilder::new(skel.progs.arxignis_tc_filter.as_fd())
+            .ifindex(ifindex)
+            .replace(true)
+            .handle(TC_HANDLE)
+            .priority(TC_PRIORITY)
+            .hook(TC_INGRESS);
+        // An existing clsact qdisc is reused
+        hook.create()?;
+        hook.attach()?;
+        log::info!("filter program attached to tc clsact ingress");
+        Ok(())
+    }
+
+    /// Remove the tc ingress filter installed by `bpf_attach_to_tc`. The clsact qdisc is
+    /// left in place since other filters may share it.
+    pub fn bpf_detach_from_tc(skel: &FilterSkel<'_>, ifindex: i32) -> Result<(), Box<dyn std::error::Error>> {
+        let mut hook = TcHookBuilder::new(skel.progs.arxignis_tc_filter.as_fd())
+            .ifindex(ifindex)
+            .handle(TC_HANDLE)
+            .priority(TC_PRIORITY)
+            .hook(TC_INGRESS);
+        hook.detach()?;
+        log::info!("tc filter detached from interface");
+        Ok(())
+    }
+
     pub fn bpf_attach_to_xdp(
         skel: &mut FilterSkel<'_>,
         ifindex: i32,
@@ -68,6 +237,48 @@ pub mod bpf_utils {
         my_ip_key_bytes.to_vec().into_boxed_slice()
     }
 
+    /// Key for the port-scoped ban maps; the trie prefix covers the port plus `prefixlen`
+    /// address bits
+    pub fn convert_ip_port_into_bpf_map_key_bytes(ip: Ipv4Addr, prefixlen: u32, port: u16) -> Box<[u8]> {
+        let my_key: bpf::types::lpm_port_key = bpf::types::lpm_port_key {
+            prefixlen: 16 + prefixlen,
+            port: port.to_be(),
+            addr: ip.octets(),
+            // Zero the trailing struct padding
+            ..Default::default()
+        };
+
+        let my_key_bytes = unsafe { plain::as_bytes(&my_key) };
+        my_key_bytes.to_vec().into_boxed_slice()
+    }
+
+    pub fn convert_ipv6_port_into_bpf_map_key_bytes(ip: Ipv6Addr, prefixlen: u32, port: u16) -> Box<[u8]> {
+        let my_key: bpf::types::lpm_port_key_v6 = bpf::types::lpm_port_key_v6 {
+            prefixlen: 16 + prefixlen,
+            port: port.to_be(),
+            addr: ip.octets(),
+            // Zero the trailing struct padding
+            ..Default::default()
+        };
+
+        let my_key_bytes = unsafe { plain::as_bytes(&my_key) };
+        my_key_bytes.to_vec().into_boxed_slice()
+    }
+
+    /// Decode an `lpm_key` read back from a BPF map into (address, prefix length)
+    pub fn convert_bpf_map_key_bytes_into_ip(bytes: &[u8]) -> Option<(Ipv4Addr, u32)> {
+        let mut key = bpf::types::lpm_key::default();
+        plain::copy_from_bytes(&mut key, bytes).ok()?;
+        Some((Ipv4Addr::from(u32::from_be(key.addr)), key.prefixlen))
+    }
+
+    /// Decode an `lpm_key_v6` read back from a BPF map into (address, prefix length)
+    pub fn convert_bpf_map_key_bytes_into_ipv6(bytes: &[u8]) -> Option<(Ipv6Addr, u32)> {
+        let mut key = bpf::types::lpm_key_v6::default();
+        plain::copy_from_bytes(&mut key, bytes).ok()?;
+        Some((Ipv6Addr::from(key.addr), key.prefixlen))
+    }
+
     pub fn bpf_detach_from_xdp(ifindex: i32) -> Result<(), Box<dyn std::error::Error>> {
         // Create a dummy XDP instance for detaching
         // We need to query first to get the existing program ID
@@ -92,92 +303,42 @@ pub mod bpf_utils {
         unsafe { libc::close(dummy_fd); }
         Err("Failed to detach XDP program from interface".into())
     }
-}
 
-pub mod http_utils {
-    use std::net::IpAddr;
+    #[cfg(test)]
+    mod tests {
+        use super::*;
 
-    /// Parse IP or CIDR notation into network and prefix length
-    pub fn parse_ip_or_cidr(entry: &str) -> Option<(IpAddr, u8)> {
-    let s = entry.trim();
-    if s.is_empty() {
-        return None;
-    }
-
-    if s.contains('/') {
-        let mut parts = s.split('/');
-        let ip_str = parts.next()?.trim();
-        let prefix_str = parts.next()?.trim();
-        if parts.next().is_some() {
-            return None; // malformed
+        fn names(names: &[&str]) -> Vec<String> {
+            names.iter().map(|name| name.to_string()).collect()
         }
 
-        let ip = ip_str.parse::<IpAddr>().ok()?;
-        let prefix: u8 = prefix_str.parse().ok()?;
-
-        // Validate prefix length
-        match ip {
-            IpAddr::V4(_) => {
-                if prefix > 32 {
-                    return None;
-                }
-            }
-            IpAddr::V6(_) => {
-                if prefix > 128 {
-                    return None;
-                }
-            }
+        #[test]
+        fn test_select_interfaces_expands_patterns() {
+            let available = names(&["lo", "eth0", "eth1", "ens3", "wlan0"]);
+            assert_eq!(select_interfaces(&names(&["eth*", "ens?"]), &[], &available), names(&["eth0", "eth1", "ens3"]));
+            // Plain names are taken as given, even when not listed on this host
+            assert_eq!(select_interfaces(&names(&[" bond0 ", ""]), &[], &available), names(&["bond0"]));
         }
 
-        Some((ip, prefix))
-    } else {
-        // Single IP address
-        let ip = s.parse::<IpAddr>().ok()?;
-        let prefix = match ip {
-            IpAddr::V4(_) => 32,
-            IpAddr::V6(_) => 128,
-        };
-        Some((ip, prefix))
-    }
-}
-
-    /// Check if an IP address is within a CIDR range
-    pub fn is_ip_in_cidr(ip: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
-    match (ip, network) {
-        (IpAddr::V4(ip), IpAddr::V4(network)) => {
-            let ip_u32 = u32::from(ip);
-            let net_u32 = u32::from(network);
-            let mask = if prefix_len == 0 {
-                0
-            } else {
-                u32::MAX.checked_shl((32 - prefix_len) as u32).unwrap_or(0)
-            };
-            (ip_u32 & mask) == (net_u32 & mask)
-        }
-        (IpAddr::V6(ip), IpAddr::V6(network)) => {
-            let ip_bytes = ip.octets();
-            let net_bytes = network.octets();
-            let prefix_bytes = prefix_len / 8;
-            let remaining_bits = prefix_len % 8;
-
-            // Check full bytes
-            for i in 0..prefix_bytes as usize {
-                if ip_bytes[i] != net_bytes[i] {
-                    return false;
-                }
-            }
+        #[test]
+        fn test_select_interfaces_exclusion_beats_explicit_names() {
+            let available = names(&["eth0", "eth1", "mgmt0"]);
+            let selected = select_interfaces(&names(&["eth*", "mgmt0"]), &names(&["mgmt*", " eth1 "]), &available);
+            assert_eq!(selected, names(&["eth0"]));
+        }
 
-            // Check remaining bits
-            if remaining_bits > 0 && prefix_bytes < 16 {
-                let mask = 0xFF << (8 - remaining_bits);
-                if (ip_bytes[prefix_bytes as usize] & mask) != (net_bytes[prefix_bytes as usize] & mask) {
-                    return false;
-                }
-            }
+        #[test]
+        fn test_select_interfaces_dedups_in_request_order() {
+            let available = names(&["eth0", "eth1"]);
+            let selected = select_interfaces(&names(&["eth1", "eth*", "eth0"]), &[], &available);
+            assert_eq!(selected, names(&["eth1", "eth0"]));
+        }
 
-            true
+        #[test]
+        fn test_select_interfaces_unmatched_pattern_selects_nothing() {
+            let available = names(&["eth0"]);
+            assert!(select_interfaces(&names(&["wg*"]), &[], &available).is_empty());
+            assert_eq!(select_interfaces(&names(&["wg*", "eth0"]), &[], &available), names(&["eth0"]));
         }
-        _ => false, // Different IP versions
     }
 }
-}
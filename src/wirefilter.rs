@@ -458,7 +458,7 @@ pub async fn init_config(base_url: String, api_key: String) -> anyhow::Result<()
     const RETRY_DELAY_MS: u64 = 1000;
 
     loop {
-        match fetch_config(base_url.clone(), api_key.clone()).await {
+        match fetch_config(base_url.clone(), api_key.clone(), None).await {
             Ok(config_response) => {
                 let filter = HttpFilter::new_from_config(&config_response.config)?;
                 set_global_http_filter(filter)?;
@@ -488,7 +488,7 @@ pub async fn update_with_config(base_url: String, api_key: String) -> anyhow::Re
     const RETRY_DELAY_MS: u64 = 1000;
 
     loop {
-        match fetch_config(base_url.clone(), api_key.clone()).await {
+        match fetch_config(base_url.clone(), api_key.clone(), None).await {
             Ok(config_response) => {
                 if let Some(filter) = HTTP_FILTER.get() {
                     filter.update_from_config(&config_response.config)?;